@@ -1,18 +1,18 @@
-use std::collections::HashMap;
 use std::env;
 use std::path::Path;
 use std::process::Command;
-use std::sync::OnceLock;
 
 use anyhow::{anyhow, Context, Result};
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use fn_error_context::context;
-use regex::Regex;
 use serde::Deserialize;
 
 use bootc_utils::CommandRunExt;
 
+pub mod parttable;
+pub mod topology;
+
 #[derive(Debug, Deserialize)]
 struct DevicesOutput {
     blockdevices: Vec<Device>,
@@ -240,50 +240,19 @@ impl Drop for LoopbackDevice {
     }
 }
 
-/// Parse key-value pairs from lsblk --pairs.
-/// Newer versions of lsblk support JSON but the one in CentOS 7 doesn't.
-fn split_lsblk_line(line: &str) -> HashMap<String, String> {
-    static REGEX: OnceLock<Regex> = OnceLock::new();
-    let regex = REGEX.get_or_init(|| Regex::new(r#"([A-Z-_]+)="([^"]+)""#).unwrap());
-    let mut fields: HashMap<String, String> = HashMap::new();
-    for cap in regex.captures_iter(line) {
-        fields.insert(cap[1].to_string(), cap[2].to_string());
-    }
-    fields
-}
-
-/// This is a bit fuzzy, but... this function will return every block device in the parent
-/// hierarchy of `device` capable of containing other partitions. So e.g. parent devices of type
-/// "part" doesn't match, but "disk" and "mpath" does.
+/// This function will return every block device in the parent hierarchy of `device`
+/// capable of containing partitions, i.e. disks, NVMe namespaces, loop devices and
+/// multipath devices (but not the individual paths of a multipath device).
 pub fn find_parent_devices(device: &str) -> Result<Vec<String>> {
-    let output = Command::new("lsblk")
-        // Older lsblk, e.g. in CentOS 7.6, doesn't support PATH, but --paths option
-        .arg("--pairs")
-        .arg("--paths")
-        .arg("--inverse")
-        .arg("--output")
-        .arg("NAME,TYPE")
-        .arg(device)
-        .run_get_string()?;
-    let mut parents = Vec::new();
-    // skip first line, which is the device itself
-    for line in output.lines().skip(1) {
-        let dev = split_lsblk_line(line);
-        let name = dev
-            .get("NAME")
-            .with_context(|| format!("device in hierarchy of {device} missing NAME"))?;
-        let kind = dev
-            .get("TYPE")
-            .with_context(|| format!("device in hierarchy of {device} missing TYPE"))?;
-        if kind == "disk" || kind == "loop" {
-            parents.push(name.clone());
-        } else if kind == "mpath" {
-            parents.push(name.clone());
-            // we don't need to know what disks back the multipath
-            break;
-        }
-    }
-    Ok(parents)
+    let topology = topology::Topology::load()?;
+    let dev = topology.resolve(device.into())?;
+    let r = topology
+        .ancestors(&dev.name)?
+        .into_iter()
+        .filter(|d| d.is_physical())
+        .map(|d| d.path().into_string())
+        .collect();
+    Ok(r)
 }
 
 /// Parse a string into mibibytes
@@ -0,0 +1,883 @@
+//! # Reading and writing partition tables
+//!
+//! A native implementation of GPT and MBR (DOS) partition tables, operating on
+//! anything implementing [`Read`]/[`Write`] and [`Seek`].  This works equally on a
+//! block device or a regular (disk image) file, and does not depend on the version
+//! of util-linux that happens to be installed.
+//!
+//! Note that this only reads and writes the on-disk structures; when operating on a
+//! block device, the caller is responsible for asking the kernel to re-read the
+//! partition table.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8Path;
+use fn_error_context::context;
+use uuid::{uuid, Uuid};
+
+/// The default alignment for partition starts; this is what all modern tooling uses.
+pub const DEFAULT_ALIGNMENT: u64 = 1024 * 1024;
+/// The default logical sector size.
+pub const DEFAULT_SECTOR_SIZE: u64 = 512;
+
+/// EFI System Partition
+pub const ESP_TYPE: Uuid = uuid!("C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+/// BIOS boot partition, used by GRUB on BIOS systems booting from GPT
+pub const BIOS_BOOT_TYPE: Uuid = uuid!("21686148-6449-6E6F-744E-656564454649");
+/// PowerPC PReP boot partition
+pub const PREP_BOOT_TYPE: Uuid = uuid!("9E1A2D38-C612-4316-AA26-8B49521E5A8B");
+/// Generic Linux filesystem data
+pub const LINUX_FILESYSTEM_TYPE: Uuid = uuid!("0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+/// Extended boot loader partition (`/boot`), per the Discoverable Partitions Specification
+pub const XBOOTLDR_TYPE: Uuid = uuid!("BC13C2FF-59E6-4262-A352-B275FD6F7172");
+
+/// The GPT attribute bit marking a partition as bootable by legacy BIOS.
+pub const GPT_ATTR_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+/// The MBR partition type which marks a protective MBR for GPT.
+pub const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+/// The MBR partition type for a Linux filesystem.
+pub const MBR_LINUX: u8 = 0x83;
+/// The MBR partition type for an EFI System Partition.
+pub const MBR_ESP: u8 = 0xEF;
+/// The MBR partition type for PReP boot.
+pub const MBR_PREP_BOOT: u8 = 0x41;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: u32 = 92;
+const GPT_ENTRY_SIZE: u32 = 128;
+/// Entries may be larger than we use (128 << n), but no larger than this.
+const GPT_MAX_ENTRY_SIZE: u32 = 512;
+/// The largest number of partition entries we accept.
+const GPT_MAX_ENTRIES: u32 = 4096;
+const GPT_DEFAULT_ENTRIES: u32 = 128;
+/// Partition names are 36 UTF-16 code units.
+const GPT_NAME_UNITS: usize = 36;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_DISK_SIGNATURE_OFFSET: usize = 440;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_MAX_PARTITIONS: usize = 4;
+/// CHS values are unused; this is the conventional "use LBA" placeholder.
+const MBR_CHS_PLACEHOLDER: [u8; 3] = [0xFE, 0xFF, 0xFF];
+
+/// Compute the (IEEE 802.3) CRC32 as used by GPT.
+fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in buf {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Round `v` up to a multiple of `align`.
+pub fn align_up(v: u64, align: u64) -> u64 {
+    v.div_ceil(align) * align
+}
+
+/// Round `v` down to a multiple of `align`.
+pub fn align_down(v: u64, align: u64) -> u64 {
+    (v / align) * align
+}
+
+fn read_at<F: Read + Seek>(f: &mut F, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    f.seek(SeekFrom::Start(offset))?;
+    f.read_exact(&mut buf)
+        .with_context(|| format!("Reading {len} bytes at offset {offset}"))?;
+    Ok(buf)
+}
+
+fn write_at<F: Write + Seek>(f: &mut F, offset: u64, buf: &[u8]) -> Result<()> {
+    f.seek(SeekFrom::Start(offset))?;
+    f.write_all(buf)
+        .with_context(|| format!("Writing {} bytes at offset {offset}", buf.len()))?;
+    Ok(())
+}
+
+fn device_size<F: Seek>(f: &mut F) -> Result<u64> {
+    Ok(f.seek(SeekFrom::End(0))?)
+}
+
+/// Find the logical sector size of a block device; regular files use [`DEFAULT_SECTOR_SIZE`].
+#[context("Querying sector size of {dev}")]
+pub fn logical_sector_size(dev: &Utf8Path) -> Result<u64> {
+    let meta = dev.metadata()?;
+    if !meta.file_type().is_block_device() {
+        return Ok(DEFAULT_SECTOR_SIZE);
+    }
+    let rdev = meta.rdev();
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0xfff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0xff);
+    let path = format!("/sys/dev/block/{major}:{minor}/queue/logical_block_size");
+    let v = std::fs::read_to_string(&path).with_context(|| format!("Reading {path}"))?;
+    v.trim().parse().with_context(|| format!("Parsing {path}"))
+}
+
+/// A request to create a partition.
+#[derive(Debug, Clone, Default)]
+pub struct NewPartition {
+    /// The partition size in bytes; if unset, the partition fills the remaining space.
+    pub size: Option<u64>,
+    /// The GPT partition name.
+    pub name: String,
+    /// The GPT partition type.
+    pub type_guid: Uuid,
+    /// The unique partition GUID; one will be generated if unset.
+    pub guid: Option<Uuid>,
+    /// The GPT attribute bits.
+    pub attributes: u64,
+}
+
+/// A partition in a GPT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// The partition number, starting at 1
+    pub partno: u32,
+    pub type_guid: Uuid,
+    pub guid: Uuid,
+    pub first_lba: u64,
+    /// The last sector (inclusive)
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    /// The size of this partition, in sectors.
+    pub fn sectors(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+
+    fn parse(partno: u32, buf: &[u8]) -> Result<Option<Self>> {
+        let type_guid = Uuid::from_bytes_le(buf[0..16].try_into().unwrap());
+        if type_guid.is_nil() {
+            return Ok(None);
+        }
+        let guid = Uuid::from_bytes_le(buf[16..32].try_into().unwrap());
+        let units = buf[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect::<Vec<_>>();
+        let name = String::from_utf16(&units)
+            .with_context(|| format!("Invalid name for partition {partno}"))?;
+        let first_lba = le_u64(buf, 32);
+        let last_lba = le_u64(buf, 40);
+        if first_lba > last_lba {
+            anyhow::bail!(
+                "Partition {partno} ends (LBA {last_lba}) before it starts (LBA {first_lba})"
+            );
+        }
+        Ok(Some(Self {
+            partno,
+            type_guid,
+            guid,
+            first_lba,
+            last_lba,
+            attributes: le_u64(buf, 48),
+            name,
+        }))
+    }
+
+    fn serialize(&self, buf: &mut [u8]) {
+        buf[0..16].copy_from_slice(&self.type_guid.to_bytes_le());
+        buf[16..32].copy_from_slice(&self.guid.to_bytes_le());
+        buf[32..40].copy_from_slice(&self.first_lba.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.last_lba.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        for (i, c) in self.name.encode_utf16().enumerate() {
+            buf[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+}
+
+/// A GUID Partition Table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gpt {
+    pub sector_size: u64,
+    /// The total size of the device in sectors
+    pub total_sectors: u64,
+    pub disk_guid: Uuid,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    /// The number of entries in the partition entry array
+    pub num_entries: u32,
+    /// The partitions, sorted by partition number
+    pub partitions: Vec<GptPartition>,
+}
+
+impl Gpt {
+    fn entries_sectors(sector_size: u64, num_entries: u32) -> u64 {
+        (u64::from(num_entries) * u64::from(GPT_ENTRY_SIZE)).div_ceil(sector_size)
+    }
+
+    /// Create a new empty partition table for a device of the given size in bytes.
+    pub fn new(size: u64, sector_size: u64) -> Result<Self> {
+        let total_sectors = size / sector_size;
+        let entries_sectors = Self::entries_sectors(sector_size, GPT_DEFAULT_ENTRIES);
+        // Protective MBR, two headers, and two copies of the entries
+        let overhead = 3 + 2 * entries_sectors;
+        if total_sectors <= overhead {
+            anyhow::bail!("Device too small for GPT: {size} bytes");
+        }
+        Ok(Self {
+            sector_size,
+            total_sectors,
+            disk_guid: Uuid::new_v4(),
+            first_usable_lba: 2 + entries_sectors,
+            last_usable_lba: total_sectors - 2 - entries_sectors,
+            num_entries: GPT_DEFAULT_ENTRIES,
+            partitions: Vec::new(),
+        })
+    }
+
+    /// Find a partition by number (starting at 1).
+    pub fn find_partno(&self, partno: u32) -> Option<&GptPartition> {
+        self.partitions.iter().find(|p| p.partno == partno)
+    }
+
+    /// Find the first free range of at least one aligned sector, returning
+    /// its first (aligned) and last sector.
+    fn find_free(&self, alignment: u64) -> Option<(u64, u64)> {
+        let align_sectors = (alignment / self.sector_size).max(1);
+        let mut used = self
+            .partitions
+            .iter()
+            .map(|p| (p.first_lba, p.last_lba))
+            .collect::<Vec<_>>();
+        used.sort_unstable();
+        let mut start = self.first_usable_lba;
+        for (first, last) in used.into_iter().chain([(self.last_usable_lba + 1, 0)]) {
+            let aligned = align_up(start, align_sectors);
+            if aligned < first {
+                return Some((aligned, first - 1));
+            }
+            start = start.max(last + 1);
+        }
+        None
+    }
+
+    /// Add a partition in the first free space, aligned to `alignment` bytes.
+    pub fn add_partition(&mut self, alignment: u64, new: NewPartition) -> Result<&GptPartition> {
+        if new.type_guid.is_nil() {
+            anyhow::bail!("Invalid nil partition type");
+        }
+        if new.name.encode_utf16().count() > GPT_NAME_UNITS {
+            anyhow::bail!("Partition name too long: {}", new.name);
+        }
+        let partno = (1..=self.num_entries)
+            .find(|n| self.find_partno(*n).is_none())
+            .ok_or_else(|| anyhow!("No free partition entries"))?;
+        let (first_lba, free_last) = self
+            .find_free(alignment)
+            .ok_or_else(|| anyhow!("No free space for partition"))?;
+        let last_lba = match new.size {
+            Some(size) => {
+                let sectors = size / self.sector_size;
+                if sectors == 0 {
+                    anyhow::bail!("Partition size too small: {size}");
+                }
+                let last = first_lba + sectors - 1;
+                if last > free_last {
+                    anyhow::bail!(
+                        "Not enough space for partition of {size} bytes (have {})",
+                        (free_last - first_lba + 1) * self.sector_size
+                    );
+                }
+                last
+            }
+            None => free_last,
+        };
+        let part = GptPartition {
+            partno,
+            type_guid: new.type_guid,
+            guid: new.guid.unwrap_or_else(Uuid::new_v4),
+            first_lba,
+            last_lba,
+            attributes: new.attributes,
+            name: new.name,
+        };
+        let idx = self.partitions.partition_point(|p| p.partno < partno);
+        self.partitions.insert(idx, part);
+        Ok(&self.partitions[idx])
+    }
+
+    fn serialize_entries(&self) -> Vec<u8> {
+        let entry_size = GPT_ENTRY_SIZE as usize;
+        let mut buf = vec![0u8; self.num_entries as usize * entry_size];
+        for p in self.partitions.iter() {
+            let offset = (p.partno as usize - 1) * entry_size;
+            p.serialize(&mut buf[offset..offset + entry_size]);
+        }
+        buf
+    }
+
+    fn serialize_header(
+        &self,
+        my_lba: u64,
+        alternate_lba: u64,
+        entries_lba: u64,
+        entries_crc: u32,
+    ) -> Vec<u8> {
+        let mut buf = vec![0u8; self.sector_size as usize];
+        buf[0..8].copy_from_slice(GPT_SIGNATURE);
+        buf[8..12].copy_from_slice(&GPT_REVISION.to_le_bytes());
+        buf[12..16].copy_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+        buf[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        buf[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.first_usable_lba.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.last_usable_lba.to_le_bytes());
+        buf[56..72].copy_from_slice(&self.disk_guid.to_bytes_le());
+        buf[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        buf[80..84].copy_from_slice(&self.num_entries.to_le_bytes());
+        buf[84..88].copy_from_slice(&GPT_ENTRY_SIZE.to_le_bytes());
+        buf[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&buf[0..GPT_HEADER_SIZE as usize]);
+        buf[16..20].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Write the protective MBR, and the primary and backup GPT headers and entries.
+    #[context("Writing GPT")]
+    pub fn write<F: Write + Seek>(&self, f: &mut F) -> Result<()> {
+        let ss = self.sector_size;
+        let last_lba = self.total_sectors - 1;
+        let entries = self.serialize_entries();
+        let entries_crc = crc32(&entries);
+        let backup_entries_lba = self.last_usable_lba + 1;
+
+        let mut mbr = Mbr::new_empty(self.sector_size, self.total_sectors);
+        mbr.partitions.push(MbrPartition {
+            partno: 1,
+            bootable: false,
+            os_type: MBR_GPT_PROTECTIVE,
+            first_lba: 1,
+            sectors: (self.total_sectors - 1).min(u32::MAX.into()),
+        });
+        mbr.write(f)?;
+
+        let primary = self.serialize_header(1, last_lba, 2, entries_crc);
+        write_at(f, ss, &primary)?;
+        write_at(f, 2 * ss, &entries)?;
+        let backup = self.serialize_header(last_lba, 1, backup_entries_lba, entries_crc);
+        write_at(f, backup_entries_lba * ss, &entries)?;
+        write_at(f, last_lba * ss, &backup)?;
+        f.flush()?;
+        Ok(())
+    }
+
+    fn read_at_lba<F: Read + Seek>(
+        f: &mut F,
+        sector_size: u64,
+        total_sectors: u64,
+        lba: u64,
+    ) -> Result<Self> {
+        let hdr = read_at(f, lba * sector_size, sector_size as usize)?;
+        if &hdr[0..8] != GPT_SIGNATURE {
+            anyhow::bail!("No GPT signature at LBA {lba}");
+        }
+        let header_size = le_u32(&hdr, 12);
+        if header_size < GPT_HEADER_SIZE || u64::from(header_size) > sector_size {
+            anyhow::bail!("Invalid GPT header size {header_size}");
+        }
+        let mut crcbuf = hdr[0..header_size as usize].to_vec();
+        crcbuf[16..20].fill(0);
+        if crc32(&crcbuf) != le_u32(&hdr, 16) {
+            anyhow::bail!("GPT header checksum mismatch at LBA {lba}");
+        }
+        if le_u64(&hdr, 24) != lba {
+            anyhow::bail!("GPT header at LBA {lba} has unexpected location");
+        }
+        let entries_lba = le_u64(&hdr, 72);
+        let num_entries = le_u32(&hdr, 80);
+        let entry_size = le_u32(&hdr, 84);
+        if !entry_size.is_power_of_two()
+            || !(GPT_ENTRY_SIZE..=GPT_MAX_ENTRY_SIZE).contains(&entry_size)
+            || num_entries > GPT_MAX_ENTRIES
+        {
+            anyhow::bail!("Unsupported GPT entries: {num_entries} of size {entry_size}");
+        }
+        let first_usable_lba = le_u64(&hdr, 40);
+        let last_usable_lba = le_u64(&hdr, 48);
+        if first_usable_lba > last_usable_lba || last_usable_lba >= total_sectors {
+            anyhow::bail!("Invalid GPT usable range {first_usable_lba}-{last_usable_lba}");
+        }
+        let entries_len = num_entries as usize * entry_size as usize;
+        let entries = read_at(f, entries_lba * sector_size, entries_len)?;
+        if crc32(&entries) != le_u32(&hdr, 88) {
+            anyhow::bail!("GPT partition entries checksum mismatch");
+        }
+        let mut partitions = Vec::new();
+        for (i, e) in entries.chunks_exact(entry_size as usize).enumerate() {
+            if let Some(p) = GptPartition::parse(i as u32 + 1, e)? {
+                if p.last_lba >= total_sectors {
+                    anyhow::bail!("Partition {} extends past the end of the device", p.partno);
+                }
+                partitions.push(p);
+            }
+        }
+        Ok(Self {
+            sector_size,
+            total_sectors,
+            disk_guid: Uuid::from_bytes_le(hdr[56..72].try_into().unwrap()),
+            first_usable_lba,
+            last_usable_lba,
+            num_entries,
+            partitions,
+        })
+    }
+
+    /// Read a GPT, falling back to the backup header if the primary is corrupt.
+    #[context("Reading GPT")]
+    pub fn read<F: Read + Seek>(f: &mut F, sector_size: u64) -> Result<Self> {
+        let total_sectors = device_size(f)? / sector_size;
+        match Self::read_at_lba(f, sector_size, total_sectors, 1) {
+            Ok(r) => Ok(r),
+            Err(e) => {
+                let Some(backup_lba) = total_sectors.checked_sub(1).filter(|&v| v > 1) else {
+                    return Err(e.context("Device too small for a backup GPT"));
+                };
+                tracing::warn!("Primary GPT is invalid ({e}), trying backup");
+                Self::read_at_lba(f, sector_size, total_sectors, backup_lba)
+                    .context("Reading backup GPT")
+            }
+        }
+    }
+}
+
+/// A primary partition in an MBR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MbrPartition {
+    /// The partition number, starting at 1
+    pub partno: u32,
+    pub bootable: bool,
+    pub os_type: u8,
+    pub first_lba: u64,
+    pub sectors: u64,
+}
+
+/// A DOS/MBR partition table.  Only primary partitions are supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mbr {
+    pub sector_size: u64,
+    pub total_sectors: u64,
+    pub disk_signature: u32,
+    pub partitions: Vec<MbrPartition>,
+}
+
+impl Mbr {
+    fn new_empty(sector_size: u64, total_sectors: u64) -> Self {
+        Self {
+            sector_size,
+            total_sectors,
+            disk_signature: 0,
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Create a new empty partition table for a device of the given size in bytes.
+    pub fn new(size: u64, sector_size: u64, disk_signature: u32) -> Result<Self> {
+        let total_sectors = size / sector_size;
+        if total_sectors < 2 {
+            anyhow::bail!("Device too small for MBR: {size} bytes");
+        }
+        Ok(Self {
+            disk_signature,
+            ..Self::new_empty(sector_size, total_sectors)
+        })
+    }
+
+    /// Find a partition by number (starting at 1).
+    pub fn find_partno(&self, partno: u32) -> Option<&MbrPartition> {
+        self.partitions.iter().find(|p| p.partno == partno)
+    }
+
+    /// Add a primary partition in the first free space, aligned to `alignment` bytes.
+    /// If `size` (in bytes) is not provided, the partition fills the remaining space.
+    pub fn add_partition(
+        &mut self,
+        alignment: u64,
+        os_type: u8,
+        size: Option<u64>,
+        bootable: bool,
+    ) -> Result<&MbrPartition> {
+        let partno = (1..=MBR_MAX_PARTITIONS as u32)
+            .find(|n| self.find_partno(*n).is_none())
+            .ok_or_else(|| anyhow!("No free primary partition entries"))?;
+        let align_sectors = (alignment / self.sector_size).max(1);
+        // Addressing is limited to 32 bits
+        let end = self.total_sectors.min(u32::MAX.into());
+        let mut used = self
+            .partitions
+            .iter()
+            .map(|p| (p.first_lba, p.first_lba + p.sectors))
+            .collect::<Vec<_>>();
+        used.sort_unstable();
+        let mut start = 1;
+        let mut free = None;
+        for (first, next) in used.into_iter().chain([(end, end)]) {
+            let aligned = align_up(start, align_sectors);
+            if aligned < first {
+                free = Some((aligned, first - aligned));
+                break;
+            }
+            start = start.max(next);
+        }
+        let (first_lba, available) = free.ok_or_else(|| anyhow!("No free space for partition"))?;
+        let sectors = match size {
+            Some(size) => {
+                let sectors = size / self.sector_size;
+                if sectors == 0 || sectors > available {
+                    anyhow::bail!("Cannot fit partition of {size} bytes");
+                }
+                sectors
+            }
+            None => available,
+        };
+        let part = MbrPartition {
+            partno,
+            bootable,
+            os_type,
+            first_lba,
+            sectors,
+        };
+        let idx = self.partitions.partition_point(|p| p.partno < partno);
+        self.partitions.insert(idx, part);
+        Ok(&self.partitions[idx])
+    }
+
+    fn parse(buf: &[u8], sector_size: u64, total_sectors: u64) -> Option<Self> {
+        if buf[510..512] != MBR_SIGNATURE {
+            return None;
+        }
+        let mut partitions = Vec::new();
+        for i in 0..MBR_MAX_PARTITIONS {
+            let e = &buf[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+            let os_type = e[4];
+            if os_type == 0 {
+                continue;
+            }
+            partitions.push(MbrPartition {
+                partno: i as u32 + 1,
+                bootable: e[0] == 0x80,
+                os_type,
+                first_lba: le_u32(e, 8).into(),
+                sectors: le_u32(e, 12).into(),
+            });
+        }
+        Some(Self {
+            sector_size,
+            total_sectors,
+            disk_signature: le_u32(buf, MBR_DISK_SIGNATURE_OFFSET),
+            partitions,
+        })
+    }
+
+    /// Read an MBR; returns `None` if there is no MBR signature.
+    #[context("Reading MBR")]
+    pub fn read<F: Read + Seek>(f: &mut F, sector_size: u64) -> Result<Option<Self>> {
+        let total_sectors = device_size(f)? / sector_size;
+        let buf = read_at(f, 0, 512)?;
+        Ok(Self::parse(&buf, sector_size, total_sectors))
+    }
+
+    /// Write the MBR.  Any boot code in the first 440 bytes is preserved.
+    #[context("Writing MBR")]
+    pub fn write<F: Write + Seek>(&self, f: &mut F) -> Result<()> {
+        let mut buf = [0u8; 512 - MBR_DISK_SIGNATURE_OFFSET];
+        buf[0..4].copy_from_slice(&self.disk_signature.to_le_bytes());
+        for p in self.partitions.iter() {
+            let e = &mut buf[(MBR_ENTRIES_OFFSET - MBR_DISK_SIGNATURE_OFFSET)
+                + (p.partno as usize - 1) * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+            e[0] = if p.bootable { 0x80 } else { 0 };
+            e[1..4].copy_from_slice(&MBR_CHS_PLACEHOLDER);
+            e[4] = p.os_type;
+            e[5..8].copy_from_slice(&MBR_CHS_PLACEHOLDER);
+            let first = u32::try_from(p.first_lba).context("Partition start out of range")?;
+            let sectors = u32::try_from(p.sectors).context("Partition size out of range")?;
+            e[8..12].copy_from_slice(&first.to_le_bytes());
+            e[12..16].copy_from_slice(&sectors.to_le_bytes());
+        }
+        let n = buf.len();
+        buf[n - 2..].copy_from_slice(&MBR_SIGNATURE);
+        write_at(f, MBR_DISK_SIGNATURE_OFFSET as u64, &buf)?;
+        f.flush()?;
+        Ok(())
+    }
+}
+
+/// A partition table of either type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiskLabel {
+    Gpt(Gpt),
+    Mbr(Mbr),
+}
+
+impl DiskLabel {
+    /// Read the partition table; returns `None` if there is none.
+    pub fn read<F: Read + Seek>(f: &mut F, sector_size: u64) -> Result<Option<Self>> {
+        let Some(mbr) = Mbr::read(f, sector_size)? else {
+            return Ok(None);
+        };
+        if mbr
+            .partitions
+            .iter()
+            .any(|p| p.os_type == MBR_GPT_PROTECTIVE)
+        {
+            Gpt::read(f, sector_size).map(|v| Some(Self::Gpt(v)))
+        } else {
+            Ok(Some(Self::Mbr(mbr)))
+        }
+    }
+
+    /// Read the partition table of the block device or disk image at `path`.
+    #[context("Reading partition table of {path}")]
+    pub fn read_path(path: &Utf8Path) -> Result<Option<Self>> {
+        let sector_size = logical_sector_size(path)?;
+        let mut f = File::open(path)?;
+        Self::read(&mut f, sector_size)
+    }
+
+    /// Write the partition table to the block device or disk image at `path`.
+    #[context("Writing partition table to {path}")]
+    pub fn write_path(&self, path: &Utf8Path) -> Result<()> {
+        let mut f = File::options().write(true).open(path)?;
+        match self {
+            DiskLabel::Gpt(gpt) => gpt.write(&mut f)?,
+            DiskLabel::Mbr(mbr) => mbr.write(&mut f)?,
+        }
+        f.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn image(size: u64) -> Cursor<Vec<u8>> {
+        Cursor::new(vec![0u8; size as usize])
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_align() {
+        assert_eq!(align_up(34, 2048), 2048);
+        assert_eq!(align_up(2048, 2048), 2048);
+        assert_eq!(align_up(0, 2048), 0);
+        assert_eq!(align_down(4095, 2048), 2048);
+    }
+
+    #[test]
+    fn test_gpt_roundtrip() -> Result<()> {
+        let size = 64 * MIB;
+        let mut f = image(size);
+        let mut gpt = Gpt::new(size, DEFAULT_SECTOR_SIZE)?;
+        assert_eq!(gpt.first_usable_lba, 34);
+        assert_eq!(gpt.last_usable_lba, size / 512 - 34);
+        let esp = gpt.add_partition(
+            DEFAULT_ALIGNMENT,
+            NewPartition {
+                size: Some(16 * MIB),
+                name: "EFI-SYSTEM".into(),
+                type_guid: ESP_TYPE,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(esp.partno, 1);
+        assert_eq!(esp.first_lba, 2048);
+        assert_eq!(esp.sectors(), 16 * MIB / 512);
+        let root = gpt
+            .add_partition(
+                DEFAULT_ALIGNMENT,
+                NewPartition {
+                    name: "root".into(),
+                    type_guid: LINUX_FILESYSTEM_TYPE,
+                    ..Default::default()
+                },
+            )?
+            .clone();
+        assert_eq!(root.partno, 2);
+        assert_eq!(root.first_lba, 2048 + 16 * MIB / 512);
+        assert_eq!(root.last_lba, gpt.last_usable_lba);
+        // No space left
+        assert!(gpt
+            .add_partition(
+                DEFAULT_ALIGNMENT,
+                NewPartition {
+                    type_guid: LINUX_FILESYSTEM_TYPE,
+                    ..Default::default()
+                }
+            )
+            .is_err());
+        gpt.write(&mut f)?;
+
+        let label = DiskLabel::read(&mut f, DEFAULT_SECTOR_SIZE)?.unwrap();
+        assert_eq!(label, DiskLabel::Gpt(gpt.clone()));
+
+        // Corrupt the primary header; we should fall back to the backup
+        let buf = f.get_mut();
+        buf[512 + 16] ^= 0xFF;
+        let read = Gpt::read(&mut f, DEFAULT_SECTOR_SIZE)?;
+        assert_eq!(read.partitions, gpt.partitions);
+        assert_eq!(read.disk_guid, gpt.disk_guid);
+
+        // And if both are corrupt, we fail
+        let buf = f.get_mut();
+        let n = buf.len();
+        buf[n - 512 + 16] ^= 0xFF;
+        assert!(Gpt::read(&mut f, DEFAULT_SECTOR_SIZE).is_err());
+
+        // Truncated devices are an error, not a panic
+        for size in [0, 1, 512, 1024] {
+            assert!(Gpt::read(&mut image(size), DEFAULT_SECTOR_SIZE).is_err());
+        }
+        Ok(())
+    }
+
+    /// Recompute the checksums of the primary GPT header and entries.
+    fn fix_primary_crcs(buf: &mut [u8]) {
+        let hdr = 512;
+        let entries_len = le_u32(buf, hdr + 80) as usize * le_u32(buf, hdr + 84) as usize;
+        let entries_crc = crc32(&buf[1024..1024 + entries_len]);
+        buf[hdr + 88..hdr + 92].copy_from_slice(&entries_crc.to_le_bytes());
+        buf[hdr + 16..hdr + 20].fill(0);
+        let crc = crc32(&buf[hdr..hdr + GPT_HEADER_SIZE as usize]);
+        buf[hdr + 16..hdr + 20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn test_gpt_invalid() -> Result<()> {
+        let size = 16 * MIB;
+        let total_sectors = size / DEFAULT_SECTOR_SIZE;
+        let mut gpt = Gpt::new(size, DEFAULT_SECTOR_SIZE)?;
+        gpt.add_partition(
+            DEFAULT_ALIGNMENT,
+            NewPartition {
+                type_guid: LINUX_FILESYSTEM_TYPE,
+                ..Default::default()
+            },
+        )?;
+        let mut valid = image(size);
+        gpt.write(&mut valid)?;
+        let read_primary = |buf: Vec<u8>| {
+            Gpt::read_at_lba(&mut Cursor::new(buf), DEFAULT_SECTOR_SIZE, total_sectors, 1)
+        };
+        assert_eq!(read_primary(valid.get_ref().clone())?, gpt);
+
+        // Entries larger than a sector, or of a size which isn't a power of two
+        for (num_entries, entry_size) in [(4, 1024u32), (64, 384), (4097, 128)] {
+            let mut buf = valid.get_ref().clone();
+            buf[512 + 80..512 + 84].copy_from_slice(&u32::to_le_bytes(num_entries));
+            buf[512 + 84..512 + 88].copy_from_slice(&entry_size.to_le_bytes());
+            fix_primary_crcs(&mut buf);
+            assert!(read_primary(buf).is_err(), "{num_entries} * {entry_size}");
+        }
+
+        // A partition ending before it starts
+        let mut buf = valid.get_ref().clone();
+        let entry = 1024;
+        buf[entry + 40..entry + 48].copy_from_slice(&1u64.to_le_bytes());
+        fix_primary_crcs(&mut buf);
+        assert!(read_primary(buf).is_err());
+
+        // A partition past the end of the device
+        let mut buf = valid.get_ref().clone();
+        buf[entry + 40..entry + 48].copy_from_slice(&total_sectors.to_le_bytes());
+        fix_primary_crcs(&mut buf);
+        assert!(read_primary(buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gpt_gaps() -> Result<()> {
+        let mut gpt = Gpt::new(64 * MIB, 4096)?;
+        // With 4k sectors, the entries take 4 sectors
+        assert_eq!(gpt.first_usable_lba, 6);
+        let mk = |size| NewPartition {
+            size: Some(size),
+            type_guid: LINUX_FILESYSTEM_TYPE,
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            gpt.add_partition(DEFAULT_ALIGNMENT, mk(4 * MIB))?;
+        }
+        gpt.partitions.retain(|p| p.partno != 2);
+        // The freed slot and space are reused
+        let p = gpt.add_partition(DEFAULT_ALIGNMENT, mk(2 * MIB))?;
+        assert_eq!(p.partno, 2);
+        assert_eq!(p.first_lba, 256 + 1024);
+        let long = NewPartition {
+            name: "x".repeat(37),
+            ..mk(MIB)
+        };
+        assert!(gpt.add_partition(DEFAULT_ALIGNMENT, long).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mbr_roundtrip() -> Result<()> {
+        let size = 32 * MIB;
+        let mut f = image(size);
+        // Boot code is preserved
+        f.get_mut()[0..4].copy_from_slice(b"boot");
+        let mut mbr = Mbr::new(size, DEFAULT_SECTOR_SIZE, 0xdeadbeef)?;
+        let p = mbr.add_partition(DEFAULT_ALIGNMENT, MBR_PREP_BOOT, Some(4 * MIB), true)?;
+        assert_eq!((p.first_lba, p.sectors), (2048, 8192));
+        let p = mbr.add_partition(DEFAULT_ALIGNMENT, MBR_LINUX, None, false)?;
+        assert_eq!((p.first_lba, p.sectors), (10240, size / 512 - 10240));
+        mbr.write(&mut f)?;
+        assert_eq!(&f.get_ref()[0..4], b"boot");
+        let label = DiskLabel::read(&mut f, DEFAULT_SECTOR_SIZE)?.unwrap();
+        assert_eq!(label, DiskLabel::Mbr(mbr));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_path() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let path = camino::Utf8PathBuf::try_from(td.path().join("disk.img"))?;
+        let f = File::create(&path)?;
+        f.set_len(16 * MIB)?;
+        drop(f);
+        assert!(DiskLabel::read_path(&path)?.is_none());
+        let mut gpt = Gpt::new(16 * MIB, DEFAULT_SECTOR_SIZE)?;
+        gpt.add_partition(
+            DEFAULT_ALIGNMENT,
+            NewPartition {
+                name: "root".into(),
+                type_guid: LINUX_FILESYSTEM_TYPE,
+                ..Default::default()
+            },
+        )?;
+        let label = DiskLabel::Gpt(gpt);
+        label.write_path(&path)?;
+        assert_eq!(DiskLabel::read_path(&path)?.unwrap(), label);
+        Ok(())
+    }
+}
@@ -0,0 +1,560 @@
+//! # Block device topology
+//!
+//! A typed model of the block device stack (disks, NVMe namespaces, partitions,
+//! dm-crypt, dm-multipath, md RAID and LVM) gathered from sysfs, along with stable
+//! identifiers (WWN and `/dev/disk/by-id` links) for each device.
+//!
+//! The main use is to find the physical devices backing e.g. the root filesystem,
+//! where a multipath device is treated as physical: its individual paths are
+//! never returned.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use fn_error_context::context;
+
+const SYSFS_CLASS_BLOCK: &str = "class/block";
+const DEV_DISK_BY_ID: &str = "disk/by-id";
+
+/// The type of a block device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// A whole disk (SCSI, virtio, etc.)
+    Disk,
+    /// An NVMe namespace
+    NvmeNamespace {
+        nsid: u32,
+    },
+    /// A partition, including partitions of a multipath device
+    Partition {
+        partno: u32,
+    },
+    Loop,
+    /// A device-mapper multipath device
+    Multipath,
+    /// A dm-crypt (e.g. LUKS) device
+    Crypt,
+    /// An LVM logical volume
+    Lvm,
+    /// An md RAID device
+    Raid {
+        level: String,
+    },
+    /// Another device-mapper device, with the prefix of its dm uuid (if any)
+    DeviceMapper {
+        subsystem: Option<String>,
+    },
+}
+
+/// A block device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDevice {
+    /// The kernel name, e.g. `sda`, `nvme0n1p2` or `dm-0`
+    pub name: String,
+    pub kind: DeviceKind,
+    /// The device number as `major:minor`
+    pub maj_min: String,
+    /// Size in bytes
+    pub size: u64,
+    /// The device-mapper name, e.g. `mpatha` or `luks-<uuid>`
+    pub dm_name: Option<String>,
+    /// The World Wide Name or other globally unique identifier
+    pub wwid: Option<String>,
+    pub serial: Option<String>,
+    pub model: Option<String>,
+    /// Links in `/dev/disk/by-id`, most stable first
+    pub by_id: Vec<Utf8PathBuf>,
+    /// The devices this one is directly built on, e.g. the disk of a partition
+    pub parents: Vec<String>,
+}
+
+impl BlockDevice {
+    /// The path to the device node.
+    pub fn path(&self) -> Utf8PathBuf {
+        match self.dm_name.as_deref() {
+            Some(dm_name) => format!("/dev/mapper/{dm_name}").into(),
+            None => format!("/dev/{}", self.name).into(),
+        }
+    }
+
+    /// A path to the device node which is stable across reboots and device
+    /// enumeration order; this falls back to [`Self::path`].
+    pub fn stable_path(&self) -> Utf8PathBuf {
+        self.by_id.first().cloned().unwrap_or_else(|| self.path())
+    }
+
+    /// Whether this device is a physical device for the purposes of e.g. bootloader
+    /// installation; multipath devices count as physical.
+    pub fn is_physical(&self) -> bool {
+        matches!(
+            self.kind,
+            DeviceKind::Disk
+                | DeviceKind::NvmeNamespace { .. }
+                | DeviceKind::Loop
+                | DeviceKind::Multipath
+        )
+    }
+}
+
+/// Rank a `/dev/disk/by-id` link name; lower is more stable.
+fn by_id_rank(name: &str) -> u8 {
+    const PREFERRED: &[&str] = &[
+        "wwn-",
+        "nvme-eui.",
+        "dm-uuid-mpath-",
+        "dm-uuid-part",
+        "nvme-",
+        "scsi-3",
+        "dm-uuid-",
+        "dm-name-",
+    ];
+    PREFERRED
+        .iter()
+        .position(|p| name.starts_with(p))
+        .unwrap_or(PREFERRED.len()) as u8
+}
+
+fn read_attr(path: &Utf8Path) -> Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(v) => {
+            let v = v.trim();
+            Ok((!v.is_empty()).then(|| v.to_owned()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading {path}")),
+    }
+}
+
+fn list_dir_names(path: &Utf8Path) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    if !path.try_exists()? {
+        return Ok(r);
+    }
+    for ent in path.read_dir_utf8()? {
+        r.push(ent?.file_name().to_owned());
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Classify a device-mapper device by its dm uuid.
+fn dm_kind(uuid: Option<&str>) -> DeviceKind {
+    let Some(uuid) = uuid else {
+        return DeviceKind::DeviceMapper { subsystem: None };
+    };
+    // kpartx partitions look like `part1-mpath-3600...`
+    if let Some(rest) = uuid.strip_prefix("part") {
+        if let Some((n, _)) = rest.split_once('-') {
+            if let Ok(partno) = n.parse() {
+                return DeviceKind::Partition { partno };
+            }
+        }
+    }
+    let subsystem = uuid.split_once('-').map(|(s, _)| s).unwrap_or(uuid);
+    match subsystem {
+        "mpath" => DeviceKind::Multipath,
+        "CRYPT" => DeviceKind::Crypt,
+        "LVM" => DeviceKind::Lvm,
+        o => DeviceKind::DeviceMapper {
+            subsystem: Some(o.to_owned()),
+        },
+    }
+}
+
+/// The block device topology of the system.
+#[derive(Debug, Default)]
+pub struct Topology {
+    devices: BTreeMap<String, BlockDevice>,
+}
+
+impl Topology {
+    /// Load the topology of the running system.
+    pub fn load() -> Result<Self> {
+        Self::load_from("/sys".into(), "/dev".into())
+    }
+
+    /// Load the topology from the given sysfs and devtmpfs mounts.
+    #[context("Loading block device topology")]
+    pub fn load_from(sysfs: &Utf8Path, devfs: &Utf8Path) -> Result<Self> {
+        let class = sysfs.join(SYSFS_CLASS_BLOCK);
+        let mut by_id: BTreeMap<String, Vec<Utf8PathBuf>> = BTreeMap::new();
+        let by_id_dir = devfs.join(DEV_DISK_BY_ID);
+        for link in list_dir_names(&by_id_dir)? {
+            let path = by_id_dir.join(&link);
+            let Ok(target) = path.read_link_utf8() else {
+                continue;
+            };
+            if let Some(name) = target.file_name() {
+                // Always report the canonical path, even if devfs is elsewhere
+                let path = Utf8Path::new("/dev").join(DEV_DISK_BY_ID).join(&link);
+                by_id.entry(name.to_owned()).or_default().push(path);
+            }
+        }
+        for links in by_id.values_mut() {
+            links.sort_by_key(|p| (by_id_rank(p.file_name().unwrap_or_default()), p.clone()));
+        }
+
+        let mut devices = BTreeMap::new();
+        for name in list_dir_names(&class)? {
+            let dir = class.join(&name);
+            let maj_min = read_attr(&dir.join("dev"))?
+                .ok_or_else(|| anyhow!("Missing dev attribute for {name}"))?;
+            let size = read_attr(&dir.join("size"))?
+                .map(|s| s.parse::<u64>())
+                .transpose()
+                .with_context(|| format!("Parsing size of {name}"))?
+                .unwrap_or_default()
+                * 512;
+            let dm_name = read_attr(&dir.join("dm/name"))?;
+            let dm_uuid = read_attr(&dir.join("dm/uuid"))?;
+            let mut parents = list_dir_names(&dir.join("slaves"))?;
+            let kind = if let Some(partno) = read_attr(&dir.join("partition"))? {
+                // The parent disk is the containing directory in the sysfs device tree
+                let real = dir
+                    .canonicalize_utf8()
+                    .with_context(|| format!("Resolving {dir}"))?;
+                if let Some(parent) = real.parent().and_then(|p| p.file_name()) {
+                    parents.push(parent.to_owned());
+                }
+                DeviceKind::Partition {
+                    partno: partno.parse().context("Parsing partition number")?,
+                }
+            } else if dm_name.is_some() {
+                dm_kind(dm_uuid.as_deref())
+            } else if let Some(level) = read_attr(&dir.join("md/level"))? {
+                DeviceKind::Raid { level }
+            } else if let Some(nsid) = read_attr(&dir.join("nsid"))? {
+                DeviceKind::NvmeNamespace {
+                    nsid: nsid.parse().context("Parsing nsid")?,
+                }
+            } else if name.starts_with("loop") {
+                DeviceKind::Loop
+            } else {
+                DeviceKind::Disk
+            };
+            let wwid = match kind {
+                DeviceKind::Multipath => dm_uuid
+                    .as_deref()
+                    .and_then(|u| u.strip_prefix("mpath-"))
+                    .map(ToOwned::to_owned),
+                _ => match read_attr(&dir.join("wwid"))? {
+                    Some(v) => Some(v),
+                    None => read_attr(&dir.join("device/wwid"))?,
+                },
+            };
+            let dev = BlockDevice {
+                kind,
+                maj_min,
+                size,
+                dm_name,
+                wwid,
+                serial: read_attr(&dir.join("device/serial"))?,
+                model: read_attr(&dir.join("device/model"))?,
+                by_id: by_id.remove(&name).unwrap_or_default(),
+                parents,
+                name: name.clone(),
+            };
+            devices.insert(name, dev);
+        }
+        Ok(Self { devices })
+    }
+
+    /// All devices, sorted by kernel name.
+    pub fn devices(&self) -> impl Iterator<Item = &BlockDevice> {
+        self.devices.values()
+    }
+
+    /// Find a device by kernel name.
+    pub fn get(&self, name: &str) -> Option<&BlockDevice> {
+        self.devices.get(name)
+    }
+
+    /// Find a device by its `major:minor` number.
+    pub fn get_by_maj_min(&self, maj_min: &str) -> Option<&BlockDevice> {
+        self.devices.values().find(|d| d.maj_min == maj_min)
+    }
+
+    /// Find a device by a path to its device node, e.g. `/dev/sda`, `/dev/mapper/mpatha`
+    /// or a `/dev/disk/by-*` link.
+    #[context("Resolving {path}")]
+    pub fn resolve(&self, path: &Utf8Path) -> Result<&BlockDevice> {
+        let path = path.canonicalize_utf8()?;
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid device path {path}"))?;
+        self.get(name)
+            .ok_or_else(|| anyhow!("Block device {name} not found"))
+    }
+
+    /// The devices which are directly built on `name`; e.g. partitions of a disk.
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a BlockDevice> + 'a {
+        self.devices
+            .values()
+            .filter(move |d| d.parents.iter().any(|p| p == name))
+    }
+
+    /// The partitions of the device, sorted by partition number.
+    pub fn partitions_of(&self, name: &str) -> Vec<&BlockDevice> {
+        let mut r = self
+            .children(name)
+            .filter(|d| matches!(d.kind, DeviceKind::Partition { .. }))
+            .collect::<Vec<_>>();
+        r.sort_by_key(|d| match d.kind {
+            DeviceKind::Partition { partno } => partno,
+            _ => unreachable!(),
+        });
+        r
+    }
+
+    /// Every device in the parent hierarchy of `name` (not including itself),
+    /// nearest first.  The paths of a multipath device are not included.
+    pub fn ancestors(&self, name: &str) -> Result<Vec<&BlockDevice>> {
+        let mut r = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut queue = vec![name.to_owned()];
+        while let Some(cur) = queue.pop() {
+            let dev = self
+                .get(&cur)
+                .ok_or_else(|| anyhow!("Block device {cur} not found"))?;
+            if cur != name {
+                r.push(dev);
+            }
+            if cur != name && dev.kind == DeviceKind::Multipath {
+                continue;
+            }
+            for parent in dev.parents.iter().rev() {
+                if seen.insert(parent.as_str()) {
+                    queue.push(parent.clone());
+                }
+            }
+        }
+        Ok(r)
+    }
+
+    /// Find the physical devices (disks, NVMe namespaces, loop devices or multipath
+    /// devices) backing `name`.  For example, for a filesystem on LVM on top of a
+    /// RAID1 of two disks, this returns both disks.
+    pub fn backing_devices(&self, name: &str) -> Result<Vec<&BlockDevice>> {
+        let dev = self
+            .get(name)
+            .ok_or_else(|| anyhow!("Block device {name} not found"))?;
+        if dev.is_physical() {
+            return Ok(vec![dev]);
+        }
+        let r = self
+            .ancestors(name)?
+            .into_iter()
+            .filter(|d| d.is_physical())
+            .filter(|d| d.kind == DeviceKind::Multipath || d.parents.is_empty())
+            .collect();
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixture {
+        _td: tempfile::TempDir,
+        sysfs: Utf8PathBuf,
+        devfs: Utf8PathBuf,
+    }
+
+    impl Fixture {
+        fn new() -> Result<Self> {
+            let td = tempfile::tempdir()?;
+            let root = Utf8PathBuf::try_from(td.path().to_owned())?;
+            let sysfs = root.join("sys");
+            let devfs = root.join("dev");
+            std::fs::create_dir_all(sysfs.join(SYSFS_CLASS_BLOCK))?;
+            std::fs::create_dir_all(devfs.join(DEV_DISK_BY_ID))?;
+            Ok(Self {
+                _td: td,
+                sysfs,
+                devfs,
+            })
+        }
+
+        /// Add a device at `devpath` (relative to `sys/devices`) with the given attributes.
+        fn add(&self, devpath: &str, attrs: &[(&str, &str)], slaves: &[&str]) -> Result<()> {
+            let dir = self.sysfs.join("devices").join(devpath);
+            std::fs::create_dir_all(&dir)?;
+            for (k, v) in attrs {
+                let p = dir.join(k);
+                std::fs::create_dir_all(p.parent().unwrap())?;
+                std::fs::write(p, format!("{v}\n"))?;
+            }
+            if !slaves.is_empty() {
+                std::fs::create_dir_all(dir.join("slaves"))?;
+                for s in slaves {
+                    std::os::unix::fs::symlink(format!("../../{s}"), dir.join("slaves").join(s))?;
+                }
+            }
+            let name = Utf8Path::new(devpath).file_name().unwrap();
+            std::os::unix::fs::symlink(&dir, self.sysfs.join(SYSFS_CLASS_BLOCK).join(name))?;
+            Ok(())
+        }
+
+        fn by_id(&self, link: &str, name: &str) -> Result<()> {
+            std::os::unix::fs::symlink(
+                format!("../../{name}"),
+                self.devfs.join(DEV_DISK_BY_ID).join(link),
+            )?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_multipath_lvm_raid() -> Result<()> {
+        let f = Fixture::new()?;
+        // Two paths to the same LUN, assembled into a multipath device with a partition
+        for (name, mm) in [("sda", "8:0"), ("sdb", "8:16")] {
+            f.add(
+                &format!("pci0/host0/{name}"),
+                &[
+                    ("dev", mm),
+                    ("size", "2097152"),
+                    ("device/wwid", "naa.600a0b80"),
+                ],
+                &[],
+            )?;
+        }
+        f.add(
+            "virtual/block/dm-0",
+            &[
+                ("dev", "253:0"),
+                ("size", "2097152"),
+                ("dm/name", "mpatha"),
+                ("dm/uuid", "mpath-3600a0b80"),
+            ],
+            &["sda", "sdb"],
+        )?;
+        f.add(
+            "virtual/block/dm-1",
+            &[
+                ("dev", "253:1"),
+                ("size", "1048576"),
+                ("dm/name", "mpatha1"),
+                ("dm/uuid", "part1-mpath-3600a0b80"),
+            ],
+            &["dm-0"],
+        )?;
+        // An NVMe namespace with two partitions, one in an md RAID1 with the multipath partition
+        f.add(
+            "pci1/nvme/nvme0/nvme0n1",
+            &[
+                ("dev", "259:0"),
+                ("size", "4194304"),
+                ("nsid", "1"),
+                ("wwid", "eui.0025388"),
+                ("device/serial", "S4EW"),
+                ("device/model", "Samsung SSD"),
+            ],
+            &[],
+        )?;
+        for (n, mm) in [(1, "259:1"), (2, "259:2")] {
+            f.add(
+                &format!("pci1/nvme/nvme0/nvme0n1/nvme0n1p{n}"),
+                &[
+                    ("dev", mm),
+                    ("size", "1048576"),
+                    ("partition", &n.to_string()),
+                ],
+                &[],
+            )?;
+        }
+        f.add(
+            "virtual/block/md127",
+            &[("dev", "9:127"), ("size", "1048000"), ("md/level", "raid1")],
+            &["dm-1", "nvme0n1p2"],
+        )?;
+        f.add(
+            "virtual/block/dm-2",
+            &[
+                ("dev", "253:2"),
+                ("size", "1000000"),
+                ("dm/name", "vg-root"),
+                ("dm/uuid", "LVM-abcdef"),
+            ],
+            &["md127"],
+        )?;
+        f.add(
+            "virtual/block/dm-3",
+            &[
+                ("dev", "253:3"),
+                ("size", "999000"),
+                ("dm/name", "luks-1234"),
+                ("dm/uuid", "CRYPT-LUKS2-1234-luks-1234"),
+            ],
+            &["dm-2"],
+        )?;
+        f.by_id("nvme-Samsung_SSD_S4EW", "nvme0n1")?;
+        f.by_id("nvme-eui.0025388", "nvme0n1")?;
+        f.by_id("dm-name-mpatha", "dm-0")?;
+        f.by_id("dm-uuid-mpath-3600a0b80", "dm-0")?;
+        f.by_id("scsi-3600a0b80", "sda")?;
+
+        let topo = Topology::load_from(&f.sysfs, &f.devfs)?;
+        let get = |n| topo.get(n).unwrap();
+
+        assert_eq!(get("dm-0").kind, DeviceKind::Multipath);
+        assert_eq!(get("dm-0").wwid.as_deref(), Some("3600a0b80"));
+        assert_eq!(get("dm-0").path(), "/dev/mapper/mpatha");
+        assert_eq!(
+            get("dm-0").stable_path(),
+            "/dev/disk/by-id/dm-uuid-mpath-3600a0b80"
+        );
+        assert_eq!(get("dm-1").kind, DeviceKind::Partition { partno: 1 });
+        assert_eq!(get("dm-2").kind, DeviceKind::Lvm);
+        assert_eq!(get("dm-3").kind, DeviceKind::Crypt);
+        assert_eq!(
+            get("md127").kind,
+            DeviceKind::Raid {
+                level: "raid1".into()
+            }
+        );
+        let nvme = get("nvme0n1");
+        assert_eq!(nvme.kind, DeviceKind::NvmeNamespace { nsid: 1 });
+        assert_eq!(nvme.size, 4194304 * 512);
+        assert_eq!(nvme.serial.as_deref(), Some("S4EW"));
+        assert_eq!(nvme.stable_path(), "/dev/disk/by-id/nvme-eui.0025388");
+        assert_eq!(get("sda").wwid.as_deref(), Some("naa.600a0b80"));
+        assert_eq!(get("sda").stable_path(), "/dev/disk/by-id/scsi-3600a0b80");
+        assert_eq!(get("sdb").stable_path(), "/dev/sdb");
+        assert_eq!(get("nvme0n1p2").parents, ["nvme0n1"]);
+        assert_eq!(
+            get_by_names(topo.partitions_of("nvme0n1")),
+            ["nvme0n1p1", "nvme0n1p2"]
+        );
+        assert_eq!(get_by_names(topo.partitions_of("dm-0")), ["dm-1"]);
+        assert_eq!(topo.get_by_maj_min("253:2").unwrap().name, "dm-2");
+
+        // The multipath paths are not included
+        let backing = topo.backing_devices("dm-3")?;
+        assert_eq!(get_by_names(backing), ["dm-0", "nvme0n1"]);
+        assert_eq!(get_by_names(topo.backing_devices("dm-1")?), ["dm-0"]);
+        assert_eq!(get_by_names(topo.backing_devices("sda")?), ["sda"]);
+        let ancestors = topo.ancestors("dm-3")?;
+        assert_eq!(
+            get_by_names(ancestors),
+            ["dm-2", "md127", "dm-1", "dm-0", "nvme0n1p2", "nvme0n1"]
+        );
+        Ok(())
+    }
+
+    fn get_by_names(devs: Vec<&BlockDevice>) -> Vec<&str> {
+        devs.into_iter().map(|d| d.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_by_id_rank() {
+        let mut v = ["nvme-Foo", "dm-name-x", "wwn-0x5000", "other", "nvme-eui.1"];
+        v.sort_by_key(|n| by_id_rank(n));
+        assert_eq!(
+            v,
+            ["wwn-0x5000", "nvme-eui.1", "nvme-Foo", "dm-name-x", "other"]
+        );
+    }
+}
@@ -1,10 +1,23 @@
+use std::collections::BTreeMap;
+use std::process::Command;
+
 use anyhow::{anyhow, bail, Context, Result};
+use bootc_utils::CommandRunExt;
 use camino::{Utf8Path, Utf8PathBuf};
 use fn_error_context::context;
+use ostree_ext::ostree;
+use serde::Deserialize;
 
+use crate::spec::{
+    BootloaderBackend, BootloaderComponent, BootloaderStatus, BootloaderUpdate, EspStatus,
+};
 use crate::task::Task;
+use bootc_blockdev::parttable::{self, DiskLabel};
+use bootc_blockdev::topology::{BlockDevice, DeviceKind, Topology};
 use bootc_blockdev::PartitionTable;
 
+pub(crate) mod systemd_boot;
+
 /// The name of the mountpoint for efi (as a subdirectory of /boot, or at the toplevel)
 pub(crate) const EFI_DIR: &str = "efi";
 #[cfg(feature = "install-to-disk")]
@@ -13,50 +26,544 @@ pub(crate) const ESP_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
 pub(crate) const PREPBOOT_GUID: &str = "9E1A2D38-C612-4316-AA26-8B49521E5A8B";
 #[cfg(feature = "install-to-disk")]
 pub(crate) const PREPBOOT_LABEL: &str = "PowerPC-PReP-boot";
-#[cfg(target_arch = "powerpc64")]
-/// We make a best-effort to support MBR partitioning too.
-pub(crate) const PREPBOOT_MBR_TYPE: &str = "41";
 
-/// Find the device to pass to bootupd. Only on powerpc64 right now
-/// we explicitly find one with a specific label.
+/// Find the device to pass to bootupd for the physical device `disk`. Only on
+/// powerpc64 right now we explicitly find the PReP partition.
 ///
 /// This should get fixed once we execute on https://github.com/coreos/bootupd/issues/432
-fn get_bootupd_device(device: &PartitionTable) -> Result<Utf8PathBuf> {
+fn get_bootupd_device(topology: &Topology, disk: &BlockDevice) -> Result<Utf8PathBuf> {
     #[cfg(target_arch = "powerpc64")]
     {
-        return device
-            .partitions
-            .iter()
-            .find(|p| matches!(p.parttype.as_str(), PREPBOOT_GUID | PREPBOOT_MBR_TYPE))
-            .ok_or_else(|| {
-                anyhow::anyhow!("Failed to find PReP partition with GUID {PREPBOOT_GUID}")
-            })
-            .map(|dev| dev.node.as_str().into());
+        let label = DiskLabel::read_path(&disk.path())?
+            .ok_or_else(|| anyhow!("No partition table on {}", disk.path()))?;
+        let partno = match &label {
+            DiskLabel::Gpt(gpt) => gpt
+                .partitions
+                .iter()
+                .find(|p| p.type_guid == parttable::PREP_BOOT_TYPE)
+                .map(|p| p.partno),
+            DiskLabel::Mbr(mbr) => mbr
+                .partitions
+                .iter()
+                .find(|p| p.os_type == parttable::MBR_PREP_BOOT)
+                .map(|p| p.partno),
+        };
+        let partno =
+            partno.ok_or_else(|| anyhow!("Failed to find PReP partition on {}", disk.path()))?;
+        return topology
+            .partitions_of(&disk.name)
+            .into_iter()
+            .find(|p| p.kind == DeviceKind::Partition { partno })
+            .map(|p| p.stable_path())
+            .ok_or_else(|| anyhow!("Device for PReP partition {partno} not found"));
     }
     #[cfg(not(target_arch = "powerpc64"))]
-    return Ok(device.path().into());
+    {
+        let _ = topology;
+        Ok(disk.stable_path())
+    }
 }
 
-#[context("Installing bootloader")]
-pub(crate) fn install_via_bootupd(
-    device: &PartitionTable,
+/// The arguments passed to `bootupctl` to install the bootloader to `devpath`.
+fn bootupd_install_args(
+    devpath: &Utf8Path,
     rootfs: &Utf8Path,
     configopts: &crate::install::InstallConfigOpts,
-) -> Result<()> {
+) -> Vec<String> {
     let verbose = std::env::var_os("BOOTC_BOOTLOADER_DEBUG").map(|_| "-vvvv");
     // bootc defaults to only targeting the platform boot method.
     let bootupd_opts = (!configopts.generic_image).then_some(["--update-firmware", "--auto"]);
 
-    let devpath = get_bootupd_device(device)?;
-    let args = ["backend", "install", "--write-uuid"]
+    ["backend", "install", "--write-uuid"]
         .into_iter()
         .chain(verbose)
         .chain(bootupd_opts.iter().copied().flatten())
-        .chain(["--device", devpath.as_str(), rootfs.as_str()]);
-    Task::new("Running bootupctl to install bootloader", "bootupctl")
+        .chain(["--device", devpath.as_str(), rootfs.as_str()])
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Install the bootloader via bootupd to each of the physical devices backing the
+/// root filesystem mounted at `rootfs`: if it is mirrored (e.g. md RAID1) across
+/// disks, each of them needs a bootloader so that the system can boot from any of
+/// them. Devices are passed by their stable path (see [`BlockDevice::stable_path`]),
+/// as the names of e.g. multipath devices are not stable across boots.
+#[context("Installing bootloader")]
+pub(crate) fn install_via_bootupd(
+    rootfs: &Utf8Path,
+    configopts: &crate::install::InstallConfigOpts,
+) -> Result<()> {
+    let topology = Topology::load()?;
+    let source = root_source(rootfs)?;
+    let dev = topology.resolve(source.as_str().into())?;
+    let disks = topology.backing_devices(&dev.name)?;
+    if disks.is_empty() {
+        bail!("No physical device found backing {source}");
+    }
+    for disk in disks {
+        let devpath = get_bootupd_device(&topology, disk)?;
+        let args = bootupd_install_args(&devpath, rootfs, configopts);
+        Task::new(
+            format!("Running bootupctl to install bootloader to {devpath}"),
+            "bootupctl",
+        )
         .args(args)
         .verbose()
-        .run()
+        .run()?;
+    }
+    Ok(())
+}
+
+/// An EFI System Partition on one of the devices backing the root filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Esp {
+    /// Stable path to the partition
+    pub(crate) device: Utf8PathBuf,
+    /// Stable path to the disk holding the partition
+    pub(crate) parent: Utf8PathBuf,
+    /// The device number as `major:minor`
+    pub(crate) maj_min: String,
+}
+
+/// The partition numbers of the ESPs in a partition table.
+fn esp_partnos(label: &DiskLabel) -> Vec<u32> {
+    match label {
+        DiskLabel::Gpt(gpt) => gpt
+            .partitions
+            .iter()
+            .filter(|p| p.type_guid == parttable::ESP_TYPE)
+            .map(|p| p.partno)
+            .collect(),
+        DiskLabel::Mbr(mbr) => mbr
+            .partitions
+            .iter()
+            .filter(|p| p.os_type == parttable::MBR_ESP)
+            .map(|p| p.partno)
+            .collect(),
+    }
+}
+
+/// Find the ESPs on all the physical devices backing the block device `source`.
+#[context("Finding ESPs for {source}")]
+pub(crate) fn find_esps(source: &str) -> Result<Vec<Esp>> {
+    let topology = Topology::load()?;
+    let mut r = Vec::new();
+    for parent in bootc_blockdev::find_parent_devices(source)? {
+        let parent = topology.resolve(parent.as_str().into())?;
+        let Some(label) = DiskLabel::read_path(&parent.path())? else {
+            continue;
+        };
+        let partnos = esp_partnos(&label);
+        for part in topology.partitions_of(&parent.name) {
+            let DeviceKind::Partition { partno } = part.kind else {
+                continue;
+            };
+            if partnos.contains(&partno) {
+                r.push(Esp {
+                    device: part.stable_path(),
+                    parent: parent.stable_path(),
+                    maj_min: part.maj_min.clone(),
+                });
+            }
+        }
+    }
+    Ok(r)
+}
+
+/// The block device holding the filesystem mounted at `root`.
+fn root_source(root: &Utf8Path) -> Result<String> {
+    let fs = crate::mount::inspect_filesystem(root)?;
+    // btrfs subvolumes are shown as e.g. `/dev/vda3[/root]`
+    let source = fs
+        .source
+        .split_once('[')
+        .map(|(dev, _)| dev)
+        .unwrap_or(fs.source.as_str());
+    Ok(source.to_owned())
+}
+
+/// Find the ESPs on the devices backing the filesystem mounted at `root`.
+pub(crate) fn find_esps_for_root(root: &Utf8Path) -> Result<Vec<Esp>> {
+    find_esps(&root_source(root)?)
+}
+
+/// Where the system may have mounted an ESP.
+const ESP_MOUNTPOINTS: [&str; 2] = ["/boot/efi", "/efi"];
+
+/// Check whether the filesystem mounted at `path` is the device `maj_min`.
+fn is_mounted_at(path: &str, maj_min: &str) -> bool {
+    rustix::fs::stat(path)
+        .map(|st| {
+            let dev = st.st_dev;
+            format!("{}:{}", rustix::fs::major(dev), rustix::fs::minor(dev)) == maj_min
+        })
+        .unwrap_or_default()
+}
+
+/// An ESP mounted either by the system, or temporarily by us.
+pub(crate) struct MountedEsp {
+    pub(crate) path: Utf8PathBuf,
+    /// Whether we mounted it, and hence need to unmount it
+    unmount: bool,
+    _tempdir: Option<tempfile::TempDir>,
+}
+
+impl MountedEsp {
+    fn new(esp: &Esp, writable: bool) -> Result<Self> {
+        for path in ESP_MOUNTPOINTS {
+            if is_mounted_at(path, &esp.maj_min) {
+                return Ok(Self {
+                    path: path.into(),
+                    unmount: false,
+                    _tempdir: None,
+                });
+            }
+        }
+        let tempdir = tempfile::tempdir()?;
+        let path = Utf8Path::from_path(tempdir.path())
+            .ok_or_else(|| anyhow!("Invalid non-UTF8 tempdir"))?
+            .to_owned();
+        let opts = if writable { "rw" } else { "ro" };
+        Task::new_quiet("mount")
+            .args(["-o", opts, esp.device.as_str(), path.as_str()])
+            .run()?;
+        Ok(Self {
+            path,
+            unmount: true,
+            _tempdir: Some(tempdir),
+        })
+    }
+}
+
+impl Drop for MountedEsp {
+    fn drop(&mut self) {
+        if !self.unmount {
+            return;
+        }
+        if let Err(e) =
+            rustix::mount::unmount(self.path.as_str(), rustix::mount::UnmountFlags::empty())
+        {
+            tracing::warn!("Failed to unmount {}: {e}", self.path);
+        }
+    }
+}
+
+/// Check whether a filesystem other than that of its parent directory is mounted at `path`.
+fn is_mountpoint(path: &Utf8Path) -> Result<bool> {
+    let parent = path.parent().unwrap_or(path);
+    let st = rustix::fs::stat(path.as_std_path()).with_context(|| format!("Querying {path}"))?;
+    let parent_st =
+        rustix::fs::stat(parent.as_std_path()).with_context(|| format!("Querying {parent}"))?;
+    Ok(st.st_dev != parent_st.st_dev)
+}
+
+/// Ensure an ESP is mounted at `target` (e.g. `boot/efi` of the filesystem at `root`), for
+/// installing the bootloader.  If nothing is mounted there yet, the first ESP on the
+/// devices backing `root` is mounted until the returned value is dropped.
+#[context("Mounting ESP at {target}")]
+pub(crate) fn mount_esp_for_install(root: &Utf8Path, target: &Utf8Path) -> Result<MountedEsp> {
+    std::fs::create_dir_all(target).with_context(|| format!("Creating {target}"))?;
+    let unmount = if is_mountpoint(target)? {
+        tracing::debug!("Using the filesystem mounted at {target}");
+        false
+    } else {
+        let esps = find_esps_for_root(root)?;
+        let Some(esp) = esps.first() else {
+            bail!("No EFI System Partition found on the devices backing {root}");
+        };
+        Task::new_quiet("mount")
+            .args([esp.device.as_str(), target.as_str()])
+            .run()?;
+        true
+    };
+    Ok(MountedEsp {
+        path: target.to_owned(),
+        unmount,
+        _tempdir: None,
+    })
+}
+
+/// The directories of an ESP holding bootloader content: `loader` holds the
+/// configuration of systemd-boot.
+const ESP_CONTENT_DIRS: [&str; 2] = ["EFI", "loader"];
+
+/// Compute a digest over the bootloader content of the ESP mounted at `esp`,
+/// or `None` if there is none.
+fn esp_digest(esp: &Utf8Path) -> Result<Option<String>> {
+    fn walk(dir: &Utf8Path, files: &mut Vec<Utf8PathBuf>) -> Result<()> {
+        for entry in dir.read_dir_utf8()? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                walk(entry.path(), files)?;
+            } else {
+                files.push(entry.into_path());
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    let mut found = false;
+    for d in ESP_CONTENT_DIRS {
+        let d = esp.join(d);
+        if d.try_exists()? {
+            found = true;
+            walk(&d, &mut files)?;
+        }
+    }
+    if !found {
+        return Ok(None);
+    }
+    files.sort();
+    let mut hasher = openssl::sha::Sha256::new();
+    for path in files {
+        let relpath = path.strip_prefix(esp)?;
+        hasher.update(relpath.as_str().as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&std::fs::read(&path).with_context(|| format!("Reading {path}"))?);
+    }
+    Ok(Some(hex::encode(hasher.finish())))
+}
+
+/// The ESP holding the bootloader content that is used as the source of truth:
+/// the one mounted by the system if any, otherwise the first.
+fn primary_esp(esps: &[Esp]) -> usize {
+    esps.iter()
+        .position(|esp| {
+            ESP_MOUNTPOINTS
+                .iter()
+                .any(|p| is_mounted_at(p, &esp.maj_min))
+        })
+        .unwrap_or_default()
+}
+
+/// Replace the bootloader content of the ESP mounted at `target` by that of `source`.
+fn copy_esp_content(source: &Utf8Path, target: &Utf8Path) -> Result<()> {
+    for d in ESP_CONTENT_DIRS {
+        let src = source.join(d);
+        let dest = target.join(d);
+        let tmp = target.join(format!("{d}.bootc-tmp"));
+        let old = target.join(format!("{d}.bootc-old"));
+        // Recover from an interruption between the renames below
+        if !dest.try_exists()? && old.try_exists()? {
+            std::fs::rename(&old, &dest).with_context(|| format!("Restoring {dest}"))?;
+        }
+        if !src.try_exists()? {
+            continue;
+        }
+        // Copy to a temporary location first, so that the content is never
+        // partially updated. vfat has no atomic exchange, so this is followed
+        // by two renames; in between, there is a short window in which `dest`
+        // does not exist. If we are interrupted there, the firmware falls back
+        // to another ESP, and the next sync restores the old content first.
+        for p in [&tmp, &old] {
+            if p.try_exists()? {
+                std::fs::remove_dir_all(p).with_context(|| format!("Removing {p}"))?;
+            }
+        }
+        Task::new_quiet("cp")
+            .args(["-r", src.as_str(), tmp.as_str()])
+            .run()?;
+        rustix::fs::sync();
+        if dest.try_exists()? {
+            std::fs::rename(&dest, &old)?;
+        }
+        std::fs::rename(&tmp, &dest)?;
+        rustix::fs::sync();
+        if old.try_exists()? {
+            std::fs::remove_dir_all(&old)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the bootloader content of the ESP mounted at `source` to the `targets`,
+/// unless they are already up to date.
+fn copy_to_esps<'a>(source: &Utf8Path, targets: impl IntoIterator<Item = &'a Esp>) -> Result<()> {
+    let digest = esp_digest(source)?;
+    for esp in targets {
+        let target = MountedEsp::new(esp, true)?;
+        if esp_digest(&target.path)? == digest {
+            tracing::debug!("{} is up to date", esp.device);
+            continue;
+        }
+        println!("Copying bootloader to {}", esp.device);
+        copy_esp_content(source, &target.path)
+            .with_context(|| format!("Updating {}", esp.device))?;
+    }
+    Ok(())
+}
+
+/// Copy the bootloader content of the primary ESP of the root filesystem to
+/// all other ESPs on its backing devices.  This is also needed if bootupd
+/// manages the bootloader, as it only updates the ESP mounted by the system.
+#[context("Synchronizing ESPs")]
+pub(crate) fn sync_esps(root: &Utf8Path) -> Result<()> {
+    let esps = find_esps_for_root(root)?;
+    if esps.len() < 2 {
+        return Ok(());
+    }
+    let primary = primary_esp(&esps);
+    let source = MountedEsp::new(&esps[primary], false)?;
+    let others = esps
+        .iter()
+        .enumerate()
+        .filter_map(|(i, esp)| (i != primary).then_some(esp));
+    copy_to_esps(&source.path, others)
+}
+
+/// At installation time, copy the bootloader content of the ESP mounted at `source`
+/// to all other ESPs on the devices backing the filesystem at `root`, so that the
+/// system can boot from any of its disks.
+#[context("Copying bootloader to all ESPs")]
+pub(crate) fn install_to_other_esps(root: &Utf8Path, source: &Utf8Path) -> Result<()> {
+    let source_dev = rustix::fs::stat(source.as_std_path())?.st_dev;
+    let source_maj_min = format!(
+        "{}:{}",
+        rustix::fs::major(source_dev),
+        rustix::fs::minor(source_dev)
+    );
+    let esps = find_esps_for_root(root)?;
+    copy_to_esps(
+        source,
+        esps.iter().filter(|esp| esp.maj_min != source_maj_min),
+    )
+}
+
+/// Gather the status of all ESPs backing the root filesystem.
+pub(crate) fn esp_status() -> Result<Vec<EspStatus>> {
+    let esps = find_esps_for_root("/sysroot".into())?;
+    if esps.is_empty() {
+        return Ok(Vec::new());
+    }
+    let primary = primary_esp(&esps);
+    let digests = esps
+        .iter()
+        .map(|esp| esp_digest(&MountedEsp::new(esp, false)?.path))
+        .collect::<Result<Vec<_>>>()?;
+    let r = esps
+        .into_iter()
+        .zip(digests.iter())
+        .enumerate()
+        .map(|(i, (esp, digest))| EspStatus {
+            device: esp.device.into_string(),
+            parent: esp.parent.into_string(),
+            primary: i == primary,
+            in_sync: digest.is_some() && *digest == digests[primary],
+            digest: digest.clone(),
+        })
+        .collect();
+    Ok(r)
+}
+
+/// Version metadata of a bootupd component.
+#[derive(Deserialize, Debug)]
+struct BootupdContent {
+    version: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct BootupdComponent {
+    installed: BootupdContent,
+    update: Option<BootupdContent>,
+    updatable: String,
+}
+
+/// The output of `bootupctl status --json`.
+#[derive(Deserialize, Debug)]
+struct BootupdStatus {
+    components: BTreeMap<String, BootupdComponent>,
+}
+
+impl BootupdStatus {
+    fn into_components(self) -> Vec<BootloaderComponent> {
+        self.components
+            .into_iter()
+            .map(|(name, c)| {
+                // Accept both the kebab-case and the older CamelCase serialization
+                let update = match c.updatable.replace('-', "").to_lowercase().as_str() {
+                    "atlatestversion" | "noupdateavailable" => BootloaderUpdate::UpToDate,
+                    "upgradable" => BootloaderUpdate::Available,
+                    "woulddowngrade" => BootloaderUpdate::WouldDowngrade,
+                    _ => BootloaderUpdate::Unknown,
+                };
+                BootloaderComponent {
+                    name,
+                    installed: Some(c.installed.version),
+                    available: c.update.map(|u| u.version),
+                    update,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Determine which backend manages the bootloader of the booted system.
+fn backend(sysroot: &ostree::Sysroot) -> Result<BootloaderBackend> {
+    if cfg!(target_arch = "s390x") {
+        return Ok(BootloaderBackend::Zipl);
+    }
+    if let Some(booted) = sysroot.booted_deployment() {
+        if !systemd_boot::find_ukis(&sysroot.repo(), &booted.csum())?.is_empty() {
+            return Ok(BootloaderBackend::SystemdBoot);
+        }
+    }
+    Ok(BootloaderBackend::Bootupd)
+}
+
+/// Gather the state of the bootloader of the booted system.
+#[context("Querying bootloader status")]
+pub(crate) fn status(sysroot: &ostree::Sysroot) -> Result<BootloaderStatus> {
+    let backend = backend(sysroot)?;
+    let components = match backend {
+        // bootupd may not be installed, or not manage this system
+        BootloaderBackend::Bootupd => match Command::new("bootupctl")
+            .args(["status", "--json"])
+            .log_debug()
+            .run_and_parse_json::<BootupdStatus>()
+        {
+            Ok(status) => Some(status.into_components()),
+            Err(e) => {
+                tracing::warn!("Failed to query bootupd: {e:#}");
+                None
+            }
+        },
+        // Neither of these track versions of the installed bootloader
+        BootloaderBackend::SystemdBoot | BootloaderBackend::Zipl => Some(Vec::new()),
+    };
+    Ok(BootloaderStatus {
+        backend,
+        components,
+    })
+}
+
+/// Update the bootloader from the booted deployment.
+#[context("Updating bootloader")]
+pub(crate) fn update(sysroot: &ostree::Sysroot) -> Result<()> {
+    match backend(sysroot)? {
+        BootloaderBackend::Bootupd => {
+            Task::new("Running bootupctl to update bootloader", "bootupctl")
+                .arg("update")
+                .verbose()
+                .run()?
+        }
+        BootloaderBackend::SystemdBoot => {
+            Task::new("Running bootctl to update systemd-boot", "bootctl")
+                .args(["update", "--graceful"])
+                .verbose()
+                .run()?
+        }
+        BootloaderBackend::Zipl => {
+            // ostree runs zipl when finalizing a deployment
+            println!("The bootloader is updated along with each deployment.");
+            return Ok(());
+        }
+    }
+    // The backends only update the ESP mounted by the system
+    if crate::install::ARCH_USES_EFI {
+        sync_esps("/sysroot".into())?;
+    }
+    Ok(())
 }
 
 #[context("Installing bootloader using zipl")]
@@ -135,3 +642,115 @@ pub(crate) fn install_via_zipl(device: &PartitionTable, boot_uuid: &str) -> Resu
         .args(["--add-files", "--verbose"]);
     zipl_task.verbose().run().context(zipl_desc)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bootc_blockdev::parttable::{Gpt, Mbr, NewPartition, DEFAULT_ALIGNMENT};
+
+    #[test]
+    fn test_esp_partnos() -> Result<()> {
+        let mut gpt = Gpt::new(1 << 30, 512)?;
+        for type_guid in [
+            parttable::BIOS_BOOT_TYPE,
+            parttable::ESP_TYPE,
+            parttable::LINUX_FILESYSTEM_TYPE,
+        ] {
+            gpt.add_partition(
+                DEFAULT_ALIGNMENT,
+                NewPartition {
+                    size: Some(1 << 20),
+                    type_guid,
+                    ..Default::default()
+                },
+            )?;
+        }
+        assert_eq!(esp_partnos(&DiskLabel::Gpt(gpt)), [2]);
+
+        let mut mbr = Mbr::new(1 << 30, 512, 0x1234)?;
+        mbr.add_partition(DEFAULT_ALIGNMENT, parttable::MBR_ESP, Some(1 << 20), true)?;
+        mbr.add_partition(DEFAULT_ALIGNMENT, parttable::MBR_LINUX, None, false)?;
+        assert_eq!(esp_partnos(&DiskLabel::Mbr(mbr)), [1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_bootupd_status() -> Result<()> {
+        let status: BootupdStatus = serde_json::from_str(indoc::indoc! { r#"
+            {
+              "components": {
+                "BIOS": {
+                  "installed": {"timestamp": "2024-10-01T00:00:00Z", "version": "grub2-tools-1:2.06-95.fc40"},
+                  "interrupted": null,
+                  "update": {"timestamp": "2024-10-01T00:00:00Z", "version": "grub2-tools-1:2.06-95.fc40"},
+                  "updatable": "at-latest-version",
+                  "adopted-from": null
+                },
+                "EFI": {
+                  "installed": {"timestamp": "2024-10-01T00:00:00Z", "version": "grub2-efi-x64-1:2.06-95.fc40"},
+                  "interrupted": null,
+                  "update": {"timestamp": "2024-11-01T00:00:00Z", "version": "grub2-efi-x64-1:2.12-1.fc41"},
+                  "updatable": "Upgradable",
+                  "adopted-from": null
+                }
+              },
+              "adoptable": {}
+            }
+        "#})?;
+        let components = status.into_components();
+        assert_eq!(components.len(), 2);
+        assert_eq!(components[0].name, "BIOS");
+        assert_eq!(components[0].update, BootloaderUpdate::UpToDate);
+        assert_eq!(components[1].update, BootloaderUpdate::Available);
+        assert_eq!(
+            components[1].available.as_deref(),
+            Some("grub2-efi-x64-1:2.12-1.fc41")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_esp_digest() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let esp = Utf8Path::from_path(td.path()).unwrap();
+        assert_eq!(esp_digest(esp)?, None);
+        std::fs::create_dir_all(esp.join("EFI/BOOT"))?;
+        std::fs::write(esp.join("EFI/BOOT/BOOTX64.EFI"), "shim")?;
+        let digest = esp_digest(esp)?.unwrap();
+        assert_eq!(digest.len(), 64);
+        assert_eq!(esp_digest(esp)?.unwrap(), digest);
+
+        std::fs::write(esp.join("EFI/BOOT/BOOTX64.EFI"), "shim2")?;
+        assert_ne!(esp_digest(esp)?.unwrap(), digest);
+        // Renames are detected too
+        std::fs::write(esp.join("EFI/BOOT/BOOTX64.EFI"), "shim")?;
+        std::fs::rename(esp.join("EFI/BOOT"), esp.join("EFI/fedora"))?;
+        assert_ne!(esp_digest(esp)?.unwrap(), digest);
+        std::fs::rename(esp.join("EFI/fedora"), esp.join("EFI/BOOT"))?;
+        // As is the systemd-boot configuration
+        std::fs::create_dir(esp.join("loader"))?;
+        std::fs::write(esp.join("loader/loader.conf"), "default bootc-00-*\n")?;
+        assert_ne!(esp_digest(esp)?.unwrap(), digest);
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_esp_content() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td = Utf8Path::from_path(td.path()).unwrap();
+        let (source, target) = (td.join("source"), td.join("target"));
+        std::fs::create_dir_all(source.join("EFI/BOOT"))?;
+        std::fs::write(source.join("EFI/BOOT/BOOTX64.EFI"), "shim")?;
+        std::fs::create_dir_all(target.join("EFI/old"))?;
+        copy_esp_content(&source, &target)?;
+        assert_eq!(esp_digest(&source)?, esp_digest(&target)?);
+
+        // Interrupted between the renames
+        std::fs::rename(target.join("EFI"), target.join("EFI.bootc-old"))?;
+        std::fs::write(source.join("EFI/BOOT/BOOTX64.EFI"), "shim2")?;
+        copy_esp_content(&source, &target)?;
+        assert_eq!(esp_digest(&source)?, esp_digest(&target)?);
+        assert!(!target.join("EFI.bootc-old").try_exists()?);
+        Ok(())
+    }
+}
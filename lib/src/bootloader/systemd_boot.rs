@@ -0,0 +1,526 @@
+//! # systemd-boot and Unified Kernel Image support
+//!
+//! If the container image ships Unified Kernel Images (UKIs) in its kernel
+//! directory (`/usr/lib/modules/$kver/*.efi`), we install systemd-boot to the
+//! ESP instead of going through bootupd, and copy the UKI of each finalized
+//! deployment unmodified to `EFI/Linux` in the ESP as a [Type #2 boot entry].
+//!
+//! The kernel command line is embedded in the (typically signed) UKI, and hence
+//! can't know which deployment it belongs to. Instead, it must contain an
+//! `ostree=/ostree/bootc/uki/$name` argument with a name which is unique to the
+//! image build; we point that path to the deployment shipping the UKI.
+//!
+//! The entries are named after the index of the deployment in ostree's
+//! deployment list, so that the default entry (and the ordering of booted and
+//! rollback deployments) follows the deployment list; they need to be kept in
+//! sync whenever it changes.  Staged deployments only get an entry once they
+//! are finalized on shutdown.
+//!
+//! [Type #2 boot entry]: https://uapi-group.org/specifications/specs/boot_loader_specification/#type-2-efi-unified-kernel-images
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::bootabletree;
+use ostree_ext::ostree::{self, gio};
+use ostree_ext::prelude::*;
+
+use crate::task::Task;
+
+/// The directory in the ESP holding Type #2 entries.
+const ESP_ENTRIES_DIR: &str = "EFI/Linux";
+/// The prefix of the entries we manage.
+const ENTRY_PREFIX: &str = "bootc-";
+const ENTRY_SUFFIX: &str = ".efi";
+/// The suffix of entries which are being written.
+const TMP_SUFFIX: &str = ".tmp";
+/// The directory in the sysroot holding the links named by the `ostree=`
+/// argument embedded in the UKIs, pointing to their deployments.
+const UKI_LINKS_DIR: &str = "ostree/bootc/uki";
+/// The systemd-boot configuration in the ESP.
+const LOADER_CONF: &str = "loader/loader.conf";
+
+/// Find the Unified Kernel Images in the kernel directory of a commit.
+pub(crate) fn find_ukis(repo: &ostree::Repo, commit: &str) -> Result<Vec<gio::File>> {
+    let cancellable = gio::Cancellable::NONE;
+    let (root, _) = repo.read_commit(commit, cancellable)?;
+    let Some(kernel_dir) = bootabletree::find_kernel_dir(&root, cancellable)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    let e = kernel_dir.enumerate_children(
+        "standard::name,standard::type",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        cancellable,
+    )?;
+    while let Some(info) = e.next_file(cancellable)? {
+        if info.file_type() != gio::FileType::Regular {
+            continue;
+        }
+        let name = info.name();
+        if name.extension().is_some_and(|ext| ext == "efi") {
+            r.push(kernel_dir.child(name));
+        }
+    }
+    r.sort_by_cached_key(|f| f.basename());
+    Ok(r)
+}
+
+/// Returns true if the kernel directory of the root filesystem `root` contains a UKI.
+pub(crate) fn has_uki_fs(root: &Dir) -> Result<bool> {
+    let Some(kernel_dir) = bootabletree::find_kernel_dir_fs(root)? else {
+        return Ok(false);
+    };
+    for entry in root.read_dir(&kernel_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_efi = Utf8Path::new(&name.to_string_lossy()).extension() == Some("efi");
+        if is_efi && entry.file_type()?.is_file() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The identifier of a deployment, which stays the same as its index changes.
+fn entry_id(deployment: &ostree::Deployment) -> String {
+    format!(
+        "{}-{}.{}",
+        deployment.osname(),
+        deployment.csum(),
+        deployment.deployserial()
+    )
+}
+
+/// The filename of the entry for the deployment at `index` with identifier `id`.
+/// The index is zero-padded, so that entries sort by it.
+fn entry_name(index: usize, id: &str) -> String {
+    format!("{ENTRY_PREFIX}{index:02}-{id}{ENTRY_SUFFIX}")
+}
+
+/// Extract the deployment identifier from an entry filename.
+fn parse_entry_name(name: &str) -> Option<&str> {
+    let (_index, id) = name
+        .strip_prefix(ENTRY_PREFIX)?
+        .strip_suffix(ENTRY_SUFFIX)?
+        .split_once('-')?;
+    Some(id)
+}
+
+/// Read the kernel command line embedded in the `.cmdline` section of the PE
+/// image of a UKI, if any.
+fn uki_cmdline(f: &mut (impl Read + Seek)) -> Result<Option<String>> {
+    let mut read_at = |offset: u64, buf: &mut [u8]| -> Result<()> {
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(buf).context("Reading PE image")
+    };
+    let u16_at = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+    let u32_at = |b: &[u8], i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+
+    let mut dos = [0u8; 0x40];
+    read_at(0, &mut dos)?;
+    if &dos[..2] != b"MZ" {
+        bail!("Not a PE image");
+    }
+    let pe_offset = u64::from(u32_at(&dos, 0x3c));
+    // The PE signature, followed by the COFF header
+    let mut header = [0u8; 24];
+    read_at(pe_offset, &mut header)?;
+    if &header[..4] != b"PE\0\0" {
+        bail!("Not a PE image");
+    }
+    let n_sections = u16_at(&header, 6);
+    let optional_header_size = u64::from(u16_at(&header, 20));
+    let section_table = pe_offset + 24 + optional_header_size;
+    for i in 0..u64::from(n_sections) {
+        let mut section = [0u8; 40];
+        read_at(section_table + i * 40, &mut section)?;
+        if &section[..8] != b".cmdline" {
+            continue;
+        }
+        let size = u32_at(&section, 8).min(u32_at(&section, 16));
+        if size > 0x10000 {
+            bail!("Invalid .cmdline section of {size} bytes");
+        }
+        let mut buf = vec![0u8; size as usize];
+        read_at(u64::from(u32_at(&section, 20)), &mut buf)?;
+        let cmdline = String::from_utf8(buf).context("Invalid .cmdline section")?;
+        return Ok(Some(cmdline.trim_end_matches('\0').trim().to_owned()));
+    }
+    Ok(None)
+}
+
+/// The name of the link below [`UKI_LINKS_DIR`] which the `ostree=` argument of
+/// the embedded command line `cmdline` of a UKI refers to.
+fn uki_link_name(cmdline: &str) -> Result<&str> {
+    let target = cmdline
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix("ostree="))
+        .ok_or_else(|| anyhow::anyhow!("No ostree= argument in the command line of the UKI"))?;
+    target
+        .strip_prefix('/')
+        .and_then(|t| t.strip_prefix(UKI_LINKS_DIR))
+        .and_then(|t| t.strip_prefix('/'))
+        .filter(|name| !name.is_empty() && !name.contains('/') && !name.starts_with('.'))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "The UKI must be built with ostree=/{UKI_LINKS_DIR}/<name>, found ostree={target}"
+            )
+        })
+}
+
+/// Point the link `name` below [`UKI_LINKS_DIR`] to `deploy_path`.
+fn write_uki_link(links: &Dir, name: &str, deploy_path: &str) -> Result<()> {
+    // The links dir is three levels below the root of the sysroot
+    let target = format!("../../../{deploy_path}");
+    if links
+        .read_link_contents(name)
+        .is_ok_and(|t| t.as_os_str() == target.as_str())
+    {
+        return Ok(());
+    }
+    let tmp = format!(".{name}.tmp");
+    links.remove_file_optional(&tmp)?;
+    links.symlink_contents(&target, &tmp)?;
+    links
+        .rename(&tmp, links, name)
+        .with_context(|| format!("Writing link {name}"))?;
+    Ok(())
+}
+
+/// Update the `default` key in loader.conf to point to the first deployment.
+fn write_loader_conf(esp: &Dir) -> Result<()> {
+    let orig = esp.read_to_string(LOADER_CONF).or_else(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Ok(String::new())
+        } else {
+            Err(e)
+        }
+    })?;
+    let default = format!("default {ENTRY_PREFIX}00-*");
+    let mut conf = orig
+        .lines()
+        .filter(|l| !l.starts_with("default"))
+        .collect::<Vec<_>>();
+    conf.push(&default);
+    let mut conf = conf.join("\n");
+    conf.push('\n');
+    if conf != orig {
+        esp.create_dir_all("loader")?;
+        esp.atomic_write(LOADER_CONF, conf)?;
+    }
+    Ok(())
+}
+
+/// Find the single UKI of `deployment`.
+fn deployment_uki(repo: &ostree::Repo, deployment: &ostree::Deployment) -> Result<gio::File> {
+    let csum = deployment.csum();
+    let mut ukis = find_ukis(repo, &csum)?;
+    match ukis.len() {
+        1 => Ok(ukis.remove(0)),
+        0 => bail!("No UKI found in {csum}"),
+        _ => bail!("Found multiple UKIs in {csum}"),
+    }
+}
+
+/// Copy the UKI of `deployment` into `dir` as `name`.
+fn write_uki(
+    repo: &ostree::Repo,
+    deployment: &ostree::Deployment,
+    dir: &Dir,
+    name: &str,
+) -> Result<()> {
+    let uki = deployment_uki(repo, deployment)?;
+    let mut instream = uki.read(gio::Cancellable::NONE)?.into_read();
+    dir.atomic_replace_with(name, |w| std::io::copy(&mut instream, w).map(drop))
+        .with_context(|| format!("Writing {name}"))?;
+    Ok(())
+}
+
+/// Verify that the UKI of a (staged) deployment can be booted by us, so that
+/// problems are reported before the deployment is finalized.
+fn check_uki(repo: &ostree::Repo, deployment: &ostree::Deployment) -> Result<()> {
+    let uki = deployment_uki(repo, deployment)?;
+    let mut buf = Vec::new();
+    uki.read(gio::Cancellable::NONE)?
+        .into_read()
+        .read_to_end(&mut buf)?;
+    let cmdline = uki_cmdline(&mut std::io::Cursor::new(buf))?.unwrap_or_default();
+    uki_link_name(&cmdline)?;
+    Ok(())
+}
+
+/// Write the UKI of every finalized deployment as a Type #2 entry to the ESP mounted
+/// at `esp`, link the path named by its `ostree=` argument to the deployment, and
+/// remove the entries and links of deployments which no longer exist.
+#[context("Syncing boot entries")]
+pub(crate) fn sync_entries(sysroot: &ostree::Sysroot, esp: &Dir) -> Result<()> {
+    let repo = &sysroot.repo();
+    let sysroot_dir = crate::utils::sysroot_dir(sysroot)?;
+    sysroot_dir.create_dir_all(UKI_LINKS_DIR)?;
+    let links = &sysroot_dir.open_dir(UKI_LINKS_DIR)?;
+    esp.create_dir_all(ESP_ENTRIES_DIR)?;
+    let entries = &esp.open_dir(ESP_ENTRIES_DIR)?;
+
+    // The existing entries by deployment, which are renamed if the index changed
+    let mut existing = BTreeMap::new();
+    for entry in entries.entries()? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if let Some(id) = parse_entry_name(&name) {
+            existing.insert(id.to_owned(), name);
+        }
+    }
+
+    // Write the new entries before pruning the old ones, so that there is always
+    // an entry to boot.  A staged deployment only gets an entry once it has been
+    // finalized, which syncs the entries again.
+    let mut wanted_entries = BTreeSet::new();
+    let mut wanted_links = BTreeSet::new();
+    let deployments = sysroot.deployments();
+    for (i, deployment) in deployments.iter().filter(|d| !d.is_staged()).enumerate() {
+        let id = entry_id(deployment);
+        let name = entry_name(i, &id);
+        // Entries are only written (or renamed) once they are known to be
+        // bootable, as the default entry is matched by its name.
+        let path = if entries.try_exists(&name)? {
+            name.clone()
+        } else if let Some(prev) = existing.get(&id) {
+            prev.clone()
+        } else {
+            let tmp = format!("{name}{TMP_SUFFIX}");
+            tracing::debug!("Writing {name}");
+            write_uki(repo, deployment, entries, &tmp)?;
+            tmp
+        };
+        let cmdline = uki_cmdline(&mut entries.open(&path)?)
+            .with_context(|| format!("Reading {path}"))?
+            .unwrap_or_default();
+        let link = uki_link_name(&cmdline).with_context(|| format!("Reading {path}"))?;
+        // Only the first deployment built from a given UKI can be booted from it
+        if !wanted_links.insert(link.to_owned()) {
+            tracing::warn!("Not creating a boot entry for {id}, as its UKI is shared with a previous deployment");
+            if path.ends_with(TMP_SUFFIX) {
+                entries.remove_file(&path)?;
+            }
+            continue;
+        }
+        write_uki_link(links, link, &sysroot.deployment_dirpath(deployment))?;
+        if path != name {
+            tracing::debug!("Renaming {path} to {name}");
+            entries.rename(&path, entries, &name)?;
+        }
+        wanted_entries.insert(name);
+    }
+    if let Some(staged) = deployments.iter().find(|d| d.is_staged()) {
+        check_uki(repo, staged).context("Checking the UKI of the staged deployment")?;
+    }
+
+    for entry in entries.entries()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let ours = parse_entry_name(name).is_some()
+            || name
+                .strip_suffix(TMP_SUFFIX)
+                .is_some_and(|n| parse_entry_name(n).is_some());
+        if ours && !wanted_entries.contains(name) {
+            tracing::debug!("Removing {name}");
+            entries.remove_file(name)?;
+        }
+    }
+    // We own this directory entirely
+    for entry in links.entries()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !name
+            .to_str()
+            .is_some_and(|name| wanted_links.contains(name))
+        {
+            tracing::debug!("Removing link {name:?}");
+            links.remove_file(&name)?;
+        }
+    }
+
+    write_loader_conf(esp)?;
+    rustix::fs::sync();
+    Ok(())
+}
+
+/// The arguments passed to `bootctl` to install systemd-boot to the ESP mounted at `esp_path`.
+pub(crate) fn install_args(
+    esp_path: &Utf8Path,
+    configopts: &crate::install::InstallConfigOpts,
+) -> Vec<String> {
+    // Like bootupd, only touch the firmware settings when targeting this machine.
+    let no_variables = configopts.generic_image.then_some("--no-variables");
+    [
+        "install",
+        "--make-entry-directory=no",
+        "--esp-path",
+        esp_path.as_str(),
+    ]
+    .into_iter()
+    .chain(no_variables)
+    .map(ToOwned::to_owned)
+    .collect()
+}
+
+/// Install systemd-boot from the running (target) image to the ESP mounted at `esp_path`.
+#[context("Installing systemd-boot")]
+pub(crate) fn install(
+    esp_path: &Utf8Path,
+    configopts: &crate::install::InstallConfigOpts,
+) -> Result<()> {
+    let mut cmd = Command::new("bootctl");
+    // We may be running in a container without udev, which bootctl uses to verify
+    // the partition type.
+    cmd.env("SYSTEMD_RELAX_ESP_CHECKS", "1");
+    Task::new_cmd("Running bootctl to install systemd-boot", cmd)
+        .args(install_args(esp_path, configopts))
+        .verbose()
+        .run()
+}
+
+/// If the booted deployment boots via a UKI, update the boot entries in all
+/// ESPs backing the root filesystem.
+pub(crate) fn sync_booted(sysroot: &ostree::Sysroot) -> Result<()> {
+    let Some(booted) = sysroot.booted_deployment() else {
+        return Ok(());
+    };
+    if find_ukis(&sysroot.repo(), &booted.csum())?.is_empty() {
+        return Ok(());
+    }
+    for esp in super::find_esps_for_root("/sysroot".into())? {
+        let mounted = super::MountedEsp::new(&esp, true)?;
+        let esp_dir = Dir::open_ambient_dir(&mounted.path, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {}", mounted.path))?;
+        sync_entries(sysroot, &esp_dir).with_context(|| format!("Updating {}", esp.device))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_name() {
+        let id = "default-c1b2a3.0";
+        let name = entry_name(1, id);
+        assert_eq!(name, "bootc-01-default-c1b2a3.0.efi");
+        assert_eq!(parse_entry_name(&name), Some(id));
+        assert!(entry_name(2, id) < entry_name(10, id));
+        for unmanaged in ["fedora.efi", "bootc-01-default.conf", "bootc.efi"] {
+            assert_eq!(parse_entry_name(unmanaged), None);
+        }
+    }
+
+    /// Build a minimal PE image with the given sections.
+    fn pe_image(sections: &[(&[u8; 8], &[u8])]) -> Vec<u8> {
+        let mut r = vec![0u8; 0x40];
+        r[..2].copy_from_slice(b"MZ");
+        r[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        let mut coff = [0u8; 24];
+        coff[..4].copy_from_slice(b"PE\0\0");
+        coff[6..8].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        r.extend_from_slice(&coff);
+        let mut offset = 0x200u32;
+        for (name, data) in sections {
+            let mut section = [0u8; 40];
+            section[..8].copy_from_slice(*name);
+            let len = data.len() as u32;
+            section[8..12].copy_from_slice(&len.to_le_bytes());
+            // The raw data is padded to the file alignment
+            section[16..20].copy_from_slice(&len.next_multiple_of(0x200).to_le_bytes());
+            section[20..24].copy_from_slice(&offset.to_le_bytes());
+            r.extend_from_slice(&section);
+            offset += len.next_multiple_of(0x200);
+        }
+        for (_, data) in sections {
+            r.resize(r.len().next_multiple_of(0x200), 0);
+            r.extend_from_slice(data);
+        }
+        r.resize(r.len().next_multiple_of(0x200), 0);
+        r
+    }
+
+    #[test]
+    fn test_uki_cmdline() -> Result<()> {
+        let cmdline = b"root=UUID=1234 rw ostree=/ostree/bootc/uki/build-1\n";
+        let pe = pe_image(&[(b".osrel\0\0", b"ID=fedora\n"), (b".cmdline", cmdline)]);
+        let found = uki_cmdline(&mut std::io::Cursor::new(pe))?.unwrap();
+        assert_eq!(found, "root=UUID=1234 rw ostree=/ostree/bootc/uki/build-1");
+        assert_eq!(uki_link_name(&found)?, "build-1");
+
+        let pe = pe_image(&[(b".linux\0\0", b"kernel")]);
+        assert_eq!(uki_cmdline(&mut std::io::Cursor::new(pe))?, None);
+        assert!(uki_cmdline(&mut std::io::Cursor::new(b"not a PE image")).is_err());
+
+        for invalid in [
+            "root=UUID=1234 rw",
+            "ostree=/ostree/boot.1/default/f00b4r/0",
+            "ostree=/ostree/bootc/uki/",
+            "ostree=/ostree/bootc/uki/a/b",
+            "ostree=/ostree/bootc/uki/..",
+        ] {
+            assert!(uki_link_name(invalid).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_uki_link() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let deploy_path = "ostree/deploy/default/deploy/c1b2a3.0";
+        write_uki_link(&td, "build-1", deploy_path)?;
+        let expected = "../../../ostree/deploy/default/deploy/c1b2a3.0";
+        assert_eq!(td.read_link_contents("build-1")?.to_str(), Some(expected));
+        // Replacing an existing link
+        write_uki_link(&td, "build-1", "ostree/deploy/default/deploy/d4e5f6.0")?;
+        assert_eq!(
+            td.read_link_contents("build-1")?.to_str(),
+            Some("../../../ostree/deploy/default/deploy/d4e5f6.0")
+        );
+        assert_eq!(td.entries()?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_uki_fs() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(!has_uki_fs(&td)?);
+        td.create_dir_all("usr/lib/modules/6.10.0")?;
+        td.write("usr/lib/modules/6.10.0/vmlinuz", "vmlinuz")?;
+        assert!(!has_uki_fs(&td)?);
+        td.write("usr/lib/modules/6.10.0/6.10.0.efi", "uki")?;
+        assert!(has_uki_fs(&td)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_loader_conf() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        write_loader_conf(&td)?;
+        assert_eq!(td.read_to_string(LOADER_CONF)?, "default bootc-00-*\n");
+
+        td.write(LOADER_CONF, "timeout 3\ndefault fedora*\neditor no\n")?;
+        write_loader_conf(&td)?;
+        assert_eq!(
+            td.read_to_string(LOADER_CONF)?,
+            "timeout 3\neditor no\ndefault bootc-00-*\n"
+        );
+        Ok(())
+    }
+}
@@ -9,6 +9,7 @@ use anyhow::{Context, Result};
 use camino::Utf8Path;
 use cap_std_ext::cap_std::fs::Dir;
 use cap_std_ext::dirext::CapStdExtDirExt;
+use cap_std_ext::RootDir;
 use fn_error_context::context;
 use ostree_ext::containers_image_proxy;
 use ostree_ext::ostree::Deployment;
@@ -54,6 +55,16 @@ pub(crate) fn query_bound_images_for_deployment(
 
 #[context("Querying bound images")]
 pub(crate) fn query_bound_images(root: &Dir) -> Result<Vec<BoundImage>> {
+    query_bound_images_each(root)?
+        .into_iter()
+        .map(|(_, r)| r)
+        .collect()
+}
+
+/// Parse each entry in [`BOUND_IMAGE_DIR`], returning its file name along with the
+/// result of parsing it. Unlike [`query_bound_images`], this does not stop at the
+/// first invalid entry.
+pub(crate) fn query_bound_images_each(root: &Dir) -> Result<Vec<(String, Result<BoundImage>)>> {
     let spec_dir = BOUND_IMAGE_DIR;
     let Some(bound_images_dir) = root.open_dir_optional(spec_dir)? else {
         tracing::debug!("Missing {spec_dir}");
@@ -69,37 +80,58 @@ pub(crate) fn query_bound_images(root: &Dir) -> Result<Vec<BoundImage>> {
         .entries()
         .context("Unable to read entries")?
     {
-        //validate entry is a symlink with correct extension
         let entry = entry?;
         let file_name = entry.file_name();
-        let file_name = if let Some(n) = file_name.to_str() {
-            n
-        } else {
-            anyhow::bail!("Invalid non-UTF8 filename: {file_name:?} in {}", spec_dir);
+        let Some(file_name) = file_name.to_str() else {
+            let r = Err(anyhow::anyhow!(
+                "Invalid non-UTF8 filename: {file_name:?} in {spec_dir}"
+            ));
+            bound_images.push((file_name.to_string_lossy().into_owned(), r));
+            continue;
         };
+        let r = parse_bound_image_link(&bound_images_dir, absroot, file_name);
+        bound_images.push((file_name.to_owned(), r));
+    }
 
-        if !entry.file_type()?.is_symlink() {
-            anyhow::bail!("Not a symlink: {file_name}");
-        }
+    Ok(bound_images)
+}
 
-        //parse the file contents
-        let path = Utf8Path::new(spec_dir).join(file_name);
-        let file_contents = absroot.read_to_string(&path)?;
+/// Parse the `.image` or `.container` file which the link `file_name` in
+/// [`BOUND_IMAGE_DIR`] points to.
+fn parse_bound_image_link(
+    bound_images_dir: &Dir,
+    absroot: &RootDir,
+    file_name: &str,
+) -> Result<BoundImage> {
+    //validate entry is a symlink with correct extension
+    if !bound_images_dir.symlink_metadata(file_name)?.is_symlink() {
+        anyhow::bail!("Not a symlink: {file_name}");
+    }
+    let file_extension = Utf8Path::new(file_name).extension();
+    if !matches!(file_extension, Some("image" | "container")) {
+        anyhow::bail!("Invalid file extension: {file_name}");
+    }
 
-        let file_ini = tini::Ini::from_string(&file_contents).context("Parse to ini")?;
-        let file_extension = Utf8Path::new(file_name).extension();
-        let bound_image = match file_extension {
-            Some("image") => parse_image_file(&file_ini).with_context(|| format!("Parsing {path}")),
-            Some("container") => {
-                parse_container_file(&file_ini).with_context(|| format!("Parsing {path}"))
-            }
-            _ => anyhow::bail!("Invalid file extension: {file_name}"),
-        }?;
+    let path = Utf8Path::new(BOUND_IMAGE_DIR).join(file_name);
+    let Some(f) = absroot.open_optional(&path)? else {
+        let target = bound_images_dir.read_link_contents(file_name)?;
+        anyhow::bail!("Link target of {path} does not exist: {target:?}");
+    };
 
-        bound_images.push(bound_image);
+    //parse the file contents
+    let file_contents = std::io::read_to_string(f)?;
+    let file_ini = tini::Ini::from_string(&file_contents).context("Parse to ini")?;
+    match file_extension {
+        Some("image") => parse_image_file(&file_ini),
+        _ => parse_container_file(&file_ini),
     }
+    .with_context(|| format!("Parsing {path}"))
+}
 
-    Ok(bound_images)
+/// Returns true if the image reference is pinned by digest, e.g.
+/// `quay.io/example/app@sha256:...`.
+pub(crate) fn image_is_pinned(image: &str) -> bool {
+    matches!(image.rsplit_once('@'), Some((_, digest)) if digest.contains(':'))
 }
 
 impl ResolvedBoundImage {
@@ -291,6 +323,57 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_query_bound_images_each() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all(BOUND_IMAGE_DIR)?;
+        td.write(
+            "good.container",
+            "[Container]\nImage=quay.io/foo/good:latest\n",
+        )?;
+        td.symlink_contents(
+            "/good.container",
+            format!("{BOUND_IMAGE_DIR}/good.container"),
+        )?;
+        td.symlink_contents("/missing.image", format!("{BOUND_IMAGE_DIR}/missing.image"))?;
+        td.write(format!("{BOUND_IMAGE_DIR}/regular.image"), "[Image]\n")?;
+        td.symlink_contents("/good.container", format!("{BOUND_IMAGE_DIR}/good.txt"))?;
+
+        let mut r = query_bound_images_each(td)?;
+        r.sort_by(|a, b| a.0.cmp(&b.0));
+        let r = r
+            .into_iter()
+            .map(|(name, r)| (name, r.map(|i| i.image).map_err(|e| format!("{e:#}"))))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            r[0],
+            (
+                "good.container".into(),
+                Ok("quay.io/foo/good:latest".into())
+            )
+        );
+        assert!(r[1]
+            .1
+            .as_ref()
+            .unwrap_err()
+            .contains("Invalid file extension"));
+        assert!(r[2].1.as_ref().unwrap_err().contains("does not exist"));
+        assert!(r[3].1.as_ref().unwrap_err().contains("Not a symlink"));
+        assert!(query_bound_images(td).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_is_pinned() {
+        let digest = "sha256:0f1e2d3c4b5a69788796a5b4c3d2e1f00f1e2d3c4b5a69788796a5b4c3d2e1f0";
+        assert!(image_is_pinned(&format!("quay.io/foo/foo@{digest}")));
+        assert!(image_is_pinned(&format!(
+            "localhost:5000/foo:latest@{digest}"
+        )));
+        assert!(!image_is_pinned("quay.io/foo/foo:latest"));
+        assert!(!image_is_pinned("localhost:5000/foo"));
+    }
+
     #[test]
     fn test_parse_spec_value() -> Result<()> {
         //should parse string with no % characters
@@ -3,7 +3,7 @@
 //! Command line tool to manage bootable ostree-based containers.
 
 use std::ffi::{CString, OsStr, OsString};
-use std::io::Seek;
+use std::io::{IsTerminal, Seek};
 use std::os::unix::process::CommandExt;
 use std::process::Command;
 
@@ -69,6 +69,15 @@ pub(crate) struct UpgradeOpts {
     #[clap(long, conflicts_with = "apply")]
     pub(crate) check: bool,
 
+    /// With `--check`, change the owner of files in `/var` for users and groups whose
+    /// numeric ID differs in the update.
+    ///
+    /// This fetches the image layers of the update to find its users and groups.
+    /// The owner is changed when first booting into the update, before other
+    /// services are started.
+    #[clap(long, requires = "check")]
+    pub(crate) fix_id_drift: bool,
+
     /// Restart or reboot into the new target image.
     ///
     /// Currently, this option always reboots.  In the future this command
@@ -122,6 +131,16 @@ pub(crate) struct SwitchOpts {
     #[clap(long)]
     pub(crate) retain: bool,
 
+    /// Deploy the image into this stateroot instead of the booted one.
+    ///
+    /// The stateroot must already exist, e.g. from
+    /// `bootc install to-existing-root --stateroot`.  Its deployments are
+    /// kept separate from the booted one, including `/etc` and `/var`, and
+    /// take their kernel arguments from the latest deployment of that
+    /// stateroot, or only from the image if there is none.
+    #[clap(long, conflicts_with = "mutate_in_place")]
+    pub(crate) stateroot: Option<String>,
+
     /// Target image to use for the next boot.
     pub(crate) target: String,
 
@@ -133,6 +152,25 @@ pub(crate) struct SwitchOpts {
 #[derive(Debug, Parser, PartialEq, Eq)]
 pub(crate) struct RollbackOpts {}
 
+/// Options controlling a factory reset
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct FactoryResetOpts {
+    /// Preserve the entire contents of `/var`.
+    #[clap(long)]
+    pub(crate) keep_var: bool,
+
+    /// Preserve this path in `/var`; may be specified multiple times.
+    #[clap(long, value_name = "PATH", conflicts_with = "keep_var")]
+    pub(crate) keep_var_path: Vec<Utf8PathBuf>,
+
+    /// Restart or reboot into the reset system.
+    #[clap(long)]
+    pub(crate) apply: bool,
+
+    #[clap(flatten)]
+    pub(crate) progress: ProgressOptions,
+}
+
 /// Perform an edit operation
 #[derive(Debug, Parser, PartialEq, Eq)]
 pub(crate) struct EditOpts {
@@ -179,6 +217,11 @@ pub(crate) struct StatusOpts {
     /// Only display status for the booted deployment.
     #[clap(long)]
     pub(crate) booted: bool,
+
+    /// Also inspect the bootloader, including the content of all EFI System Partitions
+    /// backing the root filesystem.  This requires root privileges and mounts the ESPs.
+    #[clap(long)]
+    pub(crate) bootloader: bool,
 }
 
 #[derive(Debug, clap::Subcommand, PartialEq, Eq)]
@@ -195,6 +238,19 @@ pub(crate) enum InstallOpts {
     /// complex such as RAID, LVM, LUKS etc.
     #[cfg(feature = "install-to-disk")]
     ToDisk(crate::install::InstallToDiskOpts),
+    /// Install to a new disk image file (raw or qcow2).
+    ///
+    /// Unlike `install to-disk --via-loopback`, this does not use loopback devices or
+    /// mounts; filesystems are created directly from a staging directory and written
+    /// into the partitioned image, and the bootloader is installed offline.
+    ///
+    /// Currently only EFI systems, and filesystems which can be populated at
+    /// creation time (ext4, btrfs), are supported.
+    ///
+    /// Like the other install modes, this must be run as root in a privileged
+    /// container, as the deployment is written with its final ownership and labels.
+    #[cfg(feature = "install-to-disk")]
+    ToDiskImage(crate::install::diskimage::InstallToDiskImageOpts),
     /// Install to an externally created filesystem structure.
     ///
     /// In this variant of installation, the root filesystem alongside any necessary
@@ -270,6 +326,29 @@ pub(crate) enum ContainerOpts {
         /// Example: --skip nonempty-boot --skip baseimage-root
         #[clap(long)]
         skip: Vec<String>,
+
+        /// Also run the targeted optional lints, by name, which are not run by default.
+        ///
+        /// Example: --include selinux-labels
+        #[clap(long)]
+        include: Vec<String>,
+    },
+    /// Automatically fix some of the problems detected by `bootc container lint`.
+    ///
+    /// This is intended to be invoked via e.g. `RUN bootc container fix` as the
+    /// final step of a build process. It operates on the running root, and
+    /// currently:
+    ///
+    /// - Replaces a physical `/var/run` directory with a symlink to `/run`
+    /// - Removes empty `/etc/hostname` and `/etc/resolv.conf` files injected by the build
+    /// - Generates sysusers.d entries for users and groups which lack them
+    /// - Converts content in `/var` into a generated tmpfiles.d file
+    ///
+    /// Afterwards, the lints are run again.
+    Fix {
+        /// Make warnings from the lints fatal.
+        #[clap(long)]
+        fatal_warnings: bool,
     },
 }
 
@@ -425,6 +504,14 @@ pub(crate) enum InternalsOpts {
     Fsverity(FsverityOpts),
     /// Perform cleanup actions
     Cleanup,
+    /// Copy the content of `/var` preserved by a factory reset, on its first boot
+    FactoryResetVar,
+    /// Remove the stateroot replaced by a factory reset, once booted into the new one
+    FactoryResetCleanup,
+    /// Update the systemd-boot entries after the staged deployment was finalized
+    SyncBootEntries,
+    /// Change the owner of files in /var as scheduled by `bootc upgrade --check --fix-id-drift`
+    FixIdDrift,
     /// Proxy frontend for the `ostree-ext` CLI.
     OstreeExt {
         #[clap(allow_hyphen_values = true)]
@@ -448,6 +535,54 @@ pub(crate) enum InternalsOpts {
     PublishRhsmFacts,
 }
 
+/// Subcommands which operate on the bootloader.
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum BootloaderOpts {
+    /// Display the installed bootloader version, and whether the booted
+    /// deployment contains an update.
+    Status {
+        /// The output format.
+        #[clap(long)]
+        format: Option<OutputFormat>,
+    },
+    /// Update the bootloader to the version in the booted deployment.
+    ///
+    /// On systems with multiple EFI System Partitions (e.g. a mirrored root),
+    /// all of them are updated.
+    Update {
+        /// The output format for the resulting status.
+        #[clap(long)]
+        format: Option<OutputFormat>,
+    },
+}
+
+/// Subcommands which operate on `/etc`.
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum EtcOpts {
+    /// Show how `/etc` differs from the defaults in the booted image.
+    ///
+    /// Lists files which were added, modified (content, type, mode, ownership or
+    /// SELinux label) or removed relative to `/usr/etc`, which ostree uses as the
+    /// base for merging local changes on upgrades.  Other extended attributes are
+    /// not compared.
+    Diff {
+        /// The output format.
+        #[clap(long)]
+        format: Option<OutputFormat>,
+    },
+    /// Revert paths in `/etc` to the defaults in the booted image.
+    ///
+    /// Paths which don't exist in the defaults are removed. The SELinux labels
+    /// of restored files are set according to the policy. Each path is copied
+    /// under a temporary name and then renamed into place, so it is replaced
+    /// atomically.
+    Reset {
+        /// Paths to reset, either absolute or relative to `/etc`.
+        #[clap(required = true)]
+        paths: Vec<Utf8PathBuf>,
+    },
+}
+
 #[derive(Debug, clap::Subcommand, PartialEq, Eq)]
 pub(crate) enum StateOpts {
     /// Remove all ostree deployments from this system
@@ -509,6 +644,18 @@ pub(crate) enum Opt {
     /// A systemd journal message will be logged with `MESSAGE_ID=26f3b1eb24464d12aa5e7b544a6b5468` in
     /// order to detect a rollback invocation.
     Rollback(RollbackOpts),
+    /// Reset the system to the state of a fresh installation of the booted image.
+    ///
+    /// The booted image is deployed again into a new stateroot, with `/etc` taken
+    /// purely from the image and an empty `/var`, and queued for the next boot.
+    /// Use `--keep-var` or `--keep-var-path` to carry over data from the current `/var`;
+    /// it is copied on the first boot of the reset system, so it includes changes made
+    /// until the reboot.
+    ///
+    /// The previous stateroot, including its `/etc` and `/var`, is removed
+    /// by `bootc-factory-reset-cleanup.service` once the reset system has booted
+    /// successfully; until then it remains available as a fallback in the bootloader.
+    FactoryReset(FactoryResetOpts),
     /// Apply full changes to the host specification.
     ///
     /// This command operates very similarly to `kubectl apply`; if invoked interactively,
@@ -576,6 +723,15 @@ pub(crate) enum Opt {
     /// other setup and metadata.
     #[clap(subcommand)]
     Install(InstallOpts),
+    /// Inspect and update the bootloader.
+    ///
+    /// `bootc upgrade` does not update the bootloader itself (e.g. shim and grub
+    /// in the EFI System Partition); use `bootc bootloader update` for that.
+    #[clap(subcommand)]
+    Bootloader(BootloaderOpts),
+    /// Inspect and revert local changes to `/etc`.
+    #[clap(subcommand)]
+    Etc(EtcOpts),
     /// Operations which can be executed as part of a container build.
     #[clap(subcommand)]
     Container(ContainerOpts),
@@ -760,15 +916,17 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
     let staged_image = staged.as_ref().and_then(|s| s.image.as_ref());
     let mut changed = false;
     if opts.check {
-        let imgref = imgref.clone().into();
-        let mut imp = crate::deploy::new_importer(repo, &imgref).await?;
-        match imp.prepare().await? {
-            PrepareResult::AlreadyPresent(_) => {
-                println!("No changes in: {imgref:#}");
+        let ostree_imgref = imgref.clone().into();
+        let mut imp = crate::deploy::new_importer(repo, &ostree_imgref).await?;
+        // The commit of the update, if it has been fetched
+        let update = match imp.prepare().await? {
+            PrepareResult::AlreadyPresent(state) => {
+                println!("No changes in: {ostree_imgref:#}");
+                Some(state.merge_commit.clone())
             }
             PrepareResult::Ready(r) => {
                 crate::deploy::check_bootc_label(&r.config);
-                println!("Update available for: {imgref:#}");
+                println!("Update available for: {ostree_imgref:#}");
                 if let Some(version) = r.version() {
                     println!("  Version: {version}");
                 }
@@ -779,7 +937,17 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
                         ostree_container::ManifestDiff::new(&previous_image.manifest, &r.manifest);
                     diff.print();
                 }
+                if opts.fix_id_drift {
+                    let fetched =
+                        crate::deploy::pull(repo, imgref, None, opts.quiet, prog.clone()).await?;
+                    Some(fetched.ostree_commit.clone())
+                } else {
+                    None
+                }
             }
+        };
+        if let Some(update) = update {
+            crate::iddrift::check_update(sysroot, &booted_deployment, &update, opts.fix_id_drift)?;
         }
     } else {
         let fetched = crate::deploy::pull(repo, imgref, None, opts.quiet, prog.clone()).await?;
@@ -805,7 +973,7 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
             println!("No update available.")
         } else {
             let osname = booted_deployment.osname();
-            crate::deploy::stage(sysroot, &osname, &fetched, &spec, prog.clone()).await?;
+            crate::deploy::stage(sysroot, &osname, &fetched, &spec, None, prog.clone()).await?;
             changed = true;
             if let Some(prev) = booted_image.as_ref() {
                 if let Some(fetched_manifest) = fetched.get_manifest(repo)? {
@@ -818,6 +986,7 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
     }
     if changed {
         sysroot.update_mtime()?;
+        sync_esps();
 
         if opts.apply {
             crate::reboot::reboot()?;
@@ -829,6 +998,17 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
     Ok(())
 }
 
+/// Propagate the bootloader content to all ESPs of a mirrored root; a failure here
+/// doesn't affect the staged deployment, so just warn.
+fn sync_esps() {
+    if !crate::install::ARCH_USES_EFI {
+        return;
+    }
+    if let Err(e) = crate::bootloader::sync_esps("/sysroot".into()) {
+        crate::utils::medium_visibility_warning(&format!("{e:#}"));
+    }
+}
+
 /// Implementation of the `bootc switch` CLI command.
 #[context("Switching")]
 async fn switch(opts: SwitchOpts) -> Result<()> {
@@ -864,13 +1044,32 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
     let (booted_deployment, _deployments, host) =
         crate::status::get_status_require_booted(sysroot)?;
 
+    let booted_stateroot = booted_deployment.osname();
+    let stateroot = opts
+        .stateroot
+        .as_deref()
+        .unwrap_or(booted_stateroot.as_str());
+    let targeting_booted = stateroot == booted_stateroot.as_str();
+    if !targeting_booted {
+        let sysroot_dir = crate::utils::sysroot_dir(sysroot)?;
+        if !sysroot_dir.try_exists(format!("ostree/deploy/{stateroot}"))? {
+            anyhow::bail!("Stateroot not found: {stateroot}");
+        }
+    }
+
+    // The host spec describes the booted stateroot
+    let spec = if targeting_booted {
+        host.spec.clone()
+    } else {
+        host.other_stateroot_spec(stateroot)
+    };
     let new_spec = {
-        let mut new_spec = host.spec.clone();
+        let mut new_spec = spec.clone();
         new_spec.image = Some(target.clone());
         new_spec
     };
 
-    if new_spec == host.spec {
+    if new_spec == spec {
         println!("Image specification is unchanged.");
         return Ok(());
     }
@@ -878,7 +1077,7 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
 
     let fetched = crate::deploy::pull(repo, &target, None, opts.quiet, prog.clone()).await?;
 
-    if !opts.retain {
+    if targeting_booted && !opts.retain {
         // By default, we prune the previous ostree ref so it will go away after later upgrades
         if let Some(booted_origin) = booted_deployment.origin() {
             if let Some(ostree_ref) = booted_origin.optional_string("origin", "refspec")? {
@@ -889,10 +1088,10 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
         }
     }
 
-    let stateroot = booted_deployment.osname();
-    crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec, prog.clone()).await?;
+    crate::deploy::stage(sysroot, stateroot, &fetched, &new_spec, None, prog.clone()).await?;
 
     sysroot.update_mtime()?;
+    sync_esps();
 
     if opts.apply {
         crate::reboot::reboot()?;
@@ -908,6 +1107,88 @@ async fn rollback(_opts: RollbackOpts) -> Result<()> {
     crate::deploy::rollback(sysroot).await
 }
 
+/// Implementation of the `bootc factory-reset` CLI command.
+async fn factory_reset(opts: FactoryResetOpts) -> Result<()> {
+    let prog: ProgressWriter = opts.progress.try_into()?;
+    let keep_var = if opts.keep_var {
+        crate::factory_reset::KeepVar::All
+    } else if !opts.keep_var_path.is_empty() {
+        crate::factory_reset::KeepVar::Paths(opts.keep_var_path)
+    } else {
+        crate::factory_reset::KeepVar::None
+    };
+    let sysroot = &get_storage().await?;
+    crate::factory_reset::factory_reset(sysroot, keep_var, prog).await?;
+    sync_esps();
+
+    if opts.apply {
+        crate::reboot::reboot()?;
+    }
+
+    Ok(())
+}
+
+/// Implementation of the `bootc bootloader` CLI commands.
+#[context("Bootloader")]
+async fn bootloader(opts: BootloaderOpts) -> Result<()> {
+    let sysroot = &get_storage().await?;
+    let format = match opts {
+        BootloaderOpts::Status { format } => format,
+        BootloaderOpts::Update { format } => {
+            crate::bootloader::update(sysroot)?;
+            format
+        }
+    };
+    let status = crate::bootloader::status(sysroot)?;
+    let format = format.unwrap_or_else(|| {
+        if std::io::stdout().is_terminal() {
+            OutputFormat::HumanReadable
+        } else {
+            OutputFormat::Yaml
+        }
+    });
+    let mut out = std::io::stdout().lock();
+    match format {
+        OutputFormat::Json => serde_json::to_writer(&mut out, &status).map_err(anyhow::Error::new),
+        OutputFormat::Yaml => serde_yaml::to_writer(&mut out, &status).map_err(anyhow::Error::new),
+        OutputFormat::HumanReadable => crate::status::human_render_bootloader(&mut out, &status),
+    }
+    .context("Writing to stdout")
+}
+
+/// Implementation of the `bootc etc` CLI commands.
+#[context("Operating on /etc")]
+fn etc(opts: EtcOpts) -> Result<()> {
+    match opts {
+        EtcOpts::Diff { format } => {
+            let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+            let changes = crate::etc::diff_root(root)?;
+            let format = format.unwrap_or_else(|| {
+                if std::io::stdout().is_terminal() {
+                    OutputFormat::HumanReadable
+                } else {
+                    OutputFormat::Yaml
+                }
+            });
+            let mut out = std::io::stdout().lock();
+            match format {
+                OutputFormat::Json => {
+                    serde_json::to_writer(&mut out, &changes).map_err(anyhow::Error::new)
+                }
+                OutputFormat::Yaml => {
+                    serde_yaml::to_writer(&mut out, &changes).map_err(anyhow::Error::new)
+                }
+                OutputFormat::HumanReadable => crate::etc::human_render_diff(&mut out, &changes),
+            }
+            .context("Writing to stdout")
+        }
+        EtcOpts::Reset { paths } => {
+            require_root(false)?;
+            crate::etc::reset_root(&paths)
+        }
+    }
+}
+
 /// Implementation of the `bootc edit` CLI command.
 #[context("Editing spec")]
 async fn edit(opts: EditOpts) -> Result<()> {
@@ -947,9 +1228,10 @@ async fn edit(opts: EditOpts) -> Result<()> {
     // TODO gc old layers here
 
     let stateroot = booted_deployment.osname();
-    crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec, prog.clone()).await?;
+    crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec, None, prog.clone()).await?;
 
     sysroot.update_mtime()?;
+    sync_esps();
 
     Ok(())
 }
@@ -1050,14 +1332,18 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
         Opt::Upgrade(opts) => upgrade(opts).await,
         Opt::Switch(opts) => switch(opts).await,
         Opt::Rollback(opts) => rollback(opts).await,
+        Opt::FactoryReset(opts) => factory_reset(opts).await,
         Opt::Edit(opts) => edit(opts).await,
         Opt::UsrOverlay => usroverlay().await,
+        Opt::Bootloader(opts) => bootloader(opts).await,
+        Opt::Etc(opts) => etc(opts),
         Opt::Container(opts) => match opts {
             ContainerOpts::Lint {
                 rootfs,
                 fatal_warnings,
                 list,
                 skip,
+                include,
             } => {
                 if list {
                     return lints::lint_list(std::io::stdout().lock());
@@ -1074,10 +1360,26 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 };
 
                 let root = &Dir::open_ambient_dir(rootfs, cap_std::ambient_authority())?;
+                let include = include.iter().map(|s| s.as_str());
                 let skip = skip.iter().map(|s| s.as_str());
-                lints::lint(root, warnings, root_type, skip, std::io::stdout().lock())?;
+                lints::lint(
+                    root,
+                    warnings,
+                    root_type,
+                    include,
+                    skip,
+                    std::io::stdout().lock(),
+                )?;
                 Ok(())
             }
+            ContainerOpts::Fix { fatal_warnings } => {
+                let warnings = if fatal_warnings {
+                    lints::WarningDisposition::FatalWarnings
+                } else {
+                    lints::WarningDisposition::AllowWarnings
+                };
+                crate::fix::fix(warnings)
+            }
         },
         Opt::Image(opts) => match opts {
             ImageOpts::List {
@@ -1116,8 +1418,12 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
         Opt::Install(opts) => match opts {
             #[cfg(feature = "install-to-disk")]
             InstallOpts::ToDisk(opts) => crate::install::install_to_disk(opts).await,
+            #[cfg(feature = "install-to-disk")]
+            InstallOpts::ToDiskImage(opts) => {
+                crate::install::diskimage::install_to_disk_image(opts).await
+            }
             InstallOpts::ToFilesystem(opts) => {
-                crate::install::install_to_filesystem(opts, false).await
+                crate::install::install_to_filesystem(opts, false, false).await
             }
             InstallOpts::ToExistingRoot(opts) => {
                 crate::install::install_to_existing_root(opts).await
@@ -1187,6 +1493,22 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 let sysroot = get_storage().await?;
                 crate::deploy::cleanup(&sysroot).await
             }
+            InternalsOpts::FactoryResetVar => {
+                let sysroot = get_storage().await?;
+                crate::factory_reset::copy_pending_var(&sysroot)
+            }
+            InternalsOpts::FactoryResetCleanup => {
+                let sysroot = get_storage().await?;
+                crate::factory_reset::cleanup(&sysroot).await
+            }
+            InternalsOpts::SyncBootEntries => {
+                let sysroot = get_storage().await?;
+                crate::bootloader::systemd_boot::sync_booted(&sysroot)
+            }
+            InternalsOpts::FixIdDrift => {
+                let sysroot = get_storage().await?;
+                crate::iddrift::fix_pending(&sysroot)
+            }
             InternalsOpts::BootcInstallCompletion { sysroot, stateroot } => {
                 let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
                 crate::install::completion::run_from_ostree(rootfs, &sysroot, &stateroot).await
@@ -1270,6 +1592,30 @@ mod tests {
         );
     }
 
+    #[test]
+    #[cfg(feature = "install-to-disk")]
+    fn test_parse_install_to_disk_image() {
+        use crate::install::diskimage::DiskImageFormat;
+        let o = Opt::try_parse_from([
+            "bootc",
+            "install",
+            "to-disk-image",
+            "--size=10G",
+            "--format=qcow2",
+            "disk.qcow2",
+        ])
+        .unwrap();
+        let o = match o {
+            Opt::Install(InstallOpts::ToDiskImage(opts)) => opts,
+            o => panic!("Expected disk image opts, not {o:?}"),
+        };
+        assert_eq!(o.path.as_str(), "disk.qcow2");
+        assert_eq!(o.size, "10G");
+        assert_eq!(o.format, DiskImageFormat::Qcow2);
+        // --size is required
+        assert!(Opt::try_parse_from(["bootc", "install", "to-disk-image", "disk.raw"]).is_err());
+    }
+
     #[test]
     fn test_parse_opts() {
         assert!(matches!(
@@ -1278,7 +1624,8 @@ mod tests {
                 json: false,
                 format: None,
                 format_version: None,
-                booted: false
+                booted: false,
+                bootloader: false
             })
         ));
         assert!(matches!(
@@ -1288,6 +1635,65 @@ mod tests {
                 ..
             })
         ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "status", "--bootloader"]),
+            Opt::Status(StatusOpts {
+                bootloader: true,
+                ..
+            })
+        ));
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "bootloader", "update", "--format=json"]),
+            Opt::Bootloader(BootloaderOpts::Update {
+                format: Some(OutputFormat::Json)
+            })
+        );
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "etc", "reset", "ssh/sshd_config", "/etc/hosts"]),
+            Opt::Etc(EtcOpts::Reset {
+                paths: vec!["ssh/sshd_config".into(), "/etc/hosts".into()]
+            })
+        );
+        assert!(Opt::try_parse_from(["bootc", "etc", "reset"]).is_err());
+        assert!(matches!(
+            Opt::parse_including_static([
+                "bootc",
+                "factory-reset",
+                "--keep-var-path=lib/foo",
+                "--keep-var-path=/var/lib/bar"
+            ]),
+            Opt::FactoryReset(FactoryResetOpts { keep_var: false, keep_var_path, .. }) if keep_var_path.len() == 2
+        ));
+        assert!(Opt::try_parse_from([
+            "bootc",
+            "factory-reset",
+            "--keep-var",
+            "--keep-var-path=lib/foo"
+        ])
+        .is_err());
+        assert!(matches!(
+            Opt::parse_including_static([
+                "bootc",
+                "switch",
+                "--stateroot=fedora",
+                "quay.io/example/os"
+            ]),
+            Opt::Switch(SwitchOpts { stateroot: Some(s), .. }) if s == "fedora"
+        ));
+        assert!(Opt::try_parse_from([
+            "bootc",
+            "switch",
+            "--stateroot=fedora",
+            "--mutate-in-place",
+            "quay.io/example/os"
+        ])
+        .is_err());
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "container", "fix"]),
+            Opt::Container(ContainerOpts::Fix {
+                fatal_warnings: false
+            })
+        );
     }
 
     #[test]
@@ -18,6 +18,7 @@ use ostree_ext::container::store::{ImportProgress, PrepareResult};
 use ostree_ext::oci_spec::image::{Descriptor, Digest};
 use ostree_ext::ostree::Deployment;
 use ostree_ext::ostree::{self, Sysroot};
+use ostree_ext::prelude::Cast;
 use ostree_ext::sysroot::SysrootLock;
 use ostree_ext::tokio_util::spawn_blocking_cancellable_flatten;
 
@@ -414,6 +415,7 @@ pub(crate) async fn wipe_ostree(sysroot: Sysroot) -> Result<()> {
 
 pub(crate) async fn cleanup(sysroot: &Storage) -> Result<()> {
     let bound_prune = prune_container_store(sysroot);
+    let storage = sysroot;
 
     // We create clones (just atomic reference bumps) here to move to the thread.
     let repo = sysroot.repo();
@@ -465,6 +467,8 @@ pub(crate) async fn cleanup(sysroot: &Storage) -> Result<()> {
 
     // We run these in parallel mostly because we can.
     tokio::try_join!(repo_prune, bound_prune)?;
+    // Drop the boot entries of pruned deployments
+    crate::bootloader::systemd_boot::sync_booted(storage)?;
     Ok(())
 }
 
@@ -482,18 +486,28 @@ pub(crate) fn get_base_commit(repo: &ostree::Repo, commit: &str) -> Result<Optio
 async fn deploy(
     sysroot: &Storage,
     merge_deployment: Option<&Deployment>,
+    inherit_kargs: Option<&Deployment>,
     stateroot: &str,
     image: &ImageState,
     origin: &glib::KeyFile,
 ) -> Result<Deployment> {
-    // Compute the kernel argument overrides. In practice today this API is always expecting
-    // a merge deployment. The kargs code also always looks at the booted root (which
-    // is a distinct minor issue, but not super important as right now the install path
-    // doesn't use this API).
-    let override_kargs = if let Some(deployment) = merge_deployment {
-        Some(crate::kargs::get_kargs(sysroot, &deployment, image)?)
+    // Compute the kernel argument overrides. The kargs code also always looks at the booted
+    // root (which is a distinct minor issue, but not super important as right now the install
+    // path doesn't use this API). A stateroot without deployments has no merge deployment;
+    // unless the caller explicitly inherits the kernel arguments of another one, only
+    // those of the image are used then.
+    let override_kargs = if let Some(deployment) = merge_deployment.or(inherit_kargs) {
+        crate::kargs::get_kargs(sysroot, deployment, image)?
     } else {
-        None
+        let root = sysroot
+            .repo()
+            .read_commit(image.ostree_commit.as_str(), gio::Cancellable::NONE)?
+            .0;
+        crate::kargs::get_kargs_from_ostree_root(
+            &sysroot.repo(),
+            root.downcast_ref().unwrap(),
+            std::env::consts::ARCH,
+        )?
     };
     // Clone all the things to move to worker thread
     let sysroot_clone = sysroot.sysroot.clone();
@@ -513,11 +527,10 @@ async fn deploy(
             // Because the C API expects a Vec<&str>, we need to generate a new Vec<>
             // that borrows.
             let override_kargs = override_kargs
-                .as_deref()
-                .map(|v| v.iter().map(|s| s.as_str()).collect::<Vec<_>>());
-            if let Some(kargs) = override_kargs.as_deref() {
-                opts.override_kernel_argv = Some(&kargs);
-            }
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>();
+            opts.override_kernel_argv = Some(&override_kargs);
             let deployments = sysroot.deployments();
             let merge_deployment = merge_deployment.map(|m| &deployments[m]);
             let origin = glib::KeyFile::new();
@@ -552,13 +565,16 @@ fn origin_from_imageref(imgref: &ImageReference) -> Result<glib::KeyFile> {
     Ok(origin)
 }
 
-/// Stage (queue deployment of) a fetched container image.
+/// Stage (queue deployment of) a fetched container image.  If the stateroot has
+/// no deployments yet, the kernel arguments are taken from `inherit_kargs` if set,
+/// and otherwise only from the image.
 #[context("Staging")]
 pub(crate) async fn stage(
     sysroot: &Storage,
     stateroot: &str,
     image: &ImageState,
     spec: &RequiredHostSpec<'_>,
+    inherit_kargs: Option<&Deployment>,
     prog: ProgressWriter,
 ) -> Result<()> {
     let mut subtask = SubTaskStep {
@@ -608,11 +624,17 @@ pub(crate) async fn stage(
     let deployment = crate::deploy::deploy(
         sysroot,
         merge_deployment.as_ref(),
+        inherit_kargs,
         stateroot,
         image,
         &origin,
     )
     .await?;
+    if let Some(merge_deployment) = merge_deployment.as_ref() {
+        if let Err(e) = crate::iddrift::warn_on_drift(sysroot, merge_deployment, &deployment) {
+            tracing::warn!("Failed to check for user and group ID changes: {e:#}");
+        }
+    }
 
     subtask.completed = true;
     subtasks.push(subtask.clone());
@@ -735,6 +757,7 @@ pub(crate) async fn rollback(sysroot: &Storage) -> Result<()> {
         .collect::<Vec<_>>();
     tracing::debug!("Writing new deployments: {new_deployments:?}");
     sysroot.write_deployments(&new_deployments, gio::Cancellable::NONE)?;
+    crate::bootloader::systemd_boot::sync_booted(sysroot)?;
     if reverting {
         println!("Next boot: current deployment");
     } else {
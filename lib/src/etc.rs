@@ -0,0 +1,405 @@
+//! # Inspecting and reverting changes to `/etc`
+//!
+//! ostree keeps the default `/etc` of a deployment (as shipped in the image)
+//! in `/usr/etc`, and performs a 3-way merge of the changes made locally
+//! on top of it on upgrades. This module compares the live `/etc` with
+//! those defaults, and can revert selected paths to them.
+
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::{Dir, Metadata, MetadataExt};
+use cap_std_ext::cap_tempfile;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::{gio, ostree};
+use serde::{Deserialize, Serialize};
+
+use crate::task::Task;
+
+/// The default `/etc` of the booted deployment, relative to the root.
+const DEFAULT_ETC: &str = "usr/etc";
+const ETC: &str = "etc";
+
+/// How a path in `/etc` differs from the default.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum EtcChangeKind {
+    /// The path does not exist in the default `/etc`
+    Added,
+    /// The type, metadata (including the SELinux label) or content differ
+    Modified,
+    /// The path exists in the default `/etc`, but was removed
+    Removed,
+}
+
+/// A path in `/etc` which differs from the default.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EtcChange {
+    /// The absolute path
+    pub(crate) path: Utf8PathBuf,
+    /// The kind of change
+    pub(crate) kind: EtcChangeKind,
+}
+
+/// Compare the contents of two regular files, which must be of the same size.
+fn file_contents_equal(a: &Dir, b: &Dir, path: &Utf8Path) -> Result<bool> {
+    let mut a = a.open(path)?;
+    let mut b = b.open(path)?;
+    let mut abuf = [0u8; 8192];
+    let mut bbuf = [0u8; 8192];
+    loop {
+        let n = a.read(&mut abuf)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut bbuf[..n])?;
+        if abuf[..n] != bbuf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Whether the object at `path` differs between the two roots, not including
+/// any directory contents. Of the extended attributes, only the SELinux label
+/// is compared.
+fn differs(
+    defaults: &Dir,
+    current: &Dir,
+    path: &Utf8Path,
+    a: &Metadata,
+    b: &Metadata,
+) -> Result<bool> {
+    if a.file_type() != b.file_type()
+        || a.mode() != b.mode()
+        || a.uid() != b.uid()
+        || a.gid() != b.gid()
+    {
+        return Ok(true);
+    }
+    // ostree labels the default /etc in /usr/etc as if it were in /etc
+    if crate::lsm::get_security_selinux(defaults, path)?
+        != crate::lsm::get_security_selinux(current, path)?
+    {
+        return Ok(true);
+    }
+    let r = if a.is_symlink() {
+        defaults.read_link_contents(path)? != current.read_link_contents(path)?
+    } else if a.is_file() {
+        a.len() != b.len() || !file_contents_equal(defaults, current, path)?
+    } else {
+        false
+    };
+    Ok(r)
+}
+
+fn list_dir(d: &Dir, path: &Utf8Path) -> Result<BTreeSet<String>> {
+    let path = if path.as_str().is_empty() {
+        Utf8Path::new(".")
+    } else {
+        path
+    };
+    let mut r = BTreeSet::new();
+    for ent in d.read_dir(path)? {
+        let name = ent?.file_name();
+        let name = name
+            .into_string()
+            .map_err(|name| anyhow!("Invalid non-UTF-8 filename: {name:?}"))?;
+        r.insert(name);
+    }
+    Ok(r)
+}
+
+fn diff_recurse(
+    defaults: &Dir,
+    current: &Dir,
+    path: &mut Utf8PathBuf,
+    out: &mut Vec<EtcChange>,
+) -> Result<()> {
+    let a = list_dir(defaults, path)?;
+    let b = list_dir(current, path)?;
+    for name in a.union(&b) {
+        path.push(name);
+        let change = match (a.contains(name), b.contains(name)) {
+            (true, false) => Some(EtcChangeKind::Removed),
+            (false, true) => Some(EtcChangeKind::Added),
+            _ => {
+                let ameta = defaults.symlink_metadata(&*path)?;
+                let bmeta = current.symlink_metadata(&*path)?;
+                let changed = differs(defaults, current, path, &ameta, &bmeta)?;
+                if ameta.is_dir() && bmeta.is_dir() {
+                    // Report the directory itself first, then its contents
+                    if changed {
+                        out.push(EtcChange {
+                            path: Utf8Path::new("/etc").join(&*path),
+                            kind: EtcChangeKind::Modified,
+                        });
+                    }
+                    diff_recurse(defaults, current, path, out)?;
+                    None
+                } else {
+                    changed.then_some(EtcChangeKind::Modified)
+                }
+            }
+        };
+        if let Some(kind) = change {
+            out.push(EtcChange {
+                path: Utf8Path::new("/etc").join(&*path),
+                kind,
+            });
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Compute the changes of `current` relative to `defaults`. Added or removed
+/// directories are reported once, without their contents.
+pub(crate) fn diff(defaults: &Dir, current: &Dir) -> Result<Vec<EtcChange>> {
+    let mut r = Vec::new();
+    diff_recurse(defaults, current, &mut Utf8PathBuf::new(), &mut r)?;
+    Ok(r)
+}
+
+/// Accept paths either relative to `/etc` or absolute underneath it.
+fn normalize_etc_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let relpath = if path.is_absolute() {
+        path.strip_prefix("/etc")
+            .map_err(|_| anyhow!("Path is not in /etc: {path}"))?
+    } else {
+        path
+    };
+    let mut r = Utf8PathBuf::new();
+    for c in relpath.components() {
+        match c {
+            Utf8Component::Normal(c) => r.push(c),
+            Utf8Component::CurDir => {}
+            _ => anyhow::bail!("Invalid path: {path}"),
+        }
+    }
+    if r.as_str().is_empty() {
+        anyhow::bail!("Resetting all of /etc is not supported: {path}");
+    }
+    Ok(r)
+}
+
+/// Split a normalized path into its parent directory (`.` at the toplevel)
+/// and file name.
+fn split_parent(path: &Utf8Path) -> (&Utf8Path, &str) {
+    let name = path.file_name().expect("filename");
+    let parent = path.parent().filter(|p| !p.as_str().is_empty());
+    (parent.unwrap_or(Utf8Path::new(".")), name)
+}
+
+fn open_defaults(root: &Dir) -> Result<Dir> {
+    root.open_dir_optional(DEFAULT_ETC)?
+        .ok_or_else(|| anyhow!("No default /etc found in /{DEFAULT_ETC}"))
+}
+
+/// Implementation of the `bootc etc diff` CLI command.
+#[context("Computing /etc changes")]
+pub(crate) fn diff_root(root: &Dir) -> Result<Vec<EtcChange>> {
+    let defaults = &open_defaults(root)?;
+    let current = &root.open_dir(ETC)?;
+    diff(defaults, current)
+}
+
+/// Write the changes in a human readable way.
+pub(crate) fn human_render_diff(mut out: impl Write, changes: &[EtcChange]) -> Result<()> {
+    for change in changes {
+        let c = match change.kind {
+            EtcChangeKind::Added => 'A',
+            EtcChangeKind::Modified => 'M',
+            EtcChangeKind::Removed => 'D',
+        };
+        writeln!(out, "{c} {}", change.path)?;
+    }
+    Ok(())
+}
+
+/// Revert `paths` in the `/etc` of `root` to the defaults, labeling them
+/// according to `policy` if provided.
+///
+/// Each path is replaced atomically: the default is copied to a temporary
+/// directory next to it and then renamed over it, so that a path is never
+/// observed missing or partially copied.
+fn reset(root: &Dir, paths: &[Utf8PathBuf], policy: Option<&ostree::SePolicy>) -> Result<()> {
+    let defaults = &open_defaults(root)?;
+    let current = &root.open_dir(ETC)?;
+    let paths = paths
+        .iter()
+        .map(|p| normalize_etc_path(p))
+        .collect::<Result<Vec<_>>>()?;
+    for path in paths.iter() {
+        let in_defaults = defaults.symlink_metadata_optional(path)?.is_some();
+        let in_current = current.symlink_metadata_optional(path)?.is_some();
+        if !in_defaults {
+            if in_current {
+                let (parent, name) = split_parent(path);
+                let parent = &current.open_dir(parent)?;
+                // Move it out of /etc at once, then remove it
+                let td = cap_tempfile::TempDir::new_in(parent)?;
+                parent
+                    .rename(name, &td, name)
+                    .with_context(|| format!("Removing /etc/{path}"))?;
+                td.close()?;
+                println!("Removed: /etc/{path}");
+            } else {
+                println!("Not found in /etc or its defaults: /etc/{path}");
+            }
+            continue;
+        }
+        // The topmost path that will be created, which may be a leading directory
+        let mut created = Utf8PathBuf::new();
+        for c in path.components() {
+            created.push(c);
+            if current.symlink_metadata_optional(&created)?.is_none() {
+                break;
+            }
+        }
+        let (created_parent, created_name) = split_parent(&created);
+        let parent = &current.open_dir(created_parent)?;
+        let td = cap_tempfile::TempDir::new_in(parent)?;
+        // With --parents, any missing leading directories are recreated as well.
+        // The labels are set below.
+        // At the toplevel, the parent is `.` and the path is used as is
+        let src = path.strip_prefix(created_parent).unwrap_or(path);
+        Task::new_quiet("cp")
+            .args(["-a", "--no-preserve=context", "--parents", src.as_str()])
+            .arg(format!("/proc/self/fd/{}", td.as_raw_fd()))
+            .cwd(&defaults.open_dir(created_parent)?)?
+            .run()?;
+        if let Some(policy) = policy {
+            let mut staged = Utf8PathBuf::from(created_name);
+            let mut as_path = Utf8Path::new(ETC).join(&created);
+            crate::lsm::relabel_recurse_as(&td, &mut staged, &mut as_path, policy)?;
+        }
+        if in_current {
+            // Swap in the copy; this also works if the existing path is a
+            // non-empty directory or of a different type.
+            rustix::fs::renameat_with(
+                &*td,
+                created_name,
+                parent,
+                created_name,
+                rustix::fs::RenameFlags::EXCHANGE,
+            )
+            .with_context(|| format!("Replacing /etc/{path}"))?;
+        } else {
+            td.rename(created_name, parent, created_name)
+                .with_context(|| format!("Creating /etc/{created}"))?;
+        }
+        // This now holds the previous content, if any
+        td.close()?;
+        println!("Reset: /etc/{path}");
+    }
+    Ok(())
+}
+
+/// Implementation of the `bootc etc reset` CLI command.
+#[context("Resetting /etc")]
+pub(crate) fn reset_root(paths: &[Utf8PathBuf]) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let policy = if crate::lsm::selinux_enabled()? {
+        let policy = ostree::SePolicy::new_at(root.as_raw_fd(), gio::Cancellable::NONE)?;
+        policy.csum().is_some().then_some(policy)
+    } else {
+        None
+    };
+    reset(root, paths, policy.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn test_normalize_etc_path() {
+        for (input, expected) in [
+            ("ssh/sshd_config", "ssh/sshd_config"),
+            ("/etc/ssh/sshd_config", "ssh/sshd_config"),
+            ("./hosts", "hosts"),
+        ] {
+            assert_eq!(normalize_etc_path(input.into()).unwrap(), expected);
+        }
+        for invalid in ["/etc", "/usr/etc/hosts", "../hosts", ""] {
+            assert!(normalize_etc_path(invalid.into()).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_diff() -> Result<()> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        for d in ["a", "b"] {
+            td.create_dir_all(format!("{d}/ssh"))?;
+            td.create_dir_all(format!("{d}/dropin.d"))?;
+            td.write(format!("{d}/hosts"), "127.0.0.1 localhost\n")?;
+            td.write(format!("{d}/ssh/sshd_config"), "PermitRootLogin no\n")?;
+            td.symlink_contents("../usr/lib/os-release", format!("{d}/os-release"))?;
+        }
+        let defaults = &td.open_dir("a")?;
+        let current = &td.open_dir("b")?;
+        assert_eq!(diff(defaults, current)?, Vec::new());
+
+        defaults.create_dir("removed.d")?;
+        defaults.write("removed.d/file", "foo")?;
+        current.write("ssh/sshd_config", "PermitRootLogin yes\n")?;
+        current.write("dropin.d/added.conf", "foo")?;
+        current.remove_file("os-release")?;
+        current.symlink_contents("../usr/lib/other-release", "os-release")?;
+        current.set_permissions("hosts", cap_std::fs::Permissions::from_mode(0o600))?;
+        let changes = diff(defaults, current)?;
+        let changes = changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                ("/etc/dropin.d/added.conf", EtcChangeKind::Added),
+                ("/etc/hosts", EtcChangeKind::Modified),
+                ("/etc/os-release", EtcChangeKind::Modified),
+                ("/etc/removed.d", EtcChangeKind::Removed),
+                ("/etc/ssh/sshd_config", EtcChangeKind::Modified),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset() -> Result<()> {
+        let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("usr/etc/ssh/sshd_config.d")?;
+        td.write("usr/etc/hosts", "127.0.0.1 localhost\n")?;
+        td.write("usr/etc/ssh/sshd_config.d/50-default.conf", "a")?;
+        td.create_dir_all("etc/ssh/sshd_config.d")?;
+        td.write("etc/hosts", "10.0.0.1 foo\n")?;
+        td.write("etc/ssh/sshd_config.d/99-local.conf", "b")?;
+        td.write("etc/added", "c")?;
+
+        let paths = ["/etc/hosts", "ssh/sshd_config.d", "added", "missing"].map(Utf8PathBuf::from);
+        reset(td, &paths, None)?;
+        assert_eq!(td.read_to_string("etc/hosts")?, "127.0.0.1 localhost\n");
+        assert_eq!(list_dir(td, "etc/ssh/sshd_config.d".into())?.len(), 1);
+        assert!(!td.try_exists("etc/added")?);
+        // No temporary directories are left behind
+        assert_eq!(
+            list_dir(td, "etc".into())?,
+            ["hosts", "ssh"].map(String::from).into()
+        );
+        assert_eq!(diff_root(td)?, Vec::new());
+
+        // Missing leading directories are recreated
+        td.remove_all_optional("etc/ssh")?;
+        reset(td, &["ssh/sshd_config.d/50-default.conf".into()], None)?;
+        assert_eq!(diff_root(td)?, Vec::new());
+        Ok(())
+    }
+}
@@ -0,0 +1,440 @@
+//! # Factory reset
+//!
+//! A factory reset deploys the booted image again into a new stateroot, so that
+//! it starts out with `/etc` taken purely from the image and an empty `/var`
+//! (optionally preserving all or parts of the previous one). The content of
+//! `/var` to preserve is copied early on the first boot of the new deployment by
+//! `bootc-factory-reset-var.service`, so that it includes any changes made until
+//! the reboot. The previous stateroot is kept until the new deployment has booted
+//! successfully, at which point it is removed by `bootc-factory-reset-cleanup.service`.
+
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+use serde::{Deserialize, Serialize};
+
+use crate::deploy::{ImageState, RequiredHostSpec};
+use crate::progress_jsonl::ProgressWriter;
+use crate::store::Storage;
+use crate::task::Task;
+
+/// Records a pending factory reset, relative to the physical root.
+const FACTORY_RESET_STATE: &str = "ostree/bootc/factory-reset.json";
+
+/// What to preserve from the previous `/var`.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum KeepVar {
+    /// Start with an empty `/var`
+    #[default]
+    None,
+    /// Copy all of `/var`
+    All,
+    /// Copy the given paths, relative to `/var`
+    Paths(Vec<Utf8PathBuf>),
+}
+
+/// The state of a factory reset that is waiting for its first boot.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct PendingReset {
+    /// The stateroot of the new deployment
+    stateroot: String,
+    /// The stateroot to remove once the new one has booted
+    previous: String,
+    /// What remains to be copied from the previous `/var` on the first boot
+    #[serde(default)]
+    keep_var: KeepVar,
+}
+
+impl PendingReset {
+    fn read(sysroot_dir: &Dir) -> Result<Option<Self>> {
+        let Some(f) = sysroot_dir.open_optional(FACTORY_RESET_STATE)? else {
+            return Ok(None);
+        };
+        let r = serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("Parsing {FACTORY_RESET_STATE}"))?;
+        Ok(Some(r))
+    }
+
+    fn write(&self, sysroot_dir: &Dir) -> Result<()> {
+        sysroot_dir
+            .atomic_replace_with(FACTORY_RESET_STATE, |w| {
+                serde_json::to_writer(w, self)?;
+                anyhow::Ok(())
+            })
+            .context("Writing factory reset state")
+    }
+}
+
+/// Verify that `name` can safely be used as a path component below `ostree/deploy`.
+fn validate_stateroot_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        anyhow::bail!("Invalid stateroot name: {name:?}");
+    }
+    Ok(())
+}
+
+/// Pick a name for the new stateroot. We alternate between the base name
+/// and numbered variants of it, so that resetting a system twice ends up
+/// back with the original name.
+fn new_stateroot_name(booted: &str, exists: impl Fn(&str) -> Result<bool>) -> Result<String> {
+    let base = match booted.rsplit_once('.') {
+        Some((base, n)) if !base.is_empty() && n.parse::<u32>().is_ok() => base,
+        _ => booted,
+    };
+    if !exists(base)? {
+        return Ok(base.to_owned());
+    }
+    for n in 1.. {
+        let name = format!("{base}.{n}");
+        if !exists(&name)? {
+            return Ok(name);
+        }
+    }
+    unreachable!()
+}
+
+/// Accept paths either relative to `/var` or absolute underneath it.
+fn normalize_var_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let relpath = if path.is_absolute() {
+        path.strip_prefix("/var")
+            .map_err(|_| anyhow!("Path is not in /var: {path}"))?
+    } else {
+        path
+    };
+    let mut r = Utf8PathBuf::new();
+    for c in relpath.components() {
+        match c {
+            Utf8Component::Normal(c) => r.push(c),
+            Utf8Component::CurDir => {}
+            _ => anyhow::bail!("Invalid path: {path}"),
+        }
+    }
+    if r.as_str().is_empty() {
+        anyhow::bail!("Invalid path: {path}");
+    }
+    Ok(r)
+}
+
+/// Verify that the paths to preserve exist in the previous `/var`, normalizing them.
+fn check_keep_var(sysroot_dir: &Dir, previous: &str, keep: KeepVar) -> Result<KeepVar> {
+    let KeepVar::Paths(paths) = keep else {
+        return Ok(keep);
+    };
+    let src = sysroot_dir.open_dir(format!("ostree/deploy/{previous}/var"))?;
+    let paths = paths
+        .iter()
+        .map(|p| normalize_var_path(p))
+        .collect::<Result<Vec<_>>>()?;
+    for path in paths.iter() {
+        if !src.try_exists(path)? {
+            anyhow::bail!("Path not found: /var/{path}");
+        }
+    }
+    Ok(KeepVar::Paths(paths))
+}
+
+/// Copy the requested content of the previous `/var` into the new stateroot.
+#[context("Preserving /var")]
+fn copy_var(sysroot_dir: &Dir, previous: &str, stateroot: &str, keep: &KeepVar) -> Result<()> {
+    let srcdir = format!("ostree/deploy/{previous}/var");
+    let dest = format!("../../{stateroot}/var");
+    match keep {
+        KeepVar::None => Ok(()),
+        KeepVar::All => Task::new("Copying /var", "cp")
+            .args(["-a", "--reflink=auto", "-T", ".", &dest])
+            .cwd(&sysroot_dir.open_dir(&srcdir)?)?
+            .run(),
+        KeepVar::Paths(paths) => {
+            let src = sysroot_dir.open_dir(&srcdir)?;
+            // Paths which were removed since the reset was requested are skipped
+            let mut present = Vec::new();
+            for path in paths.iter() {
+                if src.try_exists(path)? {
+                    present.push(path.as_str());
+                } else {
+                    tracing::warn!("Path not found: /var/{path}");
+                }
+            }
+            if present.is_empty() {
+                return Ok(());
+            }
+            // With --parents, the leading directories are recreated as well
+            Task::new("Copying selected paths in /var", "cp")
+                .args(["-a", "--reflink=auto", "--parents"])
+                .args(present)
+                .arg(&dest)
+                .cwd(&src)?
+                .run()
+        }
+    }
+}
+
+/// Remove the stateroot of a factory reset which never took effect: it was
+/// interrupted, or its deployment was replaced since.  This is refused if any
+/// deployment still uses the stateroot.
+fn discard_stale(sysroot: &Storage, sysroot_dir: &Dir, pending: &PendingReset) -> Result<()> {
+    validate_stateroot_name(&pending.stateroot)?;
+    let in_use = sysroot
+        .deployments()
+        .iter()
+        .chain(sysroot.booted_deployment().as_ref())
+        .any(|d| d.osname() == pending.stateroot.as_str());
+    if in_use {
+        anyhow::bail!(
+            "A factory reset into stateroot {} is already pending",
+            pending.stateroot
+        );
+    }
+    tracing::debug!("Discarding stale factory reset into {}", pending.stateroot);
+    let path = format!("ostree/deploy/{}", pending.stateroot);
+    if sysroot_dir.try_exists(&path)? {
+        sysroot_dir.remove_dir_all(&path)?;
+    }
+    sysroot_dir.remove_file(FACTORY_RESET_STATE)?;
+    Ok(())
+}
+
+/// Implementation of the `bootc factory-reset` CLI command.
+#[context("Factory reset")]
+pub(crate) async fn factory_reset(
+    sysroot: &Storage,
+    keep_var: KeepVar,
+    prog: ProgressWriter,
+) -> Result<()> {
+    let cancellable = ostree::gio::Cancellable::NONE;
+    let repo = &sysroot.repo();
+    let (booted_deployment, _deployments, host) =
+        crate::status::get_status_require_booted(sysroot)?;
+    // SAFETY: We require a booted deployment above
+    let booted = host.status.booted.as_ref().unwrap();
+    let imgref = booted
+        .image
+        .as_ref()
+        .map(|img| &img.image)
+        .ok_or_else(|| anyhow!("Booted deployment is not container image based"))?;
+    let image = booted
+        .query_image(repo)?
+        .map(|img| ImageState::from(*img))
+        .ok_or_else(|| anyhow!("Failed to find image for booted deployment"))?;
+
+    let sysroot_dir = crate::utils::sysroot_dir(sysroot)?;
+    if let Some(pending) = PendingReset::read(&sysroot_dir)? {
+        discard_stale(sysroot, &sysroot_dir, &pending)?;
+    }
+    let previous = booted_deployment.osname().to_string();
+    let keep_var = check_keep_var(&sysroot_dir, &previous, keep_var)?;
+    let stateroot = new_stateroot_name(&previous, |name| {
+        sysroot_dir
+            .try_exists(format!("ostree/deploy/{name}"))
+            .map_err(Into::into)
+    })?;
+    tracing::debug!("Resetting into new stateroot {stateroot}");
+
+    sysroot.init_osname(&stateroot, cancellable)?;
+    let pending = PendingReset {
+        stateroot: stateroot.clone(),
+        previous,
+        keep_var,
+    };
+    pending.write(&sysroot_dir)?;
+
+    // There's no merge deployment in the new stateroot, so /etc comes purely
+    // from the image; the kernel arguments are those of the booted deployment.
+    let spec = RequiredHostSpec { image: imgref };
+    crate::deploy::stage(
+        sysroot,
+        &stateroot,
+        &image,
+        &spec,
+        Some(&booted_deployment),
+        prog,
+    )
+    .await?;
+    sysroot.update_mtime()?;
+    Ok(())
+}
+
+/// Copy the content of the previous `/var` to preserve, on the first boot of the
+/// reset system.  This is a no-op if there is no pending factory reset, or if it
+/// was already done.
+#[context("Preserving /var after factory reset")]
+pub(crate) fn copy_pending_var(sysroot: &Storage) -> Result<()> {
+    let sysroot_dir = crate::utils::sysroot_dir(sysroot)?;
+    let Some(mut pending) = PendingReset::read(&sysroot_dir)? else {
+        return Ok(());
+    };
+    let booted = sysroot.require_booted_deployment()?;
+    if booted.osname() != pending.stateroot.as_str() || pending.keep_var == KeepVar::None {
+        return Ok(());
+    }
+    validate_stateroot_name(&pending.previous)?;
+    copy_var(
+        &sysroot_dir,
+        &pending.previous,
+        &pending.stateroot,
+        &pending.keep_var,
+    )?;
+    pending.keep_var = KeepVar::None;
+    pending.write(&sysroot_dir)?;
+    Ok(())
+}
+
+/// The deployments to keep once booted into the reset stateroot: all but those
+/// of the `previous` stateroot, including those of unrelated stateroots.
+fn retained_deployments(
+    deployments: Vec<ostree::Deployment>,
+    previous: &str,
+) -> Vec<ostree::Deployment> {
+    deployments
+        .into_iter()
+        .filter(|d| d.osname() != previous)
+        .collect()
+}
+
+/// Remove the previous stateroot once the system has booted into the reset one.
+/// This is a no-op if there is no pending factory reset.
+#[context("Cleaning up after factory reset")]
+pub(crate) async fn cleanup(sysroot: &Storage) -> Result<()> {
+    let sysroot_dir = crate::utils::sysroot_dir(sysroot)?;
+    let Some(pending) = PendingReset::read(&sysroot_dir)? else {
+        return Ok(());
+    };
+    let booted = sysroot.require_booted_deployment()?;
+    if booted.osname() != pending.stateroot.as_str() {
+        // Either the new deployment hasn't been booted yet, or the system
+        // was manually booted into the previous stateroot.
+        tracing::debug!("Not booted into {}", pending.stateroot);
+        return Ok(());
+    }
+    if pending.keep_var != KeepVar::None {
+        anyhow::bail!(
+            "The content of /var to preserve was not copied from stateroot {}; not removing it",
+            pending.previous
+        );
+    }
+    validate_stateroot_name(&pending.previous)?;
+
+    let deployments = retained_deployments(sysroot.deployments(), &pending.previous);
+    sysroot.write_deployments(&deployments, ostree::gio::Cancellable::NONE)?;
+    let path = format!("ostree/deploy/{}", pending.previous);
+    if sysroot_dir.try_exists(&path)? {
+        println!("Removing stateroot {}", pending.previous);
+        let sysroot_dir = sysroot_dir.try_clone()?;
+        tokio::task::spawn_blocking(move || sysroot_dir.remove_dir_all(&path)).await??;
+    }
+    sysroot_dir.remove_file(FACTORY_RESET_STATE)?;
+    crate::deploy::cleanup(sysroot).await?;
+    sysroot.update_mtime()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_stateroot_name() -> Result<()> {
+        let existing = ["default", "default.1", "fedora.2"];
+        let exists = |name: &str| Ok(existing.contains(&name));
+        assert_eq!(new_stateroot_name("default", exists)?, "default.2");
+        assert_eq!(new_stateroot_name("fedora.2", exists)?, "fedora");
+        assert_eq!(new_stateroot_name("rhel", |_| Ok(false))?, "rhel");
+        assert_eq!(new_stateroot_name("v1.x", |n| Ok(n == "v1.x"))?, "v1.x.1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_reset() -> Result<()> {
+        let pending: PendingReset =
+            serde_json::from_str(r#"{"stateroot":"default.1","previous":"default"}"#)?;
+        assert_eq!(pending.keep_var, KeepVar::None);
+        let pending = PendingReset {
+            keep_var: KeepVar::Paths(vec!["lib/containers".into()]),
+            ..pending
+        };
+        let s = serde_json::to_string(&pending)?;
+        assert_eq!(
+            s,
+            r#"{"stateroot":"default.1","previous":"default","keep-var":{"paths":["lib/containers"]}}"#
+        );
+        assert_eq!(serde_json::from_str::<PendingReset>(&s)?, pending);
+        for invalid in ["", "..", ".hidden", "a/b"] {
+            assert!(validate_stateroot_name(invalid).is_err(), "{invalid}");
+        }
+        validate_stateroot_name("default.1")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_var() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std_ext::cap_std::ambient_authority())?;
+        td.create_dir_all("ostree/deploy/default/var/lib/containers")?;
+        td.create_dir_all("ostree/deploy/default/var/log")?;
+        td.write("ostree/deploy/default/var/lib/containers/storage.conf", "x")?;
+        td.write("ostree/deploy/default/var/log/messages", "y")?;
+        td.create_dir_all("ostree/deploy/default.1/var")?;
+        let keep = KeepVar::Paths(vec!["/var/lib/containers/".into(), "log/missing".into()]);
+        assert!(check_keep_var(&td, "default", keep).is_err());
+        let keep = check_keep_var(
+            &td,
+            "default",
+            KeepVar::Paths(vec!["/var/lib/containers/".into()]),
+        )?;
+        assert_eq!(keep, KeepVar::Paths(vec!["lib/containers".into()]));
+        // Paths removed in the meantime are skipped
+        let keep = KeepVar::Paths(vec!["lib/containers".into(), "lib/gone".into()]);
+        copy_var(&td, "default", "default.1", &keep)?;
+        assert_eq!(
+            td.read_to_string("ostree/deploy/default.1/var/lib/containers/storage.conf")?,
+            "x"
+        );
+        assert!(!td.try_exists("ostree/deploy/default.1/var/log")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retained_deployments() {
+        let deployment = |index, osname: &str, serial| {
+            ostree::Deployment::new(index, osname, "abcd", serial, None, 0)
+        };
+        let deployments = vec![
+            deployment(0, "default.1", 0),
+            deployment(1, "default", 1),
+            deployment(2, "fedora", 0),
+            deployment(3, "default", 0),
+            deployment(4, "fedora", 1),
+        ];
+        let retained = retained_deployments(deployments, "default")
+            .iter()
+            .map(|d| (d.osname().to_string(), d.deployserial()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            retained,
+            [
+                ("default.1".to_owned(), 0),
+                ("fedora".to_owned(), 0),
+                ("fedora".to_owned(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize_var_path() {
+        for (input, expected) in [
+            ("lib/foo", "lib/foo"),
+            ("/var/lib/foo", "lib/foo"),
+            ("/var/lib/foo/", "lib/foo"),
+            ("./lib", "lib"),
+        ] {
+            assert_eq!(normalize_var_path(input.into()).unwrap(), expected);
+        }
+        for invalid in ["/etc/foo", "/var", "../etc", "lib/../../etc", ""] {
+            assert!(normalize_var_path(invalid.into()).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! # Implementation of `bootc container fix`
+//!
+//! This automatically remediates some of the problems reported by
+//! `bootc container lint`, and is intended to be invoked as the final
+//! `RUN` step of a container build.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use crate::lints;
+
+/// Files which may be synthesized as empty by a container runtime.
+const RUNTIME_INJECTED: &[&str] = &["etc/hostname", "etc/resolv.conf"];
+
+/// Replace a physical `/var/run` directory with the standard symlink to `/run`.
+fn fix_var_run(root: &Dir, mut out: impl Write) -> Result<()> {
+    let Some(meta) = root.symlink_metadata_optional("var/run")? else {
+        return Ok(());
+    };
+    if meta.is_symlink() {
+        return Ok(());
+    }
+    root.remove_all_optional("var/run")
+        .context("Removing /var/run")?;
+    root.symlink_contents("../run", "var/run")
+        .context("Creating /var/run")?;
+    writeln!(out, "Replaced /var/run with a symlink to /run")?;
+    Ok(())
+}
+
+/// Remove empty files which were likely synthesized by the container runtime.
+fn remove_runtime_injected(root: &Dir, mut out: impl Write) -> Result<()> {
+    for path in RUNTIME_INJECTED {
+        let Some(meta) = root.symlink_metadata_optional(path)? else {
+            continue;
+        };
+        if !(meta.is_file() && meta.len() == 0) {
+            continue;
+        }
+        match root.remove_file(path) {
+            Ok(()) => writeln!(out, "Removed empty /{path}")?,
+            // This is a bind mount set up by the runtime for the current build step
+            Err(e) if e.raw_os_error() == Some(rustix::io::Errno::BUSY.raw_os_error()) => {
+                writeln!(out, "Skipped /{path}: mounted by the container runtime")?
+            }
+            Err(e) => return Err(e).with_context(|| format!("Removing /{path}")),
+        }
+    }
+    Ok(())
+}
+
+/// Add sysusers.d entries for users and groups which lack them.
+fn generate_sysusers(root: &Dir, mut out: impl Write) -> Result<()> {
+    if let Some((n, path)) = bootc_sysusers::generate_missing(root)? {
+        writeln!(out, "Generated {n} sysusers.d entries in /{path}")?;
+    }
+    Ok(())
+}
+
+/// Implementation of the `bootc container fix` CLI command.
+#[context("Fixing container image")]
+pub(crate) fn fix(warning_disposition: lints::WarningDisposition) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let mut out = std::io::stdout().lock();
+
+    // This needs to come first, as a physical /var/run is a hard error
+    // when converting /var.
+    fix_var_run(root, &mut out)?;
+    remove_runtime_injected(root, &mut out)?;
+    generate_sysusers(root, &mut out)?;
+    let r = bootc_tmpfiles::convert_var_to_tmpfiles_current_root()?;
+    if let Some((n, path)) = r.generated {
+        writeln!(out, "Generated {n} tmpfiles.d entries for /var in /{path}")?;
+    }
+    if !r.unsupported.is_empty() {
+        writeln!(
+            out,
+            "Skipped {} files in /var which cannot be expressed in tmpfiles.d",
+            r.unsupported.len()
+        )?;
+        for path in r.unsupported.iter() {
+            tracing::debug!("Skipped /{}", path.display());
+        }
+    }
+
+    writeln!(out, "Running lints")?;
+    lints::lint(
+        root,
+        warning_disposition,
+        lints::RootType::Running,
+        [],
+        [],
+        &mut out,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_tempfile;
+
+    use super::*;
+
+    #[test]
+    fn test_fix_var_run() -> Result<()> {
+        let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let mut out = Vec::new();
+        fix_var_run(td, &mut out)?;
+        assert!(out.is_empty());
+
+        td.create_dir_all("var/run/foo")?;
+        fix_var_run(td, &mut out)?;
+        assert_eq!(td.read_link_contents("var/run")?.to_str(), Some("../run"));
+        assert!(!out.is_empty());
+
+        // Idempotent
+        out.clear();
+        fix_var_run(td, &mut out)?;
+        assert!(out.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_runtime_injected() -> Result<()> {
+        let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir("etc")?;
+        td.write("etc/hostname", "")?;
+        td.write("etc/resolv.conf", "nameserver 192.0.2.1\n")?;
+        let mut out = Vec::new();
+        remove_runtime_injected(td, &mut out)?;
+        assert!(!td.try_exists("etc/hostname")?);
+        assert!(td.try_exists("etc/resolv.conf")?);
+        assert_eq!(String::from_utf8(out)?, "Removed empty /etc/hostname\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,531 @@
+//! # Detecting UID/GID drift between deployments
+//!
+//! The content of `/var` persists across upgrades, but the numeric IDs of users
+//! and groups come from the image (or are allocated by `systemd-sysusers` from
+//! its sysusers.d configuration). If a new image version assigns a different ID to
+//! an existing user, files in `/var` owned by that user end up owned by the wrong
+//! (or no) user after rebooting into it. This detects such changes, and can
+//! re-own the affected files in `/var`.
+//!
+//! The files can't be re-owned while the previous deployment is still running,
+//! as its services would then lose access to them. Instead, the change is recorded
+//! and applied by `bootc-fix-id-drift.service` when first booting into the new
+//! deployment, before any other service accesses `/var`.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::BufReader;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bootc_sysusers::{IdChange, IdDrift};
+use camino::Utf8PathBuf;
+use cap_std::fs::{Dir, MetadataExt, Permissions, PermissionsExt};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_tempfile;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::{gio, ostree};
+use rustix::fs::{AtFlags, Gid, Uid};
+use serde::{Deserialize, Serialize};
+
+use crate::store::Storage;
+
+/// Records the ID changes to apply to `/var` on the next boot, relative to the
+/// physical root.
+const PENDING_FIX: &str = "ostree/bootc/id-drift.json";
+
+/// The files in `/etc` which determine the users and groups of a root.
+const ETC_ID_FILES: &[&str] = &["passwd", "group", "shadow", "gshadow", "machine-id"];
+/// The sysusers.d directory in `/etc`.
+const ETC_SYSUSERS_DIR: &str = "sysusers.d";
+/// The paths in `/usr` which determine the users and groups of a root.
+const USR_ID_PATHS: &[&str] = &[
+    "usr/lib/passwd",
+    "usr/lib/group",
+    "usr/lib/sysusers.d",
+    "usr/lib/os-release",
+];
+
+/// A user or group whose ID changes, as recorded in [`PENDING_FIX`].
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct PendingChange {
+    name: String,
+    old: u32,
+    new: u32,
+}
+
+/// The ID changes to apply to `/var` once booted into a deployment of `commit`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct PendingFix {
+    /// The stateroot whose `/var` to change
+    stateroot: String,
+    /// The commit of the deployment the IDs were computed for
+    commit: String,
+    /// The commit of the deployment the IDs were computed from
+    from: String,
+    users: Vec<PendingChange>,
+    groups: Vec<PendingChange>,
+}
+
+impl PendingFix {
+    fn new(stateroot: String, commit: String, from: String, drift: &IdDrift) -> Self {
+        let changes = |c: &[IdChange]| {
+            c.iter()
+                .map(|c| PendingChange {
+                    name: c.name.clone(),
+                    old: c.old,
+                    new: c.new,
+                })
+                .collect()
+        };
+        Self {
+            stateroot,
+            commit,
+            from,
+            users: changes(&drift.users),
+            groups: changes(&drift.groups),
+        }
+    }
+
+    fn drift(self) -> IdDrift {
+        let changes = |c: Vec<PendingChange>| {
+            c.into_iter()
+                .map(|c| IdChange {
+                    name: c.name,
+                    old: c.old,
+                    new: c.new,
+                })
+                .collect()
+        };
+        IdDrift {
+            users: changes(self.users),
+            groups: changes(self.groups),
+        }
+    }
+}
+
+/// The number of files in `/var` owned by users and groups whose ID changed.
+#[derive(Debug, Default)]
+pub(crate) struct VarOwnership {
+    /// Map from user name to the number of files it owns
+    pub(crate) users: BTreeMap<String, u64>,
+    /// Map from group name to the number of files it owns
+    pub(crate) groups: BTreeMap<String, u64>,
+}
+
+/// Compare the users and groups of two deployments.
+#[context("Comparing user and group IDs")]
+pub(crate) fn compare_deployments(
+    sysroot: &ostree::Sysroot,
+    old: &ostree::Deployment,
+    new: &ostree::Deployment,
+) -> Result<IdDrift> {
+    let old = crate::utils::deployment_fd(sysroot, old)?;
+    let new = crate::utils::deployment_fd(sysroot, new)?;
+    bootc_sysusers::compare_ids(&old, &new).map_err(Into::into)
+}
+
+struct Walker<'a> {
+    uids: BTreeMap<u32, (&'a str, u32)>,
+    gids: BTreeMap<u32, (&'a str, u32)>,
+    fix: bool,
+    result: VarOwnership,
+}
+
+impl Walker<'_> {
+    fn walk(&mut self, dir: &Dir, path: &mut Utf8PathBuf) -> Result<()> {
+        for ent in dir.entries()? {
+            let ent = ent?;
+            let name = ent.file_name();
+            let Some(name) = name.to_str() else {
+                // We need UTF-8 paths for error messages
+                tracing::debug!("Skipping non-UTF-8 filename {name:?} in {path}");
+                continue;
+            };
+            path.push(name);
+            let meta = ent.metadata()?;
+            if meta.is_dir() {
+                // Don't cross into other filesystems (e.g. network mounts)
+                if let Some(subdir) = dir.open_dir_noxdev(name)? {
+                    self.walk(&subdir, path)?;
+                }
+            }
+            let uid = self.uids.get(&meta.uid()).copied();
+            let gid = self.gids.get(&meta.gid()).copied();
+            if let Some((user, _)) = uid {
+                *self.result.users.entry(user.to_owned()).or_default() += 1;
+            }
+            if let Some((group, _)) = gid {
+                *self.result.groups.entry(group.to_owned()).or_default() += 1;
+            }
+            if self.fix && (uid.is_some() || gid.is_some()) {
+                tracing::debug!("Changing owner of {path}");
+                rustix::fs::chownat(
+                    dir,
+                    name,
+                    uid.map(|(_, new)| Uid::from_raw(new)),
+                    gid.map(|(_, new)| Gid::from_raw(new)),
+                    AtFlags::SYMLINK_NOFOLLOW,
+                )
+                .with_context(|| format!("Changing owner of {path}"))?;
+                // Changing the owner clears the setuid/setgid bits
+                let mode = meta.mode();
+                if !meta.file_type().is_symlink() && mode & 0o6000 != 0 {
+                    dir.set_permissions(name, Permissions::from_mode(mode & 0o7777))?;
+                }
+            }
+            path.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Find the files in `var` owned by the old ID of a user or group in `drift`.
+/// If `fix` is set, they are changed to be owned by its new ID. Mount points
+/// are not traversed.
+#[context("Scanning /var ownership")]
+pub(crate) fn scan_var(var: &Dir, drift: &IdDrift, fix: bool) -> Result<VarOwnership> {
+    let mut walker = Walker {
+        uids: drift
+            .users
+            .iter()
+            .map(|c| (c.old, (c.name.as_str(), c.new)))
+            .collect(),
+        gids: drift
+            .groups
+            .iter()
+            .map(|c| (c.old, (c.name.as_str(), c.new)))
+            .collect(),
+        fix,
+        result: VarOwnership::default(),
+    };
+    walker.walk(var, &mut Utf8PathBuf::from("/var"))?;
+    Ok(walker.result)
+}
+
+/// Print the changed IDs, along with the number of affected files in `/var` if known.
+pub(crate) fn print(drift: &IdDrift, ownership: Option<&VarOwnership>) {
+    let kinds = [
+        ("User", &drift.users, ownership.map(|o| &o.users)),
+        ("Group", &drift.groups, ownership.map(|o| &o.groups)),
+    ];
+    for (kind, changes, files) in kinds {
+        for c in changes {
+            print!("  {kind} {}: {} -> {}", c.name, c.old, c.new);
+            match files.map(|f| f.get(&c.name).copied().unwrap_or_default()) {
+                Some(n) => println!(" ({n} files in /var)"),
+                None => println!(),
+            }
+        }
+    }
+}
+
+/// Warn if the IDs of users or groups differ between the deployments.
+pub(crate) fn warn_on_drift(
+    sysroot: &ostree::Sysroot,
+    old: &ostree::Deployment,
+    new: &ostree::Deployment,
+) -> Result<()> {
+    let drift = compare_deployments(sysroot, old, new)?;
+    if drift.is_empty() {
+        return Ok(());
+    }
+    crate::utils::medium_visibility_warning(
+        "warning: The numeric IDs of users or groups differ in the new deployment; \
+        files in /var owned by them will have the wrong owner. \
+        Use `bootc upgrade --check --fix-id-drift` to re-own them when booting into it.",
+    );
+    print(&drift, None);
+    Ok(())
+}
+
+/// Read a file or symlink, returning whether it is a symlink along with its
+/// content or target.
+fn read_entry(root: &Dir, path: &str) -> Result<Option<(bool, Vec<u8>)>> {
+    let Some(meta) = root.symlink_metadata_optional(path)? else {
+        return Ok(None);
+    };
+    if meta.is_symlink() {
+        let target = root.read_link_contents(path)?;
+        Ok(Some((true, target.into_os_string().into_vec())))
+    } else {
+        Ok(Some((false, root.read(path)?)))
+    }
+}
+
+/// Apply the local modifications of the files in `/etc` of the booted root which
+/// define users and groups to the `etc` of `candidate`, as the merge of `/etc`
+/// done when deploying it keeps them.
+fn merge_etc(booted: &Dir, candidate: &Dir) -> Result<()> {
+    let mut files = ETC_ID_FILES
+        .iter()
+        .map(|f| f.to_string())
+        .collect::<Vec<_>>();
+    for dir in ["etc", "usr/etc"] {
+        let Some(d) = booted.open_dir_optional(format!("{dir}/{ETC_SYSUSERS_DIR}"))? else {
+            continue;
+        };
+        for ent in d.entries()? {
+            let name = ent?.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            files.push(format!("{ETC_SYSUSERS_DIR}/{name}"));
+        }
+    }
+    files.sort();
+    files.dedup();
+    for f in files {
+        let local = read_entry(booted, &format!("etc/{f}"))?;
+        if local == read_entry(booted, &format!("usr/etc/{f}"))? {
+            continue;
+        }
+        let path = format!("etc/{f}");
+        candidate.remove_file_optional(&path)?;
+        match local {
+            Some((true, target)) => {
+                candidate.create_dir_all(format!("etc/{ETC_SYSUSERS_DIR}"))?;
+                candidate.symlink_contents(OsString::from_vec(target), &path)?;
+            }
+            Some((false, content)) => {
+                candidate.create_dir_all(format!("etc/{ETC_SYSUSERS_DIR}"))?;
+                candidate.write(&path, content)?;
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check out the parts of `commit` which determine its users and groups, with
+/// `/etc` as it will be after merging the local modifications of `booted`.
+#[context("Checking out users and groups of {commit}")]
+fn candidate_root(
+    repo: &ostree::Repo,
+    commit: &str,
+    booted: &Dir,
+) -> Result<cap_tempfile::TempDir> {
+    let cancellable = gio::Cancellable::NONE;
+    let repo_tmp = Dir::reopen_dir(&repo.dfd_borrow())?.open_dir("tmp")?;
+    let td = cap_tempfile::TempDir::new_in(&repo_tmp)?;
+    td.create_dir_all("usr/lib")?;
+    td.create_dir("etc")?;
+    let (root, _) = repo.read_commit(commit, cancellable)?;
+    let etc_paths = ETC_ID_FILES
+        .iter()
+        .chain(&[ETC_SYSUSERS_DIR, "os-release"])
+        .map(|f| (format!("usr/etc/{f}"), format!("etc/{f}")));
+    let usr_paths = USR_ID_PATHS.iter().map(|p| (p.to_string(), p.to_string()));
+    for (src, dest) in etc_paths.chain(usr_paths) {
+        if !root.resolve_relative_path(&src).query_exists(cancellable) {
+            continue;
+        }
+        let opts = ostree::RepoCheckoutAtOptions {
+            mode: ostree::RepoCheckoutMode::User,
+            subpath: Some(Path::new("/").join(&src)),
+            ..Default::default()
+        };
+        repo.checkout_at(Some(&opts), td.as_raw_fd(), &dest, commit, cancellable)
+            .with_context(|| format!("Checking out {src}"))?;
+    }
+    merge_etc(booted, &td)?;
+    Ok(td)
+}
+
+/// Implementation of `bootc upgrade --check --fix-id-drift` (and of `--check` for
+/// an update which was already fetched): print the users and groups whose ID
+/// differs between the booted deployment and the update `commit`, along with the
+/// number of affected files in `/var`. If `fix` is set, their owner is changed
+/// when first booting into a deployment of `commit`.
+#[context("Checking user and group IDs")]
+pub(crate) fn check_update(
+    sysroot: &Storage,
+    booted: &ostree::Deployment,
+    commit: &str,
+    fix: bool,
+) -> Result<()> {
+    if booted.csum().as_str() == commit {
+        return Ok(());
+    }
+    let stateroot = booted.osname();
+    // If the update is already staged, use its actual merged /etc
+    let staged = sysroot
+        .staged_deployment()
+        .filter(|d| d.csum().as_str() == commit && d.osname() == stateroot);
+    let drift = if let Some(staged) = staged {
+        compare_deployments(sysroot, booted, &staged)?
+    } else {
+        let booted_root = crate::utils::deployment_fd(sysroot, booted)?;
+        let candidate = candidate_root(&sysroot.repo(), commit, &booted_root)?;
+        bootc_sysusers::compare_ids(&booted_root, &candidate)?
+    };
+    let sysroot_dir = crate::utils::sysroot_dir(sysroot)?;
+    if drift.is_empty() {
+        println!("No user or group ID changes in update.");
+        if fix {
+            sysroot_dir.remove_file_optional(PENDING_FIX)?;
+        }
+        return Ok(());
+    }
+    let var = Dir::open_ambient_dir("/var", cap_std::ambient_authority())?;
+    let ownership = scan_var(&var, &drift, false)?;
+    println!("User and group IDs changed in update:");
+    print(&drift, Some(&ownership));
+    if fix {
+        let pending = PendingFix::new(
+            stateroot.to_string(),
+            commit.to_owned(),
+            booted.csum().to_string(),
+            &drift,
+        );
+        sysroot_dir
+            .atomic_replace_with(PENDING_FIX, |w| {
+                serde_json::to_writer(w, &pending)?;
+                anyhow::Ok(())
+            })
+            .context("Writing pending ID changes")?;
+        println!("The owner of these files will be changed when booting into the update.");
+    }
+    Ok(())
+}
+
+/// Change the owner of the files in `/var` as recorded by `bootc upgrade --check
+/// --fix-id-drift`, once booted into the deployment the changes were computed for.
+/// This is run by `bootc-fix-id-drift.service`, before other services start.
+#[context("Changing owner of files in /var")]
+pub(crate) fn fix_pending(sysroot: &Storage) -> Result<()> {
+    let sysroot_dir = crate::utils::sysroot_dir(sysroot)?;
+    let Some(pending) = sysroot_dir.open_optional(PENDING_FIX)? else {
+        return Ok(());
+    };
+    let pending: PendingFix = serde_json::from_reader(BufReader::new(pending))
+        .with_context(|| format!("Parsing {PENDING_FIX}"))?;
+    let booted = sysroot.require_booted_deployment()?;
+    let booted_csum = booted.csum();
+    if booted.osname().as_str() != pending.stateroot || booted_csum.as_str() != pending.commit {
+        if booted_csum.as_str() != pending.from {
+            // Booted into neither the update nor the deployment the changes
+            // were computed from, so they don't apply anymore.
+            println!("Discarding ID changes computed for {}", pending.commit);
+            sysroot_dir.remove_file(PENDING_FIX)?;
+        } else {
+            tracing::debug!("Not booted into {}", pending.commit);
+        }
+        return Ok(());
+    }
+    let drift = pending.drift();
+    let var = Dir::open_ambient_dir("/var", cap_std::ambient_authority())?;
+    let ownership = scan_var(&var, &drift, true)?;
+    println!("Changed owner of files in /var:");
+    print(&drift, Some(&ownership));
+    sysroot_dir.remove_file(PENDING_FIX)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_var() -> Result<()> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("lib/app/data")?;
+        td.write("lib/app/data/file", "data")?;
+        td.symlink("data/file", "lib/app/link")?;
+        let uid = rustix::process::getuid().as_raw();
+        let gid = rustix::process::getgid().as_raw();
+        let drift = IdDrift {
+            users: vec![IdChange {
+                name: "app".into(),
+                old: uid,
+                new: uid,
+            }],
+            groups: vec![IdChange {
+                name: "other".into(),
+                old: gid.wrapping_add(1),
+                new: gid,
+            }],
+        };
+        let ownership = scan_var(&td, &drift, false)?;
+        // The directories lib, lib/app and lib/app/data, the file and the link
+        assert_eq!(ownership.users.get("app"), Some(&5));
+        assert!(ownership.groups.is_empty());
+        // Changing the owner to the same ID works without privileges
+        let ownership = scan_var(&td, &drift, true)?;
+        assert_eq!(ownership.users.get("app"), Some(&5));
+        assert_eq!(td.metadata("lib/app/data/file")?.uid(), uid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_etc() -> Result<()> {
+        let booted = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        booted.create_dir_all("usr/etc/sysusers.d")?;
+        booted.create_dir_all("etc/sysusers.d")?;
+        for root in ["etc", "usr/etc"] {
+            booted.write(format!("{root}/group"), "root:x:0:\n")?;
+            booted.write(format!("{root}/sysusers.d/a.conf"), "u a -\n")?;
+        }
+        // Locally modified, added and masked files
+        booted.write("usr/etc/passwd", "root:x:0:0::/root:/bin/bash\n")?;
+        booted.write("etc/passwd", "root:x:0:0::/root:/bin/zsh\n")?;
+        booted.write("etc/sysusers.d/local.conf", "u local -\n")?;
+        booted.write("usr/etc/sysusers.d/b.conf", "u b -\n")?;
+        booted.symlink_contents("/dev/null", "etc/sysusers.d/b.conf")?;
+
+        let candidate = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        candidate.create_dir_all("etc/sysusers.d")?;
+        candidate.write("etc/passwd", "root:x:0:0::/root:/bin/sh\n")?;
+        candidate.write("etc/group", "root:x:0:\nwheel:x:10:\n")?;
+        candidate.write("etc/sysusers.d/a.conf", "u a 500\n")?;
+        candidate.write("etc/sysusers.d/b.conf", "u b 501\n")?;
+        merge_etc(&booted, &candidate)?;
+        assert_eq!(
+            candidate.read_to_string("etc/passwd")?,
+            "root:x:0:0::/root:/bin/zsh\n"
+        );
+        // Unmodified files come from the candidate
+        assert_eq!(
+            candidate.read_to_string("etc/group")?,
+            "root:x:0:\nwheel:x:10:\n"
+        );
+        assert_eq!(
+            candidate.read_to_string("etc/sysusers.d/a.conf")?,
+            "u a 500\n"
+        );
+        assert_eq!(
+            candidate.read_to_string("etc/sysusers.d/local.conf")?,
+            "u local -\n"
+        );
+        assert_eq!(
+            candidate.read_link_contents("etc/sysusers.d/b.conf")?,
+            Path::new("/dev/null")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_fix() -> Result<()> {
+        let drift = IdDrift {
+            users: vec![IdChange {
+                name: "app".into(),
+                old: 990,
+                new: 991,
+            }],
+            groups: Vec::new(),
+        };
+        let pending = PendingFix::new("default".into(), "new".into(), "old".into(), &drift);
+        let s = serde_json::to_string(&pending)?;
+        assert_eq!(
+            s,
+            r#"{"stateroot":"default","commit":"new","from":"old","users":[{"name":"app","old":990,"new":991}],"groups":[]}"#
+        );
+        let pending: PendingFix = serde_json::from_str(&s)?;
+        assert_eq!(pending.drift(), drift);
+        Ok(())
+    }
+}
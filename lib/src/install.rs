@@ -10,6 +10,11 @@
 pub(crate) mod baseline;
 pub(crate) mod completion;
 pub(crate) mod config;
+mod configbundle;
+#[cfg(feature = "install-to-disk")]
+pub(crate) mod diskimage;
+mod hostnetwork;
+mod materializevar;
 mod osbuild;
 pub(crate) mod osconfig;
 
@@ -183,6 +188,39 @@ pub(crate) struct InstallConfigOpts {
     #[clap(long)]
     root_ssh_authorized_keys: Option<Utf8PathBuf>,
 
+    /// Create a user, in the form `NAME[:GROUP,...]`.  This option can be provided multiple times.
+    ///
+    /// Users (and additional users and groups from the install configuration) are created on
+    /// first boot via `/etc/sysusers.d/bootc-install-users.conf`.  Membership in the `wheel`
+    /// group conventionally grants `sudo` access.
+    ///
+    /// Example: --user=admin:wheel
+    #[clap(long = "user")]
+    user: Option<Vec<String>>,
+
+    /// Add SSH authorized keys for a user, in the form `NAME=PATH` where `PATH` is an
+    /// `authorized_keys` file.  This option can be provided multiple times.
+    ///
+    /// As with `--root-ssh-authorized-keys`, this is implemented via `tmpfiles.d`.
+    #[clap(long)]
+    user_ssh_authorized_keys: Option<Vec<String>>,
+
+    /// Set the password for a user, in the form `NAME=PATH` where `PATH` contains a
+    /// crypt(3) password hash (e.g. as generated by `mkpasswd`).
+    ///
+    /// The hash is provided to `systemd-sysusers` as a credential in `/etc/credstore`.
+    #[clap(long)]
+    user_password_hash: Option<Vec<String>>,
+
+    /// Apply per-machine configuration from a YAML or JSON document.
+    ///
+    /// The bundle may set the hostname, write files into `/etc` and `/var`, install,
+    /// enable or mask systemd units, and add NetworkManager connection profiles.  Everything
+    /// is written directly into the target root with the correct SELinux labels, and
+    /// the paths written are recorded in the aleph file.
+    #[clap(long)]
+    config_bundle: Option<Utf8PathBuf>,
+
     /// Perform configuration changes suitable for a "generic" disk image.
     /// At the moment:
     ///
@@ -201,6 +239,19 @@ pub(crate) struct InstallConfigOpts {
     /// The stateroot name to use. Defaults to `default`.
     #[clap(long)]
     pub(crate) stateroot: Option<String>,
+
+    /// Start from an empty `/var` instead of the `/var` content of the image, and
+    /// populate it by evaluating the tmpfiles.d configuration of the image.
+    ///
+    /// Ownership is resolved via the image's `/etc/passwd`, `/etc/group` and
+    /// sysusers.d; users and groups defined in sysusers.d are created in `/etc`
+    /// as `systemd-sysusers` would on boot.  Content is labeled with the SELinux
+    /// policy.  Entries which
+    /// can only be processed at runtime are left to `systemd-tmpfiles` on boot.
+    /// This cannot be used with an existing stateroot.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) var_from_tmpfiles: bool,
 }
 
 #[cfg(feature = "install-to-disk")]
@@ -226,6 +277,15 @@ pub(crate) struct InstallToDiskOpts {
     #[clap(long)]
     #[serde(default)]
     pub(crate) via_loopback: bool,
+
+    /// Do not modify the target device; instead, print the full installation plan
+    /// (partitioning, filesystems, LUKS setup, kernel arguments, bootloader
+    /// invocation, stateroot and bound images) as JSON to standard output.
+    /// No devices or mounts are set up; UUIDs generated at install time are
+    /// shown as placeholders such as `<root-uuid>`.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) dry_run: bool,
 }
 
 #[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -322,6 +382,17 @@ pub(crate) struct InstallToExistingRootOpts {
     #[clap(long)]
     pub(crate) acknowledge_destructive: bool,
 
+    /// Copy the hostname and NetworkManager connection profiles from the running system
+    /// into the new deployment's `/etc`.
+    ///
+    /// If the running system has no connection profiles, keyfiles are generated
+    /// for interfaces with statically configured addresses, along with the
+    /// default gateway and DNS servers, and for bonds, bridges and VLANs.  Other
+    /// virtual interfaces (e.g. veth) are skipped.  Anything already defined by
+    /// the target image is left untouched.
+    #[clap(long)]
+    pub(crate) copy_network_config: bool,
+
     /// Path to the mounted root; this is now not necessary to provide.
     /// Historically it was necessary to ensure the host rootfs was mounted at here
     /// via e.g. `-v /:/target`.
@@ -352,9 +423,17 @@ pub(crate) struct State {
     pub(crate) target_imgref: ostree_container::OstreeImageReference,
     #[allow(dead_code)]
     pub(crate) prepareroot_config: HashMap<String, String>,
+    /// Whether the target image enables a transient `/etc`
+    pub(crate) transient_etc: bool,
     pub(crate) install_config: Option<config::InstallConfiguration>,
     /// The parsed contents of the authorized_keys (not the file path)
     pub(crate) root_ssh_authorized_keys: Option<String>,
+    /// Groups to create, from the install configuration
+    pub(crate) groups: Vec<config::InstallGroup>,
+    /// Users to create, merged from the install configuration and command line
+    pub(crate) users: Vec<config::InstallUser>,
+    /// The parsed `--config-bundle`
+    pub(crate) config_bundle: Option<configbundle::LoadedConfigBundle>,
     #[allow(dead_code)]
     pub(crate) host_is_container: bool,
     /// The root filesystem of the running container
@@ -399,13 +478,18 @@ impl State {
 
 /// Path to initially deployed version information
 const BOOTC_ALEPH_PATH: &str = ".bootc-aleph.json";
+/// The bootupd client; images shipping it have their bootloader installed via bootupd.
+const BOOTUPCTL_PATH: &str = "usr/bin/bootupctl";
 
-/// The "aleph" version information is injected into /root/.bootc-aleph.json
+/// The "aleph" version information is injected into the root of the physical
+/// filesystem (i.e. `/sysroot/.bootc-aleph.json` on the booted system)
 /// and contains the image ID that was initially used to install.  This can
 /// be used to trace things like the specific version of `mkfs.ext4` or
 /// kernel version that was used.
-#[derive(Debug, Serialize)]
-struct InstallAleph {
+///
+/// Fields added later are optional so that older files can still be read.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct InstallAleph {
     /// Digested pull spec for installed image
     image: String,
     /// The version number
@@ -416,6 +500,29 @@ struct InstallAleph {
     kernel: String,
     /// The state of SELinux at install time
     selinux: String,
+    /// The version of bootc doing the installation
+    #[serde(default)]
+    bootc_version: Option<String>,
+    /// The install subcommand used
+    #[serde(default)]
+    install_mode: Option<crate::spec::InstallMode>,
+    /// The block device setup, for `to-disk`
+    #[serde(default)]
+    block_setup: Option<String>,
+    /// The type of the root filesystem
+    #[serde(default)]
+    filesystem: Option<String>,
+    #[serde(default)]
+    stateroot: Option<String>,
+    /// The kernel arguments of the initial deployment
+    #[serde(default)]
+    kargs: Vec<String>,
+    /// The logically bound images, with their digest if resolved
+    #[serde(default)]
+    bound_images: Vec<String>,
+    /// The configuration bundle applied, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config_bundle: Option<configbundle::AppliedConfigBundle>,
 }
 
 /// A mount specification is a subset of a line in `/etc/fstab`.
@@ -524,9 +631,46 @@ impl InstallAleph {
             timestamp,
             kernel: uname.release().to_str()?.to_string(),
             selinux: selinux_state.to_aleph().to_string(),
+            bootc_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            install_mode: None,
+            block_setup: None,
+            filesystem: None,
+            stateroot: None,
+            kargs: Vec::new(),
+            bound_images: Vec::new(),
+            config_bundle: None,
         };
         Ok(r)
     }
+
+    /// Read the aleph from the physical root, if present.
+    pub(crate) fn read(physical_root: &Dir) -> Result<Option<Self>> {
+        let Some(f) = physical_root.open_optional(BOOTC_ALEPH_PATH)? else {
+            return Ok(None);
+        };
+        let r = serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("Parsing {BOOTC_ALEPH_PATH}"))?;
+        Ok(Some(r))
+    }
+}
+
+impl From<InstallAleph> for crate::spec::InstallInfo {
+    fn from(aleph: InstallAleph) -> Self {
+        Self {
+            image: aleph.image,
+            version: aleph.version,
+            timestamp: aleph.timestamp,
+            bootc_version: aleph.bootc_version,
+            kernel: aleph.kernel,
+            selinux: aleph.selinux,
+            mode: aleph.install_mode,
+            block_setup: aleph.block_setup,
+            filesystem: aleph.filesystem,
+            stateroot: aleph.stateroot,
+            kargs: aleph.kargs,
+            bound_images: aleph.bound_images,
+        }
+    }
 }
 
 impl SourceInfo {
@@ -695,6 +839,32 @@ async fn initialize_ostree_root(state: &State, root_setup: &RootSetup) -> Result
     Ok((Storage::new(sysroot, &temp_run)?, has_ostree))
 }
 
+/// Final kargs, in order:
+/// - root filesystem kargs
+/// - install config kargs
+/// - kargs.d from container image
+/// - args specified on the CLI
+fn final_kargs<'a>(
+    state: &'a State,
+    root_kargs: &'a [String],
+    kargsd: &'a [String],
+) -> Vec<&'a str> {
+    // Keep this in sync with install/completion.rs for the Anaconda fixups
+    let install_config_kargs = state
+        .install_config
+        .as_ref()
+        .and_then(|c| c.kargs.as_ref())
+        .into_iter()
+        .flatten();
+    root_kargs
+        .iter()
+        .chain(install_config_kargs)
+        .chain(kargsd)
+        .chain(state.config_opts.karg.iter().flatten())
+        .map(|v| v.as_str())
+        .collect()
+}
+
 #[context("Creating ostree deployment")]
 async fn install_container(
     state: &State,
@@ -762,29 +932,10 @@ async fn install_container(
         merged_ostree_root.downcast_ref().unwrap(),
         std::env::consts::ARCH,
     )?;
-    let kargsd = kargsd.iter().map(|s| s.as_str());
-
-    // Keep this in sync with install/completion.rs for the Anaconda fixups
-    let install_config_kargs = state
-        .install_config
-        .as_ref()
-        .and_then(|c| c.kargs.as_ref())
-        .into_iter()
-        .flatten()
-        .map(|s| s.as_str());
-    // Final kargs, in order:
-    // - root filesystem kargs
-    // - install config kargs
-    // - kargs.d from container image
-    // - args specified on the CLI
-    let kargs = root_setup
-        .kargs
-        .iter()
-        .map(|v| v.as_str())
-        .chain(install_config_kargs)
-        .chain(kargsd)
-        .chain(state.config_opts.karg.iter().flatten().map(|v| v.as_str()))
-        .collect::<Vec<_>>();
+    let kargs = final_kargs(state, &root_setup.kargs, &kargsd);
+    if state.config_opts.var_from_tmpfiles {
+        materializevar::check_stateroot(sysroot, stateroot)?;
+    }
     let mut options = ostree_container::deploy::DeployOpts::default();
     options.kargs = Some(kargs.as_slice());
     options.target_imgref = Some(&state.target_imgref);
@@ -834,6 +985,16 @@ async fn install_container(
         }
     }
 
+    if state.config_opts.var_from_tmpfiles {
+        let n = materializevar::materialize_var(
+            &root_setup.physical_root,
+            Utf8Path::new(path.as_str()),
+            stateroot,
+            sepolicy,
+        )?;
+        println!("Populated /var from tmpfiles.d: {n} objects");
+    }
+
     // // Write the entry for /boot to /etc/fstab.  TODO: Encourage OSes to use the karg?
     // // Or better bind this with the grub data.
     // if let Some(boot) = root_setup.boot.as_ref() {
@@ -845,8 +1006,29 @@ async fn install_container(
     if let Some(contents) = state.root_ssh_authorized_keys.as_deref() {
         osconfig::inject_root_ssh_authorized_keys(&root, sepolicy, contents)?;
     }
+    osconfig::provision_users(&root, sepolicy, &state.groups, &state.users)?;
+
+    if let Some(host_network) = root_setup.host_network.as_ref() {
+        hostnetwork::apply(&root, sepolicy, host_network)?;
+    }
+
+    let applied_bundle = if let Some(bundle) = state.config_bundle.as_ref() {
+        let stateroot_dir = root_setup
+            .physical_root
+            .open_dir(format!("ostree/deploy/{stateroot}"))
+            .context("Opening stateroot")?;
+        Some(configbundle::apply(
+            bundle,
+            &root,
+            &stateroot_dir,
+            sepolicy,
+        )?)
+    } else {
+        None
+    };
 
-    let aleph = InstallAleph::new(&src_imageref, &imgstate, &state.selinux_state)?;
+    let mut aleph = InstallAleph::new(&src_imageref, &imgstate, &state.selinux_state)?;
+    aleph.config_bundle = applied_bundle;
     Ok((deployment, aleph))
 }
 
@@ -896,8 +1078,16 @@ pub(crate) struct RootSetup {
     rootfs_uuid: Option<String>,
     /// True if we should skip finalizing
     skip_finalize: bool,
+    /// True if a stateroot is added alongside those of an existing system
+    side_by_side: bool,
     boot: Option<MountSpec>,
     kargs: Vec<String>,
+    /// Network configuration captured from the host, for `--copy-network-config`
+    host_network: Option<hostnetwork::HostNetworkConfig>,
+    /// The install subcommand, recorded in the aleph
+    install_mode: crate::spec::InstallMode,
+    /// The block device setup, if we created it
+    block_setup: Option<String>,
 }
 
 fn require_boot_uuid(spec: &MountSpec) -> Result<&str> {
@@ -957,17 +1147,25 @@ impl SELinuxFinalState {
 /// and we aren't passed an override to disable it, then ensure
 /// the running process is labeled with install_t so it can
 /// write arbitrary labels.
+///
+/// In `dry_run` mode, the final state is computed without changing the
+/// running process or host.
 pub(crate) fn reexecute_self_for_selinux_if_needed(
     srcdata: &SourceInfo,
     override_disable_selinux: bool,
+    dry_run: bool,
 ) -> Result<SELinuxFinalState> {
     // If the target state has SELinux enabled, we need to check the host state.
     if srcdata.selinux {
         let host_selinux = crate::lsm::selinux_enabled()?;
         tracing::debug!("Target has SELinux, host={host_selinux}");
         let r = if override_disable_selinux {
-            println!("notice: Target has SELinux enabled, overriding to disable");
+            if !dry_run {
+                println!("notice: Target has SELinux enabled, overriding to disable");
+            }
             SELinuxFinalState::ForceTargetDisabled
+        } else if host_selinux && dry_run {
+            SELinuxFinalState::Enabled(None)
         } else if host_selinux {
             // /sys/fs/selinuxfs is not normally mounted, so we do that now.
             // Because SELinux enablement status is cached process-wide and was very likely
@@ -1162,11 +1360,100 @@ async fn verify_target_fetch(
     Ok(())
 }
 
+/// Split a `NAME=PATH` command line argument.
+fn parse_user_path_arg(arg: &str) -> Result<(&str, &Utf8Path)> {
+    let (name, path) = arg
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected NAME=PATH, found: {arg}"))?;
+    Ok((name, Utf8Path::new(path)))
+}
+
+fn find_user<'a>(
+    users: &'a mut [config::InstallUser],
+    name: &str,
+) -> Result<&'a mut config::InstallUser> {
+    users.iter_mut().find(|u| u.name == name).ok_or_else(|| {
+        anyhow!("Unknown user {name}; it must be specified via --user or the install configuration")
+    })
+}
+
+/// Merge the users and groups from the install configuration with those from the
+/// command line, eagerly reading any referenced files.
+fn install_users(
+    config_opts: &InstallConfigOpts,
+    install_config: Option<&config::InstallConfiguration>,
+) -> Result<(Vec<config::InstallGroup>, Vec<config::InstallUser>)> {
+    let groups = install_config
+        .and_then(|c| c.groups.clone())
+        .unwrap_or_default();
+    let mut users = install_config
+        .and_then(|c| c.users.clone())
+        .unwrap_or_default();
+    for spec in config_opts.user.iter().flatten() {
+        let (name, user_groups) = spec.split_once(':').unwrap_or((spec.as_str(), ""));
+        let user_groups = user_groups
+            .split(',')
+            .filter(|g| !g.is_empty())
+            .map(ToOwned::to_owned);
+        if let Some(user) = users.iter_mut().find(|u| u.name == name) {
+            user.groups.extend(user_groups);
+        } else {
+            users.push(config::InstallUser {
+                name: name.to_owned(),
+                groups: user_groups.collect(),
+                ..Default::default()
+            });
+        }
+    }
+    for arg in config_opts.user_ssh_authorized_keys.iter().flatten() {
+        let (name, path) = parse_user_path_arg(arg)?;
+        let contents = std::fs::read_to_string(path).with_context(|| format!("Reading {path}"))?;
+        let keys = contents
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(ToOwned::to_owned);
+        find_user(&mut users, name)?
+            .ssh_authorized_keys
+            .extend(keys);
+    }
+    for arg in config_opts.user_password_hash.iter().flatten() {
+        let (name, path) = parse_user_path_arg(arg)?;
+        let contents = std::fs::read_to_string(path).with_context(|| format!("Reading {path}"))?;
+        find_user(&mut users, name)?.password_hash = Some(contents.trim().to_owned());
+    }
+    osconfig::validate_users(&groups, &users)?;
+    Ok((groups, users))
+}
+
+/// Reject install options whose changes to `/etc` a transient `/etc` would discard on boot.
+fn check_transient_etc(ssh_keys: bool, users: bool, config_bundle: bool) -> Result<()> {
+    let conflicting = [
+        (ssh_keys, "--root-ssh-authorized-keys"),
+        (users, "users and groups"),
+        (config_bundle, "--config-bundle"),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, name)| enabled.then_some(name))
+    .collect::<Vec<_>>();
+    if !conflicting.is_empty() {
+        anyhow::bail!(
+            "The target image enables a transient /etc, which would discard the configuration from: {}",
+            conflicting.join(", ")
+        );
+    }
+    Ok(())
+}
+
 /// Preparation for an install; validates and prepares some (thereafter immutable) global state.
+///
+/// With `dry_run`, nothing is written to standard output (it is reserved for the plan),
+/// no mounts are set up and the SELinux state of the running process is left alone.
 async fn prepare_install(
     config_opts: InstallConfigOpts,
     source_opts: InstallSourceOpts,
     target_opts: InstallTargetOpts,
+    dry_run: bool,
 ) -> Result<Arc<State>> {
     tracing::trace!("Preparing install");
     let rootfs = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())
@@ -1231,36 +1518,48 @@ async fn prepare_install(
     };
     tracing::debug!("Target image reference: {target_imgref}");
 
-    // A bit of basic global state setup
-    crate::mount::ensure_mirrored_host_mount("/dev")?;
-    crate::mount::ensure_mirrored_host_mount("/var/lib/containers")?;
-    ensure_var()?;
-    setup_tmp_mounts()?;
+    // A bit of basic global state setup; a dry run only inspects the host.
+    if !dry_run {
+        crate::mount::ensure_mirrored_host_mount("/dev")?;
+        crate::mount::ensure_mirrored_host_mount("/var/lib/containers")?;
+        ensure_var()?;
+        setup_tmp_mounts()?;
+    }
     // Allocate a temporary directory we can use in various places to avoid
     // creating multiple.
     let tempdir = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
     // And continue to init global state
-    osbuild::adjust_for_bootc_image_builder(&rootfs, &tempdir)?;
+    if !dry_run {
+        osbuild::adjust_for_bootc_image_builder(&rootfs, &tempdir)?;
+    }
 
-    if !target_opts.skip_fetch_check {
+    // Checking the fetch pulls the image, which a dry run must not do
+    if !dry_run && !target_opts.skip_fetch_check {
         verify_target_fetch(&tempdir, &target_imgref).await?;
     }
 
-    // Even though we require running in a container, the mounts we create should be specific
-    // to this process, so let's enter a private mountns to avoid leaking them.
-    if !external_source && std::env::var_os("BOOTC_SKIP_UNSHARE").is_none() {
-        super::cli::ensure_self_unshared_mount_namespace()?;
-    }
+    if !dry_run {
+        // Even though we require running in a container, the mounts we create should be specific
+        // to this process, so let's enter a private mountns to avoid leaking them.
+        if !external_source && std::env::var_os("BOOTC_SKIP_UNSHARE").is_none() {
+            super::cli::ensure_self_unshared_mount_namespace()?;
+        }
 
-    setup_sys_mount("efivarfs", EFIVARFS)?;
+        setup_sys_mount("efivarfs", EFIVARFS)?;
+    }
 
     // Now, deal with SELinux state.
-    let selinux_state = reexecute_self_for_selinux_if_needed(&source, config_opts.disable_selinux)?;
+    let selinux_state =
+        reexecute_self_for_selinux_if_needed(&source, config_opts.disable_selinux, dry_run)?;
     tracing::debug!("SELinux state: {selinux_state:?}");
 
-    println!("Installing image: {:#}", &target_imgref);
-    if let Some(digest) = source.digest.as_deref() {
-        println!("Digest: {digest}");
+    if dry_run {
+        tracing::debug!("Planning install of image: {:#}", &target_imgref);
+    } else {
+        println!("Installing image: {:#}", &target_imgref);
+        if let Some(digest) = source.digest.as_deref() {
+            println!("Digest: {digest}");
+        }
     }
 
     let install_config = config::load_config()?;
@@ -1271,7 +1570,7 @@ async fn prepare_install(
     }
 
     // Convert the keyfile to a hashmap because GKeyFile isnt Send for probably bad reasons.
-    let prepareroot_config = {
+    let (prepareroot_config, transient_etc) = {
         let kf = ostree_prepareroot::require_config_from_root(&rootfs)?;
         let mut r = HashMap::new();
         for grp in kf.groups() {
@@ -1281,7 +1580,8 @@ async fn prepare_install(
                 r.insert(format!("{grp}.{key}"), value.to_string());
             }
         }
-        r
+        let transient_etc = ostree_prepareroot::transient_etc_enabled_in_config(&kf)?;
+        (r, transient_etc)
     };
 
     // Eagerly read the file now to ensure we error out early if e.g. it doesn't exist,
@@ -1291,6 +1591,19 @@ async fn prepare_install(
         .as_ref()
         .map(|p| std::fs::read_to_string(p).with_context(|| format!("Reading {p}")))
         .transpose()?;
+    let (groups, users) = install_users(&config_opts, install_config.as_ref())?;
+    let config_bundle = config_opts
+        .config_bundle
+        .as_deref()
+        .map(configbundle::load)
+        .transpose()?;
+    if transient_etc {
+        check_transient_etc(
+            root_ssh_authorized_keys.is_some(),
+            !groups.is_empty() || !users.is_empty(),
+            config_bundle.is_some(),
+        )?;
+    }
 
     // Create our global (read-only) state which gets wrapped in an Arc
     // so we can pass it to worker threads too. Right now this just
@@ -1302,7 +1615,11 @@ async fn prepare_install(
         target_imgref,
         install_config,
         prepareroot_config,
+        transient_etc,
         root_ssh_authorized_keys,
+        groups,
+        users,
+        config_bundle,
         container_root: rootfs,
         tempdir,
         host_is_container,
@@ -1325,27 +1642,74 @@ async fn install_with_sysroot(
 ) -> Result<()> {
     // And actually set up the container in that root, returning a deployment and
     // the aleph state (see below).
-    let (_deployment, aleph) = install_container(state, rootfs, &sysroot, has_ostree).await?;
-    // Write the aleph data that captures the system state at the time of provisioning for aid in future debugging.
-    rootfs
-        .physical_root
-        .atomic_replace_with(BOOTC_ALEPH_PATH, |f| {
-            serde_json::to_writer(f, &aleph)?;
-            anyhow::Ok(())
+    let (deployment, mut aleph) = install_container(state, rootfs, &sysroot, has_ostree).await?;
+    aleph.install_mode = Some(rootfs.install_mode);
+    aleph.block_setup = rootfs.block_setup.clone();
+    // This is only informational, so don't fail the install over it
+    aleph.filesystem = crate::mount::inspect_filesystem(&rootfs.physical_root_path)
+        .inspect_err(|e| tracing::warn!("Failed to inspect root filesystem: {e:#}"))
+        .ok()
+        .map(|fs| fs.fstype);
+    aleph.stateroot = Some(state.stateroot().to_owned());
+    aleph.kargs = deployment
+        .bootconfig()
+        .and_then(|bootconfig| bootconfig.get("options"))
+        .map(|options| {
+            options
+                .split_ascii_whitespace()
+                .map(ToOwned::to_owned)
+                .collect()
         })
-        .context("Writing aleph version")?;
+        .unwrap_or_default();
+    aleph.bound_images = match &bound_images {
+        BoundImages::Skip => Vec::new(),
+        BoundImages::Resolved(images) => images
+            .iter()
+            .map(|img| format!("{}@{}", img.image, img.digest))
+            .collect(),
+        BoundImages::Unresolved(images) => images.iter().map(|img| img.image.clone()).collect(),
+    };
+    // Write the aleph data that captures the system state at the time of provisioning for aid in future debugging.
+    // When adding a stateroot alongside existing ones, keep the record of the original install.
+    if rootfs.side_by_side && rootfs.physical_root.try_exists(BOOTC_ALEPH_PATH)? {
+        tracing::debug!("Keeping extant {BOOTC_ALEPH_PATH}");
+    } else {
+        rootfs
+            .physical_root
+            .atomic_replace_with(BOOTC_ALEPH_PATH, |f| {
+                serde_json::to_writer(f, &aleph)?;
+                anyhow::Ok(())
+            })
+            .context("Writing aleph version")?;
+    }
 
+    let uses_uki =
+        !crate::bootloader::systemd_boot::find_ukis(&sysroot.repo(), &deployment.csum())?
+            .is_empty();
+    if uses_uki {
+        let esp_path = rootfs
+            .physical_root_path
+            .join("boot")
+            .join(crate::bootloader::EFI_DIR);
+        let mounted_esp =
+            crate::bootloader::mount_esp_for_install(&rootfs.physical_root_path, &esp_path)?;
+        let esp = Dir::open_ambient_dir(&esp_path, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {esp_path}"))?;
+        crate::bootloader::systemd_boot::install(&esp_path, &state.config_opts)?;
+        crate::bootloader::systemd_boot::sync_entries(sysroot, &esp)?;
+        crate::bootloader::install_to_other_esps(&rootfs.physical_root_path, &esp_path)?;
+        drop(esp);
+        drop(mounted_esp);
+    } else if !cfg!(target_arch = "s390x") && state.container_root.try_exists(BOOTUPCTL_PATH)? {
+        // The devices backing the target root are passed to bootupd by their
+        // stable paths.
+        crate::bootloader::install_via_bootupd(&rootfs.physical_root_path, &state.config_opts)?;
+    }
+    // TODO: Integrate s390x support into install_via_bootupd
     // if cfg!(target_arch = "s390x") {
-    //     // TODO: Integrate s390x support into install_via_bootupd
     //     crate::bootloader::install_via_zipl(
     //         &rootfs.device_info, boot_uuid
     //     )?;
-    // } else {
-    //     crate::bootloader::install_via_bootupd(
-    //         &rootfs.device_info,
-    //         &rootfs.physical_root_path,
-    //         &state.config_opts,
-    //     )?;
     // }
     tracing::debug!("Installed bootloader");
 
@@ -1472,6 +1836,95 @@ fn installation_complete() {
     println!("Installation complete!");
 }
 
+/// Everything `bootc install to-disk` would do, as output by `--dry-run`.
+/// UUIDs which are generated at install time are shown as placeholders.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct InstallPlan {
+    source_image: String,
+    source_digest: Option<String>,
+    target_image: String,
+    stateroot: String,
+    selinux: &'static str,
+    block: baseline::RootfsPlan,
+    /// The final kernel arguments, in order
+    kargs: Vec<String>,
+    /// The bootloader installation command; unset if no bootloader is installed
+    bootloader_command: Option<Vec<String>>,
+    bound_images_mode: BoundImagesOpt,
+    /// The bound images, as found in the source image (they are not resolved)
+    bound_images: Vec<String>,
+}
+
+#[cfg(feature = "install-to-disk")]
+impl InstallPlan {
+    /// Compute the plan, without modifying the target device (or setting up a
+    /// loopback device for it) or any mounts.
+    #[context("Computing install plan")]
+    fn new(state: &State, block_opts: &InstallBlockDeviceOpts, via_loopback: bool) -> Result<Self> {
+        let device = if via_loopback {
+            baseline::PlannedDevice::loopback_placeholder(&block_opts.device)?
+        } else {
+            baseline::PlannedDevice::inspect(block_opts)?
+        };
+        let mut block =
+            baseline::plan_rootfs(state, block_opts, device, baseline::PlanUuids::Placeholder)?;
+        if matches!(state.selinux_state, SELinuxFinalState::ForceTargetDisabled) {
+            block.kargs.push("selinux=0".to_string());
+        }
+        // At install time kargs.d is read from the pulled commit; when installing
+        // from the running container that is the same content as our root.
+        let kargsd = if state.source.in_host_mountns {
+            crate::kargs::get_kargs_in_root(&state.container_root, std::env::consts::ARCH)?
+        } else {
+            tracing::warn!("Not including kargs.d from external source image");
+            Vec::new()
+        };
+        let kargs = final_kargs(state, &block.kargs, &kargsd)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect();
+
+        // Only systemd-boot is installed here; see install_with_sysroot().
+        let bootloader_command = if !state.source.in_host_mountns {
+            tracing::warn!("Not checking external source image for a UKI");
+            None
+        } else if crate::bootloader::systemd_boot::has_uki_fs(&state.container_root)? {
+            let esp = Utf8Path::new(RUN_BOOTC)
+                .join("mounts/rootfs/boot")
+                .join(crate::bootloader::EFI_DIR);
+            let args = crate::bootloader::systemd_boot::install_args(&esp, &state.config_opts);
+            Some(std::iter::once("bootctl".to_owned()).chain(args).collect())
+        } else {
+            None
+        };
+
+        let bound_images = match state.config_opts.bound_images {
+            BoundImagesOpt::Skip => Vec::new(),
+            BoundImagesOpt::Stored | BoundImagesOpt::Pull => {
+                crate::boundimage::query_bound_images(&state.container_root)?
+                    .into_iter()
+                    .map(|img| img.image)
+                    .collect()
+            }
+        };
+
+        Ok(Self {
+            source_image: state.source.imageref.to_string(),
+            source_digest: state.source.digest.clone(),
+            target_image: state.target_imgref.to_string(),
+            stateroot: state.stateroot().to_owned(),
+            selinux: state.selinux_state.to_aleph(),
+            block,
+            kargs,
+            bootloader_command,
+            bound_images_mode: state.config_opts.bound_images,
+            bound_images,
+        })
+    }
+}
+
 /// Implementation of the `bootc install to-disk` CLI command.
 #[context("Installing to disk")]
 #[cfg(feature = "install-to-disk")]
@@ -1497,7 +1950,25 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
     } else if !target_blockdev_meta.file_type().is_block_device() {
         anyhow::bail!("Not a block device: {}", block_opts.device);
     }
-    let state = prepare_install(opts.config_opts, opts.source_opts, opts.target_opts).await?;
+    let dry_run = opts.dry_run;
+    let state = prepare_install(
+        opts.config_opts,
+        opts.source_opts,
+        opts.target_opts,
+        dry_run,
+    )
+    .await?;
+
+    if dry_run {
+        let plan = InstallPlan::new(&state, &block_opts, opts.via_loopback)?;
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &plan)?;
+        writeln!(stdout)?;
+        if let Some(state) = Arc::into_inner(state) {
+            state.consume()?;
+        }
+        return Ok(());
+    }
 
     // This is all blocking stuff
     let (mut rootfs, loopback) = {
@@ -1683,13 +2154,15 @@ fn warn_on_host_root(rootfs_fd: &Dir) -> Result<()> {
 pub(crate) async fn install_to_filesystem(
     opts: InstallToFilesystemOpts,
     targeting_host_root: bool,
+    copy_network_config: bool,
 ) -> Result<()> {
     // Gather global state, destructuring the provided options.
     // IMPORTANT: We might re-execute the current process in this function (for SELinux among other things)
     // IMPORTANT: and hence anything that is done before MUST BE IDEMPOTENT.
     // IMPORTANT: In practice, we should only be gathering information before this point,
     // IMPORTANT: and not performing any mutations at all.
-    let state = prepare_install(opts.config_opts, opts.source_opts, opts.target_opts).await?;
+    let state =
+        prepare_install(opts.config_opts, opts.source_opts, opts.target_opts, false).await?;
     // And the last bit of state here is the fsopts, which we also destructure now.
     let mut fsopts = opts.filesystem_opts;
 
@@ -1721,6 +2194,19 @@ pub(crate) async fn install_to_filesystem(
         }
     }
 
+    if copy_network_config && state.transient_etc {
+        anyhow::bail!("Cannot copy the host network configuration with a transient /etc");
+    }
+    // Capture the host network configuration now, before we start mutating anything.
+    let host_network = if copy_network_config {
+        let root_path = &fsopts.root_path;
+        let host_root = Dir::open_ambient_dir(root_path, cap_std::ambient_authority())
+            .with_context(|| format!("Opening host root directory {root_path}"))?;
+        Some(hostnetwork::gather(&host_root)?)
+    } else {
+        None
+    };
+
     // Check to see if this happens to be the real host root
     if !fsopts.acknowledge_destructive {
         let root_path = &fsopts.root_path;
@@ -1754,7 +2240,23 @@ pub(crate) async fn install_to_filesystem(
         rootfs_fd
     };
 
+    // If the target already has ostree deployments (e.g. it is a booted bootc system) and
+    // a new stateroot was explicitly requested, add it alongside the existing ones instead
+    // of replacing them.
+    let side_by_side = match state.config_opts.stateroot.as_deref() {
+        Some(stateroot) if !matches!(fsopts.replace, Some(ReplaceMode::Wipe)) => {
+            rootfs_fd.try_exists("ostree/deploy")?
+                && !rootfs_fd.try_exists(format!("ostree/deploy/{stateroot}"))?
+        }
+        _ => false,
+    };
+    if side_by_side {
+        let stateroot = state.stateroot();
+        println!("Found existing ostree deployments; installing stateroot {stateroot} alongside");
+    }
+
     match fsopts.replace {
+        _ if side_by_side => {}
         Some(ReplaceMode::Wipe) => {
             let rootfs_fd = rootfs_fd.try_clone()?;
             println!("Wiping contents of root");
@@ -1873,8 +2375,9 @@ pub(crate) async fn install_to_filesystem(
         .chain(bootarg)
         .collect::<Vec<_>>();
 
-    let skip_finalize =
-        matches!(fsopts.replace, Some(ReplaceMode::Alongside)) || fsopts.skip_finalize;
+    let skip_finalize = matches!(fsopts.replace, Some(ReplaceMode::Alongside))
+        || side_by_side
+        || fsopts.skip_finalize;
     let mut rootfs = RootSetup {
         #[cfg(feature = "install-to-disk")]
         luks_device: None,
@@ -1885,6 +2388,14 @@ pub(crate) async fn install_to_filesystem(
         boot,
         kargs,
         skip_finalize,
+        side_by_side,
+        host_network,
+        install_mode: if targeting_host_root {
+            crate::spec::InstallMode::ToExistingRoot
+        } else {
+            crate::spec::InstallMode::ToFilesystem
+        },
+        block_setup: None,
     };
 
     install_to_filesystem_impl(&state, &mut rootfs).await?;
@@ -1898,6 +2409,7 @@ pub(crate) async fn install_to_filesystem(
 }
 
 pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) -> Result<()> {
+    let copy_network_config = opts.copy_network_config;
     let opts = InstallToFilesystemOpts {
         filesystem_opts: InstallTargetFilesystemOpts {
             root_path: opts.root_path,
@@ -1912,7 +2424,7 @@ pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) ->
         config_opts: opts.config_opts,
     };
 
-    install_to_filesystem(opts, true).await
+    install_to_filesystem(opts, true, copy_network_config).await
 }
 
 /// Implementation of `bootc install finalize`.
@@ -1945,6 +2457,61 @@ mod tests {
         assert_eq!(c.block_opts.device, "/dev/vda");
     }
 
+    #[test]
+    fn test_install_users() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td = Utf8Path::from_path(td.path()).unwrap();
+        let keys = td.join("keys");
+        std::fs::write(&keys, "# comment\nssh-ed25519 ABCDE admin@demo\n\n")?;
+        let hash = td.join("hash");
+        std::fs::write(&hash, "$6$salt$hash\n")?;
+        let config = config::InstallConfiguration {
+            users: Some(vec![config::InstallUser {
+                name: "admin".into(),
+                groups: vec!["ops".into()],
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        let opts: InstallToDiskOpts = serde_json::from_value(serde_json::json!({
+            "device": "/dev/vda",
+            "user": ["admin:wheel", "other"],
+            "user_ssh_authorized_keys": [format!("admin={keys}")],
+            "user_password_hash": [format!("other={hash}")],
+        }))?;
+        let (groups, users) = install_users(&opts.config_opts, Some(&config))?;
+        assert!(groups.is_empty());
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].groups, ["ops", "wheel"]);
+        assert_eq!(
+            users[0].ssh_authorized_keys,
+            ["ssh-ed25519 ABCDE admin@demo"]
+        );
+        assert_eq!(users[1].name, "other");
+        assert_eq!(users[1].password_hash.as_deref(), Some("$6$salt$hash"));
+
+        // Keys for a user that isn't being created are an error
+        let opts: InstallToDiskOpts = serde_json::from_value(serde_json::json!({
+            "device": "/dev/vda",
+            "user_ssh_authorized_keys": [format!("nobody={keys}")],
+        }))?;
+        assert!(install_users(&opts.config_opts, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_transient_etc() {
+        check_transient_etc(false, false, false).unwrap();
+        let e = check_transient_etc(true, false, true)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.ends_with(": --root-ssh-authorized-keys, --config-bundle"),
+            "{e}"
+        );
+        assert!(check_transient_etc(false, true, false).is_err());
+    }
+
     #[test]
     fn test_mountspec() {
         let mut ms = MountSpec::new("/dev/vda4", "/boot");
@@ -5,14 +5,14 @@
 //! intended to add opinionated handling of TPM2-bound LUKS too.  But that's about it;
 //! other more complex flows should set things up externally and use `bootc install to-filesystem`.
 
-use std::borrow::Cow;
 use std::fmt::Display;
-use std::fmt::Write as _;
 use std::io::Write;
 use std::process::Stdio;
 
 use anyhow::Ok;
 use anyhow::{Context, Result};
+#[cfg(feature = "install-to-disk")]
+use bootc_blockdev::parttable::{self, DiskLabel, Gpt, GptPartition, NewPartition};
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use cap_std::fs::Dir;
@@ -92,43 +92,84 @@ impl BlockSetup {
     }
 }
 
+/// A filesystem that will be created as part of the install.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PlannedFilesystem {
+    /// The label for the filesystem
+    pub(crate) label: String,
+    /// The filesystem type
+    pub(crate) fstype: String,
+    /// The device node the filesystem will be written to.  For partitions
+    /// this follows the kernel naming convention, as the actual node
+    /// only exists after partitioning.
+    pub(crate) device: String,
+    /// The filesystem UUID, if we assign one
+    pub(crate) uuid: Option<String>,
+    /// The mkfs command line (without the target device, which is appended last)
+    pub(crate) command: Vec<String>,
+}
+
+#[cfg(feature = "install-to-disk")]
+impl PlannedFilesystem {
+    /// Plan creating a filesystem of type `fs` with the UUID `u`.
+    pub(crate) fn new(device: String, fs: Filesystem, label: &str, wipe: bool, u: String) -> Self {
+        let mut command = vec![format!("mkfs.{fs}")];
+        match fs {
+            Filesystem::Xfs => {
+                if wipe {
+                    command.push("-f".into());
+                }
+                command.push("-m".into());
+                command.push(format!("uuid={u}"));
+            }
+            Filesystem::Btrfs | Filesystem::Ext4 => {
+                command.push("-U".into());
+                command.push(u.clone());
+            }
+        };
+        // Today all the above mkfs commands take -L
+        command.extend(["-L".into(), label.into()]);
+        Self {
+            label: label.into(),
+            fstype: fs.to_string(),
+            device,
+            uuid: Some(u),
+            command,
+        }
+    }
+
+    /// Plan creating the FAT filesystem for the EFI system partition.
+    pub(crate) fn new_esp(device: String) -> Self {
+        Self {
+            label: "EFI-SYSTEM".into(),
+            fstype: "vfat".into(),
+            device,
+            uuid: None,
+            command: vec!["mkfs.fat".into(), "-n".into(), "EFI-SYSTEM".into()],
+        }
+    }
+}
+
 #[cfg(feature = "install-to-disk")]
-fn mkfs<'a>(
-    dev: &str,
-    fs: Filesystem,
-    label: &str,
-    wipe: bool,
-    opts: impl IntoIterator<Item = &'a str>,
-) -> Result<uuid::Uuid> {
+fn mkfs(dev: &str, fs: &PlannedFilesystem) -> Result<()> {
     let devinfo = bootc_blockdev::list_dev(dev.into())?;
     let size = ostree_ext::glib::format_size(devinfo.size);
-    let u = uuid::Uuid::new_v4();
+    let (exe, args) = fs.command.split_first().expect("mkfs command");
+    let label = &fs.label;
+    let fstype = &fs.fstype;
     let mut t = Task::new(
-        &format!("Creating {label} filesystem ({fs}) on device {dev} (size={size})"),
-        format!("mkfs.{fs}"),
+        &format!("Creating {label} filesystem ({fstype}) on device {dev} (size={size})"),
+        exe,
     );
-    match fs {
-        Filesystem::Xfs => {
-            if wipe {
-                t.cmd.arg("-f");
-            }
-            t.cmd.arg("-m");
-            t.cmd.arg(format!("uuid={u}"));
-        }
-        Filesystem::Btrfs | Filesystem::Ext4 => {
-            t.cmd.arg("-U");
-            t.cmd.arg(u.to_string());
-        }
-    };
-    // Today all the above mkfs commands take -L
-    t.cmd.args(["-L", label]);
-    t.cmd.args(opts);
+    t.cmd.args(args);
     t.cmd.arg(dev);
     // All the mkfs commands are unnecessarily noisy by default
     t.cmd.stdout(Stdio::null());
     // But this one is notable so let's print the whole thing with verbose()
     t.verbose().run()?;
-    Ok(u)
+    Ok(())
 }
 
 #[context("Failed to wipe {dev}")]
@@ -156,12 +197,236 @@ pub(crate) fn udev_settle() -> Result<()> {
     Ok(())
 }
 
-#[context("Creating rootfs")]
+/// The target block device, as found before installation.
 #[cfg(feature = "install-to-disk")]
-pub(crate) fn install_create_rootfs(
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PlannedDevice {
+    pub(crate) path: String,
+    pub(crate) size: u64,
+    pub(crate) serial: Option<String>,
+    pub(crate) model: Option<String>,
+    /// Existing partitions (or other children), which will be wiped
+    pub(crate) children: Vec<String>,
+}
+
+#[cfg(feature = "install-to-disk")]
+impl PlannedDevice {
+    /// Inspect the target block device, verifying that it can be installed to.
+    #[context("Inspecting {}", opts.device)]
+    pub(crate) fn inspect(opts: &InstallBlockDeviceOpts) -> Result<Self> {
+        // Verify that the target is empty (if not already wiped in particular, but it's
+        // also good to verify that the wipe worked)
+        let device = bootc_blockdev::list_dev(&opts.device)?;
+
+        // Always disallow writing to mounted device
+        if is_mounted_in_pid1_mountns(&device.path())? {
+            anyhow::bail!("Device {} is mounted", device.path())
+        }
+        if !opts.wipe && device.has_children() {
+            anyhow::bail!(
+                "Detected existing partitions on {}; use e.g. `wipefs` or --wipe if you intend to overwrite",
+                opts.device
+            );
+        }
+        Ok(Self {
+            path: device.path(),
+            size: device.size,
+            serial: device.serial.clone(),
+            model: device.model.clone(),
+            children: device.children.iter().flatten().map(|c| c.path()).collect(),
+        })
+    }
+
+    /// Describe the loopback device which will be set up for the file `path`,
+    /// without setting it up.
+    pub(crate) fn loopback_placeholder(path: &Utf8Path) -> Result<Self> {
+        let size = path
+            .metadata()
+            .with_context(|| format!("Querying {path}"))?
+            .len();
+        Ok(Self {
+            path: LOOPBACK_PLACEHOLDER.into(),
+            size,
+            serial: None,
+            model: None,
+            children: Vec::new(),
+        })
+    }
+}
+
+/// Stands in for the (not yet allocated) loopback device in a plan.
+#[cfg(feature = "install-to-disk")]
+const LOOPBACK_PLACEHOLDER: &str = "/dev/loopN";
+
+/// How the UUIDs in a [`RootfsPlan`] are generated.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PlanUuids {
+    /// Freshly generated random UUIDs, used for the actual install
+    Random,
+    /// Placeholders such as `<root-uuid>`, as the actual install generates new ones
+    Placeholder,
+}
+
+#[cfg(feature = "install-to-disk")]
+impl PlanUuids {
+    fn generate(self, name: &str) -> String {
+        match self {
+            PlanUuids::Random => uuid::Uuid::new_v4().to_string(),
+            PlanUuids::Placeholder => format!("<{name}-uuid>"),
+        }
+    }
+}
+
+/// A partition that will be created on the target device.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PlannedPartition {
+    pub(crate) partno: u32,
+    pub(crate) name: &'static str,
+    /// The partition type GUID; if unset, the Linux filesystem type is used.
+    #[serde(rename = "type")]
+    pub(crate) parttype: Option<&'static str>,
+    /// The size; if unset, all remaining space is used.
+    pub(crate) size_mib: Option<u64>,
+    pub(crate) bootable: bool,
+}
+
+#[cfg(feature = "install-to-disk")]
+impl PlannedPartition {
+    /// The request to create this partition in a GPT.
+    fn to_gpt(&self) -> Result<NewPartition> {
+        let type_guid = match self.parttype {
+            Some(t) => uuid::Uuid::parse_str(t).with_context(|| format!("Parsing {t}"))?,
+            None => parttable::LINUX_FILESYSTEM_TYPE,
+        };
+        let attributes = if self.bootable {
+            parttable::GPT_ATTR_LEGACY_BIOS_BOOTABLE
+        } else {
+            0
+        };
+        Ok(NewPartition {
+            size: self.size_mib.map(|s| s * 1024 * 1024),
+            name: self.name.to_owned(),
+            type_guid,
+            guid: None,
+            attributes,
+        })
+    }
+}
+
+/// Build the GPT with identifier `label_id` holding `partitions`, for a device
+/// of `size` bytes.
+#[cfg(feature = "install-to-disk")]
+fn partition_table(
+    label_id: &str,
+    partitions: &[PlannedPartition],
+    size: u64,
+    sector_size: u64,
+) -> Result<Gpt> {
+    let mut gpt = Gpt::new(size, sector_size)?;
+    gpt.disk_guid = uuid::Uuid::parse_str(label_id).context("Parsing label id")?;
+    for part in partitions {
+        let new = gpt.add_partition(parttable::DEFAULT_ALIGNMENT, part.to_gpt()?)?;
+        // Partitions are planned to be numbered in order
+        anyhow::ensure!(
+            new.partno == part.partno,
+            "Partition {} was created as number {}",
+            part.name,
+            new.partno
+        );
+    }
+    Ok(gpt)
+}
+
+/// Find the device node of `part` among the children of `device` (as listed by
+/// lsblk), by its partition UUID. The names of the nodes depend on the device
+/// (e.g. `/dev/nvme0n1p1`, or `/dev/mapper/mpatha1` for device mapper multipath).
+#[cfg(feature = "install-to-disk")]
+fn partition_device(device: &bootc_blockdev::Device, part: &GptPartition) -> Option<String> {
+    let guid = part.guid.to_string();
+    device
+        .children
+        .iter()
+        .flatten()
+        .find(|c| {
+            c.partuuid
+                .as_deref()
+                .is_some_and(|u| u.eq_ignore_ascii_case(&guid))
+        })
+        .map(|c| c.path())
+}
+
+/// The LUKS setup for the root filesystem.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PlannedLuks {
+    pub(crate) uuid: String,
+    /// The device mapper name used when opening the device
+    pub(crate) name: &'static str,
+    /// How the volume key will be bound
+    pub(crate) enroll: &'static str,
+}
+
+/// Everything `install_create_rootfs` will do to the target block device,
+/// computed without modifying it.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RootfsPlan {
+    pub(crate) device: PlannedDevice,
+    pub(crate) wipe: bool,
+    pub(crate) block_setup: BlockSetup,
+    pub(crate) filesystem: Filesystem,
+    /// The identifier of the partition table (GPT disk GUID)
+    pub(crate) label_id: String,
+    pub(crate) partitions: Vec<PlannedPartition>,
+    pub(crate) luks: Option<PlannedLuks>,
+    pub(crate) filesystems: Vec<PlannedFilesystem>,
+    /// Kernel arguments derived from the block setup
+    pub(crate) kargs: Vec<String>,
+    #[serde(skip)]
+    esp_partno: Option<u32>,
+    #[serde(skip)]
+    boot_partno: Option<u32>,
+    #[serde(skip)]
+    root_partno: u32,
+}
+
+#[cfg(feature = "install-to-disk")]
+impl RootfsPlan {
+    fn find_filesystem(&self, label: &str) -> Option<&PlannedFilesystem> {
+        self.filesystems.iter().find(|fs| fs.label == label)
+    }
+}
+
+/// Predict the device node for partition `partno` of `device`, to describe the
+/// plan. The kernel names partitions by appending the number, with a `p` separator
+/// if the whole-disk name ends in a digit (e.g. `nvme0n1p1`, `loop0p1`). The actual
+/// installation uses the nodes found after partitioning (see [`partition_device`]).
+#[cfg(feature = "install-to-disk")]
+fn partition_node(device: &str, partno: u32) -> String {
+    let sep = if device == LOOPBACK_PLACEHOLDER || device.ends_with(|c: char| c.is_ascii_digit()) {
+        "p"
+    } else {
+        ""
+    };
+    format!("{device}{sep}{partno}")
+}
+
+/// Compute the partitioning, filesystems and kernel arguments for installing
+/// to the target block `device` (see [`PlannedDevice::inspect`]).
+#[context("Planning rootfs")]
+#[cfg(feature = "install-to-disk")]
+pub(crate) fn plan_rootfs(
     state: &State,
-    opts: InstallBlockDeviceOpts,
-) -> Result<RootSetup> {
+    opts: &InstallBlockDeviceOpts,
+    device: PlannedDevice,
+    uuids: PlanUuids,
+) -> Result<RootfsPlan> {
     let luks_name = "root";
     // Ensure we have a root filesystem upfront
     let root_filesystem = opts
@@ -172,39 +437,6 @@ pub(crate) fn install_create_rootfs(
             .and_then(|c| c.filesystem_root())
             .and_then(|r| r.fstype))
         .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
-    // Verify that the target is empty (if not already wiped in particular, but it's
-    // also good to verify that the wipe worked)
-    let device = bootc_blockdev::list_dev(&opts.device)?;
-    // Canonicalize devpath
-    let devpath: Utf8PathBuf = device.path().into();
-
-    // Always disallow writing to mounted device
-    if is_mounted_in_pid1_mountns(&device.path())? {
-        anyhow::bail!("Device {} is mounted", device.path())
-    }
-
-    // Handle wiping any existing data
-    if opts.wipe {
-        let dev = &opts.device;
-        for child in device.children.iter().flatten() {
-            let child = child.path();
-            println!("Wiping {child}");
-            wipefs(Utf8Path::new(&child))?;
-        }
-        println!("Wiping {dev}");
-        wipefs(dev)?;
-    } else if device.has_children() {
-        anyhow::bail!(
-            "Detected existing partitions on {}; use e.g. `wipefs` or --wipe if you intend to overwrite",
-            opts.device
-        );
-    }
-
-    let run_bootc = Utf8Path::new(RUN_BOOTC);
-    let mntdir = run_bootc.join("mounts");
-    if mntdir.exists() {
-        std::fs::remove_dir_all(&mntdir)?;
-    }
 
     // Use the install configuration to find the block setup, if we have one
     let block_setup = if let Some(config) = state.install_config.as_ref() {
@@ -218,12 +450,6 @@ pub(crate) fn install_create_rootfs(
         // and we need to error out.
         anyhow::bail!("No install configuration found, and no filesystem specified")
     };
-    let serial = device.serial.as_deref().unwrap_or("<unknown>");
-    let model = device.model.as_deref().unwrap_or("<unknown>");
-    println!("Block setup: {block_setup}");
-    println!("       Size: {}", device.size);
-    println!("     Serial: {serial}");
-    println!("      Model: {model}");
 
     let root_size = opts
         .root_size
@@ -232,177 +458,280 @@ pub(crate) fn install_create_rootfs(
         .transpose()
         .context("Parsing root size")?;
 
-    // Load the policy from the container root, which also must be our install root
-    let sepolicy = state.load_policy()?;
-    let sepolicy = sepolicy.as_ref();
-
-    // Create a temporary directory to use for mount points.  Note that we're
-    // in a mount namespace, so these should not be visible on the host.
-    let physical_root_path = mntdir.join("rootfs");
-    std::fs::create_dir_all(&physical_root_path)?;
-    let bootfs = mntdir.join("boot");
-    std::fs::create_dir_all(bootfs)?;
-
-    // Generate partitioning spec as input to sfdisk
-    let mut partno = 0;
-    let mut partitioning_buf = String::new();
-    writeln!(partitioning_buf, "label: gpt")?;
-    let random_label = uuid::Uuid::new_v4();
-    writeln!(&mut partitioning_buf, "label-id: {random_label}")?;
+    // Generate the partition layout
+    let mut partitions = Vec::new();
+    let mut push_partition = |name, parttype, size_mib, bootable| {
+        let partno = partitions.len() as u32 + 1;
+        partitions.push(PlannedPartition {
+            partno,
+            name,
+            parttype,
+            size_mib,
+            bootable,
+        });
+        partno
+    };
     if cfg!(target_arch = "x86_64") {
-        partno += 1;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size=1MiB, bootable, type=21686148-6449-6E6F-744E-656564454649, name="BIOS-BOOT""#
-        )?;
+        push_partition(
+            "BIOS-BOOT",
+            Some("21686148-6449-6E6F-744E-656564454649"),
+            Some(1),
+            true,
+        );
     } else if cfg!(target_arch = "powerpc64") {
         // PowerPC-PReP-boot
-        partno += 1;
-        let label = crate::bootloader::PREPBOOT_LABEL;
-        let uuid = crate::bootloader::PREPBOOT_GUID;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size=4MiB, bootable, type={uuid}, name="{label}""#
-        )?;
+        push_partition(
+            crate::bootloader::PREPBOOT_LABEL,
+            Some(crate::bootloader::PREPBOOT_GUID),
+            Some(4),
+            true,
+        );
     } else if cfg!(any(target_arch = "aarch64", target_arch = "s390x")) {
         // No bootloader partition is necessary
     } else {
         anyhow::bail!("Unsupported architecture: {}", std::env::consts::ARCH);
     }
 
-    let esp_partno = if super::ARCH_USES_EFI {
-        let esp_guid = crate::bootloader::ESP_GUID;
-        partno += 1;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size={EFIPN_SIZE_MB}MiB, type={esp_guid}, name="EFI-SYSTEM""#
-        )?;
-        Some(partno)
-    } else {
-        None
-    };
+    let esp_partno = super::ARCH_USES_EFI.then(|| {
+        push_partition(
+            "EFI-SYSTEM",
+            Some(crate::bootloader::ESP_GUID),
+            Some(EFIPN_SIZE_MB.into()),
+            false,
+        )
+    });
 
     // Initialize the /boot filesystem.  Note that in the future, we may match
     // what systemd/uapi-group encourages and make /boot be FAT32 as well, as
     // it would aid systemd-boot.
-    let boot_partno = if block_setup.requires_bootpart() {
-        partno += 1;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size={BOOTPN_SIZE_MB}MiB, name="boot""#
-        )?;
-        Some(partno)
-    } else {
-        None
+    let boot_partno = block_setup
+        .requires_bootpart()
+        .then(|| push_partition("boot", None, Some(BOOTPN_SIZE_MB.into()), false));
+    let root_partno = push_partition("root", Some(LINUX_PARTTYPE), root_size, false);
+
+    let label_id = uuids.generate("label");
+
+    let root_partition = partition_node(&device.path, root_partno);
+    let (rootdev, luks) = match block_setup {
+        BlockSetup::Direct => (root_partition, None),
+        BlockSetup::Tpm2Luks => {
+            let luks = PlannedLuks {
+                uuid: uuids.generate("luks"),
+                name: luks_name,
+                enroll: "tpm2-device=auto",
+            };
+            (format!("/dev/mapper/{luks_name}"), Some(luks))
+        }
     };
-    let rootpn = partno + 1;
-    let root_size = root_size
-        .map(|v| Cow::Owned(format!("size={v}MiB, ")))
-        .unwrap_or_else(|| Cow::Borrowed(""));
-    writeln!(
-        &mut partitioning_buf,
-        r#"{root_size}type={LINUX_PARTTYPE}, name="root""#
+
+    let mut filesystems = Vec::new();
+    if let Some(esp_partno) = esp_partno {
+        filesystems.push(PlannedFilesystem::new_esp(partition_node(
+            &device.path,
+            esp_partno,
+        )));
+    }
+    let bootfs = boot_partno.map(|bootpn| {
+        PlannedFilesystem::new(
+            partition_node(&device.path, bootpn),
+            root_filesystem,
+            "boot",
+            opts.wipe,
+            uuids.generate("boot"),
+        )
+    });
+    let rootfs = PlannedFilesystem::new(
+        rootdev,
+        root_filesystem,
+        "root",
+        opts.wipe,
+        uuids.generate("root"),
+    );
+
+    let luks_kargs = luks.iter().flat_map(|luks| {
+        [
+            format!("luks.uuid={}", luks.uuid),
+            format!("luks.options=tpm2-device=auto,headless=true"),
+        ]
+    });
+    let rootarg = format!("root=UUID={}", rootfs.uuid.as_deref().unwrap());
+    let bootarg = bootfs
+        .as_ref()
+        .map(|fs| format!("boot=UUID={}", fs.uuid.as_deref().unwrap()));
+    let kargs = luks_kargs
+        .chain([rootarg, RW_KARG.to_string()])
+        .chain(bootarg)
+        .collect::<Vec<_>>();
+    filesystems.extend(bootfs);
+    filesystems.push(rootfs);
+
+    Ok(RootfsPlan {
+        device,
+        wipe: opts.wipe,
+        block_setup,
+        filesystem: root_filesystem,
+        label_id,
+        partitions,
+        luks,
+        filesystems,
+        kargs,
+        esp_partno,
+        boot_partno,
+        root_partno,
+    })
+}
+
+#[context("Creating rootfs")]
+#[cfg(feature = "install-to-disk")]
+pub(crate) fn install_create_rootfs(
+    state: &State,
+    opts: InstallBlockDeviceOpts,
+) -> Result<RootSetup> {
+    let device = PlannedDevice::inspect(&opts)?;
+    let plan = plan_rootfs(state, &opts, device, PlanUuids::Random)?;
+    let devpath: Utf8PathBuf = plan.device.path.as_str().into();
+
+    // Handle wiping any existing data
+    if opts.wipe {
+        for child in plan.device.children.iter() {
+            println!("Wiping {child}");
+            wipefs(Utf8Path::new(child))?;
+        }
+    }
+    // Also clear any signatures on the device itself (outside of the areas
+    // written for the partition table), so they aren't detected alongside it
+    println!("Wiping {devpath}");
+    wipefs(&devpath)?;
+
+    let run_bootc = Utf8Path::new(RUN_BOOTC);
+    let mntdir = run_bootc.join("mounts");
+    if mntdir.exists() {
+        std::fs::remove_dir_all(&mntdir)?;
+    }
+
+    let block_setup = plan.block_setup;
+    let serial = plan.device.serial.as_deref().unwrap_or("<unknown>");
+    let model = plan.device.model.as_deref().unwrap_or("<unknown>");
+    println!("Block setup: {block_setup}");
+    println!("       Size: {}", plan.device.size);
+    println!("     Serial: {serial}");
+    println!("      Model: {model}");
+
+    // Load the policy from the container root, which also must be our install root
+    let sepolicy = state.load_policy()?;
+    let sepolicy = sepolicy.as_ref();
+
+    // Create a temporary directory to use for mount points.  Note that we're
+    // in a mount namespace, so these should not be visible on the host.
+    let physical_root_path = mntdir.join("rootfs");
+    std::fs::create_dir_all(&physical_root_path)?;
+    let bootfs = mntdir.join("boot");
+    std::fs::create_dir_all(bootfs)?;
+
+    let sector_size = parttable::logical_sector_size(&devpath)?;
+    let planned = partition_table(
+        &plan.label_id,
+        &plan.partitions,
+        plan.device.size,
+        sector_size,
     )?;
-    tracing::debug!("Partitioning: {partitioning_buf}");
-    Task::new("Initializing partitions", "sfdisk")
-        .arg("--wipe=always")
-        .arg(device.path())
+    tracing::debug!("Partitioning: {planned:?}");
+    println!("Initializing partitions");
+    DiskLabel::Gpt(planned.clone()).write_path(&devpath)?;
+    Task::new("Re-reading partition table", "blockdev")
+        .args(["--rereadpt", devpath.as_str()])
         .quiet()
-        .run_with_stdin_buf(Some(partitioning_buf.as_bytes()))
-        .context("Failed to run sfdisk")?;
+        .run()?;
     tracing::debug!("Created partition table");
 
     // Full udev sync; it'd obviously be better to await just the devices
     // we're targeting, but this is a simple coarse hammer.
     udev_settle()?;
 
-    // Re-read what we wrote into structured information
-    let base_partitions = &bootc_blockdev::partitions_of(&devpath)?;
+    // Re-read what we wrote, and verify it matches the plan
+    let gpt = match DiskLabel::read_path(&devpath)? {
+        Some(DiskLabel::Gpt(gpt)) => gpt,
+        _ => anyhow::bail!("No GPT found on {devpath} after partitioning"),
+    };
+    if gpt.partitions != planned.partitions {
+        anyhow::bail!("Partition table on {devpath} differs from the one written");
+    }
+    let devinfo = bootc_blockdev::list_dev(&devpath)?;
+    let find_partno = |partno: u32| -> Result<(&GptPartition, String)> {
+        let part = gpt
+            .find_partno(partno)
+            .ok_or_else(|| anyhow::anyhow!("Missing partition for index {partno}"))?;
+        let node = partition_device(&devinfo, part).ok_or_else(|| {
+            anyhow::anyhow!("Device node for partition {partno} of {devpath} not found")
+        })?;
+        Ok((part, node))
+    };
 
-    let root_partition = base_partitions.find_partno(rootpn)?;
-    if root_partition.parttype.as_str() != LINUX_PARTTYPE {
+    let rootpn = plan.root_partno;
+    let (root_partition, root_node) = find_partno(rootpn)?;
+    if root_partition.type_guid != parttable::LINUX_FILESYSTEM_TYPE {
         anyhow::bail!(
-            "root partition {partno} has type {}; expected {LINUX_PARTTYPE}",
-            root_partition.parttype.as_str()
+            "root partition {rootpn} has type {}; expected {LINUX_PARTTYPE}",
+            root_partition.type_guid
         );
     }
-    let (rootdev, root_blockdev_kargs) = match block_setup {
-        BlockSetup::Direct => (root_partition.node.to_owned(), None),
-        BlockSetup::Tpm2Luks => {
-            let uuid = uuid::Uuid::new_v4().to_string();
-            // This will be replaced via --wipe-slot=all when binding to tpm below
-            let dummy_passphrase = uuid::Uuid::new_v4().to_string();
-            let mut tmp_keyfile = tempfile::NamedTempFile::new()?;
-            tmp_keyfile.write_all(dummy_passphrase.as_bytes())?;
-            tmp_keyfile.flush()?;
-            let tmp_keyfile = tmp_keyfile.path();
-            let dummy_passphrase_input = Some(dummy_passphrase.as_bytes());
-
-            let root_devpath = root_partition.path();
-
-            Task::new("Initializing LUKS for root", "cryptsetup")
-                .args(["luksFormat", "--uuid", uuid.as_str(), "--key-file"])
-                .args([tmp_keyfile])
-                .args([root_devpath])
-                .run()?;
-            // The --wipe-slot=all removes our temporary passphrase, and binds to the local TPM device.
-            // We also use .verbose() here as the details are important/notable.
-            Task::new("Enrolling root device with TPM", "systemd-cryptenroll")
-                .args(["--wipe-slot=all", "--tpm2-device=auto", "--unlock-key-file"])
-                .args([tmp_keyfile])
-                .args([root_devpath])
-                .verbose()
-                .run_with_stdin_buf(dummy_passphrase_input)?;
-            Task::new("Opening root LUKS device", "cryptsetup")
-                .args(["luksOpen", root_devpath.as_str(), luks_name])
-                .run()?;
-            let rootdev = format!("/dev/mapper/{luks_name}");
-            let kargs = vec![
-                format!("luks.uuid={uuid}"),
-                format!("luks.options=tpm2-device=auto,headless=true"),
-            ];
-            (rootdev, Some(kargs))
-        }
+    let rootdev = if let Some(luks) = plan.luks.as_ref() {
+        let luks_name = luks.name;
+        // This will be replaced via --wipe-slot=all when binding to tpm below
+        let dummy_passphrase = uuid::Uuid::new_v4().to_string();
+        let mut tmp_keyfile = tempfile::NamedTempFile::new()?;
+        tmp_keyfile.write_all(dummy_passphrase.as_bytes())?;
+        tmp_keyfile.flush()?;
+        let tmp_keyfile = tmp_keyfile.path();
+        let dummy_passphrase_input = Some(dummy_passphrase.as_bytes());
+
+        let root_devpath = root_node.as_str();
+
+        Task::new("Initializing LUKS for root", "cryptsetup")
+            .args(["luksFormat", "--uuid", luks.uuid.as_str(), "--key-file"])
+            .args([tmp_keyfile])
+            .args([root_devpath])
+            .run()?;
+        // The --wipe-slot=all removes our temporary passphrase, and binds to the local TPM device.
+        // We also use .verbose() here as the details are important/notable.
+        Task::new("Enrolling root device with TPM", "systemd-cryptenroll")
+            .args(["--wipe-slot=all", "--tpm2-device=auto", "--unlock-key-file"])
+            .args([tmp_keyfile])
+            .args([root_devpath])
+            .verbose()
+            .run_with_stdin_buf(dummy_passphrase_input)?;
+        Task::new("Opening root LUKS device", "cryptsetup")
+            .args(["luksOpen", root_devpath.as_str(), luks_name])
+            .run()?;
+        format!("/dev/mapper/{luks_name}")
+    } else {
+        root_node
     };
 
     // Initialize the /boot filesystem
-    let bootdev = if let Some(bootpn) = boot_partno {
-        Some(base_partitions.find_partno(bootpn)?)
+    let bootdev = if let Some(bootpn) = plan.boot_partno {
+        Some(find_partno(bootpn)?.1)
     } else {
         None
     };
-    let boot_uuid = if let Some(bootdev) = bootdev {
-        Some(
-            mkfs(
-                bootdev.node.as_str(),
-                root_filesystem,
-                "boot",
-                opts.wipe,
-                [],
-            )
-            .context("Initializing /boot")?,
-        )
+    let boot_uuid = if let Some(bootdev) = bootdev.as_deref() {
+        let fs = plan.find_filesystem("boot").expect("boot filesystem");
+        mkfs(bootdev, fs).context("Initializing /boot")?;
+        fs.uuid.clone()
     } else {
         None
     };
 
     // Initialize rootfs
-    let root_uuid = mkfs(&rootdev, root_filesystem, "root", opts.wipe, [])?;
-    let rootarg = format!("root=UUID={root_uuid}");
-    let bootsrc = boot_uuid.as_ref().map(|uuid| format!("UUID={uuid}"));
-    let bootarg = bootsrc.as_deref().map(|bootsrc| format!("boot={bootsrc}"));
-    let boot = bootsrc.map(|bootsrc| MountSpec {
-        source: bootsrc,
+    let rootfs = plan.find_filesystem("root").expect("root filesystem");
+    mkfs(&rootdev, rootfs)?;
+    let root_uuid = rootfs.uuid.clone();
+    let boot = boot_uuid.map(|uuid| MountSpec {
+        source: format!("UUID={uuid}"),
         target: "/boot".into(),
         fstype: MountSpec::AUTO.into(),
         options: Some("ro".into()),
     });
-    let kargs = root_blockdev_kargs
-        .into_iter()
-        .flatten()
-        .chain([rootarg, RW_KARG.to_string()].into_iter())
-        .chain(bootarg)
-        .collect::<Vec<_>>();
 
     mount::mount(&rootdev, &physical_root_path)?;
     let target_rootfs = Dir::open_ambient_dir(&physical_root_path, cap_std::ambient_authority())?;
@@ -411,37 +740,154 @@ pub(crate) fn install_create_rootfs(
     let bootfs = physical_root_path.join("boot");
     // Create the underlying mount point directory, which should be labeled
     crate::lsm::ensure_dir_labeled(&target_rootfs, "boot", None, 0o755.into(), sepolicy)?;
-    if let Some(bootdev) = bootdev {
-        mount::mount(bootdev.node.as_str(), &bootfs)?;
+    if let Some(bootdev) = bootdev.as_deref() {
+        mount::mount(bootdev, &bootfs)?;
     }
     // And we want to label the root mount of /boot
     crate::lsm::ensure_dir_labeled(&target_rootfs, "boot", None, 0o755.into(), sepolicy)?;
 
     // Create the EFI system partition, if applicable
-    if let Some(esp_partno) = esp_partno {
-        let espdev = base_partitions.find_partno(esp_partno)?;
-        Task::new("Creating ESP filesystem", "mkfs.fat")
-            .args([espdev.node.as_str(), "-n", "EFI-SYSTEM"])
-            .verbose()
-            .quiet_output()
-            .run()?;
+    if let Some(esp_partno) = plan.esp_partno {
+        let (_, espdev) = find_partno(esp_partno)?;
+        let fs = plan.find_filesystem("EFI-SYSTEM").expect("ESP filesystem");
+        mkfs(&espdev, fs)?;
         let efifs_path = bootfs.join(crate::bootloader::EFI_DIR);
         std::fs::create_dir(&efifs_path).context("Creating efi dir")?;
     }
 
-    let luks_device = match block_setup {
-        BlockSetup::Direct => None,
-        BlockSetup::Tpm2Luks => Some(luks_name.to_string()),
-    };
-    let device_info = bootc_blockdev::partitions_of(&devpath)?;
+    let luks_device = plan.luks.as_ref().map(|luks| luks.name.to_string());
     Ok(RootSetup {
         luks_device,
         // device_info,
         physical_root_path,
         physical_root,
-        rootfs_uuid: Some(root_uuid.to_string()),
+        rootfs_uuid: root_uuid,
         boot,
-        kargs,
+        kargs: plan.kargs,
         skip_finalize: false,
+        side_by_side: false,
+        host_network: None,
+        install_mode: crate::spec::InstallMode::ToDisk,
+        block_setup: Some(block_setup.to_string()),
     })
 }
+
+#[cfg(test)]
+#[cfg(feature = "install-to-disk")]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_node() {
+        assert_eq!(partition_node("/dev/vda", 3), "/dev/vda3");
+        assert_eq!(partition_node("/dev/nvme0n1", 2), "/dev/nvme0n1p2");
+        assert_eq!(partition_node("/dev/loop0", 1), "/dev/loop0p1");
+        assert_eq!(partition_node(LOOPBACK_PLACEHOLDER, 1), "/dev/loopNp1");
+    }
+
+    #[test]
+    fn test_partition_table() -> Result<()> {
+        let mib = 1024 * 1024;
+        let partitions = [
+            PlannedPartition {
+                partno: 1,
+                name: "BIOS-BOOT",
+                parttype: Some("21686148-6449-6E6F-744E-656564454649"),
+                size_mib: Some(1),
+                bootable: true,
+            },
+            PlannedPartition {
+                partno: 2,
+                name: "boot",
+                parttype: None,
+                size_mib: Some(BOOTPN_SIZE_MB.into()),
+                bootable: false,
+            },
+            PlannedPartition {
+                partno: 3,
+                name: "root",
+                parttype: Some(LINUX_PARTTYPE),
+                size_mib: None,
+                bootable: false,
+            },
+        ];
+        let label_id = PlanUuids::Random.generate("label");
+        let gpt = partition_table(&label_id, &partitions, 2048 * mib, 512)?;
+        assert_eq!(gpt.disk_guid.to_string(), label_id);
+        let p = &gpt.partitions;
+        assert_eq!(p.len(), 3);
+        assert_eq!(p[0].type_guid, parttable::BIOS_BOOT_TYPE);
+        assert_eq!(p[0].attributes, parttable::GPT_ATTR_LEGACY_BIOS_BOOTABLE);
+        assert_eq!((p[0].first_lba, p[0].sectors()), (2048, 2048));
+        assert_eq!(p[1].name, "boot");
+        assert_eq!(p[1].type_guid, parttable::LINUX_FILESYSTEM_TYPE);
+        assert_eq!(p[1].sectors() * 512, u64::from(BOOTPN_SIZE_MB) * mib);
+        assert_eq!(p[2].last_lba, gpt.last_usable_lba);
+        // Placeholders are not valid identifiers
+        let label_id = PlanUuids::Placeholder.generate("label");
+        assert!(partition_table(&label_id, &partitions, 2048 * mib, 512).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_device() -> Result<()> {
+        let part = GptPartition {
+            partno: 2,
+            type_guid: parttable::LINUX_FILESYSTEM_TYPE,
+            guid: uuid::Uuid::parse_str("2bc4b0e1-c3d3-4a3b-9a39-5a6a1e6a4c1f")?,
+            first_lba: 2048,
+            last_lba: 4095,
+            attributes: 0,
+            name: "root".into(),
+        };
+        // A multipath device, whose partitions are device mapper devices
+        let device: bootc_blockdev::Device = serde_json::from_str(
+            r#"{"name": "mpatha", "path": "/dev/mapper/mpatha", "size": 10737418240,
+                "serial": null, "model": null, "partlabel": null, "parttype": null,
+                "partuuid": null, "maj:min": "253:0", "label": null, "fstype": null,
+                "children": [
+                  {"name": "mpatha1", "path": "/dev/mapper/mpatha1", "size": 1048576,
+                   "serial": null, "model": null, "partlabel": "BIOS-BOOT", "parttype": null,
+                   "partuuid": "0b6fe3e8-5c5e-4bd4-8f0e-6b4c0fa05a4a", "maj:min": "253:1",
+                   "label": null, "fstype": null},
+                  {"name": "mpatha2", "path": "/dev/mapper/mpatha2", "size": 1048576,
+                   "serial": null, "model": null, "partlabel": "root", "parttype": null,
+                   "partuuid": "2BC4B0E1-C3D3-4A3B-9A39-5A6A1E6A4C1F", "maj:min": "253:2",
+                   "label": null, "fstype": null}
+                ]}"#,
+        )?;
+        assert_eq!(
+            partition_device(&device, &part).as_deref(),
+            Some("/dev/mapper/mpatha2")
+        );
+        let other = GptPartition {
+            guid: uuid::Uuid::new_v4(),
+            ..part
+        };
+        assert_eq!(partition_device(&device, &other), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_planned_filesystem() {
+        let u = PlanUuids::Random.generate("root");
+        let fs = PlannedFilesystem::new("/dev/vda4".into(), Filesystem::Xfs, "root", true, u);
+        let u = fs.uuid.as_deref().unwrap();
+        assert_eq!(
+            fs.command,
+            [
+                "mkfs.xfs",
+                "-f",
+                "-m",
+                format!("uuid={u}").as_str(),
+                "-L",
+                "root"
+            ]
+        );
+        let u = PlanUuids::Placeholder.generate("boot");
+        assert_eq!(u, "<boot-uuid>");
+        let fs = PlannedFilesystem::new("/dev/vda3".into(), Filesystem::Ext4, "boot", true, u);
+        let u = fs.uuid.as_deref().unwrap();
+        assert_eq!(fs.command, ["mkfs.ext4", "-U", u, "-L", "boot"]);
+    }
+}
@@ -3,6 +3,8 @@
 //! This module handles the TOML configuration file for `bootc install`.
 
 use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
 use clap::ValueEnum;
 use fn_error_context::context;
 use serde::{Deserialize, Serialize};
@@ -58,6 +60,42 @@ pub(crate) struct BasicFilesystems {
     // pub(crate) esp: Option<FilesystemCustomization>,
 }
 
+/// A group to create at install time, via `sysusers.d`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct InstallGroup {
+    pub(crate) name: String,
+    /// The numeric group ID; allocated from the range for regular groups in
+    /// `/etc/login.defs` if unset
+    pub(crate) gid: Option<u32>,
+}
+
+/// A user to create at install time, via `sysusers.d`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct InstallUser {
+    pub(crate) name: String,
+    /// The numeric user ID; allocated from the range for regular users in
+    /// `/etc/login.defs` if unset
+    pub(crate) uid: Option<u32>,
+    /// The primary group; defaults to a group named after the user
+    pub(crate) group: Option<String>,
+    pub(crate) gecos: Option<String>,
+    /// Defaults to `/home/<name>`
+    pub(crate) home: Option<String>,
+    pub(crate) shell: Option<String>,
+    /// Supplementary groups
+    #[serde(default)]
+    pub(crate) groups: Vec<String>,
+    /// Grant administrative access via membership in the `wheel` group
+    #[serde(default)]
+    pub(crate) sudo: bool,
+    /// A crypt(3) password hash; plain text passwords are not accepted
+    pub(crate) password_hash: Option<String>,
+    #[serde(default)]
+    pub(crate) ssh_authorized_keys: Vec<String>,
+}
+
 /// The serialized [install] section
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename = "install", rename_all = "kebab-case", deny_unknown_fields)]
@@ -73,6 +111,12 @@ pub(crate) struct InstallConfiguration {
     pub(crate) kargs: Option<Vec<String>>,
     /// Supported architectures for this configuration
     pub(crate) match_architectures: Option<Vec<String>>,
+    /// Groups to create, applied at installation time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) groups: Option<Vec<InstallGroup>>,
+    /// Users to create, applied at installation time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) users: Option<Vec<InstallUser>>,
 }
 
 fn merge_basic<T>(s: &mut Option<T>, o: Option<T>, _env: &EnvProperties) {
@@ -138,6 +182,16 @@ impl Mergeable for InstallConfiguration {
                     .get_or_insert_with(Default::default)
                     .extend(other_kargs)
             }
+            if let Some(other_groups) = other.groups {
+                self.groups
+                    .get_or_insert_with(Default::default)
+                    .extend(other_groups)
+            }
+            if let Some(other_users) = other.users {
+                self.users
+                    .get_or_insert_with(Default::default)
+                    .extend(other_users)
+            }
         }
     }
 }
@@ -164,6 +218,37 @@ impl InstallConfiguration {
         }
     }
 
+    /// Check that the (merged, but not yet canonicalized) configuration is consistent
+    /// and can be supported by the target root.
+    #[cfg_attr(not(feature = "install-to-disk"), allow(unused_variables))]
+    pub(crate) fn validate(&self, root: &Dir) -> Result<()> {
+        if let (Some(a), Some(b)) = (
+            self.root_fs_type,
+            self.filesystem_root().and_then(|f| f.fstype),
+        ) {
+            if a != b {
+                anyhow::bail!("root-fs-type = {a} conflicts with filesystem.root.type = {b}");
+            }
+        }
+        #[cfg(feature = "install-to-disk")]
+        if self
+            .block
+            .iter()
+            .flatten()
+            .any(|b| *b == BlockSetup::Tpm2Luks)
+        {
+            const CRYPTSETUP: &[&str] = &["usr/sbin/cryptsetup", "usr/bin/cryptsetup"];
+            let mut found = false;
+            for path in CRYPTSETUP {
+                found |= root.try_exists(path)?;
+            }
+            if !found {
+                anyhow::bail!("Block setup {} requires cryptsetup", BlockSetup::Tpm2Luks);
+            }
+        }
+        Ok(())
+    }
+
     /// Convenience helper to access the root filesystem
     pub(crate) fn filesystem_root(&self) -> Option<&RootFS> {
         self.filesystem.as_ref().and_then(|fs| fs.root.as_ref())
@@ -172,6 +257,8 @@ impl InstallConfiguration {
     // Remove all configuration which is handled by `install to-filesystem`.
     pub(crate) fn filter_to_external(&mut self) {
         self.kargs.take();
+        self.groups.take();
+        self.users.take();
     }
 
     #[cfg(feature = "install-to-disk")]
@@ -188,30 +275,45 @@ impl InstallConfiguration {
     }
 }
 
-#[context("Loading configuration")]
-/// Load the install configuration, merging all found configuration files.
-pub(crate) fn load_config() -> Result<Option<InstallConfiguration>> {
-    let env = EnvProperties {
-        sys_arch: std::env::consts::ARCH.to_string(),
-    };
-    const SYSTEMD_CONVENTIONAL_BASES: &[&str] = &["/usr/lib", "/usr/local/lib", "/etc", "/run"];
-    let fragments = liboverdrop::scan(SYSTEMD_CONVENTIONAL_BASES, "bootc/install", &["toml"], true);
-    let mut config: Option<InstallConfiguration> = None;
+/// Find and parse the configuration files in the root at `root` (typically `/`),
+/// in the order they apply.
+pub(crate) fn read_config_fragments(
+    root: &Utf8Path,
+) -> Result<Vec<(Utf8PathBuf, InstallConfigurationToplevel)>> {
+    const SYSTEMD_CONVENTIONAL_BASES: &[&str] = &["usr/lib", "usr/local/lib", "etc", "run"];
+    let bases = SYSTEMD_CONVENTIONAL_BASES.iter().map(|b| root.join(b));
+    let fragments = liboverdrop::scan(bases, "bootc/install", &["toml"], true);
+    let mut r = Vec::new();
     for (_name, path) in fragments {
         let buf = std::fs::read_to_string(&path)?;
+        // The path as seen from inside the root
+        let path = Utf8PathBuf::try_from(path)?;
+        let path = Utf8Path::new("/").join(path.strip_prefix(root).unwrap_or(&path));
         let mut unused = std::collections::HashSet::new();
         let de = toml::Deserializer::new(&buf);
-        let mut c: InstallConfigurationToplevel = serde_ignored::deserialize(de, |path| {
+        let c: InstallConfigurationToplevel = serde_ignored::deserialize(de, |path| {
             unused.insert(path.to_string());
         })
         .with_context(|| format!("Parsing {path:?}"))?;
         for key in unused {
             eprintln!("warning: {path:?}: Unknown key {key}");
         }
+        r.push((path, c));
+    }
+    Ok(r)
+}
+
+/// Merge the configuration fragments which apply to the architecture in `env`.
+pub(crate) fn merge_config_fragments(
+    fragments: impl IntoIterator<Item = InstallConfigurationToplevel>,
+    env: &EnvProperties,
+) -> Option<InstallConfiguration> {
+    let mut config: Option<InstallConfiguration> = None;
+    for mut c in fragments {
         if let Some(config) = config.as_mut() {
             if let Some(install) = c.install {
                 tracing::debug!("Merging install config: {install:?}");
-                config.merge(install, &env);
+                config.merge(install, env);
             }
         } else {
             // Only set the config if it matches the current arch
@@ -228,6 +330,17 @@ pub(crate) fn load_config() -> Result<Option<InstallConfiguration>> {
             }
         }
     }
+    config
+}
+
+#[context("Loading configuration")]
+/// Load the install configuration, merging all found configuration files.
+pub(crate) fn load_config() -> Result<Option<InstallConfiguration>> {
+    let env = EnvProperties {
+        sys_arch: std::env::consts::ARCH.to_string(),
+    };
+    let fragments = read_config_fragments(Utf8Path::new("/"))?;
+    let mut config = merge_config_fragments(fragments.into_iter().map(|(_, c)| c), &env);
     if let Some(config) = config.as_mut() {
         config.canonicalize();
     }
@@ -305,6 +418,55 @@ kargs = ["console=ttyS0", "foo=bar"]
         )
     }
 
+    #[test]
+    fn test_parse_users() {
+        let env = EnvProperties {
+            sys_arch: "x86_64".to_string(),
+        };
+        let c: InstallConfigurationToplevel = toml::from_str(
+            r##"[[install.groups]]
+name = "ops"
+gid = 2000
+
+[[install.users]]
+name = "admin"
+uid = 1000
+groups = ["ops"]
+sudo = true
+password-hash = "$6$salt$hash"
+ssh-authorized-keys = ["ssh-ed25519 ABCDE admin@example"]
+"##,
+        )
+        .unwrap();
+        let mut install = c.install.unwrap();
+        let users = install.users.as_ref().unwrap();
+        assert_eq!(users.len(), 1);
+        let admin = &users[0];
+        assert_eq!(admin.name, "admin");
+        assert_eq!(admin.uid, Some(1000));
+        assert!(admin.sudo);
+        assert_eq!(admin.groups, ["ops"]);
+        assert_eq!(admin.ssh_authorized_keys.len(), 1);
+        assert_eq!(install.groups.as_ref().unwrap()[0].gid, Some(2000));
+        let other = InstallConfiguration {
+            users: Some(vec![InstallUser {
+                name: "other".into(),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        install.merge(other, &env);
+        assert_eq!(install.users.as_ref().unwrap().len(), 2);
+        // Unknown keys are rejected
+        assert!(toml::from_str::<InstallConfigurationToplevel>(
+            r##"[[install.users]]
+name = "admin"
+password = "hunter2"
+"##
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_parse_filesystems() {
         let env = EnvProperties {
@@ -572,4 +734,45 @@ root-fs-type = "xfs"
             )
         );
     }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let root =
+            &cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+        let env = EnvProperties {
+            sys_arch: "x86_64".to_string(),
+        };
+        let fragments = [
+            r##"[install]
+root-fs-type = "xfs"
+"##,
+            r##"[install.filesystem.root]
+type = "ext4"
+"##,
+        ]
+        .map(|s| toml::from_str::<InstallConfigurationToplevel>(s).unwrap());
+        let config = merge_config_fragments(fragments.clone().into_iter().take(1), &env).unwrap();
+        config.validate(root)?;
+        let config = merge_config_fragments(fragments, &env).unwrap();
+        let e = config.validate(root).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "root-fs-type = xfs conflicts with filesystem.root.type = ext4"
+        );
+
+        #[cfg(feature = "install-to-disk")]
+        {
+            let c: InstallConfigurationToplevel = toml::from_str(
+                r##"[install]
+block = ["tpm2-luks"]
+"##,
+            )?;
+            let config = c.install.unwrap();
+            assert!(config.validate(root).is_err());
+            root.create_dir_all("usr/sbin")?;
+            root.write("usr/sbin/cryptsetup", "")?;
+            config.validate(root)?;
+        }
+        Ok(())
+    }
 }
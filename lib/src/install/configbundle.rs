@@ -0,0 +1,683 @@
+//! # Per-machine configuration applied at install time
+//!
+//! This implements `bootc install --config-bundle`, which takes a declarative
+//! (YAML or JSON) document describing files, systemd units, the hostname and
+//! NetworkManager connection profiles, and writes them into the new deployment's
+//! `/etc` and the stateroot's `/var`.
+
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std::fs::{Dir, Permissions};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+use rustix::fs::{AtFlags, Gid, Uid};
+use serde::{Deserialize, Serialize};
+
+const ETC_SYSTEMD_SYSTEM: &str = "etc/systemd/system";
+const USR_SYSTEMD_SYSTEM: &str = "usr/lib/systemd/system";
+const NM_SYSTEM_CONNECTIONS: &str = "etc/NetworkManager/system-connections";
+
+/// A file to write.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct BundleFile {
+    /// Absolute path, which must be under `/etc` or `/var`
+    pub(crate) path: Utf8PathBuf,
+    pub(crate) contents: String,
+    /// Octal file mode; defaults to `0644`
+    pub(crate) mode: Option<String>,
+    #[serde(default)]
+    pub(crate) uid: u32,
+    #[serde(default)]
+    pub(crate) gid: u32,
+}
+
+/// A systemd unit to install, enable or mask.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct BundleUnit {
+    pub(crate) name: String,
+    /// If unset, the unit must be provided by the image.
+    pub(crate) contents: Option<String>,
+    /// Enable the unit via its `[Install]` section, like `systemctl enable`: this
+    /// handles `WantedBy=`, `RequiredBy=`, `Alias=` and `Also=`.  A template
+    /// is enabled with its `DefaultInstance=`.
+    #[serde(default)]
+    pub(crate) enabled: bool,
+    #[serde(default)]
+    pub(crate) mask: bool,
+}
+
+/// A NetworkManager connection profile in keyfile format.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct BundleConnection {
+    /// The connection name; the file is written as `<name>.nmconnection`
+    pub(crate) name: String,
+    pub(crate) contents: String,
+}
+
+/// The toplevel configuration bundle.
+#[derive(Debug, Clone, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct ConfigBundle {
+    pub(crate) hostname: Option<String>,
+    #[serde(default)]
+    pub(crate) files: Vec<BundleFile>,
+    #[serde(default)]
+    pub(crate) units: Vec<BundleUnit>,
+    #[serde(default)]
+    pub(crate) network_connections: Vec<BundleConnection>,
+}
+
+/// What was applied from a configuration bundle; this is recorded in the aleph.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct AppliedConfigBundle {
+    /// SHA-256 of the bundle document
+    pub(crate) sha256: String,
+    /// All paths written, in order
+    pub(crate) paths: Vec<String>,
+}
+
+/// A parsed configuration bundle and the digest of its source.
+#[derive(Debug)]
+pub(crate) struct LoadedConfigBundle {
+    pub(crate) bundle: ConfigBundle,
+    pub(crate) sha256: String,
+}
+
+fn validate_simple_name(kind: &str, name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\0', '\n']) {
+        anyhow::bail!("Invalid {kind} name: {name:?}");
+    }
+    Ok(())
+}
+
+fn validate_hostname(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with(['-', '.'])
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.'));
+    if !valid {
+        anyhow::bail!("Invalid hostname: {name:?}");
+    }
+    Ok(())
+}
+
+fn parse_mode(mode: Option<&str>) -> Result<u32> {
+    let Some(mode) = mode else {
+        return Ok(0o644);
+    };
+    let v = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .with_context(|| format!("Parsing mode {mode}"))?;
+    if v > 0o7777 {
+        anyhow::bail!("Invalid mode {mode}");
+    }
+    Ok(v)
+}
+
+/// Check that the path is absolute, normalized, and under `/etc` or `/var`.
+fn validate_path(path: &Utf8Path) -> Result<()> {
+    let mut components = path.components();
+    if components.next() != Some(Utf8Component::RootDir) {
+        anyhow::bail!("Path must be absolute: {path}");
+    }
+    if !matches!(
+        components.next(),
+        Some(Utf8Component::Normal("etc" | "var"))
+    ) {
+        anyhow::bail!("Path must be under /etc or /var: {path}");
+    }
+    let mut n = 0;
+    for c in components {
+        if !matches!(c, Utf8Component::Normal(_)) {
+            anyhow::bail!("Path must be normalized: {path}");
+        }
+        n += 1;
+    }
+    if n == 0 {
+        anyhow::bail!("Invalid path: {path}");
+    }
+    Ok(())
+}
+
+impl ConfigBundle {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(hostname) = self.hostname.as_deref() {
+            validate_hostname(hostname)?;
+        }
+        for f in self.files.iter() {
+            validate_path(&f.path)?;
+            parse_mode(f.mode.as_deref())?;
+        }
+        for unit in self.units.iter() {
+            validate_simple_name("unit", &unit.name)?;
+            if !unit.name.contains('.') {
+                anyhow::bail!("Invalid unit name (missing suffix): {}", unit.name);
+            }
+            if unit.mask && (unit.enabled || unit.contents.is_some()) {
+                anyhow::bail!("Unit {} cannot be both masked and provided", unit.name);
+            }
+        }
+        for conn in self.network_connections.iter() {
+            validate_simple_name("connection", &conn.name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read, parse and validate a configuration bundle.
+#[context("Loading config bundle {path}")]
+pub(crate) fn load(path: &Utf8Path) -> Result<LoadedConfigBundle> {
+    let buf = std::fs::read(path)?;
+    // YAML is a superset of JSON, so this handles both.
+    let bundle: ConfigBundle = serde_yaml::from_slice(&buf)?;
+    bundle.validate()?;
+    let sha256 = hex::encode(openssl::sha::sha256(&buf));
+    Ok(LoadedConfigBundle { bundle, sha256 })
+}
+
+/// The `[Install]` section of a unit.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct UnitInstall {
+    /// The units named in `WantedBy=` and `RequiredBy=`, with the suffix of the
+    /// directory holding the symlinks (`wants` or `requires`)
+    targets: Vec<(&'static str, String)>,
+    alias: Vec<String>,
+    also: Vec<String>,
+    default_instance: Option<String>,
+}
+
+impl UnitInstall {
+    fn parse(contents: &str) -> Self {
+        let mut in_install = false;
+        let mut r = Self::default();
+        for line in contents.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_install = line == "[Install]";
+                continue;
+            }
+            if !in_install {
+                continue;
+            }
+            let Some((k, v)) = line.split_once('=') else {
+                continue;
+            };
+            let values = v.split_whitespace().map(ToOwned::to_owned);
+            match k.trim() {
+                "WantedBy" => r.targets.extend(values.map(|t| ("wants", t))),
+                "RequiredBy" => r.targets.extend(values.map(|t| ("requires", t))),
+                "Alias" => r.alias.extend(values),
+                "Also" => r.also.extend(values),
+                "DefaultInstance" => r.default_instance = Some(v.trim().to_owned()),
+                _ => {}
+            }
+        }
+        r
+    }
+
+    fn is_empty(&self) -> bool {
+        self.targets.is_empty() && self.alias.is_empty() && self.also.is_empty()
+    }
+}
+
+/// Split a unit name like `foo@bar.service` into its prefix, instance and suffix;
+/// the instance is empty for a template.  Returns `None` for units which are
+/// neither templates nor instances.
+fn split_unit_instance(name: &str) -> Option<(&str, &str, &str)> {
+    let (prefix, rest) = name.split_once('@')?;
+    let (instance, suffix) = rest.rsplit_once('.')?;
+    Some((prefix, instance, suffix))
+}
+
+/// Find the file of the unit `name` in the target root, looking at the
+/// template for an instance which does not have its own file.  Returns the
+/// absolute path of the file and its contents.
+fn find_unit(root: &Dir, name: &str) -> Result<(String, String)> {
+    let mut names = vec![Cow::Borrowed(name)];
+    if let Some((prefix, instance, suffix)) = split_unit_instance(name) {
+        if !instance.is_empty() {
+            names.push(Cow::Owned(format!("{prefix}@.{suffix}")));
+        }
+    }
+    for name in names.iter() {
+        for dir in [ETC_SYSTEMD_SYSTEM, USR_SYSTEMD_SYSTEM] {
+            let path = Utf8Path::new(dir).join(&**name);
+            if let Some(contents) = root.open_optional(&path)?.map(std::io::read_to_string) {
+                let contents = contents.with_context(|| format!("Reading unit /{path}"))?;
+                return Ok((format!("/{path}"), contents));
+            }
+        }
+    }
+    anyhow::bail!("Unit not found: {name}")
+}
+
+/// The destination for writing files: the deployment root for `/etc`, and the stateroot
+/// directory (containing `var`) for `/var`.  Both are opened such that paths relative
+/// to them are also the absolute paths used for SELinux labeling.
+struct Target<'a> {
+    root: &'a Dir,
+    stateroot: &'a Dir,
+    sepolicy: Option<&'a ostree::SePolicy>,
+    applied: Vec<String>,
+}
+
+impl Target<'_> {
+    fn dir_for(&self, path: &Utf8Path) -> &Dir {
+        if path.starts_with("var") {
+            self.stateroot
+        } else {
+            self.root
+        }
+    }
+
+    /// Create any missing parent directories of `path`, labeling them.
+    fn ensure_parents(&self, path: &Utf8Path) -> Result<()> {
+        let d = self.dir_for(path);
+        let mut cur = Utf8PathBuf::new();
+        for c in path.parent().into_iter().flat_map(|p| p.components()) {
+            cur.push(c);
+            if !d.try_exists(&cur)? {
+                crate::lsm::ensure_dir_labeled(d, &cur, None, 0o755.into(), self.sepolicy)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn label(&mut self, path: &Utf8Path) -> Result<()> {
+        let d = self.dir_for(path);
+        if let Some(policy) = self.sepolicy {
+            let meta = d.symlink_metadata(path)?;
+            crate::lsm::ensure_labeled(d, path, &meta, policy)?;
+        }
+        self.applied.push(format!("/{path}"));
+        Ok(())
+    }
+
+    #[context("Writing {path}")]
+    fn write(
+        &mut self,
+        path: &Utf8Path,
+        contents: &[u8],
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<()> {
+        self.ensure_parents(path)?;
+        let d = self.dir_for(path);
+        d.atomic_write_with_perms(path, contents, Permissions::from_mode(mode))?;
+        if uid != 0 || gid != 0 {
+            rustix::fs::chownat(
+                d,
+                path.as_std_path(),
+                Some(Uid::from_raw(uid)),
+                Some(Gid::from_raw(gid)),
+                AtFlags::SYMLINK_NOFOLLOW,
+            )?;
+        }
+        self.label(path)
+    }
+
+    #[context("Creating symlink {path}")]
+    fn symlink(&mut self, target: &str, path: &Utf8Path) -> Result<()> {
+        self.ensure_parents(path)?;
+        let d = self.dir_for(path);
+        d.remove_file_optional(path)?;
+        d.symlink(target, path)?;
+        self.label(path)
+    }
+}
+
+/// Enable the unit `name` via its `[Install]` section, like `systemctl enable`.
+/// A template is enabled with its `DefaultInstance=`.  Units named in `Also=`
+/// are enabled as well; `enabled` tracks the units already processed.
+#[context("Enabling unit {name}")]
+fn enable_unit(target: &mut Target, name: &str, enabled: &mut BTreeSet<String>) -> Result<()> {
+    if !enabled.insert(name.to_owned()) {
+        return Ok(());
+    }
+    let Some((_, unit_suffix)) = name.rsplit_once('.') else {
+        anyhow::bail!("Invalid unit name (missing suffix)");
+    };
+    let (unit_path, contents) = find_unit(target.root, name)?;
+    let install = UnitInstall::parse(&contents);
+    if install.is_empty() {
+        anyhow::bail!("No WantedBy=, RequiredBy=, Alias= or Also= in [Install] section");
+    }
+    // The name of the symlinks, which for a template is that of its default instance
+    let (link_name, instance) = match split_unit_instance(name) {
+        Some((prefix, "", suffix)) => {
+            let Some(instance) = install.default_instance.as_deref() else {
+                anyhow::bail!(
+                    "Template unit has no DefaultInstance=; enable an instance such as {prefix}@foo.{suffix} instead"
+                );
+            };
+            (format!("{prefix}@{instance}.{suffix}"), Some(instance))
+        }
+        Some((_, instance, _)) => (name.to_owned(), Some(instance)),
+        None => (name.to_owned(), None),
+    };
+    for (suffix, wanted_by) in install.targets.iter() {
+        let link = Utf8Path::new(ETC_SYSTEMD_SYSTEM)
+            .join(format!("{wanted_by}.{suffix}"))
+            .join(&link_name);
+        target.symlink(&unit_path, &link)?;
+    }
+    for alias in install.alias.iter() {
+        validate_simple_name("alias", alias)?;
+        if alias.rsplit_once('.').map(|(_, s)| s) != Some(unit_suffix) {
+            anyhow::bail!("Alias {alias} does not have the unit type suffix .{unit_suffix}");
+        }
+        // An alias of a template refers to the same instance
+        let alias = match (split_unit_instance(alias), instance) {
+            (Some((prefix, "", suffix)), Some(instance)) => {
+                Cow::Owned(format!("{prefix}@{instance}.{suffix}"))
+            }
+            _ => Cow::Borrowed(alias.as_str()),
+        };
+        let link = Utf8Path::new(ETC_SYSTEMD_SYSTEM).join(&*alias);
+        target.symlink(&unit_path, &link)?;
+    }
+    for also in install.also.iter() {
+        validate_simple_name("unit", also)?;
+        enable_unit(target, also, enabled)?;
+    }
+    Ok(())
+}
+
+/// Apply the configuration bundle to the deployment root `root`, with `stateroot`
+/// being the directory holding the deployment's persistent `var`.
+#[context("Applying config bundle")]
+pub(crate) fn apply(
+    loaded: &LoadedConfigBundle,
+    root: &Dir,
+    stateroot: &Dir,
+    sepolicy: Option<&ostree::SePolicy>,
+) -> Result<AppliedConfigBundle> {
+    let bundle = &loaded.bundle;
+    let mut target = Target {
+        root,
+        stateroot,
+        sepolicy,
+        applied: Vec::new(),
+    };
+
+    if let Some(hostname) = bundle.hostname.as_deref() {
+        target.write(
+            "etc/hostname".into(),
+            format!("{hostname}\n").as_bytes(),
+            0o644,
+            0,
+            0,
+        )?;
+    }
+
+    for f in bundle.files.iter() {
+        let path = f.path.strip_prefix("/")?;
+        let mode = parse_mode(f.mode.as_deref())?;
+        target.write(path, f.contents.as_bytes(), mode, f.uid, f.gid)?;
+    }
+
+    for unit in bundle.units.iter() {
+        let etc_path = Utf8Path::new(ETC_SYSTEMD_SYSTEM).join(&unit.name);
+        if unit.mask {
+            target.symlink("/dev/null", &etc_path)?;
+        } else if let Some(contents) = unit.contents.as_deref() {
+            target.write(&etc_path, contents.as_bytes(), 0o644, 0, 0)?;
+        } else {
+            // Otherwise it must be provided by the image
+            find_unit(target.root, &unit.name)?;
+        }
+    }
+    // Enable units only once all are written, as they may refer to each other via Also=
+    let mut enabled = BTreeSet::new();
+    for unit in bundle.units.iter().filter(|u| u.enabled) {
+        enable_unit(&mut target, &unit.name, &mut enabled)?;
+    }
+
+    for conn in bundle.network_connections.iter() {
+        let path = Utf8Path::new(NM_SYSTEM_CONNECTIONS).join(format!("{}.nmconnection", conn.name));
+        // NetworkManager ignores keyfiles readable by other users
+        target.write(&path, conn.contents.as_bytes(), 0o600, 0, 0)?;
+    }
+
+    for path in target.applied.iter() {
+        println!("Injected: {path}");
+    }
+    Ok(AppliedConfigBundle {
+        sha256: loaded.sha256.clone(),
+        paths: target.applied,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUNDLE: &str = indoc::indoc! { r#"
+        hostname: node1.example.com
+        files:
+          - path: /etc/motd
+            contents: "Welcome\n"
+          - path: /var/lib/example/data
+            contents: "state"
+            mode: "0600"
+        units:
+          - name: example.service
+            contents: |
+              [Unit]
+              Description=Example
+              [Service]
+              ExecStart=/usr/bin/true
+              [Install]
+              WantedBy=multi-user.target
+            enabled: true
+          - name: debug-shell.service
+            mask: true
+        network-connections:
+          - name: eth0
+            contents: |
+              [connection]
+              id=eth0
+              type=ethernet
+    "#};
+
+    #[test]
+    fn test_parse() {
+        let bundle: ConfigBundle = serde_yaml::from_str(BUNDLE).unwrap();
+        bundle.validate().unwrap();
+        assert_eq!(bundle.files.len(), 2);
+        assert_eq!(bundle.units.len(), 2);
+        // JSON works too
+        let bundle: ConfigBundle =
+            serde_yaml::from_str(r#"{"hostname": "foo", "files": []}"#).unwrap();
+        assert_eq!(bundle.hostname.as_deref(), Some("foo"));
+        // Unknown fields are rejected
+        assert!(serde_yaml::from_str::<ConfigBundle>("hostnme: foo").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        for ok in ["/etc/foo", "/var/lib/foo/bar"] {
+            validate_path(ok.into()).unwrap();
+        }
+        for bad in [
+            "etc/foo",
+            "/usr/bin/foo",
+            "/etc/../usr/foo",
+            "/etc",
+            "/var/./x",
+        ] {
+            assert!(validate_path(bad.into()).is_err(), "{bad}");
+        }
+        assert_eq!(parse_mode(None).unwrap(), 0o644);
+        assert_eq!(parse_mode(Some("0755")).unwrap(), 0o755);
+        assert!(parse_mode(Some("0999")).is_err());
+        assert!(validate_hostname("-foo").is_err());
+        assert!(validate_hostname("foo_bar").is_err());
+    }
+
+    #[test]
+    fn test_unit_install() {
+        let unit = indoc::indoc! { "
+            [Unit]
+            WantedBy=ignored
+            [Install]
+            WantedBy=a.target b.target
+            RequiredBy=c.target
+            Alias=alias.service
+            Also=other.socket
+            DefaultInstance=tty1
+        "};
+        assert_eq!(
+            UnitInstall::parse(unit),
+            UnitInstall {
+                targets: vec![
+                    ("wants", "a.target".to_owned()),
+                    ("wants", "b.target".to_owned()),
+                    ("requires", "c.target".to_owned())
+                ],
+                alias: vec!["alias.service".into()],
+                also: vec!["other.socket".into()],
+                default_instance: Some("tty1".into()),
+            }
+        );
+        assert!(UnitInstall::parse("[Service]\nExecStart=/bin/true\n").is_empty());
+        assert_eq!(
+            split_unit_instance("getty@tty1.service"),
+            Some(("getty", "tty1", "service"))
+        );
+        assert_eq!(
+            split_unit_instance("getty@.service"),
+            Some(("getty", "", "service"))
+        );
+        assert_eq!(split_unit_instance("sshd.service"), None);
+    }
+
+    #[test]
+    fn test_enable_units() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("deploy/usr/lib/systemd/system")?;
+        td.create_dir_all("stateroot/var")?;
+        let root = &td.open_dir("deploy")?;
+        let stateroot = &td.open_dir("stateroot")?;
+        root.write(
+            "usr/lib/systemd/system/serial@.service",
+            "[Install]\nWantedBy=multi-user.target\nAlias=console@.service\nDefaultInstance=ttyS0\n",
+        )?;
+        root.write(
+            "usr/lib/systemd/system/nodefault@.service",
+            "[Install]\nWantedBy=multi-user.target\n",
+        )?;
+        let bundle: ConfigBundle = serde_yaml::from_str(indoc::indoc! { r#"
+            units:
+              - name: serial@.service
+                enabled: true
+              - name: serial@ttyS1.service
+                enabled: true
+              - name: app.service
+                contents: |
+                  [Install]
+                  Also=app.socket
+                enabled: true
+              - name: app.socket
+                contents: |
+                  [Install]
+                  WantedBy=sockets.target
+        "#})?;
+        let loaded = LoadedConfigBundle {
+            bundle,
+            sha256: "abc".into(),
+        };
+        let applied = apply(&loaded, root, stateroot, None)?;
+        assert_eq!(
+            applied.paths,
+            [
+                "/etc/systemd/system/app.service",
+                "/etc/systemd/system/app.socket",
+                "/etc/systemd/system/multi-user.target.wants/serial@ttyS0.service",
+                "/etc/systemd/system/console@ttyS0.service",
+                "/etc/systemd/system/multi-user.target.wants/serial@ttyS1.service",
+                "/etc/systemd/system/console@ttyS1.service",
+                "/etc/systemd/system/sockets.target.wants/app.socket",
+            ]
+        );
+        assert_eq!(
+            root.read_link("etc/systemd/system/multi-user.target.wants/serial@ttyS1.service")?
+                .to_str()
+                .unwrap(),
+            "/usr/lib/systemd/system/serial@.service"
+        );
+
+        // A template without DefaultInstance= can't be enabled directly
+        let bundle: ConfigBundle =
+            serde_yaml::from_str("units: [{name: nodefault@.service, enabled: true}]")?;
+        let loaded = LoadedConfigBundle {
+            bundle,
+            sha256: "abc".into(),
+        };
+        let err = apply(&loaded, root, stateroot, None).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("no DefaultInstance="),
+            "{err:#}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("deploy/etc")?;
+        td.create_dir_all("stateroot/var")?;
+        let root = &td.open_dir("deploy")?;
+        let stateroot = &td.open_dir("stateroot")?;
+        let bundle: ConfigBundle = serde_yaml::from_str(BUNDLE)?;
+        let loaded = LoadedConfigBundle {
+            bundle,
+            sha256: "abc".into(),
+        };
+        let applied = apply(&loaded, root, stateroot, None)?;
+        assert_eq!(applied.sha256, "abc");
+        assert_eq!(
+            applied.paths,
+            [
+                "/etc/hostname",
+                "/etc/motd",
+                "/var/lib/example/data",
+                "/etc/systemd/system/example.service",
+                "/etc/systemd/system/debug-shell.service",
+                "/etc/systemd/system/multi-user.target.wants/example.service",
+                "/etc/NetworkManager/system-connections/eth0.nmconnection",
+            ]
+        );
+        assert_eq!(root.read_to_string("etc/hostname")?, "node1.example.com\n");
+        assert_eq!(stateroot.read_to_string("var/lib/example/data")?, "state");
+        let mode = stateroot
+            .metadata("var/lib/example/data")?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o600);
+        assert_eq!(
+            root.read_link("etc/systemd/system/multi-user.target.wants/example.service")?
+                .to_str()
+                .unwrap(),
+            "/etc/systemd/system/example.service"
+        );
+        assert_eq!(
+            root.read_link("etc/systemd/system/debug-shell.service")?
+                .to_str()
+                .unwrap(),
+            "/dev/null"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,598 @@
+//! # Creating disk images without loopback devices
+//!
+//! This implements `bootc install to-disk-image`.  Rather than partitioning a
+//! (loopback) block device and mounting filesystems, we install into plain staging
+//! directories, then create each filesystem as a regular file populated directly
+//! from its directory (e.g. `mkfs.ext4 -d`), and finally splice those into a
+//! partitioned disk image.  The bootloader is installed offline by copying
+//! the EFI binaries shipped in the image for bootupd, and writing the state
+//! file bootupd would have written so that it can update them later.
+//!
+//! The resulting images only boot via UEFI: there is no BIOS boot partition
+//! and no boot code in the MBR, so legacy BIOS boot is not supported.
+//!
+//! This removes the need for loopback devices and `/dev`, but not for privileges:
+//! setup goes through the same [`super::prepare_install`] as the other install modes,
+//! which requires root in a privileged container, as the deployment is written with
+//! its final ownership and SELinux labels.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use bootc_blockdev::parttable::{self, Gpt, NewPartition};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::prelude::CapStdExtDirExt;
+use clap::ValueEnum;
+use fn_error_context::context;
+use ostree_ext::ostree;
+use serde::{Deserialize, Serialize};
+
+use super::baseline::{PlannedFilesystem, EFIPN_SIZE_MB};
+use super::config::Filesystem;
+use super::{InstallConfigOpts, InstallSourceOpts, InstallTargetOpts, RootSetup, RW_KARG};
+use crate::task::Task;
+
+/// The EFI binaries bootupd would install to the ESP.
+const BOOTUPD_EFI_UPDATES: &str = "usr/lib/bootupd/updates/EFI";
+/// The version metadata of the EFI binaries above.
+const BOOTUPD_EFI_META: &str = "usr/lib/bootupd/updates/EFI.json";
+/// The static GRUB configuration shipped for bootupd.
+const BOOTUPD_GRUB_STATIC: &str = "usr/lib/bootupd/grub2-static";
+/// The state file of bootupd in the physical root.
+const BOOTUPD_STATE: &str = "boot/bootupd-state.json";
+/// The name of the bootupd component for the EFI binaries.
+const BOOTUPD_EFI_COMPONENT: &str = "EFI";
+/// The sector size we use for disk images.
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(clap::ValueEnum, Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DiskImageFormat {
+    /// A raw disk image
+    #[default]
+    Raw,
+    /// A qcow2 image (requires qemu-img)
+    Qcow2,
+}
+
+impl std::fmt::Display for DiskImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, clap::Parser, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct InstallToDiskImageOpts {
+    /// Path to the disk image to create; it must not already exist.
+    pub(crate) path: Utf8PathBuf,
+
+    /// The disk image format.
+    #[clap(long, value_enum, default_value_t)]
+    #[serde(default)]
+    pub(crate) format: DiskImageFormat,
+
+    /// Size of the disk image (default specifier: M).  Allowed specifiers: M (mebibytes), G (gibibytes), T (tebibytes).
+    #[clap(long)]
+    pub(crate) size: String,
+
+    /// Target root filesystem type.  It must support being created from a
+    /// directory; currently this is ext4 or btrfs.
+    #[clap(long, value_enum)]
+    pub(crate) filesystem: Option<Filesystem>,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub(crate) source_opts: InstallSourceOpts,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub(crate) target_opts: InstallTargetOpts,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub(crate) config_opts: InstallConfigOpts,
+}
+
+/// Arguments to populate a filesystem of the given type from `dir` at creation time.
+fn mkfs_populate_args(fs: Filesystem, dir: &Utf8Path) -> Result<[String; 2]> {
+    let arg = match fs {
+        Filesystem::Ext4 => "-d",
+        Filesystem::Btrfs => "--rootdir",
+        Filesystem::Xfs => anyhow::bail!(
+            "Creating {fs} filesystems from a directory is not supported; use e.g. --filesystem=ext4"
+        ),
+    };
+    Ok([arg.to_owned(), dir.to_string()])
+}
+
+/// The partition table for a disk image of `size` bytes: the ESP followed by the root partition.
+fn disk_image_layout(size: u64) -> Result<Gpt> {
+    let mut gpt = Gpt::new(size, SECTOR_SIZE)?;
+    gpt.add_partition(
+        parttable::DEFAULT_ALIGNMENT,
+        NewPartition {
+            size: Some(u64::from(EFIPN_SIZE_MB) * 1024 * 1024),
+            name: "EFI-SYSTEM".into(),
+            type_guid: parttable::ESP_TYPE,
+            ..Default::default()
+        },
+    )?;
+    gpt.add_partition(
+        parttable::DEFAULT_ALIGNMENT,
+        NewPartition {
+            name: "root".into(),
+            type_guid: parttable::LINUX_FILESYSTEM_TYPE,
+            ..Default::default()
+        },
+    )?;
+    Ok(gpt)
+}
+
+/// The GRUB configuration placed next to the EFI binaries; it chains to the
+/// configuration in /boot on the root filesystem.
+fn esp_grub_config(boot_uuid: &str) -> String {
+    format!(
+        "search --no-floppy --fs-uuid --set=dev {boot_uuid}\n\
+         set prefix=($dev)/boot/grub2\n\
+         export $prefix\n\
+         configfile $prefix/grub.cfg\n"
+    )
+}
+
+/// The version of a bootupd component (`ContentMetadata` in bootupd).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct BootupdContentMetadata {
+    timestamp: chrono::DateTime<chrono::Utc>,
+    version: String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct BootupdFileMetadata {
+    size: u64,
+    sha512: String,
+}
+
+/// The files installed for a component, keyed by their path relative to the `EFI` directory.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct BootupdFileTree {
+    children: BTreeMap<String, BootupdFileMetadata>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct BootupdInstalledContent {
+    meta: BootupdContentMetadata,
+    filetree: Option<BootupdFileTree>,
+    adopted_from: Option<BootupdContentMetadata>,
+}
+
+/// The contents of `/boot/bootupd-state.json` (`SavedState` in bootupd).
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct BootupdState {
+    installed: BTreeMap<String, BootupdInstalledContent>,
+    pending: Option<BTreeMap<String, BootupdContentMetadata>>,
+    static_configs: Option<BootupdContentMetadata>,
+}
+
+impl BootupdFileTree {
+    /// Gather the sizes and digests of the files below `dir`.
+    fn new_from_dir(dir: &Utf8Path) -> Result<Self> {
+        fn walk(
+            root: &Utf8Path,
+            dir: &Utf8Path,
+            children: &mut BTreeMap<String, BootupdFileMetadata>,
+        ) -> Result<()> {
+            for entry in dir.read_dir_utf8()? {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    walk(root, path, children)?;
+                    continue;
+                }
+                let buf = std::fs::read(path).with_context(|| format!("Reading {path}"))?;
+                let relpath = path.strip_prefix(root)?;
+                children.insert(
+                    relpath.to_string(),
+                    BootupdFileMetadata {
+                        size: buf.len() as u64,
+                        sha512: hex::encode(openssl::sha::sha512(&buf)),
+                    },
+                );
+            }
+            Ok(())
+        }
+        let mut children = BTreeMap::new();
+        walk(dir, dir, &mut children)?;
+        Ok(Self { children })
+    }
+}
+
+/// The state bootupd records after installing the EFI binaries at `efi_updates`
+/// along with its static GRUB configuration.
+fn bootupd_state(meta: BootupdContentMetadata, efi_updates: &Utf8Path) -> Result<BootupdState> {
+    let installed = BootupdInstalledContent {
+        meta: meta.clone(),
+        filetree: Some(BootupdFileTree::new_from_dir(efi_updates)?),
+        adopted_from: None,
+    };
+    Ok(BootupdState {
+        installed: [(BOOTUPD_EFI_COMPONENT.to_owned(), installed)].into(),
+        pending: None,
+        static_configs: Some(meta),
+    })
+}
+
+/// Find the path to the (single) deployment in the installed physical root.
+#[context("Finding deployment")]
+fn find_deployment_root(physical_root_path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let sysroot = ostree::Sysroot::new(Some(&ostree::gio::File::for_path(physical_root_path)));
+    sysroot.load(ostree::gio::Cancellable::NONE)?;
+    let deployment = sysroot
+        .deployments()
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Failed to find deployment in {physical_root_path}"))?;
+    Ok(physical_root_path.join(sysroot.deployment_dirpath(&deployment).as_str()))
+}
+
+/// Install the bootloader without access to a block device: copy the EFI binaries
+/// into the ESP staging directory, and write the static GRUB configuration and
+/// the bootupd state into /boot.  Only UEFI boot is set up.
+#[context("Installing bootloader offline")]
+fn install_bootloader_offline(
+    deployment_root_path: &Utf8Path,
+    physical_root: &Dir,
+    esp_path: &Utf8Path,
+    boot_uuid: &str,
+) -> Result<()> {
+    let deployment_root =
+        Dir::open_ambient_dir(deployment_root_path, cap_std::ambient_authority())?;
+    if !deployment_root.try_exists(BOOTUPD_EFI_UPDATES)? {
+        anyhow::bail!("Missing /{BOOTUPD_EFI_UPDATES}; the image must include bootupd");
+    }
+    let meta: BootupdContentMetadata = serde_json::from_str(
+        &deployment_root
+            .read_to_string(BOOTUPD_EFI_META)
+            .with_context(|| format!("Reading /{BOOTUPD_EFI_META}"))?,
+    )
+    .with_context(|| format!("Parsing /{BOOTUPD_EFI_META}"))?;
+    let src = deployment_root_path.join(BOOTUPD_EFI_UPDATES);
+    Task::new("Copying EFI binaries", "cp")
+        .args(["-a", "--no-target-directory", src.as_str()])
+        .arg(esp_path.join("EFI").as_str())
+        .run()?;
+
+    let esp = Dir::open_ambient_dir(esp_path.join("EFI"), cap_std::ambient_authority())?;
+    let esp_config = esp_grub_config(boot_uuid);
+    for ent in esp.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        // EFI/BOOT holds the fallback shim, the vendor directories hold grub
+        if !ent.file_type()?.is_dir() || name.eq_ignore_ascii_case("BOOT") {
+            continue;
+        }
+        let vendor = esp.open_dir(&name)?;
+        vendor.atomic_write("grub.cfg", esp_config.as_bytes())?;
+    }
+
+    let grub_static = deployment_root
+        .open_dir_optional(BOOTUPD_GRUB_STATIC)?
+        .ok_or_else(|| anyhow::anyhow!("Missing /{BOOTUPD_GRUB_STATIC}"))?;
+    let mut grubcfg = grub_static.read_to_string("grub-static-pre.cfg")?;
+    if let Some(configs) = grub_static.open_dir_optional("configs.d")? {
+        let mut names = configs
+            .entries()?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        names.sort();
+        for name in names {
+            if !name.to_str().is_some_and(|n| n.ends_with(".cfg")) {
+                continue;
+            }
+            grubcfg.push_str(&configs.read_to_string(&name)?);
+        }
+    }
+    grubcfg.push_str(&grub_static.read_to_string("grub-static-post.cfg")?);
+    physical_root.create_dir_all("boot/grub2")?;
+    physical_root.atomic_write("boot/grub2/grub.cfg", grubcfg.as_bytes())?;
+    physical_root.atomic_write(
+        "boot/grub2/bootuuid.cfg",
+        format!("set BOOT_UUID=\"{boot_uuid}\"\n"),
+    )?;
+    let state = bootupd_state(meta, &src)?;
+    physical_root
+        .atomic_replace_with(BOOTUPD_STATE, |w| {
+            serde_json::to_writer(w, &state)?;
+            anyhow::Ok(())
+        })
+        .context("Writing bootupd state")?;
+    tracing::debug!("Installed bootloader");
+    Ok(())
+}
+
+/// Write the filesystem image at `src` into `disk` at the byte `offset`, preserving holes.
+fn splice_into(disk: &Utf8Path, src: &Utf8Path, offset: u64) -> Result<()> {
+    Task::new(format!("Writing {src} into disk image"), "dd")
+        .args([
+            format!("if={src}"),
+            format!("of={disk}"),
+            "bs=1M".into(),
+            format!("seek={offset}"),
+            "oflag=seek_bytes".into(),
+            "conv=notrunc,sparse".into(),
+            "status=none".into(),
+        ])
+        .run()
+}
+
+/// Create the partitioned raw disk image at `disk` from the staged root and ESP.
+#[context("Creating disk image")]
+fn create_disk_image(
+    disk: &Utf8Path,
+    size_mib: u64,
+    workdir: &Utf8Path,
+    fstype: Filesystem,
+    rootfs: &PlannedFilesystem,
+    root_dir: &Utf8Path,
+    esp_dir: &Utf8Path,
+) -> Result<()> {
+    let size = size_mib * 1024 * 1024;
+    let gpt = disk_image_layout(size)?;
+    let mut f = std::fs::File::create_new(disk).with_context(|| format!("Creating {disk}"))?;
+    f.set_len(size)?;
+    gpt.write(&mut f)?;
+    drop(f);
+    // SAFETY: These were created above
+    let esp_part = gpt.find_partno(1).unwrap();
+    let root_part = gpt.find_partno(2).unwrap();
+
+    // The ESP; mkfs.fat takes the size in KiB
+    let esp_img = workdir.join("esp.img");
+    let esp_kib = esp_part.sectors() * SECTOR_SIZE / 1024;
+    let esp_fs = PlannedFilesystem::new_esp(esp_img.to_string());
+    let (exe, args) = esp_fs.command.split_first().expect("mkfs command");
+    Task::new("Creating ESP filesystem", exe)
+        .args(args)
+        .args(["-C", esp_img.as_str()])
+        .arg(esp_kib.to_string())
+        .verbose()
+        .quiet_output()
+        .run()?;
+    Task::new("Copying ESP contents", "mcopy")
+        .args(["-s", "-p", "-i", esp_img.as_str()])
+        .arg(esp_dir.join("EFI").as_str())
+        .arg("::/")
+        .run()?;
+    splice_into(disk, &esp_img, esp_part.first_lba * SECTOR_SIZE)?;
+    std::fs::remove_file(&esp_img)?;
+
+    // And the root filesystem
+    let root_img = Utf8Path::new(&rootfs.device);
+    let f = std::fs::File::create_new(root_img).with_context(|| format!("Creating {root_img}"))?;
+    f.set_len(root_part.sectors() * SECTOR_SIZE)?;
+    drop(f);
+    let populate = mkfs_populate_args(fstype, root_dir)?;
+    let (exe, args) = rootfs.command.split_first().expect("mkfs command");
+    Task::new(format!("Creating root filesystem ({fstype})"), exe)
+        .args(args)
+        .args(populate)
+        .arg(root_img.as_str())
+        .verbose()
+        .quiet_output()
+        .run()?;
+    splice_into(disk, root_img, root_part.first_lba * SECTOR_SIZE)?;
+    std::fs::remove_file(root_img)?;
+
+    Ok(())
+}
+
+/// Implementation of the `bootc install to-disk-image` CLI command.
+#[context("Installing to disk image")]
+pub(crate) async fn install_to_disk_image(mut opts: InstallToDiskImageOpts) -> Result<()> {
+    if !super::ARCH_USES_EFI {
+        anyhow::bail!(
+            "Disk images are not supported on {}",
+            std::env::consts::ARCH
+        );
+    }
+    let size_mib = bootc_blockdev::parse_size_mib(&opts.size).context("Parsing size")?;
+    if size_mib <= (EFIPN_SIZE_MB as u64) + 2 {
+        anyhow::bail!("Disk image size is too small: {}", opts.size);
+    }
+    if opts.path.try_exists()? {
+        anyhow::bail!("Refusing to overwrite existing {}", opts.path);
+    }
+    let parent = opts
+        .path
+        .parent()
+        .filter(|p| !p.as_str().is_empty())
+        .unwrap_or(Utf8Path::new("."));
+    if !opts.config_opts.generic_image {
+        crate::utils::medium_visibility_warning(
+            "Automatically enabling --generic-image when installing to a disk image",
+        );
+        opts.config_opts.generic_image = true;
+    }
+    // Reject unsupported filesystems before doing any real work
+    if let Some(fs) = opts.filesystem {
+        mkfs_populate_args(fs, Utf8Path::new("."))?;
+    }
+
+    let state =
+        super::prepare_install(opts.config_opts, opts.source_opts, opts.target_opts, false).await?;
+    let fstype = opts
+        .filesystem
+        .or(state
+            .install_config
+            .as_ref()
+            .and_then(|c| c.filesystem_root())
+            .and_then(|r| r.fstype))
+        .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
+    mkfs_populate_args(fstype, Utf8Path::new("."))?;
+
+    // Staging directories; these are next to the target as they will be large.
+    let workdir = tempfile::Builder::new()
+        .prefix(".bootc-disk-image-")
+        .tempdir_in(parent)?;
+    let workdir_path = Utf8Path::from_path(workdir.path())
+        .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 path: {:?}", workdir.path()))?;
+    let root_path = workdir_path.join("root");
+    let esp_path = workdir_path.join("esp");
+    std::fs::create_dir(&root_path)?;
+    std::fs::create_dir(&esp_path)?;
+
+    let rootfs_plan = PlannedFilesystem::new(
+        workdir_path.join("root.img").into_string(),
+        fstype,
+        "root",
+        false,
+        uuid::Uuid::new_v4().to_string(),
+    );
+    let root_uuid = rootfs_plan.uuid.clone().expect("root uuid");
+
+    let physical_root = Dir::open_ambient_dir(&root_path, cap_std::ambient_authority())?;
+    {
+        let sepolicy = state.load_policy()?;
+        let sepolicy = sepolicy.as_ref();
+        crate::lsm::ensure_dir_labeled(
+            &physical_root,
+            "",
+            Some("/".into()),
+            0o755.into(),
+            sepolicy,
+        )?;
+        crate::lsm::ensure_dir_labeled(&physical_root, "boot", None, 0o755.into(), sepolicy)?;
+    }
+    let mut rootfs = RootSetup {
+        luks_device: None,
+        physical_root_path: root_path.clone(),
+        physical_root,
+        rootfs_uuid: Some(root_uuid.clone()),
+        // There's nothing mounted to finalize
+        skip_finalize: true,
+        side_by_side: false,
+        boot: None,
+        kargs: vec![format!("root=UUID={root_uuid}"), RW_KARG.to_string()],
+        host_network: None,
+        install_mode: crate::spec::InstallMode::ToDiskImage,
+        block_setup: None,
+    };
+
+    super::install_to_filesystem_impl(&state, &mut rootfs).await?;
+
+    let deployment_root_path = find_deployment_root(&root_path)?;
+    install_bootloader_offline(
+        &deployment_root_path,
+        &rootfs.physical_root,
+        &esp_path,
+        &root_uuid,
+    )?;
+    drop(rootfs);
+
+    let raw_path = match opts.format {
+        DiskImageFormat::Raw => opts.path.clone(),
+        DiskImageFormat::Qcow2 => workdir_path.join("disk.raw"),
+    };
+    {
+        let raw_path = raw_path.clone();
+        let workdir_path = workdir_path.to_owned();
+        tokio::task::spawn_blocking(move || {
+            create_disk_image(
+                &raw_path,
+                size_mib,
+                &workdir_path,
+                fstype,
+                &rootfs_plan,
+                &root_path,
+                &esp_path,
+            )
+        })
+        .await??;
+    }
+    if opts.format == DiskImageFormat::Qcow2 {
+        Task::new(format!("Converting to {}", opts.format), "qemu-img")
+            .args(["convert", "-f", "raw", "-O", "qcow2", raw_path.as_str()])
+            .arg(opts.path.as_str())
+            .run()?;
+    }
+    workdir.close()?;
+
+    if let Some(state) = std::sync::Arc::into_inner(state) {
+        state.consume()?;
+    } else {
+        tracing::warn!("Failed to consume state Arc");
+    }
+    println!("Wrote {} ({})", opts.path, opts.format);
+    std::io::stdout().flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_populate_args() {
+        let d = Utf8Path::new("/run/staging");
+        assert_eq!(
+            mkfs_populate_args(Filesystem::Ext4, d).unwrap(),
+            ["-d", "/run/staging"]
+        );
+        assert_eq!(
+            mkfs_populate_args(Filesystem::Btrfs, d).unwrap(),
+            ["--rootdir", "/run/staging"]
+        );
+        assert!(mkfs_populate_args(Filesystem::Xfs, d).is_err());
+    }
+
+    #[test]
+    fn test_disk_image_layout() {
+        let gpt = disk_image_layout(10 * 1024 * 1024 * 1024).unwrap();
+        let esp = gpt.find_partno(1).unwrap();
+        assert_eq!(esp.type_guid, parttable::ESP_TYPE);
+        assert_eq!(esp.first_lba, 2048);
+        assert_eq!(esp.sectors() * SECTOR_SIZE, 512 * 1024 * 1024);
+        let root = gpt.find_partno(2).unwrap();
+        assert_eq!(root.name, "root");
+        assert_eq!(root.first_lba, esp.last_lba + 1);
+        assert_eq!(root.last_lba, gpt.last_usable_lba);
+        assert!(disk_image_layout(256 * 1024 * 1024).is_err());
+    }
+
+    #[test]
+    fn test_bootupd_state() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efi = Utf8Path::from_path(td.path()).unwrap();
+        std::fs::create_dir_all(efi.join("BOOT"))?;
+        std::fs::create_dir_all(efi.join("fedora"))?;
+        std::fs::write(efi.join("BOOT/BOOTX64.EFI"), "shim")?;
+        std::fs::write(efi.join("fedora/grubx64.efi"), "")?;
+        let meta: BootupdContentMetadata = serde_json::from_str(
+            r#"{"timestamp":"2024-05-01T12:00:00Z","version":"grub2-2.12-1.fc40,shim-15.8-3"}"#,
+        )?;
+        let state = bootupd_state(meta.clone(), efi)?;
+        let state = serde_json::to_value(&state)?;
+        let installed = &state["installed"]["EFI"];
+        assert_eq!(installed["meta"]["version"], meta.version.as_str());
+        assert!(installed["adopted-from"].is_null());
+        assert!(state["pending"].is_null());
+        assert_eq!(state["static-configs"], installed["meta"]);
+        let children = installed["filetree"]["children"].as_object().unwrap();
+        assert_eq!(
+            children.keys().collect::<Vec<_>>(),
+            ["BOOT/BOOTX64.EFI", "fedora/grubx64.efi"]
+        );
+        let shim = &children["BOOT/BOOTX64.EFI"];
+        assert_eq!(shim["size"], 4);
+        assert_eq!(
+            shim["sha512"],
+            hex::encode(openssl::sha::sha512(b"shim")).as_str()
+        );
+        Ok(())
+    }
+}
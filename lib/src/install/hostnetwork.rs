@@ -0,0 +1,591 @@
+//! # Carrying network configuration over from the running system
+//!
+//! This implements `--copy-network-config` for `bootc install to-existing-root`.
+//! The hostname and NetworkManager connection profiles are gathered from the
+//! host root before anything is modified, and are then injected into the new
+//! deployment's `/etc`.  If the host has no connection profiles, the current
+//! statically configured addresses, bonds, bridges and VLANs are translated
+//! into keyfiles instead.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::{cap_std, dirext::CapStdExtDirExt};
+use fn_error_context::context;
+use ostree_ext::ostree;
+use serde::Deserialize;
+
+const HOSTNAME: &str = "etc/hostname";
+const NM_DIR: &str = "etc/NetworkManager";
+const NM_CONNECTIONS: &str = "etc/NetworkManager/system-connections";
+/// Profiles generated at runtime, e.g. by `nm-initrd-generator` from `ip=` kernel arguments.
+const NM_RUN_CONNECTIONS: &str = "run/NetworkManager/system-connections";
+const NM_KEYFILE_SUFFIX: &str = ".nmconnection";
+/// The stub resolver used by systemd-resolved; not useful as an upstream server.
+const RESOLVED_STUB: &str = "127.0.0.53";
+const RESOLVED_UPLINK_CONF: &str = "run/systemd/resolve/resolv.conf";
+
+/// Network configuration gathered from the running system.
+#[derive(Debug, Default)]
+pub(crate) struct HostNetworkConfig {
+    pub(crate) hostname: Option<String>,
+    /// NetworkManager keyfiles, keyed by file name
+    pub(crate) connections: BTreeMap<String, Vec<u8>>,
+}
+
+/// An entry from `ip -json -details address show`.
+#[derive(Debug, Deserialize)]
+struct IpLink {
+    ifname: String,
+    #[serde(default)]
+    link_type: String,
+    /// The controller (bond or bridge) of a port
+    master: Option<String>,
+    /// The parent of e.g. a VLAN
+    link: Option<String>,
+    linkinfo: Option<IpLinkInfo>,
+    #[serde(default)]
+    addr_info: Vec<IpAddrInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IpLinkInfo {
+    /// Unset for physical interfaces
+    info_kind: Option<String>,
+    /// Kind specific data, e.g. the bond mode or VLAN ID
+    #[serde(default)]
+    info_data: serde_json::Value,
+}
+
+impl IpLink {
+    fn kind(&self) -> Option<&str> {
+        self.linkinfo.as_ref()?.info_kind.as_deref()
+    }
+
+    /// The NetworkManager connection type for this link, if it is translated.
+    /// Other virtual interfaces such as veth or tun devices are typically
+    /// managed by other software and are skipped.
+    fn connection_type(&self) -> Option<&'static str> {
+        if self.link_type != "ether" {
+            return None;
+        }
+        let r = match self.kind() {
+            None => "ethernet",
+            Some("bond") => "bond",
+            Some("bridge") => "bridge",
+            Some("vlan") => "vlan",
+            Some(_) => return None,
+        };
+        Some(r)
+    }
+
+    fn static_addrs(&self) -> impl Iterator<Item = &IpAddrInfo> {
+        self.addr_info
+            .iter()
+            .filter(|a| a.scope == "global" && !a.dynamic)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct IpAddrInfo {
+    family: String,
+    local: IpAddr,
+    prefixlen: u8,
+    #[serde(default)]
+    scope: String,
+    /// Set for addresses obtained via DHCP or SLAAC
+    #[serde(default)]
+    dynamic: bool,
+}
+
+/// An entry from `ip -json route show default`.
+#[derive(Debug, Deserialize)]
+struct IpRoute {
+    gateway: Option<IpAddr>,
+    dev: Option<String>,
+}
+
+fn read_optional_string(root: &Dir, path: &str) -> Result<Option<String>> {
+    let Some(mut f) = root.open_optional(path)? else {
+        return Ok(None);
+    };
+    let mut r = String::new();
+    std::io::Read::read_to_string(&mut f, &mut r).with_context(|| format!("Reading {path}"))?;
+    Ok(Some(r))
+}
+
+/// Read `/etc/resolv.conf`, following a symlink to e.g. `/run/systemd/resolve/stub-resolv.conf`
+/// relative to `root`.
+fn read_resolv_conf(root: &Dir) -> Result<Option<String>> {
+    let mut path = Utf8PathBuf::from("etc/resolv.conf");
+    // Bound the number of symlinks we'll follow
+    for _ in 0..8 {
+        let Some(meta) = root.symlink_metadata_optional(&path)? else {
+            return Ok(None);
+        };
+        if !meta.is_symlink() {
+            return read_optional_string(root, path.as_str());
+        }
+        let target = Utf8PathBuf::try_from(root.read_link(&path)?)?;
+        path = if target.is_absolute() {
+            target.as_str().trim_start_matches('/').into()
+        } else {
+            path.parent().unwrap_or(Utf8Path::new("")).join(target)
+        };
+    }
+    anyhow::bail!("Too many levels of symbolic links in /etc/resolv.conf")
+}
+
+/// Parse the `nameserver` entries from a `resolv.conf`.
+fn parse_nameservers(contents: &str) -> Vec<IpAddr> {
+    contents
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|v| v.trim().parse().ok())
+        .collect()
+}
+
+/// Find the upstream DNS servers of the host, looking through the systemd-resolved stub if necessary.
+fn host_nameservers(root: &Dir) -> Result<Vec<IpAddr>> {
+    let stub: IpAddr = RESOLVED_STUB.parse().unwrap();
+    let mut r = parse_nameservers(&read_resolv_conf(root)?.unwrap_or_default());
+    if r.contains(&stub) {
+        r = parse_nameservers(
+            &read_optional_string(root, RESOLVED_UPLINK_CONF)?.unwrap_or_default(),
+        );
+    }
+    r.retain(|a| !a.is_loopback());
+    Ok(r)
+}
+
+/// Whether a keyfile is generated for `link`.  Bonds and bridges are translated
+/// if they have a port which is translated as well (which excludes e.g. bridges
+/// for containers with only veth ports), and VLANs always, as NetworkManager would
+/// not recreate them otherwise.  Other interfaces are only translated if they
+/// have statically configured global addresses, and ports if their controller is.
+fn is_translated(links: &[IpLink], link: &IpLink) -> bool {
+    let Some(ty) = link.connection_type() else {
+        return false;
+    };
+    if let Some(master) = link.master.as_deref() {
+        return links
+            .iter()
+            .find(|l| l.ifname == master)
+            .is_some_and(|m| is_translated(links, m));
+    }
+    match ty {
+        "bond" | "bridge" => links.iter().any(|l| {
+            l.master.as_deref() == Some(link.ifname.as_str()) && l.connection_type().is_some()
+        }),
+        "vlan" => true,
+        _ => link.static_addrs().next().is_some(),
+    }
+}
+
+/// Generate NetworkManager keyfiles from the interface state; see [`is_translated`]
+/// for the interfaces which are included.  Interfaces with only dynamic addresses
+/// are skipped, as they will be configured by DHCP by default anyways.
+fn keyfiles_from_ip_state(
+    links: &[IpLink],
+    routes: &[IpRoute],
+    nameservers: &[IpAddr],
+) -> BTreeMap<String, Vec<u8>> {
+    let mut r = BTreeMap::new();
+    for link in links.iter().filter(|l| is_translated(links, l)) {
+        let ifname = link.ifname.as_str();
+        let ty = link.connection_type().expect("translated link");
+        let mut buf = format!("[connection]\nid={ifname}\ntype={ty}\ninterface-name={ifname}\n");
+        if let Some(master) = link.master.as_deref() {
+            let kind = links
+                .iter()
+                .find(|l| l.ifname == master)
+                .and_then(|l| l.kind())
+                .unwrap_or_default();
+            buf.push_str(&format!("master={master}\nslave-type={kind}\n"));
+        }
+        buf.push_str("autoconnect=true\n");
+        let data = link
+            .linkinfo
+            .as_ref()
+            .map(|l| &l.info_data)
+            .unwrap_or(&serde_json::Value::Null);
+        let get_str = |k: &str| data.get(k).and_then(|v| v.as_str());
+        let get_u64 = |k: &str| data.get(k).and_then(|v| v.as_u64());
+        match ty {
+            "bond" => {
+                buf.push_str("\n[bond]\n");
+                if let Some(mode) = get_str("mode") {
+                    buf.push_str(&format!("mode={mode}\n"));
+                }
+                if let Some(miimon) = get_u64("miimon").filter(|&v| v > 0) {
+                    buf.push_str(&format!("miimon={miimon}\n"));
+                }
+            }
+            "bridge" => {
+                buf.push_str("\n[bridge]\n");
+                if let Some(stp) = get_u64("stp_state") {
+                    buf.push_str(&format!("stp={}\n", stp != 0));
+                }
+            }
+            "vlan" => {
+                buf.push_str("\n[vlan]\n");
+                if let Some(id) = get_u64("id") {
+                    buf.push_str(&format!("id={id}\n"));
+                }
+                if let Some(parent) = link.link.as_deref() {
+                    buf.push_str(&format!("parent={parent}\n"));
+                }
+            }
+            _ => {}
+        }
+        // Ports are configured via their controller
+        if link.master.is_some() {
+            r.insert(format!("{ifname}{NM_KEYFILE_SUFFIX}"), buf.into_bytes());
+            continue;
+        }
+        let statics = link.static_addrs().collect::<Vec<_>>();
+        for (section, family, v4) in [("ipv4", "inet", true), ("ipv6", "inet6", false)] {
+            let addrs = statics
+                .iter()
+                .filter(|a| a.family == family)
+                .collect::<Vec<_>>();
+            buf.push_str(&format!("\n[{section}]\n"));
+            if addrs.is_empty() {
+                buf.push_str("method=auto\n");
+                continue;
+            }
+            buf.push_str("method=manual\n");
+            for (i, a) in addrs.iter().enumerate() {
+                buf.push_str(&format!("address{}={}/{}\n", i + 1, a.local, a.prefixlen));
+            }
+            let gateway = routes.iter().find_map(|route| {
+                let gw = route.gateway?;
+                (route.dev.as_deref() == Some(ifname) && gw.is_ipv4() == v4).then_some(gw)
+            });
+            if let Some(gw) = gateway {
+                buf.push_str(&format!("gateway={gw}\n"));
+            }
+            let dns = nameservers
+                .iter()
+                .filter(|a| a.is_ipv4() == v4)
+                .map(|a| format!("{a};"))
+                .collect::<String>();
+            if !dns.is_empty() {
+                buf.push_str(&format!("dns={dns}\n"));
+            }
+        }
+        r.insert(format!("{ifname}{NM_KEYFILE_SUFFIX}"), buf.into_bytes());
+    }
+    r
+}
+
+/// Query the address and default route state from the host network namespace.
+fn query_host_ip_state() -> Result<(Vec<IpLink>, Vec<IpRoute>)> {
+    let ip = |args: &[&str]| {
+        let mut c = Command::new("nsenter");
+        c.args(["--target", "1", "--net", "--", "ip", "-json", "-details"])
+            .args(args);
+        c
+    };
+    let links = ip(&["address", "show"]).run_and_parse_json()?;
+    let mut routes: Vec<IpRoute> = ip(&["-4", "route", "show", "default"]).run_and_parse_json()?;
+    let routes6: Vec<IpRoute> = ip(&["-6", "route", "show", "default"]).run_and_parse_json()?;
+    routes.extend(routes6);
+    Ok((links, routes))
+}
+
+/// Gather the network configuration from the host root filesystem `root`.  This must
+/// be done before any modifications are made.
+#[context("Gathering host network configuration")]
+pub(crate) fn gather(root: &Dir) -> Result<HostNetworkConfig> {
+    let hostname = read_optional_string(root, HOSTNAME)?
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty());
+
+    let mut connections = BTreeMap::new();
+    // Persistent profiles take precedence over runtime generated ones
+    for dir in [NM_CONNECTIONS, NM_RUN_CONNECTIONS] {
+        let Some(d) = root.open_dir_optional(dir)? else {
+            continue;
+        };
+        for ent in d.entries()? {
+            let ent = ent?;
+            let name = ent.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if !name.ends_with(NM_KEYFILE_SUFFIX)
+                || !ent.file_type()?.is_file()
+                || connections.contains_key(name)
+            {
+                continue;
+            }
+            let contents = d
+                .read(name)
+                .with_context(|| format!("Reading {dir}/{name}"))?;
+            connections.insert(name.to_owned(), contents);
+        }
+    }
+
+    if connections.is_empty() {
+        tracing::debug!("No NetworkManager profiles found; translating ip state");
+        let (links, routes) = query_host_ip_state()?;
+        let nameservers = host_nameservers(root)?;
+        connections = keyfiles_from_ip_state(&links, &routes, &nameservers);
+    }
+
+    Ok(HostNetworkConfig {
+        hostname,
+        connections,
+    })
+}
+
+/// Write the gathered configuration into the deployment root `root`.  Anything
+/// the target image already defines is left untouched.
+#[context("Injecting host network configuration")]
+pub(crate) fn apply(
+    root: &Dir,
+    sepolicy: Option<&ostree::SePolicy>,
+    config: &HostNetworkConfig,
+) -> Result<()> {
+    if let Some(hostname) = config.hostname.as_deref() {
+        if root.try_exists(HOSTNAME)? {
+            println!("Skipping hostname: /{HOSTNAME} is defined by the target image");
+        } else {
+            crate::lsm::atomic_replace_labeled(root, HOSTNAME, 0o644.into(), sepolicy, |w| {
+                writeln!(w, "{hostname}").map_err(Into::into)
+            })?;
+            println!("Injected: /{HOSTNAME}");
+        }
+    }
+
+    if config.connections.is_empty() {
+        return Ok(());
+    }
+    if !root.try_exists(NM_DIR)? {
+        crate::utils::medium_visibility_warning(
+            "Target image does not appear to use NetworkManager; not copying connection profiles",
+        );
+        return Ok(());
+    }
+    if !root.try_exists(NM_CONNECTIONS)? {
+        crate::lsm::ensure_dir_labeled(root, NM_CONNECTIONS, None, 0o700.into(), sepolicy)?;
+    }
+    for (name, contents) in config.connections.iter() {
+        let path = Utf8Path::new(NM_CONNECTIONS).join(name);
+        if root.try_exists(&path)? {
+            println!("Skipping /{path}: defined by the target image");
+            continue;
+        }
+        // NetworkManager ignores keyfiles readable by other users
+        crate::lsm::atomic_replace_labeled(root, &path, 0o600.into(), sepolicy, |w| {
+            w.write_all(contents).map_err(Into::into)
+        })?;
+        println!("Injected: /{path}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_tempfile;
+
+    const IP_ADDR: &str = r#"[
+      {"ifindex":1,"ifname":"lo","link_type":"loopback","addr_info":[{"family":"inet","local":"127.0.0.1","prefixlen":8,"scope":"host"}]},
+      {"ifindex":2,"ifname":"eth0","link_type":"ether","addr_info":[
+        {"family":"inet","local":"192.0.2.10","prefixlen":24,"scope":"global"},
+        {"family":"inet6","local":"2001:db8::10","prefixlen":64,"scope":"global"},
+        {"family":"inet6","local":"fe80::1","prefixlen":64,"scope":"link"}]},
+      {"ifindex":3,"ifname":"eth1","link_type":"ether","addr_info":[
+        {"family":"inet","local":"198.51.100.7","prefixlen":24,"scope":"global","dynamic":true}]}
+    ]"#;
+    const IP_ROUTE: &str = r#"[{"dst":"default","gateway":"192.0.2.1","dev":"eth0"},{"dst":"default","gateway":"198.51.100.1","dev":"eth1"}]"#;
+
+    #[test]
+    fn test_keyfiles_from_ip_state() {
+        let links: Vec<IpLink> = serde_json::from_str(IP_ADDR).unwrap();
+        let routes: Vec<IpRoute> = serde_json::from_str(IP_ROUTE).unwrap();
+        let dns = ["192.0.2.53".parse().unwrap()];
+        let r = keyfiles_from_ip_state(&links, &routes, &dns);
+        assert_eq!(r.len(), 1);
+        let eth0 = std::str::from_utf8(r.get("eth0.nmconnection").unwrap()).unwrap();
+        similar_asserts::assert_eq!(
+            eth0,
+            indoc::indoc! { "
+                [connection]
+                id=eth0
+                type=ethernet
+                interface-name=eth0
+                autoconnect=true
+
+                [ipv4]
+                method=manual
+                address1=192.0.2.10/24
+                gateway=192.0.2.1
+                dns=192.0.2.53;
+
+                [ipv6]
+                method=manual
+                address1=2001:db8::10/64
+            "}
+        );
+    }
+
+    const IP_ADDR_VIRTUAL: &str = r#"[
+      {"ifindex":2,"ifname":"eth0","link_type":"ether","master":"bond0","linkinfo":{"info_slave_kind":"bond"}},
+      {"ifindex":3,"ifname":"eth1","link_type":"ether","master":"bond0","linkinfo":{"info_slave_kind":"bond"}},
+      {"ifindex":4,"ifname":"bond0","link_type":"ether","linkinfo":{"info_kind":"bond","info_data":{"mode":"active-backup","miimon":100}},"addr_info":[
+        {"family":"inet","local":"192.0.2.10","prefixlen":24,"scope":"global"}]},
+      {"ifindex":5,"ifname":"bond0.100","link":"bond0","link_type":"ether","linkinfo":{"info_kind":"vlan","info_data":{"protocol":"802.1Q","id":100}},"addr_info":[
+        {"family":"inet","local":"198.51.100.7","prefixlen":24,"scope":"global","dynamic":true}]},
+      {"ifindex":6,"ifname":"docker0","link_type":"ether","linkinfo":{"info_kind":"bridge","info_data":{"stp_state":0}},"addr_info":[
+        {"family":"inet","local":"172.17.0.1","prefixlen":16,"scope":"global"}]},
+      {"ifindex":7,"ifname":"veth1","link":null,"link_type":"ether","master":"docker0","linkinfo":{"info_kind":"veth","info_slave_kind":"bridge"}},
+      {"ifindex":8,"ifname":"vxlan0","link_type":"ether","linkinfo":{"info_kind":"vxlan","info_data":{"id":70000}},"addr_info":[
+        {"family":"inet","local":"203.0.113.1","prefixlen":24,"scope":"global"}]}
+    ]"#;
+
+    #[test]
+    fn test_keyfiles_from_ip_state_virtual() {
+        let links: Vec<IpLink> = serde_json::from_str(IP_ADDR_VIRTUAL).unwrap();
+        let routes: Vec<IpRoute> = serde_json::from_str(IP_ROUTE).unwrap();
+        let r = keyfiles_from_ip_state(&links, &routes, &[]);
+        let r = r
+            .iter()
+            .map(|(k, v)| (k.as_str(), std::str::from_utf8(v).unwrap()))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            r.keys().copied().collect::<Vec<_>>(),
+            [
+                "bond0.100.nmconnection",
+                "bond0.nmconnection",
+                "eth0.nmconnection",
+                "eth1.nmconnection"
+            ]
+        );
+        similar_asserts::assert_eq!(
+            r["eth1.nmconnection"],
+            indoc::indoc! { "
+                [connection]
+                id=eth1
+                type=ethernet
+                interface-name=eth1
+                master=bond0
+                slave-type=bond
+                autoconnect=true
+            "}
+        );
+        similar_asserts::assert_eq!(
+            r["bond0.nmconnection"],
+            indoc::indoc! { "
+                [connection]
+                id=bond0
+                type=bond
+                interface-name=bond0
+                autoconnect=true
+
+                [bond]
+                mode=active-backup
+                miimon=100
+
+                [ipv4]
+                method=manual
+                address1=192.0.2.10/24
+
+                [ipv6]
+                method=auto
+            "}
+        );
+        similar_asserts::assert_eq!(
+            r["bond0.100.nmconnection"],
+            indoc::indoc! { "
+                [connection]
+                id=bond0.100
+                type=vlan
+                interface-name=bond0.100
+                autoconnect=true
+
+                [vlan]
+                id=100
+                parent=bond0
+
+                [ipv4]
+                method=auto
+
+                [ipv6]
+                method=auto
+            "}
+        );
+    }
+
+    #[test]
+    fn test_nameservers() -> Result<()> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(host_nameservers(&td)?.is_empty());
+        td.create_dir_all("etc")?;
+        td.create_dir_all("run/systemd/resolve")?;
+        td.write(
+            "run/systemd/resolve/stub-resolv.conf",
+            "nameserver 127.0.0.53\noptions edns0\n",
+        )?;
+        td.write(
+            RESOLVED_UPLINK_CONF,
+            "# uplink\nnameserver 192.0.2.53\nnameserver ::1\n",
+        )?;
+        td.symlink("../run/systemd/resolve/stub-resolv.conf", "etc/resolv.conf")?;
+        assert_eq!(
+            host_nameservers(&td)?,
+            ["192.0.2.53".parse::<IpAddr>().unwrap()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather_apply() -> Result<()> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("host/etc/NetworkManager/system-connections")?;
+        td.create_dir_all("host/run/NetworkManager/system-connections")?;
+        let host = &td.open_dir("host")?;
+        host.write(HOSTNAME, "myhost\n")?;
+        host.write(format!("{NM_CONNECTIONS}/eth0.nmconnection"), "eth0")?;
+        host.write(format!("{NM_CONNECTIONS}/ignored.txt"), "ignored")?;
+        host.write(format!("{NM_RUN_CONNECTIONS}/eth0.nmconnection"), "runtime")?;
+        host.write(format!("{NM_RUN_CONNECTIONS}/eth1.nmconnection"), "eth1")?;
+        let config = gather(host)?;
+        assert_eq!(config.hostname.as_deref(), Some("myhost"));
+        assert_eq!(
+            config.connections.keys().collect::<Vec<_>>(),
+            ["eth0.nmconnection", "eth1.nmconnection"]
+        );
+        assert_eq!(config.connections["eth0.nmconnection"], b"eth0");
+
+        td.create_dir_all("target/etc/NetworkManager/system-connections")?;
+        let target = &td.open_dir("target")?;
+        target.write(format!("{NM_CONNECTIONS}/eth1.nmconnection"), "image")?;
+        apply(target, None, &config)?;
+        assert_eq!(target.read_to_string(HOSTNAME)?, "myhost\n");
+        assert_eq!(
+            target.read_to_string(format!("{NM_CONNECTIONS}/eth0.nmconnection"))?,
+            "eth0"
+        );
+        assert_eq!(
+            target.read_to_string(format!("{NM_CONNECTIONS}/eth1.nmconnection"))?,
+            "image"
+        );
+
+        // A target without NetworkManager only gets the hostname
+        td.create_dir_all("target2/etc")?;
+        let target = &td.open_dir("target2")?;
+        apply(target, None, &config)?;
+        assert!(target.try_exists(HOSTNAME)?);
+        assert!(!target.try_exists(NM_DIR)?);
+        Ok(())
+    }
+}
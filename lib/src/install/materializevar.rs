@@ -0,0 +1,363 @@
+//! # Populating `/var` from tmpfiles.d at install time
+//!
+//! This implements `bootc install --var-from-tmpfiles`. Instead of keeping the
+//! copy of the image's `/var` which ostree makes when a stateroot is first deployed,
+//! the stateroot's `/var` is emptied and populated by evaluating the tmpfiles.d
+//! configuration of the new deployment, matching what `systemd-tmpfiles --create --boot`
+//! would produce on the first boot.
+
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bootc_sysusers::IdMapping;
+use bootc_tmpfiles::{Node, NodeKind};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::{Dir, Permissions};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+use rustix::fs::{AtFlags, FileType, Gid, Mode, Uid};
+
+use crate::task::Task;
+
+/// Parse a device number in the form `major:minor`.
+fn parse_device(device: &str) -> Result<rustix::fs::Dev> {
+    let (major, minor) = device
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Invalid device number: {device}"))?;
+    Ok(rustix::fs::makedev(major.parse()?, minor.parse()?))
+}
+
+/// Resolve the owner of a node to numeric IDs.
+fn resolve_owner(ids: &IdMapping, path: &Utf8Path, node: &Node) -> Result<(u32, u32)> {
+    let uid = ids.uid(&node.user).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Unknown user {} for {path}; it must be defined in /etc/passwd or sysusers.d",
+            node.user
+        )
+    })?;
+    let gid = ids.gid(&node.group).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Unknown group {} for {path}; it must be defined in /etc/group or sysusers.d",
+            node.group
+        )
+    })?;
+    Ok((uid, gid))
+}
+
+/// Create the users and groups which `systemd-sysusers` would create on the first
+/// boot in the `/etc` of the deployment `root`, so that the files created in `/var`
+/// can be owned by them and the same IDs are used when booting. Returns the IDs of
+/// all users and groups.
+#[context("Allocating users and groups")]
+fn allocate_users(root: &Dir, sepolicy: Option<&ostree::SePolicy>) -> Result<IdMapping> {
+    let db = bootc_sysusers::allocate(root)?;
+    let files = [
+        ("etc/passwd", db.passwd(), 0o644),
+        ("etc/shadow", db.shadow(), 0o000),
+        ("etc/group", db.group(), 0o644),
+        ("etc/gshadow", db.gshadow(), 0o000),
+    ];
+    for (path, contents, mode) in files {
+        let existing = root.read_to_string(path).ok().unwrap_or_default();
+        if existing == contents {
+            continue;
+        }
+        crate::lsm::atomic_replace_labeled(root, path, Mode::from_raw_mode(mode), sepolicy, |w| {
+            w.write_all(contents.as_bytes()).map_err(Into::into)
+        })?;
+    }
+    Ok(db.ids())
+}
+
+/// Create a single node at the absolute path `path` (under `/var`). Returns `false`
+/// if the node was skipped because it can only be created at runtime.
+fn create_node(
+    physical_root: &Dir,
+    deployment_path: &Utf8Path,
+    stateroot_dir: &Dir,
+    stateroot_path: &Utf8Path,
+    ids: &IdMapping,
+    path: &Utf8Path,
+    node: &Node,
+) -> Result<bool> {
+    // Relative to the stateroot directory
+    let rel = path.strip_prefix("/")?;
+    let (uid, gid) = resolve_owner(ids, path, node)?;
+    match &node.kind {
+        NodeKind::Directory => stateroot_dir.create_dir(rel)?,
+        NodeKind::File { contents: None } => {
+            tracing::debug!("Skipping {path} with contents only known at runtime");
+            return Ok(false);
+        }
+        NodeKind::File {
+            contents: Some(contents),
+        } => stateroot_dir.atomic_write(rel, contents)?,
+        NodeKind::Symlink { target } => stateroot_dir.symlink(target, rel)?,
+        NodeKind::Fifo => rustix::fs::mknodat(
+            stateroot_dir,
+            rel.as_std_path(),
+            FileType::Fifo,
+            Mode::from_raw_mode(node.mode),
+            0,
+        )?,
+        NodeKind::CharDevice { device } | NodeKind::BlockDevice { device } => {
+            let Some(device) = device.as_deref() else {
+                tracing::debug!("Skipping {path} without a device number");
+                return Ok(false);
+            };
+            let ftype = if matches!(node.kind, NodeKind::CharDevice { .. }) {
+                FileType::CharacterDevice
+            } else {
+                FileType::BlockDevice
+            };
+            rustix::fs::mknodat(
+                stateroot_dir,
+                rel.as_std_path(),
+                ftype,
+                Mode::from_raw_mode(node.mode),
+                parse_device(device)?,
+            )?
+        }
+        NodeKind::Copy { source } => {
+            let source = Utf8Path::from_path(source)
+                .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF-8 path: {source:?}"))?;
+            let source = deployment_path.join(source.strip_prefix("/")?);
+            if !physical_root.try_exists(&source)? {
+                tracing::debug!("Skipping {path}: {source} does not exist");
+                return Ok(false);
+            }
+            // The copy keeps the metadata of the source.
+            return Task::new_quiet("cp")
+                .args(["-a", "--reflink=auto", "-T"])
+                .args([source.as_str(), stateroot_path.join(rel).as_str()])
+                .cwd(physical_root)?
+                .run()
+                .map(|()| true);
+        }
+    }
+    if (uid, gid) != (0, 0) {
+        rustix::fs::chownat(
+            stateroot_dir,
+            rel.as_std_path(),
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+            AtFlags::SYMLINK_NOFOLLOW,
+        )?;
+    }
+    // Changing the owner clears the setuid/setgid bits, so this comes last.
+    if !matches!(node.kind, NodeKind::Symlink { .. }) {
+        stateroot_dir.set_permissions(rel, Permissions::from_mode(node.mode))?;
+    }
+    Ok(true)
+}
+
+/// `/var` is shared by all deployments of a stateroot, so it may only be replaced
+/// if there are none yet.  Note that the stateroot itself (including its `/var`)
+/// already exists at this point, as it is initialized before deploying.
+pub(crate) fn check_stateroot(sysroot: &ostree::Sysroot, stateroot: &str) -> Result<()> {
+    if sysroot
+        .deployments()
+        .iter()
+        .any(|d| d.osname() == stateroot)
+    {
+        anyhow::bail!(
+            "Cannot use --var-from-tmpfiles with existing deployments of stateroot {stateroot}"
+        );
+    }
+    Ok(())
+}
+
+/// Replace the contents of the stateroot's `/var` with the result of evaluating
+/// the tmpfiles.d configuration of the deployment at `deployment_path` (relative
+/// to `physical_root`). Owners are resolved via the deployment's `/etc/passwd`,
+/// `/etc/group` and sysusers.d, creating the users and groups sysusers.d defines
+/// in its `/etc` (see [`allocate_users`]); entries which can only be processed at runtime
+/// are left to `systemd-tmpfiles` on boot. Returns the number of created objects.
+#[context("Populating /var from tmpfiles.d")]
+pub(crate) fn materialize_var(
+    physical_root: &Dir,
+    deployment_path: &Utf8Path,
+    stateroot: &str,
+    sepolicy: Option<&ostree::SePolicy>,
+) -> Result<usize> {
+    let root = &physical_root
+        .open_dir(deployment_path)
+        .context("Opening deployment dir")?;
+    let stateroot_path = Utf8PathBuf::from(format!("ostree/deploy/{stateroot}"));
+    let stateroot_dir = &physical_root
+        .open_dir(&stateroot_path)
+        .context("Opening stateroot")?;
+
+    let ids = allocate_users(root, sepolicy)?;
+    let specifiers = bootc_tmpfiles::Specifiers::from_root(root)?;
+    let entries = bootc_tmpfiles::read_entries(root, &specifiers)?;
+    let tree = bootc_tmpfiles::evaluate(&entries, Path::new("/var"));
+
+    super::remove_all_in_dir_no_xdev(&stateroot_dir.open_dir("var")?, true)?;
+    let mut n = 0;
+    for (path, node) in tree.iter() {
+        let path = Utf8Path::from_path(path)
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF-8 path: {path:?}"))?;
+        // The directory itself is managed by ostree
+        if path == "/var" {
+            continue;
+        }
+        let created = create_node(
+            physical_root,
+            deployment_path,
+            stateroot_dir,
+            &stateroot_path,
+            &ids,
+            path,
+            node,
+        )
+        .with_context(|| format!("Creating {path}"))?;
+        if created {
+            n += 1;
+        }
+    }
+
+    if let Some(policy) = sepolicy {
+        crate::lsm::relabel_recurse(stateroot_dir, &mut Utf8PathBuf::from("var"), policy)?;
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_tempfile;
+
+    use super::*;
+
+    const DEPLOYMENT: &str = "ostree/deploy/default/deploy/1234.0";
+
+    /// Write a minimal deployment root to [`DEPLOYMENT`].
+    fn write_deployment(td: &Dir) -> Result<()> {
+        td.create_dir_all(DEPLOYMENT)?;
+        let root = td.open_dir(DEPLOYMENT)?;
+        root.create_dir_all("etc")?;
+        root.write("etc/passwd", "root:x:0:0:root:/root:/bin/bash\n")?;
+        root.write("etc/group", "root:x:0:\n")?;
+        root.create_dir_all("usr/lib/tmpfiles.d")?;
+        root.create_dir_all("usr/share/factory/var/lib/copied/sub")?;
+        root.write("usr/share/factory/var/lib/copied/sub/file", "copied")?;
+        Ok(())
+    }
+
+    fn newroot() -> Result<cap_tempfile::TempDir> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("ostree/deploy/default/var/lib/fromimage")?;
+        write_deployment(&td)?;
+        Ok(td)
+    }
+
+    #[test]
+    fn test_materialize_var() -> Result<()> {
+        let td = &newroot()?;
+        let root = td.open_dir(DEPLOYMENT)?;
+        root.write(
+            "usr/lib/tmpfiles.d/test.conf",
+            indoc::indoc! { r#"
+            d /var/lib/foo 0700 - - -
+            f /var/lib/foo/config 0600 root root - x=y
+            L /var/lib/foo/link - - - - ../bar
+            C /var/lib/copied
+            C /var/lib/missing - - - - /usr/share/missing
+            d /var/log/journal/%m 2755 root root - -
+            d /run/foo 0755 - - -
+        "# },
+        )?;
+        let n = materialize_var(td, DEPLOYMENT.into(), "default", None)?;
+        assert_eq!(n, 5);
+        let var = td.open_dir("ostree/deploy/default/var")?;
+        assert!(!var.try_exists("lib/fromimage")?);
+        assert_eq!(
+            var.metadata("lib/foo")?.permissions().mode() & 0o7777,
+            0o700
+        );
+        assert_eq!(var.read_to_string("lib/foo/config")?, "x=y");
+        assert_eq!(
+            var.metadata("lib/foo/config")?.permissions().mode() & 0o7777,
+            0o600
+        );
+        assert_eq!(
+            var.read_link_contents("lib/foo/link")?.to_str(),
+            Some("../bar")
+        );
+        assert_eq!(var.read_to_string("lib/copied/sub/file")?, "copied");
+        assert!(!var.try_exists("lib/missing")?);
+        assert!(!var.try_exists("log/journal")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocate_users() -> Result<()> {
+        use cap_std::fs::MetadataExt;
+
+        let td = &newroot()?;
+        let root = td.open_dir(DEPLOYMENT)?;
+        root.create_dir_all("usr/lib/sysusers.d")?;
+        root.write("usr/lib/sysusers.d/app.conf", "u app - - /var/lib/app\n")?;
+        let ids = allocate_users(&root, None)?;
+        assert_eq!(ids.uid("app"), Some(999));
+        assert_eq!(ids.gid("app"), Some(999));
+        assert_eq!(
+            root.read_to_string("etc/passwd")?,
+            "root:x:0:0:root:/root:/bin/bash\napp:x:999:999::/var/lib/app:/usr/sbin/nologin\n"
+        );
+        assert_eq!(root.read_to_string("etc/group")?, "root:x:0:\napp:x:999:\n");
+        // Nothing changes when allocating again
+        let passwd = root.metadata("etc/passwd")?;
+        assert_eq!(allocate_users(&root, None)?.uid("app"), Some(999));
+        assert_eq!(root.metadata("etc/passwd")?.ino(), passwd.ino());
+        Ok(())
+    }
+
+    #[test]
+    fn test_materialize_var_unknown_user() -> Result<()> {
+        let td = &newroot()?;
+        let root = td.open_dir(DEPLOYMENT)?;
+        root.write(
+            "usr/lib/tmpfiles.d/test.conf",
+            "d /var/lib/foo 0700 nosuchuser - -\n",
+        )?;
+        let err = materialize_var(td, DEPLOYMENT.into(), "default", None).unwrap_err();
+        assert!(format!("{err:#}").contains("Unknown user nosuchuser"));
+        Ok(())
+    }
+    /// Follow the order of an install: the stateroot is initialized before
+    /// deploying, and `/var` is only populated afterwards.
+    #[test]
+    fn test_materialize_var_install_ordering() -> Result<()> {
+        use std::os::fd::{AsFd, AsRawFd};
+
+        let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let cancellable = ostree::gio::Cancellable::NONE;
+        let sysroot = {
+            let path = format!("/proc/self/fd/{}", td.as_fd().as_raw_fd());
+            ostree::Sysroot::new(Some(&ostree::gio::File::for_path(path)))
+        };
+        sysroot.ensure_initialized(cancellable)?;
+        sysroot.load(cancellable)?;
+        sysroot.init_osname("default", cancellable)?;
+        // The stateroot exists, but has no deployments yet
+        assert!(td.try_exists("ostree/deploy/default/var")?);
+        check_stateroot(&sysroot, "default")?;
+
+        write_deployment(td)?;
+        let root = td.open_dir(DEPLOYMENT)?;
+        root.write(
+            "usr/lib/tmpfiles.d/test.conf",
+            "d /var/lib/foo 0700 - - -\n",
+        )?;
+        // `/var/lib` and `/var/lib/foo`
+        let n = materialize_var(td, DEPLOYMENT.into(), "default", None)?;
+        assert_eq!(n, 2);
+        assert!(td.try_exists("ostree/deploy/default/var/lib/foo")?);
+        Ok(())
+    }
+}
@@ -1,15 +1,44 @@
 use std::borrow::Cow;
-use std::io::Write;
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
 
 use anyhow::{Context, Result};
+use bootc_sysusers::IdMapping;
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std::fs::Dir;
 use cap_std_ext::{cap_std, dirext::CapStdExtDirExt};
 use fn_error_context::context;
 use ostree_ext::ostree;
 
+use super::config::{InstallGroup, InstallUser};
+
 const ETC_TMPFILES: &str = "etc/tmpfiles.d";
 const ROOT_SSH_TMPFILE: &str = "bootc-root-ssh.conf";
+const ETC_SYSUSERS: &str = "etc/sysusers.d";
+const USERS_SYSUSERS: &str = "bootc-install-users.conf";
+const USERS_TMPFILE: &str = "bootc-install-users.conf";
+/// systemd-sysusers.service imports `passwd.*` credentials, which are also
+/// searched for in this directory.
+const ETC_CREDSTORE: &str = "etc/credstore";
+/// The group conventionally granted administrative access via sudo.
+const SUDO_GROUP: &str = "wheel";
+/// The shadow-utils configuration, defining the ID ranges of regular users and groups.
+const LOGIN_DEFS: &str = "etc/login.defs";
+
+/// Resolve a toplevel directory in `root` (e.g. `root` or `home`), following
+/// it if it's a symlink (e.g. `/home` -> `/var/home`).  This is done eagerly
+/// in order to avoid tmpfiles.d clashes/problems.
+fn resolve_toplevel_symlink<'a>(root: &Dir, name: &'a str) -> Result<Cow<'a, Utf8Path>> {
+    let meta = root.symlink_metadata_optional(name)?;
+    if meta.as_ref().filter(|m| m.is_symlink()).is_some() {
+        let path = root.read_link(name)?;
+        let path =
+            Utf8PathBuf::try_from(path).with_context(|| format!("Reading /{name} symlink"))?;
+        Ok(Cow::Owned(path.as_str().trim_start_matches('/').into()))
+    } else {
+        Ok(Cow::Borrowed(Utf8Path::new(name)))
+    }
+}
 
 #[context("Injecting root authorized_keys")]
 pub(crate) fn inject_root_ssh_authorized_keys(
@@ -20,17 +49,8 @@ pub(crate) fn inject_root_ssh_authorized_keys(
     // While not documented right now, this one looks like it does not newline wrap
     let b64_encoded = ostree_ext::glib::base64_encode(contents.as_bytes());
 
-    // Eagerly resolve the path of /root in order to avoid tmpfiles.d clashes/problems.
     // If it's local state (i.e. /root -> /var/roothome) then we resolve that symlink now.
-    let roothome_meta = root.symlink_metadata_optional("root")?;
-    let root_path = if roothome_meta.as_ref().filter(|m| m.is_symlink()).is_some() {
-        let path = root.read_link("root")?;
-        Utf8PathBuf::try_from(path)
-            .context("Reading /root symlink")
-            .map(Cow::Owned)?
-    } else {
-        Cow::Borrowed(Utf8Path::new("root"))
-    };
+    let root_path = resolve_toplevel_symlink(root, "root")?;
 
     // See the example in https://systemd.io/CREDENTIALS/
     let tmpfiles_content =
@@ -50,6 +70,334 @@ pub(crate) fn inject_root_ssh_authorized_keys(
     Ok(())
 }
 
+/// Validate a user or group name, using the conservative rules of `systemd-sysusers`.
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 31
+        && name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'));
+    if !valid {
+        anyhow::bail!("Invalid user or group name: {name:?}");
+    }
+    Ok(())
+}
+
+/// Verify that the configured users and groups are well-formed.
+pub(crate) fn validate_users(groups: &[InstallGroup], users: &[InstallUser]) -> Result<()> {
+    for group in groups {
+        validate_name(&group.name)?;
+    }
+    let mut seen = std::collections::HashSet::new();
+    for user in users {
+        validate_name(&user.name)?;
+        if user.name == "root" {
+            anyhow::bail!("Cannot configure the root user; use --root-ssh-authorized-keys");
+        }
+        if !seen.insert(user.name.as_str()) {
+            anyhow::bail!("User {} is specified multiple times", user.name);
+        }
+        for group in user.groups.iter().chain(user.group.as_ref()) {
+            validate_name(group)?;
+        }
+        if let Some(hash) = user.password_hash.as_deref() {
+            // All crypt(3) hashes we expect start with `$`; this guards against
+            // accidentally passing a plain text password.
+            if !hash.starts_with('$') || hash.contains(char::is_whitespace) {
+                anyhow::bail!("Invalid password hash for user {}", user.name);
+            }
+        }
+        for field in [
+            user.gecos.as_deref(),
+            user.home.as_deref(),
+            user.shell.as_deref(),
+        ] {
+            if field.is_some_and(|v| v.contains(['"', '\n'])) {
+                anyhow::bail!("Invalid field for user {}: {field:?}", user.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn user_home(user: &InstallUser) -> Cow<'_, str> {
+    user.home
+        .as_deref()
+        .map(Cow::Borrowed)
+        .unwrap_or_else(|| Cow::Owned(format!("/home/{}", user.name)))
+}
+
+/// The ranges of IDs for regular (as opposed to system) users and groups.
+#[derive(Debug, PartialEq, Eq)]
+struct IdRanges {
+    uid: (u32, u32),
+    gid: (u32, u32),
+}
+
+impl Default for IdRanges {
+    /// The defaults of shadow-utils.
+    fn default() -> Self {
+        Self {
+            uid: (1000, 60000),
+            gid: (1000, 60000),
+        }
+    }
+}
+
+impl IdRanges {
+    /// Parse the `UID_MIN`, `UID_MAX`, `GID_MIN` and `GID_MAX` settings of `login.defs`.
+    fn parse(s: &str) -> Self {
+        let mut r = Self::default();
+        for line in s.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(k), Some(Ok(v))) = (fields.next(), fields.next().map(str::parse)) else {
+                continue;
+            };
+            match k {
+                "UID_MIN" => r.uid.0 = v,
+                "UID_MAX" => r.uid.1 = v,
+                "GID_MIN" => r.gid.0 = v,
+                "GID_MAX" => r.gid.1 = v,
+                _ => {}
+            }
+        }
+        r
+    }
+
+    fn load(root: &Dir) -> Result<Self> {
+        let Some(mut f) = root.open_optional(LOGIN_DEFS)? else {
+            return Ok(Self::default());
+        };
+        let mut s = String::new();
+        f.read_to_string(&mut s)
+            .with_context(|| format!("Reading /{LOGIN_DEFS}"))?;
+        Ok(Self::parse(&s))
+    }
+}
+
+/// Assign IDs to the configured groups and users which lack one, the way `useradd`
+/// does: the lowest free ID of the range for regular users and groups.  Otherwise
+/// `systemd-sysusers` would allocate them as system users.  The UID of a user
+/// with a per-user group is also free as a GID, so that both are the same.
+/// `existing` holds the users and groups of the target root.
+fn allocate_ids(
+    ranges: &IdRanges,
+    existing: &IdMapping,
+    groups: &[InstallGroup],
+    users: &[InstallUser],
+) -> Result<(Vec<InstallGroup>, Vec<InstallUser>)> {
+    let mut uids = existing
+        .users
+        .values()
+        .copied()
+        .chain(users.iter().filter_map(|u| u.uid))
+        .collect::<BTreeSet<_>>();
+    let mut gids = existing
+        .groups
+        .values()
+        .copied()
+        .chain(groups.iter().filter_map(|g| g.gid))
+        .chain(
+            users
+                .iter()
+                .filter(|u| u.group.is_none())
+                .filter_map(|u| u.uid),
+        )
+        .collect::<BTreeSet<_>>();
+    let first_free =
+        |(min, max): (u32, u32), ok: &dyn Fn(u32) -> bool| (min..=max).find(|&id| ok(id));
+
+    let mut r_groups = Vec::new();
+    for group in groups {
+        let mut group = group.clone();
+        if group.gid.is_none() {
+            let gid = match existing.groups.get(&group.name) {
+                Some(&gid) => gid,
+                None => first_free(ranges.gid, &|id| !gids.contains(&id)).ok_or_else(|| {
+                    anyhow::anyhow!("No free GID in {:?} for group {}", ranges.gid, group.name)
+                })?,
+            };
+            gids.insert(gid);
+            group.gid = Some(gid);
+        }
+        r_groups.push(group);
+    }
+    let mut r_users = Vec::new();
+    for user in users {
+        let mut user = user.clone();
+        if let Some(group) = user.group.as_deref() {
+            if !existing.groups.contains_key(group) && !groups.iter().any(|g| g.name == group) {
+                anyhow::bail!(
+                    "Primary group {group} of user {} is not defined in the image or the configuration",
+                    user.name
+                );
+            }
+        }
+        if user.uid.is_none() {
+            let per_user_group = user.group.is_none();
+            let uid = match existing.users.get(&user.name) {
+                Some(&uid) => uid,
+                None => first_free(ranges.uid, &|id| {
+                    !uids.contains(&id) && !(per_user_group && gids.contains(&id))
+                })
+                .ok_or_else(|| {
+                    anyhow::anyhow!("No free UID in {:?} for user {}", ranges.uid, user.name)
+                })?,
+            };
+            uids.insert(uid);
+            if per_user_group {
+                gids.insert(uid);
+            }
+            user.uid = Some(uid);
+        }
+        r_users.push(user);
+    }
+    Ok((r_groups, r_users))
+}
+
+/// Generate the `sysusers.d` entries for the provided groups and users.
+fn users_sysusers_conf(groups: &[InstallGroup], users: &[InstallUser]) -> String {
+    let mut r = String::new();
+    for group in groups {
+        let gid = group.gid.map(|v| v.to_string());
+        let gid = gid.as_deref().unwrap_or("-");
+        r.push_str(&format!("g {} {gid}\n", group.name));
+    }
+    for user in users {
+        let name = &user.name;
+        let uid = user.uid.map(|v| v.to_string());
+        let uid = uid.as_deref().unwrap_or("-");
+        let uid = match user.group.as_deref() {
+            Some(group) => Cow::Owned(format!("{uid}:{group}")),
+            None => Cow::Borrowed(uid),
+        };
+        let gecos = user
+            .gecos
+            .as_deref()
+            .map(|v| Cow::Owned(format!("\"{v}\"")))
+            .unwrap_or(Cow::Borrowed("-"));
+        let home = user_home(user);
+        let shell = user.shell.as_deref().unwrap_or("-");
+        r.push_str(&format!("u {name} {uid} {gecos} {home} {shell}\n"));
+        let sudo = user.sudo.then_some(SUDO_GROUP);
+        for group in user.groups.iter().map(|g| g.as_str()).chain(sudo) {
+            r.push_str(&format!("m {name} {group}\n"));
+        }
+    }
+    r
+}
+
+/// Resolve the path `home` in `root`, following its toplevel directory if it's
+/// a symlink (e.g. `/home` -> `/var/home`, or `/srv` -> `/var/srv`).
+fn resolve_home(root: &Dir, home: &str) -> Result<String> {
+    let relpath = home.trim_start_matches('/');
+    let (toplevel, rest) = relpath.split_once('/').unwrap_or((relpath, ""));
+    if toplevel.is_empty() {
+        return Ok(home.to_owned());
+    }
+    let toplevel = resolve_toplevel_symlink(root, toplevel)?;
+    let r = if rest.is_empty() {
+        format!("/{toplevel}")
+    } else {
+        format!("/{toplevel}/{rest}")
+    };
+    Ok(r)
+}
+
+/// Generate the `tmpfiles.d` entries creating each user's home directory and
+/// SSH authorized keys, owned by the user and their primary group.
+fn users_tmpfiles_conf(root: &Dir, users: &[InstallUser]) -> Result<String> {
+    let mut r = String::new();
+    for user in users {
+        let name = &user.name;
+        let group = user.group.as_deref().unwrap_or(name);
+        let home = resolve_home(root, &user_home(user))?;
+        r.push_str(&format!("d {home} 0700 {name} {group} -\n"));
+        if user.ssh_authorized_keys.is_empty() {
+            continue;
+        }
+        let mut keys = user.ssh_authorized_keys.join("\n");
+        keys.push('\n');
+        let b64_encoded = ostree_ext::glib::base64_encode(keys.as_bytes());
+        r.push_str(&format!("d {home}/.ssh 0700 {name} {group} -\n"));
+        r.push_str(&format!(
+            "f~ {home}/.ssh/authorized_keys 600 {name} {group} - {b64_encoded}\n"
+        ));
+    }
+    Ok(r)
+}
+
+/// Provision the configured users and groups in the target root.  This writes:
+///
+/// - `sysusers.d` entries for the users, groups and group memberships, with the
+///   IDs which are not configured allocated as for regular users
+/// - `tmpfiles.d` entries for home directories and SSH authorized keys
+/// - `passwd.hashed-password.<user>` credentials consumed by `systemd-sysusers`
+///
+/// so that `/etc/passwd` and friends are only modified by the standard tooling on boot.
+#[context("Provisioning users")]
+pub(crate) fn provision_users(
+    root: &Dir,
+    sepolicy: Option<&ostree::SePolicy>,
+    groups: &[InstallGroup],
+    users: &[InstallUser],
+) -> Result<()> {
+    if groups.is_empty() && users.is_empty() {
+        return Ok(());
+    }
+    validate_users(groups, users)?;
+    let ranges = IdRanges::load(root)?;
+    let existing = bootc_sysusers::allocate(root)
+        .context("Reading users and groups")?
+        .ids();
+    let (groups, users) = &allocate_ids(&ranges, &existing, groups, users)?;
+
+    let sysusers_content = users_sysusers_conf(groups, users);
+    crate::lsm::ensure_dir_labeled(root, ETC_SYSUSERS, None, 0o755.into(), sepolicy)?;
+    let sysusers_dir = root.open_dir(ETC_SYSUSERS)?;
+    crate::lsm::atomic_replace_labeled(
+        &sysusers_dir,
+        USERS_SYSUSERS,
+        0o644.into(),
+        sepolicy,
+        |w| w.write_all(sysusers_content.as_bytes()).map_err(Into::into),
+    )?;
+    println!("Injected: {ETC_SYSUSERS}/{USERS_SYSUSERS}");
+
+    if !users.is_empty() {
+        let tmpfiles_content = users_tmpfiles_conf(root, users)?;
+        crate::lsm::ensure_dir_labeled(root, ETC_TMPFILES, None, 0o755.into(), sepolicy)?;
+        let tmpfiles_dir = root.open_dir(ETC_TMPFILES)?;
+        crate::lsm::atomic_replace_labeled(
+            &tmpfiles_dir,
+            USERS_TMPFILE,
+            0o644.into(),
+            sepolicy,
+            |w| w.write_all(tmpfiles_content.as_bytes()).map_err(Into::into),
+        )?;
+        println!("Injected: {ETC_TMPFILES}/{USERS_TMPFILE}");
+    }
+
+    for user in users {
+        let Some(hash) = user.password_hash.as_deref() else {
+            continue;
+        };
+        crate::lsm::ensure_dir_labeled(root, ETC_CREDSTORE, None, 0o700.into(), sepolicy)?;
+        let credstore = root.open_dir(ETC_CREDSTORE)?;
+        let name = format!("passwd.hashed-password.{}", user.name);
+        crate::lsm::atomic_replace_labeled(&credstore, &name, 0o600.into(), sepolicy, |w| {
+            w.write_all(hash.as_bytes()).map_err(Into::into)
+        })?;
+        println!("Injected: {ETC_CREDSTORE}/{name}");
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -88,4 +436,142 @@ mod tests {
     );
         Ok(())
     }
+
+    fn test_users() -> (Vec<InstallGroup>, Vec<InstallUser>) {
+        let groups = vec![
+            InstallGroup {
+                name: "ops".into(),
+                gid: Some(2000),
+            },
+            InstallGroup {
+                name: "dev".into(),
+                gid: None,
+            },
+        ];
+        let users = vec![
+            InstallUser {
+                name: "admin".into(),
+                uid: Some(1000),
+                gecos: Some("Administrator".into()),
+                groups: vec!["ops".into()],
+                sudo: true,
+                password_hash: Some("$6$salt$hash".into()),
+                ssh_authorized_keys: vec!["ssh-ed25519 ABCDE admin@demo".into()],
+                ..Default::default()
+            },
+            InstallUser {
+                name: "svc".into(),
+                group: Some("ops".into()),
+                home: Some("/srv/svc".into()),
+                shell: Some("/sbin/nologin".into()),
+                ..Default::default()
+            },
+        ];
+        (groups, users)
+    }
+
+    #[test]
+    fn test_validate_users() {
+        let (groups, users) = test_users();
+        validate_users(&groups, &users).unwrap();
+        let mut bad = users.clone();
+        bad[0].password_hash = Some("hunter2".into());
+        assert!(validate_users(&groups, &bad).is_err());
+        let mut bad = users.clone();
+        bad[1].name = "root".into();
+        assert!(validate_users(&groups, &bad).is_err());
+        let mut bad = users.clone();
+        bad[1].name = "admin".into();
+        assert!(validate_users(&groups, &bad).is_err());
+        let mut bad = users.clone();
+        bad[1].name = "9lives".into();
+        assert!(validate_users(&groups, &bad).is_err());
+        let mut bad = users;
+        bad[1].group = Some("-".into());
+        assert!(validate_users(&groups, &bad).is_err());
+    }
+
+    #[test]
+    fn test_id_ranges() {
+        assert_eq!(IdRanges::parse(""), IdRanges::default());
+        let ranges = IdRanges::parse(indoc::indoc! { "
+            # Min/max values for automatic uid selection in useradd(8)
+            UID_MIN                  5000
+            UID_MAX                 60000
+            SYS_UID_MIN               201
+            GID_MIN\t5000
+            GID_MAX invalid
+        "});
+        assert_eq!(ranges.uid, (5000, 60000));
+        assert_eq!(ranges.gid, (5000, 60000));
+    }
+
+    #[test]
+    fn test_allocate_ids() {
+        let (groups, mut users) = test_users();
+        let mut existing = IdMapping::default();
+        existing.users.insert("root".into(), 0);
+        existing.groups.insert("wheel".into(), 10);
+        // An unrelated group with the lowest regular GID
+        existing.groups.insert("image".into(), 1001);
+        users.push(InstallUser {
+            name: "carol".into(),
+            ..Default::default()
+        });
+        let ranges = IdRanges::default();
+        let (groups, users) = allocate_ids(&ranges, &existing, &groups, &users).unwrap();
+        assert_eq!(groups[0].gid, Some(2000));
+        // 1000 is the group of admin
+        assert_eq!(groups[1].gid, Some(1002));
+        assert_eq!(users[0].uid, Some(1000));
+        // svc has no per-user group, so the GID of its UID may be taken
+        assert_eq!(users[1].uid, Some(1001));
+        assert_eq!(users[2].uid, Some(1003));
+
+        let (groups, mut users) = test_users();
+        users[1].group = Some("missing".into());
+        assert!(allocate_ids(&ranges, &existing, &groups, &users).is_err());
+        users[1].group = Some("wheel".into());
+        allocate_ids(&ranges, &existing, &groups, &users).unwrap();
+    }
+
+    #[test]
+    fn test_provision_users() -> Result<()> {
+        let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        root.create_dir("etc")?;
+        root.create_dir("var")?;
+        root.symlink("var/home", "home")?;
+        root.symlink("/var/srv", "srv")?;
+        root.write(LOGIN_DEFS, "UID_MIN 1000\nGID_MIN 1000\n")?;
+        let (groups, users) = test_users();
+        provision_users(root, None, &groups, &users).unwrap();
+
+        let content = root.read_to_string(format!("{ETC_SYSUSERS}/{USERS_SYSUSERS}"))?;
+        similar_asserts::assert_eq!(
+            content,
+            indoc::indoc! { r#"
+                g ops 2000
+                g dev 1001
+                u admin 1000 "Administrator" /home/admin -
+                m admin ops
+                m admin wheel
+                u svc 1001:ops - /srv/svc /sbin/nologin
+            "#}
+        );
+        let content = root.read_to_string(format!("{ETC_TMPFILES}/{USERS_TMPFILE}"))?;
+        similar_asserts::assert_eq!(
+            content,
+            indoc::indoc! { "
+                d /var/home/admin 0700 admin admin -
+                d /var/home/admin/.ssh 0700 admin admin -
+                f~ /var/home/admin/.ssh/authorized_keys 600 admin admin - c3NoLWVkMjU1MTkgQUJDREUgYWRtaW5AZGVtbwo=
+                d /var/srv/svc 0700 svc ops -
+            "}
+        );
+        let content =
+            root.read_to_string(format!("{ETC_CREDSTORE}/passwd.hashed-password.admin"))?;
+        assert_eq!(content, "$6$salt$hash");
+        assert!(!root.try_exists(format!("{ETC_CREDSTORE}/passwd.hashed-password.svc"))?);
+        Ok(())
+    }
 }
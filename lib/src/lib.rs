@@ -7,8 +7,12 @@
 mod boundimage;
 pub mod cli;
 pub(crate) mod deploy;
+mod etc;
+mod factory_reset;
+mod fix;
 pub(crate) mod generator;
 mod glyph;
+mod iddrift;
 mod image;
 mod imgstorage;
 pub(crate) mod journal;
@@ -8,10 +8,11 @@
 use std::collections::BTreeSet;
 use std::env::consts::ARCH;
 use std::fmt::Write as WriteFmt;
+use std::os::fd::AsRawFd;
 use std::os::unix::ffi::OsStrExt;
 
 use anyhow::Result;
-use bootc_utils::PathQuotedDisplay;
+use bootc_utils::{CommandRunExt, PathQuotedDisplay};
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
@@ -21,6 +22,7 @@ use fn_error_context::context;
 use indoc::indoc;
 use linkme::distributed_slice;
 use ostree_ext::ostree_prepareroot;
+use ostree_ext::{gio, ostree};
 use serde::Serialize;
 
 /// Reference to embedded default baseimage content that should exist.
@@ -31,13 +33,19 @@ const BASEIMAGE_REF: &str = "usr/share/doc/bootc/baseimage/base";
 struct LintError(String);
 
 /// The outer error is for unexpected fatal runtime problems; the
-/// inner error is for the lint failing in an expected way.
-type LintResult = Result<std::result::Result<(), LintError>>;
+/// inner error is for the lint failing in an expected way. A passing
+/// lint may return informational output.
+type LintResult = Result<std::result::Result<Option<String>, LintError>>;
 
 /// Everything is OK - we didn't encounter a runtime error, and
 /// the targeted check passed.
 fn lint_ok() -> LintResult {
-    Ok(Ok(()))
+    Ok(Ok(None))
+}
+
+/// The targeted check passed, and found something worth showing.
+fn lint_ok_info(msg: impl Into<String>) -> LintResult {
+    Ok(Ok(Some(msg.into())))
 }
 
 /// We successfully found a lint failure.
@@ -96,6 +104,9 @@ struct Lint {
     // Set if this only applies to a specific root type.
     #[serde(skip_serializing_if = "Option::is_none")]
     root_type: Option<RootType>,
+    // Set if this only runs when explicitly included.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    optional: bool,
 }
 
 impl Lint {
@@ -110,6 +121,7 @@ impl Lint {
             f: f,
             description: description,
             root_type: None,
+            optional: false,
         }
     }
 
@@ -124,6 +136,7 @@ impl Lint {
             f: f,
             description: description,
             root_type: None,
+            optional: false,
         }
     }
 
@@ -131,6 +144,13 @@ impl Lint {
         self.root_type = Some(v);
         self
     }
+
+    /// Only run this lint if it is explicitly included, e.g. because
+    /// it is expensive or only works in some environments.
+    const fn set_optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
 }
 
 pub(crate) fn lint_list(output: impl std::io::Write) -> Result<()> {
@@ -150,6 +170,7 @@ struct LintExecutionResult {
 fn lint_inner<'skip>(
     root: &Dir,
     root_type: RootType,
+    include: impl IntoIterator<Item = &'skip str>,
     skip: impl IntoIterator<Item = &'skip str>,
     mut output: impl std::io::Write,
 ) -> Result<LintExecutionResult> {
@@ -157,10 +178,16 @@ fn lint_inner<'skip>(
     let mut warnings = 0usize;
     let mut passed = 0usize;
     let mut skipped = 0usize;
+    let include: std::collections::HashSet<_> = include.into_iter().collect();
     let skip: std::collections::HashSet<_> = skip.into_iter().collect();
     for lint in LINTS {
         let name = lint.name;
 
+        // Optional lints which are not included don't count as skipped
+        if lint.optional && !include.contains(name) {
+            continue;
+        }
+
         if skip.contains(name) {
             skipped += 1;
             continue;
@@ -178,8 +205,8 @@ fn lint_inner<'skip>(
             Err(e) => anyhow::bail!("Unexpected runtime error running lint {name}: {e}"),
         };
 
-        if let Err(e) = r {
-            match lint.ty {
+        match r {
+            Err(e) => match lint.ty {
                 LintType::Fatal => {
                     writeln!(output, "Failed lint: {name}: {e}")?;
                     fatal += 1;
@@ -188,11 +215,14 @@ fn lint_inner<'skip>(
                     writeln!(output, "Lint warning: {name}: {e}")?;
                     warnings += 1;
                 }
+            },
+            Ok(info) => {
+                if let Some(info) = info {
+                    writeln!(output, "Lint info: {name}: {info}")?;
+                }
+                tracing::debug!("OK {name} (type={:?})", lint.ty);
+                passed += 1;
             }
-        } else {
-            // We'll be quiet for now
-            tracing::debug!("OK {name} (type={:?})", lint.ty);
-            passed += 1;
         }
     }
 
@@ -212,10 +242,11 @@ pub(crate) fn lint<'skip>(
     root: &Dir,
     warning_disposition: WarningDisposition,
     root_type: RootType,
+    include: impl IntoIterator<Item = &'skip str>,
     skip: impl IntoIterator<Item = &'skip str>,
     mut output: impl std::io::Write,
 ) -> Result<()> {
-    let r = lint_inner(root, root_type, skip, &mut output)?;
+    let r = lint_inner(root, root_type, include, skip, &mut output)?;
     writeln!(output, "Checks passed: {}", r.passed)?;
     if r.skipped > 0 {
         writeln!(output, "Checks skipped: {}", r.skipped)?;
@@ -310,6 +341,112 @@ fn check_parse_kargs(root: &Dir) -> LintResult {
     lint_ok()
 }
 
+#[distributed_slice(LINTS)]
+static LINT_INSTALL_CONFIG: Lint = Lint::new_fatal(
+    "install-config",
+    indoc! { r#"
+Verify that the configuration files for `bootc install` in /usr/lib/bootc/install
+can be parsed and merged, for the current architecture as well as each architecture
+named in `match-architectures`. This also checks that the merged configuration is
+consistent, and that the image supports the enabled block setups (e.g. `tpm2-luks`
+requires cryptsetup).
+"#},
+    check_install_config,
+);
+fn check_install_config(root: &Dir) -> LintResult {
+    use crate::install::config::{merge_config_fragments, read_config_fragments, EnvProperties};
+
+    let rootpath = Utf8PathBuf::from(format!("/proc/self/fd/{}", root.as_raw_fd()));
+    let fragments = match read_config_fragments(&rootpath) {
+        Ok(f) => f,
+        Err(e) => return lint_err(format!("{e:#}")),
+    };
+    let mut archs = BTreeSet::from([ARCH.to_owned()]);
+    archs.extend(
+        fragments
+            .iter()
+            .filter_map(|(_, c)| c.install.as_ref()?.match_architectures.as_ref())
+            .flatten()
+            .cloned(),
+    );
+    let mut errs = Vec::new();
+    for arch in archs {
+        let env = EnvProperties { sys_arch: arch };
+        let config = merge_config_fragments(fragments.iter().map(|(_, c)| c.clone()), &env);
+        tracing::debug!("install config for {}: {config:?}", env.sys_arch);
+        if let Some(Err(e)) = config.map(|c| c.validate(root)) {
+            errs.push(format!("{}: {e:#}", env.sys_arch));
+        }
+    }
+    if errs.is_empty() {
+        return lint_ok();
+    }
+    lint_err(format!(
+        "Invalid install configuration:\n  {}",
+        errs.join("\n  ")
+    ))
+}
+
+#[distributed_slice(LINTS)]
+static LINT_BOUND_IMAGES: Lint = Lint::new_fatal(
+    "bound-images",
+    indoc! { r#"
+Verify that each entry in /usr/lib/bootc/bound-images.d is a symbolic link to an
+existing `.image` or `.container` file in the image, and that the image reference
+in it can be used for a logically bound image. The resolved image references
+are listed in the output.
+"#},
+    check_bound_images,
+);
+fn check_bound_images(root: &Dir) -> LintResult {
+    let mut images = crate::boundimage::query_bound_images_each(root)?;
+    if images.is_empty() {
+        return lint_ok();
+    }
+    images.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut errs = String::new();
+    let mut msg = format!("Found {} bound images:\n", images.len());
+    for (name, r) in images {
+        match r {
+            Ok(image) => writeln!(msg, "  {}: {name}", image.image)?,
+            Err(e) => writeln!(errs, "  {name}: {e:#}")?,
+        }
+    }
+    if !errs.is_empty() {
+        return lint_err(format!("Invalid bound images:\n{}", errs.trim_end()));
+    }
+    lint_ok_info(msg.trim_end())
+}
+
+#[distributed_slice(LINTS)]
+static LINT_BOUND_IMAGES_PINNED: Lint = Lint::new_fatal(
+    "bound-images-pinned",
+    indoc! { r#"
+Strict mode for logically bound images: check that every bound image reference
+is pinned by digest (e.g. `quay.io/example/app@sha256:...`) rather than a tag,
+so that each version of the image always binds the same content. This lint is
+optional; run it with `--include bound-images-pinned`.
+"#},
+    check_bound_images_pinned,
+)
+.set_optional();
+fn check_bound_images_pinned(root: &Dir) -> LintResult {
+    // Invalid entries are reported by the bound-images lint
+    let unpinned = crate::boundimage::query_bound_images_each(root)?
+        .into_iter()
+        .filter_map(|(name, r)| r.ok().map(|image| (name, image.image)))
+        .filter(|(_, image)| !crate::boundimage::image_is_pinned(image))
+        .collect::<BTreeSet<_>>();
+    if unpinned.is_empty() {
+        return lint_ok();
+    }
+    let mut msg = String::from("Bound images not pinned by digest:\n");
+    for (name, image) in unpinned {
+        writeln!(msg, "  {image}: {name}")?;
+    }
+    lint_err(msg)
+}
+
 #[distributed_slice(LINTS)]
 static LINT_KERNEL: Lint = Lint::new_fatal(
     "kernel",
@@ -325,6 +462,248 @@ fn check_kernel(root: &Dir) -> LintResult {
     lint_ok()
 }
 
+#[distributed_slice(LINTS)]
+static LINT_INITRAMFS: Lint = Lint::new_warning(
+    "initramfs",
+    indoc! { r#"
+Check that the kernel has an initramfs at /usr/lib/modules/$kver/initramfs.img
+which was regenerated after the last change to kernel modules or the dracut
+configuration, that modules.dep matches the installed kernel modules, and that
+kernel modules needed by the kernel arguments in /usr/lib/bootc/kargs.d (such as
+the driver for rootfstype=) and by the `root-fs-type` of the `bootc install`
+configuration are included in the initramfs. Listing the contents of the
+initramfs requires lsinitrd.
+"#},
+    check_initramfs,
+);
+
+/// Tool used to list the contents of an initramfs.
+const LSINITRD: &str = "/usr/bin/lsinitrd";
+/// Files and directories with dracut configuration.
+const DRACUT_CONFIG: &[&str] = &[
+    "etc/dracut.conf",
+    "etc/dracut.conf.d",
+    "usr/lib/dracut/dracut.conf.d",
+];
+
+/// Convert the path of a kernel module to its name, e.g. `kernel/fs/xfs/xfs.ko.xz`
+/// to `xfs`. As with modprobe, dashes and underscores are equivalent.
+fn kernel_module_name(path: &str) -> Option<String> {
+    let name = path.rsplit('/').next()?;
+    let name = [".ko", ".ko.xz", ".ko.zst", ".ko.gz"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))?;
+    Some(name.replace('-', "_"))
+}
+
+/// Collect the kernel modules below `path` in `root` as paths relative to `path`,
+/// also tracking the modification time of the newest one.
+fn find_kernel_modules(
+    root: &Dir,
+    path: &Utf8Path,
+    rel: &mut Utf8PathBuf,
+    found: &mut BTreeSet<Utf8PathBuf>,
+    newest: &mut Option<(i64, Utf8PathBuf)>,
+) -> Result<()> {
+    for ent in root.read_dir(path.join(&*rel))? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        rel.push(name);
+        let ty = ent.file_type()?;
+        if ty.is_dir() {
+            find_kernel_modules(root, path, rel, found, newest)?;
+        } else if ty.is_file() && kernel_module_name(name).is_some() {
+            let mtime = ent.metadata()?.mtime();
+            if !matches!(newest, Some((t, _)) if *t >= mtime) {
+                *newest = Some((mtime, path.join(&*rel)));
+            }
+            found.insert(rel.clone());
+        }
+        rel.pop();
+    }
+    Ok(())
+}
+
+/// The kernel modules which must be in the initramfs for the provided kernel arguments.
+fn kargs_required_modules(kargs: &[String]) -> BTreeSet<String> {
+    let mut r = BTreeSet::new();
+    for karg in kargs {
+        let (k, v) = karg.split_once('=').unwrap_or((karg.as_str(), ""));
+        match k {
+            "rootfstype" => {
+                r.insert(v.replace('-', "_"));
+            }
+            "rd.driver.pre" | "rd.driver.post" => {
+                r.extend(
+                    v.split(',')
+                        .filter(|m| !m.is_empty())
+                        .map(|m| m.replace('-', "_")),
+                );
+            }
+            k if k.starts_with("rd.luks") => {
+                r.insert("dm_crypt".into());
+            }
+            _ => {}
+        }
+    }
+    r
+}
+
+/// The root filesystem type in the merged `bootc install` configuration for the
+/// current architecture.  Invalid configurations are reported by the
+/// `install-config` lint instead.
+fn install_root_fs_type(root: &Dir) -> Option<crate::install::config::Filesystem> {
+    use crate::install::config::{merge_config_fragments, read_config_fragments, EnvProperties};
+
+    let rootpath = Utf8PathBuf::from(format!("/proc/self/fd/{}", root.as_raw_fd()));
+    let fragments = read_config_fragments(&rootpath).ok()?;
+    let env = EnvProperties {
+        sys_arch: ARCH.to_owned(),
+    };
+    let mut config = merge_config_fragments(fragments.into_iter().map(|(_, c)| c), &env)?;
+    config.canonicalize();
+    config.root_fs_type
+}
+
+/// List the names of the kernel modules in an initramfs, if lsinitrd is available.
+fn initramfs_modules(root: &Dir, path: &Utf8Path) -> Result<Option<BTreeSet<String>>> {
+    if !std::path::Path::new(LSINITRD).try_exists()? {
+        tracing::debug!("{LSINITRD} not found; not inspecting initramfs");
+        return Ok(None);
+    }
+    let fdpath = format!("/proc/self/fd/{}/{path}", root.as_raw_fd());
+    let out = std::process::Command::new(LSINITRD)
+        .arg(fdpath)
+        .run_get_string()?;
+    Ok(Some(
+        out.lines()
+            .filter_map(|l| l.split_whitespace().find(|w| w.contains("lib/modules/")))
+            .filter_map(kernel_module_name)
+            .collect(),
+    ))
+}
+
+fn check_initramfs(root: &Dir) -> LintResult {
+    let Some(kdir) = ostree_ext::bootabletree::find_kernel_dir_fs(root)? else {
+        return lint_ok();
+    };
+    let initramfs = kdir.join("initramfs.img");
+    let Some(initramfs_meta) = root.symlink_metadata_optional(&initramfs)? else {
+        return lint_err(format!("Missing /{initramfs}"));
+    };
+    let mut problems = Vec::new();
+
+    let mut modules = BTreeSet::new();
+    let mut newest = None;
+    find_kernel_modules(
+        root,
+        &kdir,
+        &mut Utf8PathBuf::new(),
+        &mut modules,
+        &mut newest,
+    )?;
+    for path in DRACUT_CONFIG.iter().map(Utf8Path::new) {
+        let Some(meta) = root.symlink_metadata_optional(path)? else {
+            continue;
+        };
+        let mut candidates = vec![(meta.mtime(), path.to_owned())];
+        if meta.is_dir() {
+            for ent in root.read_dir(path)? {
+                let ent = ent?;
+                if let Some(name) = ent.file_name().to_str() {
+                    candidates.push((ent.metadata()?.mtime(), path.join(name)));
+                }
+            }
+        }
+        for c in candidates {
+            if !matches!(newest, Some((t, _)) if t >= c.0) {
+                newest = Some(c);
+            }
+        }
+    }
+    if let Some((mtime, path)) = newest {
+        if mtime > initramfs_meta.mtime() {
+            problems.push(format!(
+                "/{initramfs} is older than /{path}; regenerate it with dracut"
+            ));
+        }
+    }
+
+    let modules_dep = kdir.join("modules.dep");
+    let deps = root
+        .open_optional(&modules_dep)?
+        .map(std::io::read_to_string)
+        .transpose()?;
+    if let Some(deps) = deps {
+        let listed = deps
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .map(|(m, _)| Utf8PathBuf::from(m))
+            .collect::<BTreeSet<_>>();
+        if let Some(m) = modules.difference(&listed).next() {
+            problems.push(format!(
+                "/{modules_dep} does not list {m}; regenerate it with depmod"
+            ));
+        }
+        if let Some(m) = listed.difference(&modules).next() {
+            problems.push(format!(
+                "/{modules_dep} lists missing module {m}; regenerate it with depmod"
+            ));
+        }
+    } else if !modules.is_empty() {
+        problems.push(format!("Missing /{modules_dep}; generate it with depmod"));
+    }
+
+    let mut required = kargs_required_modules(&crate::kargs::get_kargs_in_root(root, ARCH)?);
+    if let Some(fstype) = install_root_fs_type(root) {
+        required.insert(fstype.to_string());
+    }
+    if !required.is_empty() {
+        let builtin = root
+            .open_optional(kdir.join("modules.builtin"))?
+            .map(std::io::read_to_string)
+            .transpose()?
+            .unwrap_or_default();
+        let builtin = builtin
+            .lines()
+            .filter_map(kernel_module_name)
+            .collect::<BTreeSet<_>>();
+        let available = modules
+            .iter()
+            .filter_map(|m| kernel_module_name(m.as_str()))
+            .collect::<BTreeSet<_>>();
+        let required = required
+            .into_iter()
+            .filter(|m| !builtin.contains(m))
+            .collect::<Vec<_>>();
+        // Only inspect the initramfs if there's something to look for
+        let in_initramfs = if required.iter().any(|m| available.contains(m)) {
+            initramfs_modules(root, &initramfs)?
+        } else {
+            None
+        };
+        for m in required {
+            if !available.contains(&m) {
+                problems.push(format!(
+                    "Kernel module {m} needed by the kernel arguments or root filesystem is not installed"
+                ));
+            } else if in_initramfs.as_ref().is_some_and(|i| !i.contains(&m)) {
+                problems.push(format!(
+                    "Kernel module {m} needed by the kernel arguments or root filesystem is not in the initramfs"
+                ));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        return lint_ok();
+    }
+    lint_err(problems.join("\n"))
+}
+
 // This one can be lifted in the future, see https://github.com/containers/bootc/issues/975
 #[distributed_slice(LINTS)]
 static LINT_UTF8: Lint = Lint::new_fatal(
@@ -471,6 +850,92 @@ fn collect_nonempty_regfiles(
     Ok(())
 }
 
+/// The sshd configuration files which may contain `HostKey` directives, relative to `/etc/ssh`.
+fn sshd_config_paths(sshdir: &Dir) -> Result<Vec<Utf8PathBuf>> {
+    let mut r = Vec::new();
+    if sshdir.try_exists("sshd_config")? {
+        r.push(Utf8PathBuf::from("sshd_config"));
+    }
+    if let Some(d) = sshdir.open_dir_optional("sshd_config.d")? {
+        let mut dropins = Vec::new();
+        for ent in d.entries_utf8()? {
+            let name = ent?.file_name()?;
+            if name.ends_with(".conf") {
+                dropins.push(Utf8Path::new("sshd_config.d").join(name));
+            }
+        }
+        dropins.sort();
+        r.extend(dropins);
+    }
+    Ok(r)
+}
+
+#[distributed_slice(LINTS)]
+static LINT_TRANSIENT_ETC: Lint = Lint::new_warning(
+    "etc-transient",
+    indoc! { r#"
+If /etc is transient (the `etc.transient` option in prepare-root.conf), it is reset
+from the image on every boot. Check that machine-specific state which is
+normally generated into /etc on the first boot is persisted elsewhere:
+
+- The machine ID should be provided via the `systemd.machine_id=` kernel argument
+  in /usr/lib/bootc/kargs.d, or /etc/machine-id should be a symlink into /var.
+- If sshd is installed, its host keys should be configured via `HostKey` outside of /etc.
+"#},
+    check_transient_etc,
+);
+fn check_transient_etc(root: &Dir) -> LintResult {
+    let Some(config) = ostree_prepareroot::load_config_from_root(root)? else {
+        return lint_ok();
+    };
+    if !ostree_prepareroot::transient_etc_enabled_in_config(&config)? {
+        return lint_ok();
+    }
+    let mut msg = String::new();
+
+    let machine_id_karg = crate::kargs::get_kargs_in_root(root, ARCH)?
+        .iter()
+        .any(|k| k.starts_with("systemd.machine_id="));
+    let machine_id_link = if root
+        .symlink_metadata_optional("etc/machine-id")?
+        .is_some_and(|m| m.is_symlink())
+    {
+        let target = root.read_link_contents("etc/machine-id")?;
+        target.starts_with("/var") || target.starts_with("../var")
+    } else {
+        false
+    };
+    if !machine_id_karg && !machine_id_link {
+        msg.push_str("The machine ID is not persisted outside of /etc\n");
+    }
+
+    if root.try_exists("usr/sbin/sshd")? {
+        let mut hostkeys = Vec::new();
+        if let Some(sshdir) = root.open_dir_optional("etc/ssh")? {
+            for path in sshd_config_paths(&sshdir)? {
+                let contents = sshdir.read_to_string(&path)?;
+                hostkeys.extend(contents.lines().filter_map(|line| {
+                    let (k, v) = line.trim().split_once(char::is_whitespace)?;
+                    k.eq_ignore_ascii_case("HostKey")
+                        .then(|| v.trim().to_owned())
+                }));
+            }
+        }
+        if hostkeys.is_empty() {
+            msg.push_str("sshd host keys use the default location in /etc/ssh\n");
+        }
+        for key in hostkeys.iter().filter(|k| k.starts_with("/etc/")) {
+            writeln!(msg, "sshd host key is in /etc: {key}")?;
+        }
+    }
+
+    if msg.is_empty() {
+        lint_ok()
+    } else {
+        lint_err(msg)
+    }
+}
+
 #[distributed_slice(LINTS)]
 static LINT_VARLOG: Lint = Lint::new_warning(
     "var-log",
@@ -625,6 +1090,270 @@ fn check_boot(root: &Dir) -> LintResult {
     lint_err(format!("Found non-empty /boot: {first:?}{others}"))
 }
 
+#[distributed_slice(LINTS)]
+static LINT_IMPORT_FILTERED: Lint = Lint::new_warning(
+    "import-filtered",
+    indoc! { r#"
+Check for content that will be dropped or moved when the image is imported into ostree
+on the host. Content in /run, /tmp, /proc, /sys and /dev is always dropped, as is any
+toplevel directory other than /usr, /etc and /var unless composefs or a transient root
+is enabled in prepare-root.conf. With ostree versions older than 2024.3, content in /var
+is moved to /usr/share/factory/var.
+"#},
+    check_import_filtered,
+);
+
+/// Content of the image which is changed when importing it into ostree.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ImportFiltered {
+    /// Toplevel paths whose content is dropped, along with the number of entries
+    dropped: Vec<(String, u64)>,
+    /// Toplevel paths whose content is moved, along with the destination and
+    /// the number of entries
+    moved: Vec<(String, Utf8PathBuf, u64)>,
+}
+
+/// Count the entries in a directory recursively, without crossing mount points.
+fn count_entries_noxdev(d: &Dir) -> Result<u64> {
+    let mut n = 0;
+    for ent in d.entries()? {
+        let ent = ent?;
+        n += 1;
+        if ent.file_type()?.is_dir() {
+            if let Some(d) = d.open_dir_noxdev(ent.file_name())? {
+                n += count_entries_noxdev(&d)?;
+            }
+        }
+    }
+    Ok(n)
+}
+
+fn find_import_filtered(
+    root: &Dir,
+    options: &ostree_ext::tar::WriteTarOptions,
+) -> Result<ImportFiltered> {
+    let mut r = ImportFiltered::default();
+    for ent in root.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        // Non-UTF-8 filenames are covered by the utf8 lint
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        // /boot is covered by the nonempty-boot lint, and /sysroot is managed by ostree
+        if matches!(name, "boot" | "sysroot") {
+            continue;
+        }
+        let ty = ent.file_type()?;
+        // Toplevel symlinks such as /bin are provided by the base image
+        if ty.is_symlink() {
+            continue;
+        }
+        let n = if ty.is_dir() {
+            // Mount points (e.g. /proc) are not part of the image
+            let Some(d) = root.open_dir_noxdev(name)? else {
+                continue;
+            };
+            count_entries_noxdev(&d)?
+        } else {
+            0
+        };
+        if ty.is_dir() && n == 0 {
+            continue;
+        }
+        // The toplevel directories for API filesystems are kept, but not their
+        // contents; so check a path below the toplevel.
+        let probe = Utf8Path::new(name).join("x");
+        match ostree_ext::tar::imported_path(&probe, options)? {
+            ostree_ext::tar::ImportedPath::Filtered => r.dropped.push((name.to_owned(), n)),
+            ostree_ext::tar::ImportedPath::Imported(p) => {
+                // /usr/etc is transparently handled as /etc
+                if name == "etc" || p == probe {
+                    continue;
+                }
+                let dest = p.parent().unwrap_or(&p).to_owned();
+                r.moved.push((name.to_owned(), dest, n));
+            }
+        }
+    }
+    r.dropped.sort();
+    r.moved.sort();
+    Ok(r)
+}
+
+fn check_import_filtered(root: &Dir) -> LintResult {
+    let allow_nonusr = ostree_prepareroot::load_config_from_root(root)?
+        .map(|config| ostree_prepareroot::overlayfs_enabled_in_config(&config))
+        .transpose()?
+        .unwrap_or_default();
+    // Keep this in sync with the container import
+    let mut options = ostree_ext::tar::WriteTarOptions::default();
+    options.allow_nonusr = allow_nonusr;
+    options.retain_var = ostree::check_version(2024, 3);
+    let r = find_import_filtered(root, &options)?;
+    if r == ImportFiltered::default() {
+        return lint_ok();
+    }
+    let mut msg = String::new();
+    if !r.dropped.is_empty() {
+        msg.push_str("Found content which will not be imported:\n");
+        for (path, n) in r.dropped.iter() {
+            writeln!(msg, "  /{path} ({n} entries)")?;
+        }
+    }
+    if !r.moved.is_empty() {
+        msg.push_str("Found content which will be moved when imported:\n");
+        for (path, dest, n) in r.moved.iter() {
+            writeln!(msg, "  /{path} ({n} entries) -> /{dest}")?;
+        }
+    }
+    lint_err(msg)
+}
+
+/// Directories whose SELinux labels are checked by the selinux-labels lint.
+const SELINUX_LABELED_DIRS: &[&str] = &["usr", "etc", "opt"];
+/// Files which may be bind mounted by a container runtime.
+const RUNTIME_MOUNTED: &[&str] = &["etc/hostname", "etc/hosts", "etc/resolv.conf"];
+
+#[distributed_slice(LINTS)]
+static LINT_SELINUX_LABELS: Lint = Lint::new_warning(
+    "selinux-labels",
+    indoc! { r#"
+Check that the SELinux labels of content in /usr, /etc and /opt match the labels
+assigned by the SELinux policy of the image. Mislabeled content commonly results
+from copying files from another stage in a multi-stage build, and causes
+denials at runtime.
+
+This requires the labels stored in the image to be visible, which is not the case
+in a container on a host with SELinux enabled; instead, mount the image (e.g. via
+`podman image mount`) and use `--rootfs`. This lint is optional; run it with
+`--include selinux-labels`.
+
+Mislabeled paths are not attributed to the layer which introduced them, as the
+layer history of the image is not available from its root filesystem. Mislabeled
+content is grouped by its topmost mislabeled directory, which usually corresponds
+to a single `COPY` or `ADD` instruction.
+"#},
+    check_selinux_labels,
+)
+.set_optional();
+
+/// A path whose SELinux label does not match the policy.
+#[derive(Debug, PartialEq, Eq)]
+struct Mislabeled {
+    path: Utf8PathBuf,
+    expected: String,
+    found: Option<String>,
+    /// The number of mislabeled paths underneath this one
+    below: usize,
+}
+
+/// Collect the paths under `path` whose label differs from the policy, in pre-order.
+fn find_mislabeled(
+    root: &Dir,
+    path: &mut Utf8PathBuf,
+    policy: &ostree::SePolicy,
+    out: &mut Vec<Mislabeled>,
+) -> Result<()> {
+    let meta = root.symlink_metadata(&*path)?;
+    if !RUNTIME_MOUNTED.contains(&path.as_str()) {
+        let abspath = Utf8Path::new("/").join(&*path);
+        if let Some(expected) =
+            policy.label(abspath.as_str(), meta.mode(), gio::Cancellable::NONE)?
+        {
+            let found = crate::lsm::get_security_selinux(root, path)?;
+            if found.as_deref() != Some(expected.as_str()) {
+                out.push(Mislabeled {
+                    path: path.clone(),
+                    expected: expected.into(),
+                    found,
+                    below: 0,
+                });
+            }
+        }
+    }
+    if !meta.is_dir() {
+        return Ok(());
+    }
+    for ent in root.read_dir(&*path)? {
+        let ent = ent?;
+        let name = ent.file_name();
+        // Non-UTF-8 filenames are covered by the utf8 lint
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        path.push(name);
+        find_mislabeled(root, path, policy, out)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Fold mislabeled paths into their topmost mislabeled ancestor; content that was
+/// copied in a single build step typically ends up all below one directory.
+fn group_mislabeled(entries: Vec<Mislabeled>) -> Vec<Mislabeled> {
+    let mut r: Vec<Mislabeled> = Vec::new();
+    for e in entries {
+        if let Some(last) = r.last_mut() {
+            if e.path.starts_with(&last.path) {
+                last.below += 1;
+                continue;
+            }
+        }
+        r.push(e);
+    }
+    r
+}
+
+fn check_selinux_labels(root: &Dir) -> LintResult {
+    if !crate::lsm::have_selinux_policy(root)? {
+        return lint_ok();
+    }
+    let policy = ostree::SePolicy::new_at(root.as_raw_fd(), gio::Cancellable::NONE)?;
+    if policy.name().is_none() {
+        return lint_ok();
+    }
+    let mut mislabeled = Vec::new();
+    for d in SELINUX_LABELED_DIRS {
+        if root.symlink_metadata_optional(d)?.is_some() {
+            find_mislabeled(root, &mut Utf8PathBuf::from(*d), &policy, &mut mislabeled)?;
+        }
+    }
+    if mislabeled.is_empty() {
+        return lint_ok();
+    }
+    let total = mislabeled.len();
+    let found = mislabeled
+        .iter()
+        .map(|m| m.found.as_deref())
+        .collect::<BTreeSet<_>>();
+    // A container runtime applies a single label to everything.
+    if let [Some(label)] = found.into_iter().collect::<Vec<_>>().as_slice() {
+        if label.contains(":container_file_t:") {
+            return lint_err(format!(
+                "All {total} mislabeled paths have the label {label}; the labels of the image are likely not visible in this container"
+            ));
+        }
+    }
+    let groups = group_mislabeled(mislabeled);
+    let mut msg = format!("Found {total} paths with SELinux labels not matching the policy:\n");
+    if let Some((samples, rest)) = bootc_utils::iterator_split_nonempty_rest_count(groups.iter(), 5)
+    {
+        for m in samples {
+            let found = m.found.as_deref().unwrap_or("no label");
+            write!(msg, "  /{}: expected {}, found {found}", m.path, m.expected)?;
+            if m.below > 0 {
+                write!(msg, " (and {} more paths below)", m.below)?;
+            }
+            msg.push('\n');
+        }
+        if rest > 0 {
+            writeln!(msg, "  ...and {} more", rest)?;
+        }
+    }
+    lint_err(msg)
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::LazyLock;
@@ -634,7 +1363,7 @@ mod tests {
     static ALTROOT_LINTS: LazyLock<usize> = LazyLock::new(|| {
         LINTS
             .iter()
-            .filter(|lint| lint.root_type != Some(RootType::Running))
+            .filter(|lint| lint.root_type != Some(RootType::Running) && !lint.optional)
             .count()
     });
 
@@ -647,6 +1376,7 @@ mod tests {
         let root = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
         root.create_dir_all("usr/lib/modules/5.7.2")?;
         root.write("usr/lib/modules/5.7.2/vmlinuz", "vmlinuz")?;
+        root.write("usr/lib/modules/5.7.2/initramfs.img", "initramfs")?;
 
         root.create_dir("boot")?;
         root.create_dir("sysroot")?;
@@ -680,10 +1410,10 @@ mod tests {
         let mut out = Vec::new();
         let warnings = WarningDisposition::FatalWarnings;
         let root_type = RootType::Alternative;
-        lint(root, warnings, root_type, [], &mut out).unwrap();
+        lint(root, warnings, root_type, [], [], &mut out).unwrap();
         root.create_dir_all("var/run/foo")?;
         let mut out = Vec::new();
-        assert!(lint(root, warnings, root_type, [], &mut out).is_err());
+        assert!(lint(root, warnings, root_type, [], [], &mut out).is_err());
         Ok(())
     }
 
@@ -694,14 +1424,17 @@ mod tests {
         // Verify that all lints run
         let mut out = Vec::new();
         let root_type = RootType::Alternative;
-        let r = lint_inner(root, root_type, [], &mut out).unwrap();
-        let running_only_lints = LINTS.len().checked_sub(*ALTROOT_LINTS).unwrap();
+        let r = lint_inner(root, root_type, [], [], &mut out).unwrap();
+        let running_only_lints = LINTS
+            .iter()
+            .filter(|lint| lint.root_type == Some(RootType::Running) && !lint.optional)
+            .count();
         assert_eq!(r.passed, *ALTROOT_LINTS);
         assert_eq!(r.fatal, 0);
         assert_eq!(r.skipped, running_only_lints);
         assert_eq!(r.warnings, 0);
 
-        let r = lint_inner(root, root_type, ["var-log"], &mut out).unwrap();
+        let r = lint_inner(root, root_type, [], ["var-log"], &mut out).unwrap();
         // Trigger a failure in var-log
         root.create_dir_all("var/log/dnf")?;
         root.write("var/log/dnf/dnf.log", b"dummy dnf log")?;
@@ -712,11 +1445,18 @@ mod tests {
 
         // But verify that not skipping it results in a warning
         let mut out = Vec::new();
-        let r = lint_inner(root, root_type, [], &mut out).unwrap();
+        let r = lint_inner(root, root_type, [], [], &mut out).unwrap();
         assert_eq!(r.passed, ALTROOT_LINTS.checked_sub(1).unwrap());
         assert_eq!(r.fatal, 0);
         assert_eq!(r.skipped, running_only_lints);
         assert_eq!(r.warnings, 1);
+
+        // Optional lints only run when included
+        let mut out = Vec::new();
+        let r = lint_inner(root, root_type, ["selinux-labels"], [], &mut out).unwrap();
+        assert_eq!(r.passed, *ALTROOT_LINTS);
+        assert_eq!(r.skipped, running_only_lints);
+        assert_eq!(r.warnings, 1);
         Ok(())
     }
 
@@ -736,6 +1476,152 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_kernel_module_name() {
+        let cases = [
+            ("kernel/fs/xfs/xfs.ko.xz", Some("xfs")),
+            (
+                "usr/lib/modules/6.8.5/kernel/drivers/md/dm-crypt.ko.zst",
+                Some("dm_crypt"),
+            ),
+            ("extra/foo.ko", Some("foo")),
+            ("kernel/fs/xfs", None),
+            ("modules.dep", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(kernel_module_name(path).as_deref(), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_kargs_required_modules() {
+        let kargs = [
+            "rootfstype=xfs",
+            "rd.driver.pre=virtio-blk,nvme",
+            "rd.luks.uuid=abc",
+            "quiet",
+        ]
+        .map(ToOwned::to_owned);
+        let r = kargs_required_modules(&kargs);
+        assert_eq!(
+            r.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+            ["dm_crypt", "nvme", "virtio_blk", "xfs"]
+        );
+    }
+
+    #[test]
+    fn test_initramfs() -> Result<()> {
+        let root = &passing_fixture()?;
+        let kdir = Utf8Path::new("usr/lib/modules/5.7.2");
+        check_initramfs(root).unwrap().unwrap();
+
+        root.remove_file(kdir.join("initramfs.img"))?;
+        assert!(check_initramfs(root).unwrap().is_err());
+        root.write(kdir.join("initramfs.img"), "initramfs")?;
+        root.open(kdir.join("initramfs.img"))?
+            .into_std()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH)?;
+
+        // A module newer than the initramfs, which is missing from modules.dep
+        root.create_dir_all(kdir.join("kernel/fs/xfs"))?;
+        root.write(kdir.join("kernel/fs/xfs/xfs.ko.xz"), "xfs")?;
+        let e = check_initramfs(root).unwrap().unwrap_err().to_string();
+        assert!(e.contains("is older than /usr/lib/modules/5.7.2/kernel/fs/xfs/xfs.ko.xz"));
+        assert!(e.contains("Missing /usr/lib/modules/5.7.2/modules.dep"));
+
+        root.write(
+            kdir.join("modules.dep"),
+            "kernel/fs/xfs/xfs.ko.xz:\nkernel/fs/ext4/ext4.ko.xz:\n",
+        )?;
+        root.write(kdir.join("initramfs.img"), "initramfs")?;
+        let e = check_initramfs(root).unwrap().unwrap_err().to_string();
+        assert_eq!(e, "/usr/lib/modules/5.7.2/modules.dep lists missing module kernel/fs/ext4/ext4.ko.xz; regenerate it with depmod");
+        root.write(kdir.join("modules.dep"), "kernel/fs/xfs/xfs.ko.xz:\n")?;
+        root.write(kdir.join("initramfs.img"), "initramfs")?;
+        check_initramfs(root).unwrap().unwrap();
+
+        // Modules needed by kernel arguments must be installed, unless built in
+        root.create_dir_all("usr/lib/bootc/kargs.d")?;
+        root.write(
+            "usr/lib/bootc/kargs.d/10-rootfs.toml",
+            r#"kargs = ["rootfstype=btrfs"]"#,
+        )?;
+        let e = check_initramfs(root).unwrap().unwrap_err().to_string();
+        assert_eq!(
+            e,
+            "Kernel module btrfs needed by the kernel arguments or root filesystem is not installed"
+        );
+        root.write(kdir.join("modules.builtin"), "kernel/fs/btrfs/btrfs.ko\n")?;
+        check_initramfs(root).unwrap().unwrap();
+
+        // As well as the module for the root filesystem of the install configuration
+        root.create_dir_all("usr/lib/bootc/install")?;
+        root.write(
+            "usr/lib/bootc/install/00-base.toml",
+            "[install.filesystem.root]\ntype = \"ext4\"\n",
+        )?;
+        let e = check_initramfs(root).unwrap().unwrap_err().to_string();
+        assert_eq!(
+            e,
+            "Kernel module ext4 needed by the kernel arguments or root filesystem is not installed"
+        );
+        root.write(
+            kdir.join("modules.builtin"),
+            "kernel/fs/btrfs/btrfs.ko\nkernel/fs/ext4/ext4.ko\n",
+        )?;
+        check_initramfs(root).unwrap().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_bound_images() -> Result<()> {
+        const BOUND_IMAGE_DIR: &str = "usr/lib/bootc/bound-images.d";
+        const QUADLET_DIR: &str = "usr/share/containers/systemd";
+        let digest = "sha256:0f1e2d3c4b5a69788796a5b4c3d2e1f00f1e2d3c4b5a69788796a5b4c3d2e1f0";
+        let root = &fixture()?;
+        assert_eq!(check_bound_images(root).unwrap().unwrap(), None);
+        check_bound_images_pinned(root).unwrap().unwrap();
+
+        root.create_dir_all(BOUND_IMAGE_DIR)?;
+        root.create_dir_all(QUADLET_DIR)?;
+        root.write(
+            format!("{QUADLET_DIR}/app.container"),
+            format!("[Container]\nImage=quay.io/example/app@{digest}\n"),
+        )?;
+        root.symlink_contents(
+            format!("/{QUADLET_DIR}/app.container"),
+            format!("{BOUND_IMAGE_DIR}/app.container"),
+        )?;
+        let info = check_bound_images(root).unwrap().unwrap().unwrap();
+        assert!(info.contains(&format!("quay.io/example/app@{digest}: app.container")));
+        check_bound_images_pinned(root).unwrap().unwrap();
+
+        root.write(
+            format!("{QUADLET_DIR}/db.image"),
+            "[Image]\nImage=quay.io/example/db:latest\n",
+        )?;
+        root.symlink_contents(
+            format!("/{QUADLET_DIR}/db.image"),
+            format!("{BOUND_IMAGE_DIR}/db.image"),
+        )?;
+        let info = check_bound_images(root).unwrap().unwrap().unwrap();
+        assert!(info.contains("quay.io/example/db:latest: db.image"));
+        let e = check_bound_images_pinned(root).unwrap().unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("quay.io/example/db:latest: db.image"));
+        assert!(!e.to_string().contains("app.container"));
+
+        // A link to a file which is not in the image
+        root.symlink_contents(
+            format!("/{QUADLET_DIR}/missing.image"),
+            format!("{BOUND_IMAGE_DIR}/missing.image"),
+        )?;
+        let e = check_bound_images(root).unwrap().unwrap_err();
+        assert!(e.to_string().contains("missing.image: Link target"), "{e}");
+        Ok(())
+    }
+
     #[test]
     fn test_kargs() -> Result<()> {
         let root = &fixture()?;
@@ -746,6 +1632,46 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_install_config() -> Result<()> {
+        let root = &fixture()?;
+        check_install_config(root).unwrap().unwrap();
+        root.create_dir_all("usr/lib/bootc/install")?;
+        root.write(
+            "usr/lib/bootc/install/00-base.toml",
+            "[install]\nroot-fs-type = \"xfs\"\n",
+        )?;
+        check_install_config(root).unwrap().unwrap();
+
+        // A typo
+        root.write(
+            "usr/lib/bootc/install/10-typo.toml",
+            "[install]\nroot-fs-typ = \"xfs\"\n",
+        )?;
+        let e = check_install_config(root).unwrap().unwrap_err();
+        assert!(e.to_string().contains("10-typo.toml"), "{e}");
+        root.remove_file("usr/lib/bootc/install/10-typo.toml")?;
+
+        // A conflict, only for another architecture
+        root.write(
+            "usr/lib/bootc/install/20-other.toml",
+            indoc! { r#"
+            [install]
+            match-architectures = ["other"]
+            [install.filesystem.root]
+            type = "ext4"
+            "#},
+        )?;
+        let e = check_install_config(root).unwrap().unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("other: root-fs-type = xfs conflicts"),
+            "{e}"
+        );
+        assert!(!e.to_string().contains(&format!("{ARCH}:")), "{e}");
+        Ok(())
+    }
+
     #[test]
     fn test_usr_etc() -> Result<()> {
         let root = &fixture()?;
@@ -910,6 +1836,47 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_transient_etc() -> Result<()> {
+        let td = &passing_fixture()?;
+        // Not enabled
+        check_transient_etc(td).unwrap().unwrap();
+
+        td.create_dir_all("etc/ostree")?;
+        td.write(
+            "etc/ostree/prepare-root.conf",
+            "[composefs]\nenabled = true\n[etc]\ntransient = true\n",
+        )?;
+        assert!(check_transient_etc(td).unwrap().is_err());
+
+        td.symlink_contents("../var/lib/machine-id", "etc/machine-id")?;
+        check_transient_etc(td).unwrap().unwrap();
+        td.remove_file("etc/machine-id")?;
+        td.create_dir_all("usr/lib/bootc/kargs.d")?;
+        td.write(
+            "usr/lib/bootc/kargs.d/10-machine-id.toml",
+            r#"kargs = ["systemd.machine_id=0123456789abcdef0123456789abcdef"]"#,
+        )?;
+        check_transient_etc(td).unwrap().unwrap();
+
+        td.create_dir_all("usr/sbin")?;
+        td.write("usr/sbin/sshd", "")?;
+        assert!(check_transient_etc(td).unwrap().is_err());
+        td.create_dir_all("etc/ssh/sshd_config.d")?;
+        td.write(
+            "etc/ssh/sshd_config",
+            "HostKey /etc/ssh/ssh_host_ed25519_key\n",
+        )?;
+        assert!(check_transient_etc(td).unwrap().is_err());
+        td.write("etc/ssh/sshd_config", "PermitRootLogin no\n")?;
+        td.write(
+            "etc/ssh/sshd_config.d/10-hostkeys.conf",
+            "HostKey /var/lib/sshd/ssh_host_ed25519_key\n",
+        )?;
+        check_transient_etc(td).unwrap().unwrap();
+        Ok(())
+    }
+
     #[test]
     fn test_buildah_injected() -> Result<()> {
         let td = fixture()?;
@@ -922,6 +1889,76 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_import_filtered() -> Result<()> {
+        let root = &passing_fixture()?;
+        check_import_filtered(root).unwrap().unwrap();
+        root.create_dir_all("tmp/cache")?;
+        root.write("tmp/cache/foo", "foo")?;
+        root.write("tmp/bar", "bar")?;
+        root.create_dir_all("opt/app")?;
+        root.create_dir_all("var/lib/app")?;
+        root.create_dir("empty")?;
+        root.symlink("usr/bin", "bin")?;
+
+        let mut options = ostree_ext::tar::WriteTarOptions::default();
+        options.allow_nonusr = true;
+        options.retain_var = true;
+        let r = find_import_filtered(root, &options)?;
+        assert_eq!(r.dropped, [("tmp".to_owned(), 3)]);
+        assert!(r.moved.is_empty());
+
+        options.allow_nonusr = false;
+        options.retain_var = false;
+        let r = find_import_filtered(root, &options)?;
+        assert_eq!(r.dropped, [("opt".to_owned(), 1), ("tmp".to_owned(), 3)]);
+        assert_eq!(
+            r.moved,
+            [(
+                "var".to_owned(),
+                Utf8PathBuf::from("usr/share/factory/var"),
+                2
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_mislabeled() {
+        let m = |path: &str| Mislabeled {
+            path: path.into(),
+            expected: "system_u:object_r:lib_t:s0".into(),
+            found: Some("system_u:object_r:user_home_t:s0".into()),
+            below: 0,
+        };
+        let groups = group_mislabeled(vec![
+            m("usr/lib/foo"),
+            m("usr/lib/foo/a"),
+            m("usr/lib/foo/a/b"),
+            m("usr/lib/foobar"),
+            m("usr/lib/other/c"),
+        ]);
+        let groups = groups
+            .iter()
+            .map(|g| (g.path.as_str(), g.below))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            [
+                ("usr/lib/foo", 2),
+                ("usr/lib/foobar", 0),
+                ("usr/lib/other/c", 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_selinux_labels_no_policy() -> Result<()> {
+        let root = &passing_fixture()?;
+        check_selinux_labels(root).unwrap().unwrap();
+        Ok(())
+    }
+
     #[test]
     fn test_list() {
         let mut r = Vec::new();
@@ -230,6 +230,22 @@ pub(crate) fn has_security_selinux(root: &Dir, path: &Utf8Path) -> Result<SELinu
     }
 }
 
+/// Read the SELinux label of a path (without following symlinks), if it has one.
+pub(crate) fn get_security_selinux(root: &Dir, path: &Utf8Path) -> Result<Option<String>> {
+    // TODO: avoid hardcoding a max size here
+    let mut buf = [0u8; 2048];
+    let fdpath = format!("/proc/self/fd/{}/{path}", root.as_raw_fd());
+    match rustix::fs::lgetxattr(fdpath, "security.selinux", &mut buf) {
+        Ok(n) => {
+            // The value is usually NUL terminated
+            let label = buf[..n].strip_suffix(b"\0").unwrap_or(&buf[..n]);
+            Ok(Some(String::from_utf8_lossy(label).into_owned()))
+        }
+        Err(rustix::io::Errno::OPNOTSUPP) | Err(rustix::io::Errno::NODATA) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to look up context for {path:?}")),
+    }
+}
+
 pub(crate) fn set_security_selinux_path(root: &Dir, path: &Utf8Path, label: &[u8]) -> Result<()> {
     // TODO: avoid hardcoding a max size here
     let fdpath = format!("/proc/self/fd/{}/", root.as_raw_fd());
@@ -322,6 +338,50 @@ pub(crate) fn ensure_dir_labeled_recurse(
     Ok(())
 }
 
+/// Set the SELinux label of `path` and everything below it to the label the policy
+/// assigns, regardless of any existing label.
+pub(crate) fn relabel_recurse(
+    root: &Dir,
+    path: &mut Utf8PathBuf,
+    policy: &ostree::SePolicy,
+) -> Result<()> {
+    let mut as_path = path.clone();
+    relabel_recurse_as(root, path, &mut as_path, policy)
+}
+
+/// Like [`relabel_recurse`], but look up the labels as if `path` was located
+/// at `as_path` (relative to the root), e.g. for content staged under a
+/// temporary name.
+pub(crate) fn relabel_recurse_as(
+    root: &Dir,
+    path: &mut Utf8PathBuf,
+    as_path: &mut Utf8PathBuf,
+    policy: &ostree::SePolicy,
+) -> Result<()> {
+    let metadata = root.symlink_metadata(&*path)?;
+    let abspath = Utf8Path::new("/").join(&*as_path);
+    let label = require_label(policy, &abspath, metadata.mode())?;
+    tracing::trace!("Setting label for {path} to {label}");
+    set_security_selinux_path(root, path, label.as_bytes())
+        .with_context(|| format!("Labeling {path}"))?;
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+    for ent in root.read_dir(&*path)? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF-8 filename: {name:?}"))?;
+        path.push(name);
+        as_path.push(name);
+        relabel_recurse_as(root, path, as_path, policy)?;
+        path.pop();
+        as_path.pop();
+    }
+    Ok(())
+}
+
 /// A wrapper for creating a directory, also optionally setting a SELinux label.
 pub(crate) fn ensure_dir_labeled(
     root: &Dir,
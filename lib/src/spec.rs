@@ -159,6 +159,179 @@ pub struct HostStatus {
     /// The detected type of system
     #[serde(rename = "type")]
     pub ty: Option<HostType>,
+    /// The EFI System Partitions on the devices backing the root filesystem
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub esps: Vec<EspStatus>,
+    /// The state of the bootloader
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootloader: Option<BootloaderStatus>,
+    /// How the system was originally installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install: Option<InstallInfo>,
+    /// Deployments of stateroots other than the booted one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub other_stateroots: Vec<StaterootStatus>,
+    /// How `/etc` is managed in the booted system
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etc_mode: Option<EtcMode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+/// How `/etc` is managed
+pub enum EtcMode {
+    /// Local changes persist, and are merged with the image on updates
+    Persistent,
+    /// Reset to the default from the image on every boot
+    Transient,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+/// The deployments of a stateroot, i.e. an operating system installed side by
+/// side with the booted one
+pub struct StaterootStatus {
+    /// The name of the stateroot
+    pub name: String,
+    /// The deployments, in boot order
+    pub deployments: Vec<BootEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+/// The `bootc install` subcommand used to install a system
+pub enum InstallMode {
+    /// `bootc install to-disk`
+    ToDisk,
+    /// `bootc install to-disk-image`
+    ToDiskImage,
+    /// `bootc install to-filesystem`
+    ToFilesystem,
+    /// `bootc install to-existing-root`
+    ToExistingRoot,
+}
+
+impl Display for InstallMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            InstallMode::ToDisk => "to-disk",
+            InstallMode::ToDiskImage => "to-disk-image",
+            InstallMode::ToFilesystem => "to-filesystem",
+            InstallMode::ToExistingRoot => "to-existing-root",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+/// Information recorded at install time.  Fields other than `image`
+/// may be unset for systems installed by older versions of bootc.
+pub struct InstallInfo {
+    /// The installed image, by digest
+    pub image: String,
+    /// The version of the installed image, if any
+    pub version: Option<String>,
+    /// The build timestamp of the installed image, if any
+    pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    /// The version of bootc which performed the installation
+    pub bootc_version: Option<String>,
+    /// The `uname -r` of the kernel performing the installation
+    pub kernel: String,
+    /// The state of SELinux at install time
+    pub selinux: String,
+    /// How the system was installed
+    pub mode: Option<InstallMode>,
+    /// The block device setup for `to-disk`, e.g. `direct` or `tpm2-luks`
+    pub block_setup: Option<String>,
+    /// The type of the root filesystem
+    pub filesystem: Option<String>,
+    /// The stateroot of the initial deployment
+    pub stateroot: Option<String>,
+    /// The kernel arguments of the initial deployment
+    #[serde(default)]
+    pub kargs: Vec<String>,
+    /// The logically bound images pulled at install time
+    #[serde(default)]
+    pub bound_images: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+/// The software managing the bootloader
+pub enum BootloaderBackend {
+    /// bootupd, managing e.g. shim and grub
+    Bootupd,
+    /// systemd-boot, booting Unified Kernel Images
+    SystemdBoot,
+    /// zipl on s390x
+    Zipl,
+}
+
+impl Display for BootloaderBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            BootloaderBackend::Bootupd => "bootupd",
+            BootloaderBackend::SystemdBoot => "systemd-boot",
+            BootloaderBackend::Zipl => "zipl",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+/// Whether a bootloader component can be updated
+pub enum BootloaderUpdate {
+    /// The installed version matches the booted deployment
+    UpToDate,
+    /// The booted deployment has a newer version
+    Available,
+    /// The booted deployment has an older version
+    WouldDowngrade,
+    /// The state could not be determined
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+/// A separately updated part of the bootloader, e.g. its EFI or BIOS part
+pub struct BootloaderComponent {
+    /// The name of the component
+    pub name: String,
+    /// The installed version
+    pub installed: Option<String>,
+    /// The version in the booted deployment
+    pub available: Option<String>,
+    /// Whether an update is available
+    pub update: BootloaderUpdate,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+/// The state of the bootloader
+pub struct BootloaderStatus {
+    /// The software managing the bootloader
+    pub backend: BootloaderBackend,
+    /// The components of the bootloader; unset if they could not be determined
+    #[serde(default)]
+    pub components: Option<Vec<BootloaderComponent>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+/// The state of an EFI System Partition
+pub struct EspStatus {
+    /// Path to the partition
+    pub device: String,
+    /// Path to the disk holding the partition
+    pub parent: String,
+    /// Whether this ESP is the source of bootloader updates for the others
+    pub primary: bool,
+    /// SHA-256 digest of the bootloader content, unset if there is none
+    pub digest: Option<String>,
+    /// Whether the bootloader content matches the primary ESP
+    pub in_sync: bool,
 }
 
 impl Host {
@@ -178,6 +351,23 @@ impl Host {
             status: Default::default(),
         }
     }
+
+    /// The spec of another stateroot, derived from the deployment it boots
+    /// next; it has no image if the stateroot has no deployments.
+    pub(crate) fn other_stateroot_spec(&self, name: &str) -> HostSpec {
+        let image = self
+            .status
+            .other_stateroots
+            .iter()
+            .find(|s| s.name == name)
+            .and_then(|s| s.deployments.first())
+            .and_then(|entry| entry.image.as_ref())
+            .map(|img| img.image.clone());
+        HostSpec {
+            image,
+            boot_order: BootOrder::Default,
+        }
+    }
 }
 
 impl Default for Host {
@@ -261,6 +451,20 @@ mod tests {
         assert_eq!(host.spec.image.as_ref().unwrap().signature, None);
     }
 
+    #[test]
+    fn test_other_stateroot_spec() {
+        const SPEC_FIXTURE: &str = include_str!("fixtures/spec-booted-stateroots.yaml");
+        let host: Host = serde_yaml::from_str(SPEC_FIXTURE).unwrap();
+        let spec = host.other_stateroot_spec("fedora");
+        assert_eq!(
+            spec.image.as_ref().unwrap().image,
+            "quay.io/fedora/fedora-bootc:41"
+        );
+        assert_eq!(spec.boot_order, BootOrder::Default);
+        assert_ne!(spec, host.spec);
+        assert_eq!(host.other_stateroot_spec("rhel"), HostSpec::default());
+    }
+
     #[test]
     fn test_parse_ostreeremote() {
         const SPEC_FIXTURE: &str = include_str!("fixtures/spec-ostree-remote.yaml");
@@ -5,6 +5,8 @@ use std::io::Read;
 use std::io::Write;
 
 use anyhow::{Context, Result};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
 use fn_error_context::context;
 use ostree::glib;
 use ostree_container::OstreeImageReference;
@@ -13,9 +15,10 @@ use ostree_ext::container_utils::ostree_booted;
 use ostree_ext::keyfileext::KeyFileExt;
 use ostree_ext::oci_spec;
 use ostree_ext::ostree;
+use ostree_ext::ostree_prepareroot;
 
 use crate::cli::OutputFormat;
-use crate::spec::{BootEntry, BootOrder, Host, HostSpec, HostStatus, HostType};
+use crate::spec::{BootEntry, BootOrder, EtcMode, Host, HostSpec, HostStatus, HostType};
 use crate::spec::{ImageReference, ImageSignature};
 use crate::store::{CachedImageStatus, ContainerImageStore, Storage};
 
@@ -226,6 +229,25 @@ pub(crate) fn get_status(
         BootOrder::Default
     };
     tracing::debug!("Rollback queued={rollback_queued:?}");
+    // Only group by stateroot if we know which one is ours
+    let other_stateroots = if stateroot.is_some() {
+        let mut r = Vec::<crate::spec::StaterootStatus>::new();
+        for d in other_deployments.iter() {
+            let entry = boot_entry_from_deployment(sysroot, d)
+                .with_context(|| format!("Deployment of stateroot {}", d.osname()))?;
+            let name = d.osname();
+            match r.iter_mut().find(|s| s.name == name.as_str()) {
+                Some(s) => s.deployments.push(entry),
+                None => r.push(crate::spec::StaterootStatus {
+                    name: name.into(),
+                    deployments: vec![entry],
+                }),
+            }
+        }
+        r
+    } else {
+        Vec::new()
+    };
     let other = {
         related_deployments.extend(other_deployments);
         related_deployments
@@ -281,10 +303,28 @@ pub(crate) fn get_status(
         rollback,
         rollback_queued,
         ty,
+        esps: Vec::new(),
+        bootloader: None,
+        install: None,
+        other_stateroots,
+        etc_mode: None,
     };
     Ok((deployments, host))
 }
 
+/// Determine how `/etc` is managed from the prepare-root configuration in `root`.
+fn etc_mode(root: &Dir) -> Result<EtcMode> {
+    let transient = match ostree_prepareroot::load_config_from_root(root)? {
+        Some(config) => ostree_prepareroot::transient_etc_enabled_in_config(&config)?,
+        None => false,
+    };
+    Ok(if transient {
+        EtcMode::Transient
+    } else {
+        EtcMode::Persistent
+    })
+}
+
 /// Implementation of the `bootc status` CLI command.
 #[context("Status")]
 pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
@@ -298,7 +338,31 @@ pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
     } else {
         let sysroot = super::cli::get_storage().await?;
         let booted_deployment = sysroot.booted_deployment();
-        let (_deployments, host) = get_status(&sysroot, booted_deployment.as_ref())?;
+        let (_deployments, mut host) = get_status(&sysroot, booted_deployment.as_ref())?;
+        let sysroot_dir = crate::utils::sysroot_dir(&sysroot)?;
+        match crate::install::InstallAleph::read(&sysroot_dir) {
+            Ok(aleph) => host.status.install = aleph.map(Into::into),
+            Err(e) => tracing::debug!("Failed to read install metadata: {e:#}"),
+        }
+        if booted_deployment.is_some() {
+            let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+            match etc_mode(&root) {
+                Ok(mode) => host.status.etc_mode = Some(mode),
+                Err(e) => tracing::debug!("Failed to determine /etc mode: {e:#}"),
+            }
+        }
+        // Inspecting the bootloader runs bootupctl and mounts the ESPs, so it's
+        // opt-in and best-effort
+        if opts.bootloader {
+            match crate::bootloader::esp_status() {
+                Ok(esps) => host.status.esps = esps,
+                Err(e) => tracing::warn!("Failed to gather ESP status: {e:#}"),
+            }
+            match crate::bootloader::status(&sysroot) {
+                Ok(bootloader) => host.status.bootloader = Some(bootloader),
+                Err(e) => tracing::warn!("Failed to gather bootloader status: {e:#}"),
+            }
+        }
         host
     };
 
@@ -433,6 +497,115 @@ fn human_readable_output_booted(mut out: impl Write, host: &Host) -> Result<()>
             }
         }
     }
+    if host.status.etc_mode == Some(EtcMode::Transient) {
+        writeln!(out)?;
+        writeln!(out, "/etc: transient (reset from the image on every boot)")?;
+    }
+    // With a single ESP there's nothing interesting to show
+    if host.status.esps.len() > 1 {
+        writeln!(out)?;
+        writeln!(out, "EFI System Partitions:")?;
+        for esp in host.status.esps.iter() {
+            let state = if esp.primary {
+                "primary"
+            } else if esp.in_sync {
+                "in sync"
+            } else {
+                "out of sync"
+            };
+            writeln!(out, "  {}: {state}", esp.device)?;
+        }
+    }
+    if let Some(bootloader) = host.status.bootloader.as_ref() {
+        writeln!(out)?;
+        human_render_bootloader(&mut out, bootloader)?;
+    }
+    for stateroot in host.status.other_stateroots.iter() {
+        writeln!(out)?;
+        human_render_stateroot(&mut out, stateroot)?;
+    }
+    if let Some(install) = host.status.install.as_ref() {
+        writeln!(out)?;
+        human_render_install(&mut out, install)?;
+    }
+    Ok(())
+}
+
+/// Write the deployments of a stateroot other than the booted one.
+fn human_render_stateroot(
+    mut out: impl Write,
+    stateroot: &crate::spec::StaterootStatus,
+) -> Result<()> {
+    writeln!(out, "Stateroot: {}", stateroot.name)?;
+    for entry in stateroot.deployments.iter() {
+        if let Some(image) = entry.image.as_ref() {
+            let imagename = &image.image.image;
+            let digest = &image.image_digest;
+            match image.version.as_deref() {
+                Some(version) => writeln!(out, "  {imagename} ({version}, {digest})")?,
+                None => writeln!(out, "  {imagename} ({digest})")?,
+            }
+        } else if let Some(ostree) = entry.ostree.as_ref() {
+            writeln!(out, "  ostree {}", ostree.checksum)?;
+        } else {
+            writeln!(out, "  (unknown)")?;
+        }
+    }
+    Ok(())
+}
+
+/// Write how the system was installed.
+fn human_render_install(mut out: impl Write, install: &crate::spec::InstallInfo) -> Result<()> {
+    let prefix = "Installed";
+    let prefix_len = prefix.len();
+    write!(out, "{prefix}: {}", install.image)?;
+    if let Some(timestamp) = install.timestamp.as_ref() {
+        write!(out, " ({timestamp})")?;
+    }
+    writeln!(out)?;
+    if let Some(mode) = install.mode {
+        write_row_name(&mut out, "Via", prefix_len)?;
+        write!(out, "bootc install {mode}")?;
+        if let Some(version) = install.bootc_version.as_deref() {
+            write!(out, " (bootc {version})")?;
+        }
+        writeln!(out)?;
+    }
+    if let Some(fs) = install.filesystem.as_deref() {
+        write_row_name(&mut out, "Rootfs", prefix_len)?;
+        match install.block_setup.as_deref() {
+            Some(block_setup) => writeln!(out, "{fs} ({block_setup})")?,
+            None => writeln!(out, "{fs}")?,
+        }
+    }
+    Ok(())
+}
+
+/// Write the state of the bootloader.
+pub(crate) fn human_render_bootloader(
+    mut out: impl Write,
+    bootloader: &crate::spec::BootloaderStatus,
+) -> Result<()> {
+    let prefix = "Bootloader";
+    let prefix_len = prefix.len();
+    let Some(components) = bootloader.components.as_ref() else {
+        writeln!(out, "{prefix}: {} (state unknown)", bootloader.backend)?;
+        return Ok(());
+    };
+    writeln!(out, "{prefix}: {}", bootloader.backend)?;
+    for component in components.iter() {
+        write_row_name(&mut out, &component.name, prefix_len)?;
+        let installed = component.installed.as_deref().unwrap_or("unknown");
+        match (component.update, component.available.as_deref()) {
+            (crate::spec::BootloaderUpdate::Available, Some(available)) => {
+                writeln!(out, "{installed} (update available: {available})")?
+            }
+            (crate::spec::BootloaderUpdate::WouldDowngrade, _) => {
+                writeln!(out, "{installed} (newer than booted deployment)")?
+            }
+            _ => writeln!(out, "{installed}")?,
+        }
+    }
     Ok(())
 }
 
@@ -542,6 +715,100 @@ mod tests {
         similar_asserts::assert_eq!(w, expected);
     }
 
+    #[test]
+    fn test_human_readable_esps() {
+        let w =
+            human_status_from_spec_fixture(include_str!("fixtures/spec-booted-esps.yaml")).unwrap();
+        let expected = indoc::indoc! { r"
+          ● Booted image: quay.io/centos-bootc/centos-bootc:stream9
+                  Digest: sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38
+                 Version: stream9.20240807.0
+
+          EFI System Partitions:
+            /dev/disk/by-id/wwn-0x5000c500a1b2c3d4-part2: primary
+            /dev/disk/by-id/wwn-0x5000c500e5f6a7b8-part2: out of sync
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
+
+    #[test]
+    fn test_human_readable_bootloader() {
+        let w =
+            human_status_from_spec_fixture(include_str!("fixtures/spec-booted-bootloader.yaml"))
+                .unwrap();
+        let expected = indoc::indoc! { r"
+          ● Booted image: quay.io/centos-bootc/centos-bootc:stream9
+                  Digest: sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38
+                 Version: stream9.20240807.0
+
+          Bootloader: bootupd
+                BIOS: grub2-tools-1:2.06-95.el9
+                 EFI: grub2-efi-x64-1:2.06-95.el9 (update available: grub2-efi-x64-1:2.12-1.el9)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
+
+    #[test]
+    fn test_human_render_bootloader_unknown() {
+        let status = crate::spec::BootloaderStatus {
+            backend: crate::spec::BootloaderBackend::Bootupd,
+            components: None,
+        };
+        let mut w = Vec::new();
+        human_render_bootloader(&mut w, &status).unwrap();
+        assert_eq!(
+            String::from_utf8(w).unwrap(),
+            "Bootloader: bootupd (state unknown)\n"
+        );
+    }
+
+    #[test]
+    fn test_human_readable_install() {
+        let w = human_status_from_spec_fixture(include_str!("fixtures/spec-booted-install.yaml"))
+            .unwrap();
+        let expected = indoc::indoc! { r"
+          ● Booted image: quay.io/centos-bootc/centos-bootc:stream9
+                  Digest: sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38
+                 Version: stream9.20240807.0
+
+          Installed: quay.io/centos-bootc/centos-bootc@sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38 (2024-08-07 12:00:00 UTC)
+                Via: bootc install to-disk (bootc 1.1.4)
+             Rootfs: xfs (tpm2-luks)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
+
+    #[test]
+    fn test_human_readable_stateroots() {
+        let w =
+            human_status_from_spec_fixture(include_str!("fixtures/spec-booted-stateroots.yaml"))
+                .unwrap();
+        let expected = indoc::indoc! { r"
+          ● Booted image: quay.io/centos-bootc/centos-bootc:stream9
+                  Digest: sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38
+                 Version: stream9.20240807.0
+
+          Stateroot: fedora
+            quay.io/fedora/fedora-bootc:41 (41.20241001.0, sha256:8c0d3c5d6a0bfbc6a1e2ac3c6f5d7c8c1f4f7a3b9e2d0c6b5a4f3e2d1c0b9a87)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
+
+    #[test]
+    fn test_human_readable_transient_etc() {
+        let w =
+            human_status_from_spec_fixture(include_str!("fixtures/spec-booted-transient-etc.yaml"))
+                .unwrap();
+        let expected = indoc::indoc! { r"
+          ● Booted image: quay.io/centos-bootc/centos-bootc:stream9
+                  Digest: sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38
+                 Version: stream9.20240807.0
+
+          /etc: transient (reset from the image on every boot)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
+
     #[test]
     fn test_convert_signatures() {
         use std::str::FromStr;
@@ -158,6 +158,14 @@ pub fn overlayfs_enabled_in_config(config: &glib::KeyFile) -> Result<bool> {
     Ok(root_transient || composefs.maybe_enabled())
 }
 
+/// Query whether the config enables a transient `/etc`, which is reset to the
+/// default from the image on every boot.
+pub fn transient_etc_enabled_in_config(config: &glib::KeyFile) -> Result<bool> {
+    Ok(config
+        .optional_bool("etc", "transient")?
+        .unwrap_or_default())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -228,4 +236,26 @@ enabled = false
             assert!(overlayfs_enabled_in_config(&kf).unwrap());
         }
     }
+
+    #[test]
+    fn test_transient_etc_enabled() {
+        let d0 = indoc::indoc! { r#"
+[root]
+transient = true
+"# };
+        let d1 = indoc::indoc! { r#"
+[etc]
+transient = false
+"# };
+        for v in ["", d0, d1] {
+            let kf = glib::KeyFile::new();
+            kf.load_from_data(v, glib::KeyFileFlags::empty()).unwrap();
+            assert!(!transient_etc_enabled_in_config(&kf).unwrap());
+        }
+
+        let e0 = "[composefs]\nenabled = yes\n[etc]\ntransient = true\n";
+        let kf = glib::KeyFile::new();
+        kf.load_from_data(e0, glib::KeyFileFlags::empty()).unwrap();
+        assert!(transient_etc_enabled_in_config(&kf).unwrap());
+    }
 }
@@ -238,6 +238,34 @@ fn normalize_validate_path<'a>(
     Ok(NormalizedPathResult::Normal(ret))
 }
 
+/// How a path in a container image layer is handled by [`write_tar`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportedPath {
+    /// The path is imported, possibly at a different location (e.g. content in
+    /// `var` may be moved to `usr/share/factory/var`). This is the relative path
+    /// in the resulting commit.
+    Imported(Utf8PathBuf),
+    /// The path is not imported.
+    Filtered,
+}
+
+/// Predict how [`write_tar`] with the provided options handles a path in a layer,
+/// using the same filtering logic.
+pub fn imported_path(path: &Utf8Path, options: &WriteTarOptions) -> Result<ImportedPath> {
+    let config = TarImportConfig {
+        allow_nonusr: options.allow_nonusr,
+        remap_factory_var: !options.retain_var,
+    };
+    let path = path.strip_prefix("/").unwrap_or(path);
+    let r = match normalize_validate_path(path, &config)? {
+        NormalizedPathResult::Filtered(_) => ImportedPath::Filtered,
+        NormalizedPathResult::Normal(p) => {
+            ImportedPath::Imported(p.strip_prefix(".").map(ToOwned::to_owned).unwrap_or(p))
+        }
+    };
+    Ok(r)
+}
+
 /// Perform various filtering on imported tar archives.
 ///  - Move /etc to /usr/etc
 ///  - Entirely drop files not in /usr
@@ -588,6 +616,34 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_imported_path() -> Result<()> {
+        let mut options = WriteTarOptions::default();
+        let cases = [
+            ("/usr/bin/blah", Some("usr/bin/blah")),
+            ("etc/foo", Some("usr/etc/foo")),
+            ("var/lib/foo", Some("usr/share/factory/var/lib/foo")),
+            ("tmp/foo", None),
+            ("opt/foo", None),
+        ];
+        for (k, v) in cases {
+            let expected = v.map_or(ImportedPath::Filtered, |v| ImportedPath::Imported(v.into()));
+            assert_eq!(imported_path(k.into(), &options)?, expected, "{k}");
+        }
+        options.allow_nonusr = true;
+        options.retain_var = true;
+        let cases = [
+            ("var/lib/foo", Some("var/lib/foo")),
+            ("tmp/foo", None),
+            ("opt/foo", Some("opt/foo")),
+        ];
+        for (k, v) in cases {
+            let expected = v.map_or(ImportedPath::Filtered, |v| ImportedPath::Imported(v.into()));
+            assert_eq!(imported_path(k.into(), &options)?, expected, "{k}");
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn tar_filter() -> Result<()> {
         let tempd = tempfile::tempdir()?;
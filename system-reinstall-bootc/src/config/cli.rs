@@ -4,4 +4,8 @@ use clap::Parser;
 pub(crate) struct Cli {
     /// The bootc container image to install, e.g. quay.io/fedora/fedora-bootc:41
     pub(crate) bootc_image: String,
+
+    /// Copy the hostname and network configuration of this system into the new one.
+    #[clap(long)]
+    pub(crate) copy_network_config: bool,
 }
@@ -10,6 +10,10 @@ pub(crate) struct ReinstallConfig {
     /// The bootc image to install on the system.
     pub(crate) bootc_image: String,
 
+    /// Carry the hostname and network configuration over to the new system.
+    #[serde(default)]
+    pub(crate) copy_network_config: bool,
+
     /// The raw CLI arguments that were used to invoke the program. None if the config was loaded
     /// from a file.
     #[serde(skip_deserializing)]
@@ -20,6 +24,7 @@ impl ReinstallConfig {
     pub fn parse_from_cli(cli: cli::Cli) -> Self {
         Self {
             bootc_image: cli.bootc_image,
+            copy_network_config: cli.copy_network_config,
             cli_flags: Some(std::env::args().collect::<Vec<String>>()),
         }
     }
@@ -30,7 +30,11 @@ fn run() -> Result<()> {
 
     prompt::get_ssh_keys(ssh_key_file_path)?;
 
-    let mut reinstall_podman_command = podman::command(&config.bootc_image, ssh_key_file_path);
+    let mut reinstall_podman_command = podman::command(
+        &config.bootc_image,
+        ssh_key_file_path,
+        config.copy_network_config,
+    );
 
     println!();
 
@@ -4,7 +4,7 @@ use bootc_utils::CommandRunExt;
 use std::process::Command;
 use which::which;
 
-pub(crate) fn command(image: &str, ssh_key_file: &str) -> Command {
+pub(crate) fn command(image: &str, ssh_key_file: &str, copy_network_config: bool) -> Command {
     let mut podman_command_and_args = [
         // We use podman to run the bootc container. This might change in the future to remove the
         // podman dependency.
@@ -49,6 +49,10 @@ pub(crate) fn command(image: &str, ssh_key_file: &str) -> Command {
     bootc_command_and_args.push("--root-ssh-authorized-keys".to_string());
     bootc_command_and_args.push(ROOT_KEY_MOUNT_POINT.to_string());
 
+    if copy_network_config {
+        bootc_command_and_args.push("--copy-network-config".to_string());
+    }
+
     let all_args = [
         podman_command_and_args,
         vec![image.to_string()],
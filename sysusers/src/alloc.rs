@@ -0,0 +1,557 @@
+//! Offline allocation of users and groups, as done by `systemd-sysusers`.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::BTreeSet;
+use std::io::BufReader;
+
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+
+use crate::nameservice::group::GroupEntry;
+use crate::nameservice::gshadow::GshadowEntry;
+use crate::nameservice::passwd::PasswdEntry;
+use crate::nameservice::shadow::ShadowEntry;
+use crate::nameservice::{group, gshadow, passwd, shadow};
+use crate::{Error, GroupReference, IdMapping, Result, SysusersEntry};
+
+/// The range used for allocating IDs if there are no `r` lines.
+const DEFAULT_RANGE: (u32, u32) = (1, 999);
+/// The shell of users other than root, unless specified.
+const NOLOGIN: &str = "/usr/sbin/nologin";
+
+/// The user and group databases in `/etc` (`passwd`, `shadow`, `group` and `gshadow`).
+#[derive(Debug, Clone, Default)]
+pub struct UserDatabase {
+    passwd: Vec<PasswdEntry>,
+    shadow: Vec<ShadowEntry>,
+    group: Vec<GroupEntry>,
+    gshadow: Vec<GshadowEntry>,
+    /// Users defined in `/usr/lib/passwd`, which are not part of `/etc/passwd`
+    alt_users: BTreeSet<String>,
+    /// Groups defined in `/usr/lib/group`, which are not part of `/etc/group`
+    alt_groups: BTreeSet<String>,
+}
+
+/// Render entries in the format of their database file.
+fn render<'a, T: 'a>(
+    entries: impl IntoIterator<Item = &'a T>,
+    f: impl Fn(&T, &mut Vec<u8>) -> anyhow::Result<()>,
+) -> String {
+    let mut buf = Vec::new();
+    for e in entries {
+        // SAFETY: Writing to a Vec cannot fail
+        f(e, &mut buf).unwrap();
+    }
+    // SAFETY: All fields are strings
+    String::from_utf8(buf).unwrap()
+}
+
+/// Find the highest ID in `ranges` that is at most `cursor` and for which `ok`
+/// returns true, and move the cursor below it.
+fn search(
+    ranges: &[(u32, u32)],
+    cursor: &mut Option<u32>,
+    ok: impl Fn(u32) -> bool,
+) -> Option<u32> {
+    while let Some(max) = *cursor {
+        let candidate = ranges
+            .iter()
+            .filter(|(start, _)| *start <= max)
+            .map(|(_, end)| (*end).min(max))
+            .max()?;
+        *cursor = candidate.checked_sub(1);
+        if ok(candidate) {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+/// The pending changes while processing sysusers.d entries.
+struct Allocator<'a> {
+    db: &'a mut UserDatabase,
+    ranges: Vec<(u32, u32)>,
+    uid_cursor: Option<u32>,
+    gid_cursor: Option<u32>,
+    last_change: u32,
+}
+
+impl Allocator<'_> {
+    /// Whether `uid` is free for user `name`; if `check_gid` is set, it must also not
+    /// be used as a GID by a group with a different name.
+    fn uid_ok(&self, uid: u32, name: &str, check_gid: bool) -> bool {
+        !self.db.passwd.iter().any(|p| p.uid == uid)
+            && !(check_gid && self.db.group.iter().any(|g| g.gid == uid && g.name != name))
+    }
+
+    /// Whether `gid` is free for group `name`; if `check_uid` is set, it must also not
+    /// be used as a UID by a user with a different name.
+    fn gid_ok(&self, gid: u32, name: &str, check_uid: bool) -> bool {
+        !self.db.group.iter().any(|g| g.gid == gid)
+            && !(check_uid
+                && self
+                    .db
+                    .passwd
+                    .iter()
+                    .any(|p| p.uid == gid && p.name != name))
+    }
+
+    /// Create group `name` unless it exists, returning its GID. The requested `gid`
+    /// is used if free; otherwise `uid` (of the user with the same name), and otherwise
+    /// one is allocated.
+    fn add_group(&mut self, name: &str, gid: Option<u32>, uid: Option<u32>) -> Result<u32> {
+        if let Some(gid) = self.db.gid(name) {
+            return Ok(gid);
+        }
+        let gid = gid
+            .filter(|&gid| self.gid_ok(gid, name, false))
+            .or_else(|| uid.filter(|&uid| self.gid_ok(uid, name, true)));
+        let gid = match gid {
+            Some(gid) => gid,
+            None => {
+                let mut cursor = self.gid_cursor;
+                let r = search(&self.ranges, &mut cursor, |gid| {
+                    self.gid_ok(gid, name, true)
+                });
+                self.gid_cursor = cursor;
+                r.ok_or_else(|| Error::AllocationFailure(format!("a GID for group {name}")))?
+            }
+        };
+        self.db.group.push(GroupEntry {
+            name: name.to_owned(),
+            passwd: "x".into(),
+            gid,
+            users: Vec::new(),
+        });
+        self.db.gshadow.push(GshadowEntry {
+            name: name.to_owned(),
+            passwd: "!*".into(),
+            admins: Vec::new(),
+            members: Vec::new(),
+        });
+        Ok(gid)
+    }
+
+    /// Create a user unless it exists, along with its primary group.
+    fn add_user(&mut self, entry: &SysusersEntry) -> Result<()> {
+        let SysusersEntry::User {
+            name,
+            uid,
+            pgid,
+            gecos,
+            home,
+            shell,
+            locked,
+            ..
+        } = entry
+        else {
+            return Ok(());
+        };
+        let gid = match pgid {
+            Some(GroupReference::Name(g)) => self.db.gid(g).ok_or_else(|| {
+                Error::AllocationFailure(format!("user {name}: unknown primary group {g}"))
+            })?,
+            // An existing group with that GID is used; otherwise a group with the
+            // name of the user is created with it
+            Some(GroupReference::Numeric(gid)) => {
+                if self.db.group.iter().any(|g| g.gid == *gid) {
+                    *gid
+                } else {
+                    self.add_group(name, Some(*gid), None)?
+                }
+            }
+            None => self.add_group(name, None, *uid)?,
+        };
+        if self.db.uid(name).is_some() {
+            return Ok(());
+        }
+        let uid = uid
+            .filter(|&uid| self.uid_ok(uid, name, false))
+            .or_else(|| Some(gid).filter(|&gid| self.uid_ok(gid, name, true)));
+        let uid = match uid {
+            Some(uid) => uid,
+            None => {
+                let mut cursor = self.uid_cursor;
+                let r = search(&self.ranges, &mut cursor, |uid| {
+                    self.uid_ok(uid, name, true)
+                });
+                self.uid_cursor = cursor;
+                r.ok_or_else(|| Error::AllocationFailure(format!("a UID for user {name}")))?
+            }
+        };
+        let default_shell = if uid == 0 { "/bin/sh" } else { NOLOGIN };
+        self.db.passwd.push(PasswdEntry {
+            name: name.clone(),
+            passwd: "x".into(),
+            uid,
+            gid,
+            gecos: gecos.clone().unwrap_or_default(),
+            home_dir: home.clone().unwrap_or_else(|| "/".into()),
+            shell: shell.clone().unwrap_or_else(|| default_shell.into()),
+        });
+        self.db.shadow.push(ShadowEntry {
+            namp: name.clone(),
+            pwdp: "!*".into(),
+            lstchg: Some(self.last_change),
+            min: None,
+            max: None,
+            warn: None,
+            inact: None,
+            // Locked accounts are expired
+            expire: locked.then_some(1),
+            flag: String::new(),
+        });
+        Ok(())
+    }
+
+    /// Add `user` as a member of `group`.
+    fn add_member(&mut self, user: &str, group: &str) {
+        if let Some(g) = self.db.group.iter_mut().find(|g| g.name == group) {
+            g.users.retain(|u| !u.is_empty());
+            if !g.users.iter().any(|u| u == user) {
+                g.users.push(user.to_owned());
+            }
+        }
+        if let Some(g) = self.db.gshadow.iter_mut().find(|g| g.name == group) {
+            if !g.members.iter().any(|u| u == user) {
+                g.members.push(user.to_owned());
+            }
+        }
+    }
+}
+
+impl UserDatabase {
+    /// Load the databases from `/etc` in the target root; missing files are empty.
+    pub fn load(rootfs: &Dir) -> Result<Self> {
+        let passwd = passwd::load_etc_passwd(rootfs)
+            .map_err(|e| Error::PasswdLoadFailure(e.to_string()))?
+            .unwrap_or_default();
+        let shadow = shadow::load_etc_shadow(rootfs)
+            .map_err(|e| Error::ShadowLoadFailure(e.to_string()))?
+            .unwrap_or_default();
+        let group = if rootfs.try_exists("etc/group")? {
+            group::load_etc_group(rootfs).map_err(|e| Error::GroupLoadFailure(e.to_string()))?
+        } else {
+            Vec::new()
+        };
+        let gshadow = gshadow::load_etc_gshadow(rootfs)
+            .map_err(|e| Error::GshadowLoadFailure(e.to_string()))?
+            .unwrap_or_default();
+        Ok(Self {
+            passwd,
+            shadow,
+            group,
+            gshadow,
+            ..Default::default()
+        })
+    }
+
+    /// Add the users and groups from `/usr/lib/passwd` and `/usr/lib/group`, as used
+    /// by nss-altfiles, which are not already defined in `/etc`. They are taken into
+    /// account when allocating IDs, but are not part of the rendered `/etc` databases.
+    pub(crate) fn add_altfiles(&mut self, rootfs: &Dir) -> Result<()> {
+        if let Some(r) = rootfs.open_optional("usr/lib/passwd")? {
+            let entries = passwd::parse_passwd_content(BufReader::new(r))
+                .map_err(|e| Error::PasswdLoadFailure(format!("usr/lib/passwd: {e}")))?;
+            for e in entries {
+                if self.uid(&e.name).is_none() {
+                    self.alt_users.insert(e.name.clone());
+                    self.passwd.push(e);
+                }
+            }
+        }
+        if let Some(r) = rootfs.open_optional("usr/lib/group")? {
+            let entries = group::parse_group_content(BufReader::new(r))
+                .map_err(|e| Error::GroupLoadFailure(format!("usr/lib/group: {e}")))?;
+            for e in entries {
+                if self.gid(&e.name).is_none() {
+                    self.alt_groups.insert(e.name.clone());
+                    self.group.push(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up the UID of a user.
+    pub fn uid(&self, name: &str) -> Option<u32> {
+        self.passwd.iter().find(|p| p.name == name).map(|p| p.uid)
+    }
+
+    /// Look up the GID of a group.
+    pub fn gid(&self, name: &str) -> Option<u32> {
+        self.group.iter().find(|g| g.name == name).map(|g| g.gid)
+    }
+
+    /// The IDs of all users and groups.
+    pub fn ids(&self) -> IdMapping {
+        IdMapping {
+            users: self
+                .passwd
+                .iter()
+                .map(|p| (p.name.clone(), p.uid))
+                .collect(),
+            groups: self.group.iter().map(|g| (g.name.clone(), g.gid)).collect(),
+        }
+    }
+
+    /// Create the users, groups and memberships defined by `entries` (in configuration
+    /// order, see [`crate::read_entries`]) which do not exist yet, the same way
+    /// `systemd-sysusers` does: all groups are created before users, the first
+    /// definition of each user and group is used, and missing IDs are allocated
+    /// downwards from the top of the configured ranges. `last_change` is the date of
+    /// the last password change for new users, in days since the epoch.
+    pub fn apply(&mut self, entries: &[SysusersEntry], last_change: u32) -> Result<()> {
+        let mut groups = Vec::new();
+        let mut users = Vec::new();
+        let mut members = Vec::new();
+        let mut ranges = Vec::new();
+        let mut seen_groups = BTreeSet::new();
+        let mut seen_users = BTreeSet::new();
+        for e in entries {
+            match e {
+                SysusersEntry::User { name, .. } => {
+                    if seen_users.insert(name.as_str()) {
+                        users.push(e.clone());
+                    }
+                }
+                SysusersEntry::Group { name, id, .. } => {
+                    if seen_groups.insert(name.as_str()) {
+                        groups.push((name.clone(), *id));
+                    }
+                }
+                SysusersEntry::Membership { user, group } => {
+                    if !members.contains(&(user, group)) {
+                        members.push((user, group));
+                    }
+                }
+                SysusersEntry::Range { start, end } => ranges.push((*start, *end)),
+            }
+        }
+        // Users and groups referenced by memberships are created implicitly.
+        for &(user, group) in members.iter() {
+            if seen_groups.insert(group.as_str()) {
+                groups.push((group.clone(), None));
+            }
+            if seen_users.insert(user.as_str()) {
+                users.push(SysusersEntry::User {
+                    name: user.clone(),
+                    uid: None,
+                    pgid: None,
+                    id_path: None,
+                    gecos: None,
+                    home: None,
+                    shell: None,
+                    locked: false,
+                });
+            }
+        }
+        if ranges.is_empty() {
+            ranges.push(DEFAULT_RANGE);
+        }
+        let top = ranges.iter().map(|(_, end)| *end).max();
+
+        let mut alloc = Allocator {
+            db: self,
+            ranges,
+            uid_cursor: top,
+            gid_cursor: top,
+            last_change,
+        };
+        for (name, gid) in groups {
+            alloc.add_group(&name, gid, None)?;
+        }
+        for user in users.iter() {
+            alloc.add_user(user)?;
+        }
+        for (user, group) in members {
+            alloc.add_member(user, group);
+        }
+        Ok(())
+    }
+
+    /// The contents of `/etc/passwd`.
+    pub fn passwd(&self) -> String {
+        let entries = self
+            .passwd
+            .iter()
+            .filter(|e| !self.alt_users.contains(&e.name));
+        render(entries, |e, w| e.to_writer(w))
+    }
+
+    /// The contents of `/etc/shadow`.
+    pub fn shadow(&self) -> String {
+        render(&self.shadow, |e, w| e.to_writer(w))
+    }
+
+    /// The contents of `/etc/group`. Members added to groups from `/usr/lib/group`
+    /// are not included, as that can't be changed.
+    pub fn group(&self) -> String {
+        let entries = self
+            .group
+            .iter()
+            .filter(|e| !self.alt_groups.contains(&e.name));
+        render(entries, |e, w| e.to_writer(w))
+    }
+
+    /// The contents of `/etc/gshadow`.
+    pub fn gshadow(&self) -> String {
+        render(&self.gshadow, |e, w| e.to_writer(w))
+    }
+}
+
+/// Compute the user and group databases which `systemd-sysusers` would produce
+/// from the existing `/etc` databases and the sysusers.d configuration in the target
+/// root. Users and groups from nss-altfiles (`/usr/lib/passwd` and `/usr/lib/group`)
+/// are not created again, and their IDs are not reused. The date of the last password
+/// change honors `SOURCE_DATE_EPOCH`.
+pub fn allocate(rootfs: &Dir) -> Result<UserDatabase> {
+    let now = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+    let days = u32::try_from(now / 86400).unwrap_or(u32::MAX);
+    let mut db = UserDatabase::load(rootfs)?;
+    db.add_altfiles(rootfs)?;
+    db.apply(&crate::read_entries(rootfs)?, days)?;
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+    use cap_std_ext::cap_std;
+    use indoc::indoc;
+
+    fn parse(s: &str) -> Vec<SysusersEntry> {
+        s.lines()
+            .filter_map(|l| SysusersEntry::parse(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_search() {
+        let ranges = &[(10, 12), (20, 21)];
+        let mut cursor = Some(u32::MAX);
+        let taken = [21, 11];
+        let mut found = Vec::new();
+        while let Some(id) = search(ranges, &mut cursor, |id| !taken.contains(&id)) {
+            found.push(id);
+        }
+        assert_eq!(found, [20, 12, 10]);
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir("etc")?;
+        td.write("etc/passwd", "root:x:0:0:Super User:/root:/bin/bash\n")?;
+        td.write("etc/group", "root:x:0:\nwheel:x:10:\n")?;
+        let mut db = UserDatabase::load(&td)?;
+        db.apply(
+            &parse(indoc! { r#"
+                g wheel 11
+                g input -
+                u root 0 "Someone Else"
+                u web -
+                u db 500 "Database" /var/lib/db
+                u admin 501:wheel
+                u! locked 998
+                m admin input
+                m web newgroup
+            "#}),
+            20000,
+        )?;
+        similar_asserts::assert_eq!(
+            db.passwd(),
+            indoc! { r#"
+                root:x:0:0:Super User:/root:/bin/bash
+                web:x:997:997::/:/usr/sbin/nologin
+                db:x:500:500:Database:/var/lib/db:/usr/sbin/nologin
+                admin:x:501:10::/:/usr/sbin/nologin
+                locked:x:998:996::/:/usr/sbin/nologin
+            "#}
+        );
+        similar_asserts::assert_eq!(
+            db.group(),
+            indoc! { r#"
+                root:x:0:
+                wheel:x:10:
+                input:x:999:admin
+                newgroup:x:998:web
+                web:x:997:
+                db:x:500:
+                locked:x:996:
+            "#}
+        );
+        similar_asserts::assert_eq!(
+            db.shadow(),
+            indoc! { r#"
+                web:!*:20000::::::
+                db:!*:20000::::::
+                admin:!*:20000::::::
+                locked:!*:20000:::::1:
+            "#}
+        );
+        assert!(db.gshadow().starts_with("input:!*::admin\n"));
+        // The requested UID is free, but the GID is not
+        assert_eq!(db.ids().uid("locked"), Some(998));
+        Ok(())
+    }
+
+    #[test]
+    fn test_numeric_primary_group() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir("etc")?;
+        td.write("etc/group", "users:x:100:\n")?;
+        let mut db = UserDatabase::load(&td)?;
+        db.apply(&parse("u foo 500:100\nu bar 501:600\n"), 20000)?;
+        similar_asserts::assert_eq!(
+            db.passwd(),
+            indoc! { r#"
+                foo:x:500:100::/:/usr/sbin/nologin
+                bar:x:501:600::/:/usr/sbin/nologin
+            "#}
+        );
+        // The existing group is used, and a group is only created for a free GID
+        similar_asserts::assert_eq!(
+            db.group(),
+            indoc! { r#"
+                users:x:100:
+                bar:x:600:
+            "#}
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocate_altfiles() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("etc")?;
+        td.create_dir_all("usr/lib/sysusers.d")?;
+        td.write("etc/passwd", "root:x:0:0:Super User:/root:/bin/bash\n")?;
+        td.write("etc/group", "root:x:0:\n")?;
+        td.write("usr/lib/passwd", "daemon:x:999:999::/:/usr/sbin/nologin\n")?;
+        td.write("usr/lib/group", "daemon:x:999:\n")?;
+        td.write("usr/lib/sysusers.d/test.conf", "u daemon -\nu web -\n")?;
+        let db = allocate(&td)?;
+        // daemon is not created again, and its IDs are not reused
+        similar_asserts::assert_eq!(
+            db.passwd(),
+            indoc! { r#"
+                root:x:0:0:Super User:/root:/bin/bash
+                web:x:998:998::/:/usr/sbin/nologin
+            "#}
+        );
+        similar_asserts::assert_eq!(db.group(), "root:x:0:\nweb:x:998:\n");
+        assert_eq!(db.ids().uid("daemon"), Some(999));
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! Detecting users and groups whose numeric ID differs between two versions of a root.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::BTreeMap;
+
+use cap_std_ext::cap_std::fs::Dir;
+
+use crate::{IdMapping, Result, UserDatabase};
+
+/// A user or group whose numeric ID changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdChange {
+    /// The user or group name
+    pub name: String,
+    /// The ID in the old root
+    pub old: u32,
+    /// The ID in the new root
+    pub new: u32,
+}
+
+/// The users and groups whose numeric ID changed between two roots.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IdDrift {
+    /// Users with a changed UID
+    pub users: Vec<IdChange>,
+    /// Groups with a changed GID
+    pub groups: Vec<IdChange>,
+}
+
+impl IdDrift {
+    /// Returns true if no IDs changed.
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty()
+    }
+
+    /// Map from old to new UID.
+    pub fn uid_map(&self) -> BTreeMap<u32, u32> {
+        self.users.iter().map(|c| (c.old, c.new)).collect()
+    }
+
+    /// Map from old to new GID.
+    pub fn gid_map(&self) -> BTreeMap<u32, u32> {
+        self.groups.iter().map(|c| (c.old, c.new)).collect()
+    }
+}
+
+/// Compute the IDs of all users and groups as they will be seen when booting the
+/// root: the databases in `/etc`, the nss-altfiles databases in `/usr/lib`, and the
+/// users and groups which `systemd-sysusers` would create.
+pub fn effective_ids(rootfs: &Dir) -> Result<IdMapping> {
+    let mut db = UserDatabase::load(rootfs)?;
+    db.add_altfiles(rootfs)?;
+    // The password change date does not matter here
+    db.apply(&crate::read_entries(rootfs)?, 0)?;
+    Ok(db.ids())
+}
+
+fn changes(old: &BTreeMap<String, u32>, new: &BTreeMap<String, u32>) -> Vec<IdChange> {
+    old.iter()
+        .filter_map(|(name, &old)| {
+            let new = *new.get(name)?;
+            (old != new).then(|| IdChange {
+                name: name.clone(),
+                old,
+                new,
+            })
+        })
+        .collect()
+}
+
+/// Compare the effective user and group IDs (see [`effective_ids`]) of two roots,
+/// returning the users and groups present in both whose ID differs.
+pub fn compare_ids(old: &Dir, new: &Dir) -> Result<IdDrift> {
+    let old = effective_ids(old)?;
+    let new = effective_ids(new)?;
+    Ok(IdDrift {
+        users: changes(&old.users, &new.users),
+        groups: changes(&old.groups, &new.groups),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+    use cap_std_ext::cap_std;
+    use cap_std_ext::cap_tempfile;
+    use indoc::indoc;
+
+    fn newroot(passwd: &str, group: &str, sysusers: &str) -> Result<cap_tempfile::TempDir> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("etc")?;
+        td.create_dir_all("usr/lib/sysusers.d")?;
+        td.write("etc/passwd", passwd)?;
+        td.write("etc/group", group)?;
+        td.write("usr/lib/sysusers.d/test.conf", sysusers)?;
+        Ok(td)
+    }
+
+    #[test]
+    fn test_compare_ids() -> Result<()> {
+        let old = newroot(
+            indoc! { r#"
+                root:x:0:0:root:/root:/bin/bash
+                app:x:900:900::/var/lib/app:/sbin/nologin
+            "# },
+            "root:x:0:\napp:x:900:\n",
+            "u static 800 - - -\nu dynamic - - - -\n",
+        )?;
+        let new = newroot(
+            indoc! { r#"
+                root:x:0:0:root:/root:/bin/bash
+                app:x:901:900::/var/lib/app:/sbin/nologin
+            "# },
+            "root:x:0:\napp:x:900:\n",
+            "u static 801 - - -\nu dynamic - - - -\nu added - - - -\n",
+        )?;
+        new.create_dir_all("usr/lib")?;
+        new.write("usr/lib/group", "app:x:950:\naltfiles:x:960:\n")?;
+        assert!(compare_ids(&old, &old)?.is_empty());
+
+        let drift = compare_ids(&old, &new)?;
+        let users = drift
+            .users
+            .iter()
+            .map(|c| format!("{} {}->{}", c.name, c.old, c.new))
+            .collect::<Vec<_>>();
+        // The allocation of "dynamic" is unaffected by the new user, which is
+        // created after it.
+        assert_eq!(users, ["app 900->901", "static 800->801"]);
+        let groups = drift
+            .groups
+            .iter()
+            .map(|c| format!("{} {}->{}", c.name, c.old, c.new))
+            .collect::<Vec<_>>();
+        assert_eq!(groups, ["static 800->801"]);
+        assert_eq!(drift.uid_map(), BTreeMap::from([(800, 801), (900, 901)]));
+        Ok(())
+    }
+}
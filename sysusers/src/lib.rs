@@ -1,21 +1,38 @@
 //! Parse and generate systemd sysusers.d entries.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+mod alloc;
+mod drift;
 #[allow(dead_code)]
 mod nameservice;
 
+use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
-use std::io::{BufRead, BufReader};
-use std::num::ParseIntError;
+use std::io::{BufRead, BufReader, Write};
+use std::num::{NonZeroUsize, ParseIntError};
 use std::path::PathBuf;
 use std::str::FromStr;
 
-use camino::Utf8Path;
-use cap_std_ext::dirext::{CapStdExtDirExt, CapStdExtDirExtUtf8};
-use cap_std_ext::{cap_std::fs::Dir, cap_std::fs_utf8::Dir as DirUtf8};
+use camino::Utf8PathBuf;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::cap_std::fs::{MetadataExt, Permissions, PermissionsExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
 use thiserror::Error;
 
+pub use alloc::{allocate, UserDatabase};
+pub use bootc_utils::Specifiers;
+pub use drift::{compare_ids, effective_ids, IdChange, IdDrift};
+
 const SYSUSERSD: &str = "usr/lib/sysusers.d";
+/// The directories searched for sysusers.d files, in order of precedence
+const SYSUSERS_DIRS: &[&str] = &[
+    "etc/sysusers.d",
+    "run/sysusers.d",
+    "usr/local/lib/sysusers.d",
+    SYSUSERSD,
+];
+/// The prefix of the files we generate
+const BOOTC_GENERATED_PREFIX: &str = "bootc-autogenerated";
 
 /// An error when processing sysusers
 #[derive(Debug, Error)]
@@ -33,6 +50,25 @@ pub enum Error {
     PasswdLoadFailure(String),
     #[error("Failed to load etc/group: {0}")]
     GroupLoadFailure(String),
+    #[error("Failed to load etc/shadow: {0}")]
+    ShadowLoadFailure(String),
+    #[error("Failed to load etc/gshadow: {0}")]
+    GshadowLoadFailure(String),
+    #[error("Unknown specifier: %{0}")]
+    UnknownSpecifier(char),
+    #[error("Specifier %{0} can only be resolved at runtime")]
+    UnresolvedSpecifier(char),
+    #[error("Failed to allocate {0}")]
+    AllocationFailure(String),
+}
+
+impl From<bootc_utils::SpecifierError> for Error {
+    fn from(e: bootc_utils::SpecifierError) -> Self {
+        match e {
+            bootc_utils::SpecifierError::Unknown(c) => Self::UnknownSpecifier(c),
+            bootc_utils::SpecifierError::Unresolved(c) => Self::UnresolvedSpecifier(c),
+        }
+    }
 }
 
 /// The type of Result.
@@ -67,20 +103,30 @@ impl FromStr for GroupReference {
 }
 
 /// A parsed sysusers.d entry
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[allow(missing_docs)]
 pub enum SysusersEntry {
-    /// Defines a user
+    /// Defines a user (`u`, or `u!` for a locked account)
     User {
         name: String,
         uid: Option<u32>,
         pgid: Option<GroupReference>,
-        gecos: String,
+        /// Use the owner of this file for the IDs, if it exists
+        id_path: Option<String>,
+        gecos: Option<String>,
         home: Option<String>,
         shell: Option<String>,
+        locked: bool,
     },
     /// Defines a group
-    Group { name: String, id: Option<u32> },
+    Group {
+        name: String,
+        id: Option<u32>,
+        /// Use the group of this file for the ID, if it exists
+        id_path: Option<String>,
+    },
+    /// Adds a user to a group
+    Membership { user: String, group: String },
     /// Defines a range of uids
     Range { start: u32, end: u32 },
 }
@@ -130,48 +176,77 @@ impl SysusersEntry {
         Self::next_optional_token(s).map(|(a, b)| (a.map(|v| v.to_owned()), b))
     }
 
+    /// Parse the ID field of a `u` line.
+    fn parse_user_id(
+        id: Option<&str>,
+    ) -> std::result::Result<(Option<u32>, Option<GroupReference>, Option<String>), ParseIntError>
+    {
+        let Some(id) = id else {
+            return Ok((None, None, None));
+        };
+        if id.starts_with('/') {
+            return Ok((None, None, Some(id.to_owned())));
+        }
+        let (uid, gid) = id.split_once(':').unwrap_or((id, id));
+        let uid = Some(uid)
+            .filter(|&v| v != "-")
+            .map(u32::from_str)
+            .transpose()?;
+        let pgid = Some(gid)
+            .filter(|&v| v != "-")
+            .map(GroupReference::from_str)
+            .transpose()?;
+        Ok((uid, pgid, None))
+    }
+
     pub(crate) fn parse(s: &str) -> Result<Option<SysusersEntry>> {
         let err = || Error::ParseFailure(s.to_owned());
         let (ftype, s) = Self::next_token(s).ok_or_else(err.clone())?;
         let r = match ftype {
-            "u" => {
+            "u" | "u!" => {
                 let (name, s) = Self::next_token_owned(s).ok_or_else(err.clone())?;
-                let (id, s) = Self::next_optional_token(s).ok_or_else(err.clone())?;
-                let (uid, pgid) = id
-                    .and_then(|v| v.split_once(':'))
-                    .or_else(|| id.map(|id| (id, id)))
-                    .map(|(uid, gid)| (Some(uid), Some(gid)))
-                    .unwrap_or((None, None));
-                let uid = uid
-                    .filter(|&v| v != "-")
-                    .map(|id| id.parse())
-                    .transpose()
-                    .map_err(|_| err())?;
-                let pgid = pgid.map(|id| id.parse()).transpose().map_err(|_| err())?;
-                let (gecos, s) = Self::next_token_owned(s).ok_or_else(err.clone())?;
+                let (id, s) = Self::next_optional_token(s).unwrap_or_default();
+                let (uid, pgid, id_path) = Self::parse_user_id(id).map_err(|_| err())?;
+                let (gecos, s) = Self::next_optional_token_owned(s).unwrap_or_default();
                 let (home, s) = Self::next_optional_token_owned(s).unwrap_or_default();
                 let (shell, _) = Self::next_optional_token_owned(s).unwrap_or_default();
                 SysusersEntry::User {
                     name,
                     uid,
                     pgid,
+                    id_path,
                     gecos,
                     home,
                     shell,
+                    locked: ftype == "u!",
                 }
             }
             "g" => {
                 let (name, s) = Self::next_token_owned(s).ok_or_else(err.clone())?;
-                let (id, _) = Self::next_optional_token(s).ok_or_else(err.clone())?;
-                let id = id.map(|id| id.parse()).transpose().map_err(|_| err())?;
-                SysusersEntry::Group { name, id }
+                let (id, _) = Self::next_optional_token(s).unwrap_or_default();
+                let (id, id_path) = match id {
+                    Some(p) if p.starts_with('/') => (None, Some(p.to_owned())),
+                    id => (
+                        id.map(|id| id.parse()).transpose().map_err(|_| err())?,
+                        None,
+                    ),
+                };
+                SysusersEntry::Group { name, id, id_path }
+            }
+            "m" => {
+                let (user, s) = Self::next_token_owned(s).ok_or_else(err.clone())?;
+                let (group, _) = Self::next_token_owned(s).ok_or_else(err.clone())?;
+                SysusersEntry::Membership { user, group }
             }
             "r" => {
                 let (_, s) = Self::next_optional_token(s).ok_or_else(err.clone())?;
                 let (range, _) = Self::next_token(s).ok_or_else(err.clone())?;
-                let (start, end) = range.split_once('-').ok_or_else(err.clone())?;
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
                 let start: u32 = start.parse().map_err(|_| err())?;
                 let end: u32 = end.parse().map_err(|_| err())?;
+                if start > end {
+                    return Err(err());
+                }
                 SysusersEntry::Range { start, end }
             }
             // In the case of a sysusers entry that is of unknown type, we skip it out of conservatism
@@ -179,59 +254,147 @@ impl SysusersEntry {
         };
         Ok(Some(r))
     }
+
+    /// Expand the specifiers in the fields of this entry.
+    pub fn expand(&mut self, specifiers: &Specifiers) -> Result<()> {
+        let expand_opt = |v: &mut Option<String>| -> Result<()> {
+            if let Some(v) = v.as_mut() {
+                *v = specifiers.expand(v)?;
+            }
+            Ok(())
+        };
+        match self {
+            SysusersEntry::User {
+                name,
+                id_path,
+                gecos,
+                home,
+                shell,
+                ..
+            } => {
+                *name = specifiers.expand(name)?;
+                expand_opt(id_path)?;
+                expand_opt(gecos)?;
+                expand_opt(home)?;
+                expand_opt(shell)?;
+            }
+            SysusersEntry::Group { name, id_path, .. } => {
+                *name = specifiers.expand(name)?;
+                expand_opt(id_path)?;
+            }
+            SysusersEntry::Membership { user, group } => {
+                *user = specifiers.expand(user)?;
+                *group = specifiers.expand(group)?;
+            }
+            SysusersEntry::Range { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Replace a file reference in the ID field by the owner of that file in `rootfs`.
+    /// If the file does not exist, the ID is allocated as usual.
+    fn resolve_id_path(&mut self, rootfs: &Dir) -> Result<()> {
+        let id_path = match self {
+            SysusersEntry::User { id_path, .. } | SysusersEntry::Group { id_path, .. } => {
+                id_path.take()
+            }
+            _ => None,
+        };
+        let Some(path) = id_path else {
+            return Ok(());
+        };
+        let meta = match rootfs.metadata(path.trim_start_matches('/')) {
+            Ok(m) => m,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(Error::PathIo {
+                    path: path.into(),
+                    err,
+                })
+            }
+        };
+        match self {
+            SysusersEntry::User { uid, pgid, .. } => {
+                *uid = Some(meta.uid());
+                *pgid = Some(meta.gid().into());
+            }
+            SysusersEntry::Group { id, .. } => *id = Some(meta.gid()),
+            _ => {}
+        }
+        Ok(())
+    }
 }
 
-/// Read all tmpfiles.d entries in the target directory, and return a mapping
-/// from (file path) => (single tmpfiles.d entry line)
-pub fn read_sysusers(rootfs: &Dir) -> Result<Vec<SysusersEntry>> {
-    let Some(d) = rootfs.open_dir_optional(SYSUSERSD)? else {
-        return Ok(Default::default());
-    };
-    let d = DirUtf8::from_cap_std(d);
+/// Find the sysusers.d configuration files in the target root, returning their
+/// paths relative to the root in the order they are processed.
+pub fn config_files(rootfs: &Dir) -> Result<Vec<PathBuf>> {
+    Ok(bootc_utils::config_files(rootfs, SYSUSERS_DIRS)?)
+}
+
+/// Read and parse all sysusers.d entries in the target root in configuration order.
+/// Specifiers are expanded (entries using specifiers whose value is not known
+/// offline are skipped), and file references in ID fields are resolved.
+pub fn read_entries(rootfs: &Dir) -> Result<Vec<SysusersEntry>> {
+    let specifiers = Specifiers::from_root(rootfs)?;
     let mut result = Vec::new();
-    let mut found_users = BTreeSet::new();
-    let mut found_groups = BTreeSet::new();
-    for name in d.filenames_sorted()? {
-        let Some("conf") = Utf8Path::new(&name).extension() else {
-            continue;
+    for path in config_files(rootfs)? {
+        let in_file = |e: Error| Error::ParseFailureInFile {
+            path: path.clone(),
+            err: e.to_string(),
         };
-        let r = d.open(&name).map(BufReader::new)?;
+        let r = rootfs.open(&path).map(BufReader::new)?;
         for line in r.lines() {
             let line = line?;
-            if line.is_empty() || line.starts_with("#") {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
                 continue;
             }
-            let Some(e) = SysusersEntry::parse(&line).map_err(|e| Error::ParseFailureInFile {
-                path: name.clone().into(),
-                err: e.to_string(),
-            })?
-            else {
+            let Some(mut e) = SysusersEntry::parse(line).map_err(in_file)? else {
                 continue;
             };
-            match e {
-                SysusersEntry::User {
-                    ref name, ref pgid, ..
-                } if !found_users.contains(name.as_str()) => {
-                    found_users.insert(name.clone());
-                    found_groups.insert(name.clone());
-                    // Users implicitly create a group with the same name
-                    let pgid = pgid.as_ref().and_then(|g| match g {
-                        GroupReference::Numeric(n) => Some(*n),
-                        GroupReference::Name(_) => None,
-                    });
-                    result.push(SysusersEntry::Group {
-                        name: name.clone(),
-                        id: pgid,
-                    });
-                    result.push(e);
-                }
-                SysusersEntry::Group { ref name, .. } if !found_groups.contains(name.as_str()) => {
-                    found_groups.insert(name.clone());
-                    result.push(e);
-                }
-                _ => {
-                    // Ignore others.
-                }
+            match e.expand(&specifiers) {
+                Ok(()) => {}
+                Err(Error::UnresolvedSpecifier(_)) => continue,
+                Err(e) => return Err(in_file(e)),
+            }
+            e.resolve_id_path(rootfs)?;
+            result.push(e);
+        }
+    }
+    Ok(result)
+}
+
+/// Read all sysusers.d entries in the target root, returning the first definition
+/// of each user and group. Each user is preceded by its implicitly created group.
+pub fn read_sysusers(rootfs: &Dir) -> Result<Vec<SysusersEntry>> {
+    let mut result = Vec::new();
+    let mut found_users = BTreeSet::new();
+    let mut found_groups = BTreeSet::new();
+    for e in read_entries(rootfs)? {
+        match e {
+            SysusersEntry::User {
+                ref name, ref pgid, ..
+            } if !found_users.contains(name.as_str()) => {
+                found_users.insert(name.clone());
+                found_groups.insert(name.clone());
+                // Users implicitly create a group with the same name
+                let pgid = pgid.as_ref().and_then(|g| match g {
+                    GroupReference::Numeric(n) => Some(*n),
+                    GroupReference::Name(_) => None,
+                });
+                result.push(SysusersEntry::Group {
+                    name: name.clone(),
+                    id: pgid,
+                    id_path: None,
+                });
+                result.push(e);
+            }
+            SysusersEntry::Group { ref name, .. } if !found_groups.contains(name.as_str()) => {
+                found_groups.insert(name.clone());
+                result.push(e);
+            }
+            _ => {
+                // Ignore others.
             }
         }
     }
@@ -305,10 +468,10 @@ pub fn analyze(rootfs: &Dir) -> Result<SysusersAnalysis> {
                 } => {
                     users.insert(name, SysuserData { uid, pgid });
                 }
-                SysusersEntry::Group { name, id } => {
+                SysusersEntry::Group { name, id, .. } => {
                     groups.insert(name, SysgroupData { id });
                 }
-                SysusersEntry::Range { .. } => {
+                SysusersEntry::Membership { .. } | SysusersEntry::Range { .. } => {
                     // Nothing to do here
                 }
             }
@@ -325,6 +488,144 @@ pub fn analyze(rootfs: &Dir) -> Result<SysusersAnalysis> {
     })
 }
 
+/// The numeric IDs of the users and groups known in a root.
+#[derive(Debug, Default)]
+pub struct IdMapping {
+    /// Map from user name to uid
+    pub users: BTreeMap<String, u32>,
+    /// Map from group name to gid
+    pub groups: BTreeMap<String, u32>,
+}
+
+impl IdMapping {
+    /// Look up a user by name, also accepting a numeric uid.
+    pub fn uid(&self, name: &str) -> Option<u32> {
+        name.parse().ok().or_else(|| self.users.get(name).copied())
+    }
+
+    /// Look up a group by name, also accepting a numeric gid.
+    pub fn gid(&self, name: &str) -> Option<u32> {
+        name.parse().ok().or_else(|| self.groups.get(name).copied())
+    }
+}
+
+/// Gather the numeric IDs of users and groups from `/etc/passwd` and `/etc/group`,
+/// as well as sysusers.d entries with a static ID. Users and groups which would
+/// be dynamically allocated by `systemd-sysusers` are not included.
+pub fn read_ids(rootfs: &Dir) -> Result<IdMapping> {
+    let mut r = IdMapping::default();
+    let passwd = nameservice::passwd::load_etc_passwd(rootfs)
+        .map_err(|e| Error::PasswdLoadFailure(e.to_string()))?
+        .unwrap_or_default();
+    r.users.extend(passwd.into_iter().map(|e| (e.name, e.uid)));
+    if rootfs.try_exists("etc/group")? {
+        let group = nameservice::group::load_etc_group(rootfs)
+            .map_err(|e| Error::GroupLoadFailure(e.to_string()))?;
+        r.groups.extend(group.into_iter().map(|e| (e.name, e.gid)));
+    }
+    for ent in read_sysusers(rootfs)? {
+        match ent {
+            SysusersEntry::User {
+                name,
+                uid: Some(uid),
+                ..
+            } => {
+                r.users.entry(name).or_insert(uid);
+            }
+            SysusersEntry::Group {
+                name, id: Some(id), ..
+            } => {
+                r.groups.entry(name).or_insert(id);
+            }
+            _ => {}
+        }
+    }
+    Ok(r)
+}
+
+/// Quote a sysusers.d field if needed, mapping an empty value to `-`.
+fn sysusers_field(s: &str) -> Cow<'_, str> {
+    if s.is_empty() {
+        Cow::Borrowed("-")
+    } else if s.contains(char::is_whitespace) {
+        Cow::Owned(format!("\"{s}\""))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// Render sysusers.d entries equivalent to the given passwd and group entries.
+/// Groups come first, so that users can refer to them by name.
+fn render_entries(
+    users: &[nameservice::passwd::PasswdEntry],
+    groups: &[nameservice::group::GroupEntry],
+    all_groups: &[nameservice::group::GroupEntry],
+) -> String {
+    let mut r = String::new();
+    for g in groups {
+        r.push_str(&format!("g {} {}\n", g.name, g.gid));
+    }
+    for u in users {
+        let gid = all_groups
+            .iter()
+            .find(|g| g.gid == u.gid)
+            .map(|g| g.name.clone())
+            .unwrap_or_else(|| u.gid.to_string());
+        r.push_str(&format!(
+            "u {} {}:{gid} {} {} {}\n",
+            u.name,
+            u.uid,
+            sysusers_field(&u.gecos),
+            sysusers_field(&u.home_dir),
+            sysusers_field(&u.shell)
+        ));
+    }
+    r
+}
+
+/// Write a sysusers.d file into the target root covering the entries of
+/// `/etc/passwd` and `/etc/group` which are not already defined via sysusers.d.
+/// Returns the number of generated entries and the path of the file, if any.
+pub fn generate_missing(rootfs: &Dir) -> Result<Option<(NonZeroUsize, Utf8PathBuf)>> {
+    let analysis = analyze(rootfs)?;
+    if analysis.is_empty() {
+        return Ok(None);
+    }
+    let users = nameservice::passwd::load_etc_passwd(rootfs)
+        .map_err(|e| Error::PasswdLoadFailure(e.to_string()))?
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|e| analysis.missing_users.contains(&e.name))
+        .collect::<Vec<_>>();
+    let all_groups = nameservice::group::load_etc_group(rootfs)
+        .map_err(|e| Error::GroupLoadFailure(e.to_string()))?;
+    let groups = all_groups
+        .iter()
+        .filter(|e| analysis.missing_groups.contains(&e.name))
+        .cloned()
+        .collect::<Vec<_>>();
+    // SAFETY: The analysis is not empty
+    let count = NonZeroUsize::new(users.len() + groups.len()).unwrap();
+    let contents = render_entries(&users, &groups, &all_groups);
+
+    rootfs.create_dir_all(SYSUSERSD)?;
+    let mut n = 0;
+    let path = loop {
+        let path = Utf8PathBuf::from(format!("{SYSUSERSD}/{BOOTC_GENERATED_PREFIX}-{n}.conf"));
+        if !rootfs.try_exists(&path)? {
+            break path;
+        }
+        n += 1;
+    };
+    rootfs.atomic_replace_with(&path, |w| -> std::io::Result<()> {
+        w.get_mut()
+            .as_file_mut()
+            .set_permissions(Permissions::from_mode(0o644))?;
+        w.write_all(contents.as_bytes())
+    })?;
+    Ok(Some((count, path)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -332,6 +633,7 @@ mod tests {
     use std::io::Write;
 
     use anyhow::Result;
+    use camino::Utf8Path;
     use cap_std_ext::cap_std;
     use indoc::indoc;
 
@@ -410,9 +712,11 @@ mod tests {
                 name: "root".into(),
                 uid: Some(0),
                 pgid: Some(0.into()),
-                gecos: "Super User".into(),
+                id_path: None,
+                gecos: Some("Super User".into()),
                 home: Some("/root".into()),
-                shell: Some("/bin/bash".into())
+                shell: Some("/bin/bash".into()),
+                locked: false,
             }
         );
         assert_eq!(
@@ -421,9 +725,11 @@ mod tests {
                 name: "root".into(),
                 uid: Some(0),
                 pgid: Some(0.into()),
-                gecos: "Super User".into(),
+                id_path: None,
+                gecos: Some("Super User".into()),
                 home: Some("/root".into()),
-                shell: None
+                shell: None,
+                locked: false,
             }
         );
         assert_eq!(
@@ -432,9 +738,11 @@ mod tests {
                 name: "bin".into(),
                 uid: Some(1),
                 pgid: Some(1.into()),
-                gecos: "bin".into(),
+                id_path: None,
+                gecos: Some("bin".into()),
                 home: Some("/bin".into()),
-                shell: None
+                shell: None,
+                locked: false,
             }
         );
         let _ = entries.next().unwrap();
@@ -444,9 +752,11 @@ mod tests {
                 name: "adm".into(),
                 uid: Some(3),
                 pgid: Some(4.into()),
-                gecos: "adm".into(),
+                id_path: None,
+                gecos: Some("adm".into()),
                 home: Some("/var/adm".into()),
-                shell: None
+                shell: None,
+                locked: false,
             }
         );
         assert_eq!(entries.count(), 9);
@@ -458,9 +768,11 @@ mod tests {
                 name: "qemu".into(),
                 uid: Some(107),
                 pgid: Some(GroupReference::Name("qemu".into())),
-                gecos: "qemu user".into(),
+                id_path: None,
+                gecos: Some("qemu user".into()),
                 home: None,
-                shell: None
+                shell: None,
+                locked: false,
             }
         );
         assert_eq!(
@@ -469,9 +781,11 @@ mod tests {
                 name: "vboxadd".into(),
                 uid: None,
                 pgid: Some(1.into()),
-                gecos: "-".into(),
+                id_path: None,
+                gecos: None,
                 home: Some("/var/run/vboxadd".into()),
-                shell: None
+                shell: None,
+                locked: false,
             }
         );
         assert_eq!(entries.count(), 0);
@@ -490,6 +804,7 @@ mod tests {
             SysusersEntry::Group {
                 name: "root".into(),
                 id: Some(0),
+                id_path: None,
             }
         );
         assert_eq!(
@@ -497,12 +812,160 @@ mod tests {
             SysusersEntry::Group {
                 name: "bin".into(),
                 id: Some(1),
+                id_path: None,
             }
         );
         assert_eq!(entries.count(), 28);
         Ok(())
     }
 
+    #[test]
+    fn test_sysusers_parse_extended() -> Result<()> {
+        let mut entries = parse_all(indoc! { r#"
+            u! locked - "Locked User"
+            u fromfile /var/lib/foo
+            u minimal
+            g grp /var/lib/foo
+            m minimal grp
+            r - 500
+            r - 600-700
+        "#});
+        assert_eq!(
+            entries.next().unwrap(),
+            SysusersEntry::User {
+                name: "locked".into(),
+                uid: None,
+                pgid: None,
+                id_path: None,
+                gecos: Some("Locked User".into()),
+                home: None,
+                shell: None,
+                locked: true,
+            }
+        );
+        assert!(matches!(
+            entries.next().unwrap(),
+            SysusersEntry::User { id_path: Some(p), uid: None, .. } if p == "/var/lib/foo"
+        ));
+        assert!(matches!(
+            entries.next().unwrap(),
+            SysusersEntry::User {
+                uid: None,
+                gecos: None,
+                ..
+            }
+        ));
+        assert_eq!(
+            entries.next().unwrap(),
+            SysusersEntry::Group {
+                name: "grp".into(),
+                id: None,
+                id_path: Some("/var/lib/foo".into()),
+            }
+        );
+        assert_eq!(
+            entries.next().unwrap(),
+            SysusersEntry::Membership {
+                user: "minimal".into(),
+                group: "grp".into()
+            }
+        );
+        assert_eq!(
+            entries.next().unwrap(),
+            SysusersEntry::Range {
+                start: 500,
+                end: 500
+            }
+        );
+        assert_eq!(
+            entries.next().unwrap(),
+            SysusersEntry::Range {
+                start: 600,
+                end: 700
+            }
+        );
+        assert!(SysusersEntry::parse("r - 700-600").is_err());
+        assert!(SysusersEntry::parse("m onlyuser").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand() -> Result<()> {
+        let specifiers = Specifiers {
+            os_release: [("ID".to_owned(), "fedora".to_owned())].into(),
+            ..Default::default()
+        };
+        let mut e = SysusersEntry::parse("u %o-user%% - \"%o user\"")?.unwrap();
+        e.expand(&specifiers)?;
+        match e {
+            SysusersEntry::User { name, gecos, .. } => {
+                assert_eq!(name, "fedora-user%");
+                assert_eq!(gecos.as_deref(), Some("fedora user"));
+            }
+            o => panic!("Unexpected entry {o:?}"),
+        }
+        let mut e = SysusersEntry::parse("g %m -")?.unwrap();
+        assert!(matches!(
+            e.expand(&specifiers),
+            Err(Error::UnresolvedSpecifier('m'))
+        ));
+        // tmpfiles.d-only specifiers are not known
+        let mut e = SysusersEntry::parse("g %t -")?.unwrap();
+        assert!(matches!(
+            e.expand(&specifiers),
+            Err(Error::UnknownSpecifier('t'))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_entries() -> Result<()> {
+        let root = &cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        root.create_dir_all("usr/lib")?;
+        root.write("usr/lib/os-release", "ID=\"fedora\"\n")?;
+        root.create_dir_all(SYSUSERSD)?;
+        root.create_dir_all("etc/sysusers.d")?;
+        root.write(
+            Utf8Path::new(SYSUSERSD).join("a.conf"),
+            indoc! { r#"
+                u %o-user - "%o user"
+                u boot-%b -
+                g fromfile /etc/sysusers.d
+                g missing /nonexistent
+            "#},
+        )?;
+        root.write(Utf8Path::new(SYSUSERSD).join("b.conf"), "u masked -\n")?;
+        root.write(Utf8Path::new(SYSUSERSD).join("c.conf"), "u overridden -\n")?;
+        root.symlink_contents("/dev/null", "etc/sysusers.d/b.conf")?;
+        root.write("etc/sysusers.d/c.conf", "u local -\n")?;
+        let gid = root.metadata("etc/sysusers.d")?.gid();
+
+        let entries = read_entries(root)?;
+        assert_eq!(entries.len(), 4);
+        assert!(matches!(
+            &entries[0],
+            SysusersEntry::User { name, gecos: Some(gecos), .. } if name == "fedora-user" && gecos == "fedora user"
+        ));
+        assert_eq!(
+            entries[1],
+            SysusersEntry::Group {
+                name: "fromfile".into(),
+                id: Some(gid),
+                id_path: None
+            }
+        );
+        assert_eq!(
+            entries[2],
+            SysusersEntry::Group {
+                name: "missing".into(),
+                id: None,
+                id_path: None
+            }
+        );
+        assert!(matches!(&entries[3], SysusersEntry::User { name, .. } if name == "local"));
+        Ok(())
+    }
+
     fn newroot() -> Result<cap_std_ext::cap_tempfile::TempDir> {
         let root = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
         root.create_dir("etc")?;
@@ -566,6 +1029,41 @@ mod tests {
             "systemd-journal"
         ]));
 
+        let (count, path) = generate_missing(root)?.unwrap();
+        assert_eq!(count.get(), 9);
+        assert_eq!(path, "usr/lib/sysusers.d/bootc-autogenerated-0.conf");
+        let generated = root.read_to_string(&path)?;
+        assert!(generated.starts_with("g sudo 16\n"));
+        assert!(generated.ends_with(indoc! { r#"
+            g sshd 981
+            u passim 982:passim "Local Caching Server" /usr/share/empty /usr/bin/nologin
+            u avahi 70:avahi "Avahi mDNS/DNS-SD Stack" /var/run/avahi-daemon /sbin/nologin
+        "#}));
+        assert!(analyze(root).unwrap().is_empty());
+        assert!(generate_missing(root)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_ids() -> Result<()> {
+        let root = &newroot()?;
+        root.write("etc/passwd", "qemu:x:107:107:qemu user:/:/sbin/nologin\n")?;
+        root.write("etc/group", "qemu:x:107:\nbin:x:42:\n")?;
+        root.write(
+            Utf8Path::new(SYSUSERSD).join("other.conf"),
+            OTHER_SYSUSERS_REF,
+        )?;
+        let ids = read_ids(root)?;
+        assert_eq!(ids.uid("qemu"), Some(107));
+        assert_eq!(ids.uid("ftp"), Some(14));
+        assert_eq!(ids.uid("1234"), Some(1234));
+        // Dynamically allocated
+        assert_eq!(ids.uid("vboxadd"), None);
+        // /etc/group takes precedence
+        assert_eq!(ids.gid("bin"), Some(42));
+        assert_eq!(ids.gid("wheel"), Some(10));
+        assert_eq!(ids.gid("nosuchgroup"), None);
         Ok(())
     }
 }
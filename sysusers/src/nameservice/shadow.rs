@@ -3,7 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use anyhow::{anyhow, Context, Result};
-use std::io::{BufRead, Write};
+use cap_std_ext::{cap_std::fs::Dir, dirext::CapStdExtDirExt};
+use std::io::{BufRead, BufReader, Write};
 
 /// Entry from shadow file.
 // Field names taken from (presumably glibc's) /usr/include/shadow.h, descriptions adapted
@@ -107,6 +108,14 @@ pub(crate) fn parse_shadow_content(content: impl BufRead) -> Result<Vec<ShadowEn
     Ok(entries)
 }
 
+pub(crate) fn load_etc_shadow(rootfs: &Dir) -> Result<Option<Vec<ShadowEntry>>> {
+    if let Some(r) = rootfs.open_optional("etc/shadow")? {
+        parse_shadow_content(BufReader::new(r)).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,406 @@
+//! A typed model of tmpfiles.d lines, and parsing thereof.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::ffi::OsString;
+use std::iter::Peekable;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+
+use bootc_utils::Specifiers;
+
+use crate::{impl_unescape_path_until, EndOfRecord, Error, Result};
+
+/// The type of a tmpfiles.d line, without modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryType {
+    /// `f`
+    CreateFile,
+    /// `w`
+    WriteFile,
+    /// `d`
+    CreateDirectory,
+    /// `D`
+    CreateOrCleanDirectory,
+    /// `e`
+    CleanDirectory,
+    /// `v`
+    CreateSubvolume,
+    /// `q`
+    CreateSubvolumeInheritQuota,
+    /// `Q`
+    CreateSubvolumeNewQuota,
+    /// `p`
+    CreateFifo,
+    /// `L`
+    CreateSymlink,
+    /// `c`
+    CreateCharDevice,
+    /// `b`
+    CreateBlockDevice,
+    /// `C`
+    Copy,
+    /// `x`
+    Ignore,
+    /// `X`
+    IgnoreDirectory,
+    /// `r`
+    Remove,
+    /// `R`
+    RemoveRecursive,
+    /// `z`
+    AdjustMode,
+    /// `Z`
+    AdjustModeRecursive,
+    /// `t`
+    SetXattr,
+    /// `T`
+    SetXattrRecursive,
+    /// `h`
+    SetAttr,
+    /// `H`
+    SetAttrRecursive,
+    /// `a`
+    SetAcl,
+    /// `A`
+    SetAclRecursive,
+}
+
+impl EntryType {
+    const ALL: &'static [(char, Self)] = &[
+        ('f', Self::CreateFile),
+        ('w', Self::WriteFile),
+        ('d', Self::CreateDirectory),
+        ('D', Self::CreateOrCleanDirectory),
+        ('e', Self::CleanDirectory),
+        ('v', Self::CreateSubvolume),
+        ('q', Self::CreateSubvolumeInheritQuota),
+        ('Q', Self::CreateSubvolumeNewQuota),
+        ('p', Self::CreateFifo),
+        ('L', Self::CreateSymlink),
+        ('c', Self::CreateCharDevice),
+        ('b', Self::CreateBlockDevice),
+        ('C', Self::Copy),
+        ('x', Self::Ignore),
+        ('X', Self::IgnoreDirectory),
+        ('r', Self::Remove),
+        ('R', Self::RemoveRecursive),
+        ('z', Self::AdjustMode),
+        ('Z', Self::AdjustModeRecursive),
+        ('t', Self::SetXattr),
+        ('T', Self::SetXattrRecursive),
+        ('h', Self::SetAttr),
+        ('H', Self::SetAttrRecursive),
+        ('a', Self::SetAcl),
+        ('A', Self::SetAclRecursive),
+    ];
+
+    fn from_char(c: char) -> Option<Self> {
+        Self::ALL.iter().find(|(k, _)| *k == c).map(|(_, v)| *v)
+    }
+
+    /// The character used for this type in tmpfiles.d.
+    pub fn as_char(&self) -> char {
+        // SAFETY: All variants are in the table
+        Self::ALL.iter().find(|(_, v)| v == self).unwrap().0
+    }
+
+    /// Whether this type creates a filesystem object (if it does not exist).
+    pub fn is_create(&self) -> bool {
+        matches!(
+            self,
+            Self::CreateFile
+                | Self::WriteFile
+                | Self::CreateDirectory
+                | Self::CreateOrCleanDirectory
+                | Self::CleanDirectory
+                | Self::CreateSubvolume
+                | Self::CreateSubvolumeInheritQuota
+                | Self::CreateSubvolumeNewQuota
+                | Self::CreateFifo
+                | Self::CreateSymlink
+                | Self::CreateCharDevice
+                | Self::CreateBlockDevice
+                | Self::Copy
+        )
+    }
+}
+
+/// The modifiers which may follow the type character.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryFlags {
+    /// `+`: The type-specific "replace" or "append" behavior
+    pub plus: bool,
+    /// `!`: Only applied at boot
+    pub boot_only: bool,
+    /// `-`: Errors when creating the object are ignored
+    pub ignore_errors: bool,
+    /// `=`: Remove an existing object of a different type
+    pub force: bool,
+    /// `~`: The argument is base64 encoded
+    pub base64: bool,
+    /// `^`: The argument names a credential
+    pub credential: bool,
+    /// `$`: The object is removed by `systemd-tmpfiles --purge`
+    pub purge: bool,
+}
+
+/// The mode field of a tmpfiles.d line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMode {
+    /// The access mode bits
+    pub mode: u32,
+    /// `~`: Mask the mode of an existing object with this one
+    pub masked: bool,
+    /// `:`: Only apply the mode when creating the object
+    pub only_create: bool,
+}
+
+/// A parsed tmpfiles.d line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TmpfilesEntry {
+    /// The line type
+    pub ty: EntryType,
+    /// The modifiers of the type
+    pub flags: EntryFlags,
+    /// The absolute path
+    pub path: PathBuf,
+    /// The mode, if set
+    pub mode: Option<EntryMode>,
+    /// The owning user, if set
+    pub user: Option<String>,
+    /// The owning group, if set
+    pub group: Option<String>,
+    /// The age used for cleanup, if set
+    pub age: Option<String>,
+    /// The type-specific argument, if set
+    pub argument: Option<String>,
+}
+
+/// Parse the next whitespace-separated (and possibly quoted) field.
+fn next_field<I>(src: &mut Peekable<I>) -> Result<Option<Vec<u8>>>
+where
+    I: Iterator<Item = u8>,
+{
+    while src.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    if src.peek().is_none() {
+        return Ok(None);
+    }
+    let mut r = Vec::new();
+    if src.next_if_eq(&b'"').is_some() {
+        impl_unescape_path_until(src, &mut r, EndOfRecord::Quote)?;
+        if src.next() != Some(b'"') {
+            return Err(Error::MalformedTmpfilesPath);
+        }
+    } else {
+        impl_unescape_path_until(src, &mut r, EndOfRecord::Whitespace)?;
+    }
+    Ok(Some(r))
+}
+
+/// Parse an optional field, where `-` means unset.
+fn optional_field(v: Option<Vec<u8>>) -> Result<Option<String>> {
+    let Some(v) = v else {
+        return Ok(None);
+    };
+    let v = String::from_utf8(v).map_err(|_| Error::MalformedTmpfilesPath)?;
+    Ok(Some(v).filter(|v| v != "-"))
+}
+
+fn parse_mode(s: &str) -> Option<EntryMode> {
+    let (masked, s) = s.strip_prefix('~').map_or((false, s), |s| (true, s));
+    let (only_create, s) = s.strip_prefix(':').map_or((false, s), |s| (true, s));
+    let mode = u32::from_str_radix(s, 8).ok().filter(|&m| m <= 0o7777)?;
+    Some(EntryMode {
+        mode,
+        masked,
+        only_create,
+    })
+}
+
+impl TmpfilesEntry {
+    /// Parse a single line. Returns `None` for empty lines and comments.
+    /// Specifiers are not expanded; see [`TmpfilesEntry::expand`].
+    pub fn parse(line: &str) -> Result<Option<Self>> {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Ok(None);
+        }
+        let err = || Error::MalformedTmpfilesEntry(line.to_owned());
+        let mut src = trimmed.bytes().peekable();
+
+        let ty = next_field(&mut src)?.ok_or_else(err)?;
+        let ty = String::from_utf8(ty).map_err(|_| err())?;
+        let mut chars = ty.chars();
+        let ty = chars
+            .next()
+            .and_then(EntryType::from_char)
+            .ok_or_else(err)?;
+        let mut flags = EntryFlags::default();
+        for c in chars {
+            let flag = match c {
+                '+' => &mut flags.plus,
+                '!' => &mut flags.boot_only,
+                '-' => &mut flags.ignore_errors,
+                '=' => &mut flags.force,
+                '~' => &mut flags.base64,
+                '^' => &mut flags.credential,
+                '$' => &mut flags.purge,
+                _ => return Err(err()),
+            };
+            *flag = true;
+        }
+
+        let path = next_field(&mut src)?.ok_or_else(err)?;
+        let path = PathBuf::from(OsString::from_vec(path));
+        let mode = optional_field(next_field(&mut src)?)?
+            .map(|m| parse_mode(&m).ok_or_else(err))
+            .transpose()?;
+        let user = optional_field(next_field(&mut src)?)?;
+        let group = optional_field(next_field(&mut src)?)?;
+        let age = optional_field(next_field(&mut src)?)?;
+        // The argument is the remainder of the line, which may contain whitespace
+        while src.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        let argument = if src.peek().is_some() {
+            let mut r = Vec::new();
+            impl_unescape_path_until(&mut src, &mut r, EndOfRecord::End)?;
+            optional_field(Some(r))?
+        } else {
+            None
+        };
+
+        Ok(Some(Self {
+            ty,
+            flags,
+            path,
+            mode,
+            user,
+            group,
+            age,
+            argument,
+        }))
+    }
+}
+
+/// The values of the specifiers which are specific to tmpfiles.d; these are
+/// fixed for the system instance of `systemd-tmpfiles`.
+fn tmpfiles_specifier(c: char) -> Option<&'static str> {
+    let r = match c {
+        'C' => "/var/cache",
+        'E' => "/etc",
+        'g' | 'u' => "root",
+        'G' | 'U' => "0",
+        'h' => "/root",
+        'L' => "/var/log",
+        'S' => "/var/lib",
+        't' => "/run",
+        _ => return None,
+    };
+    Some(r)
+}
+
+impl TmpfilesEntry {
+    /// Expand the specifiers in the path and argument of this entry.
+    pub fn expand(&mut self, specifiers: &Specifiers) -> Result<()> {
+        if let Some(path) = self.path.to_str().filter(|p| p.contains('%')) {
+            self.path = specifiers.expand_with(path, tmpfiles_specifier)?.into();
+        }
+        // Encoded arguments are passed through verbatim
+        let verbatim = self.flags.base64 || self.flags.credential;
+        if let Some(arg) = self.argument.as_mut().filter(|_| !verbatim) {
+            *arg = specifiers.expand_with(arg, tmpfiles_specifier)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let e = TmpfilesEntry::parse("d /var/lib/foo 0750 foo foo 10d")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            e,
+            TmpfilesEntry {
+                ty: EntryType::CreateDirectory,
+                flags: Default::default(),
+                path: "/var/lib/foo".into(),
+                mode: Some(EntryMode {
+                    mode: 0o750,
+                    masked: false,
+                    only_create: false
+                }),
+                user: Some("foo".into()),
+                group: Some("foo".into()),
+                age: Some("10d".into()),
+                argument: None,
+            }
+        );
+
+        let e = TmpfilesEntry::parse(r#"f+! "/var/lib/a b" ~:0644 - - - hello world\n"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(e.ty, EntryType::CreateFile);
+        assert!(e.flags.plus && e.flags.boot_only && !e.flags.ignore_errors);
+        assert_eq!(e.path, PathBuf::from("/var/lib/a b"));
+        let mode = e.mode.unwrap();
+        assert!(mode.masked && mode.only_create);
+        assert_eq!(mode.mode, 0o644);
+        assert_eq!(e.user, None);
+        assert_eq!(e.argument.as_deref(), Some("hello world\n"));
+
+        let e = TmpfilesEntry::parse("L /var/run - - - - ../run")
+            .unwrap()
+            .unwrap();
+        assert_eq!(e.ty, EntryType::CreateSymlink);
+        assert_eq!(e.ty.as_char(), 'L');
+        assert_eq!(e.argument.as_deref(), Some("../run"));
+
+        let e = TmpfilesEntry::parse("d$ /var/cache/foo").unwrap().unwrap();
+        assert!(e.flags.purge && !e.flags.plus);
+
+        let e = TmpfilesEntry::parse("  R /var/tmp/foo").unwrap().unwrap();
+        assert_eq!(e.ty, EntryType::RemoveRecursive);
+        assert_eq!(e.mode, None);
+
+        for empty in ["", "   ", "# comment", "  # indented comment"] {
+            assert!(TmpfilesEntry::parse(empty).unwrap().is_none());
+        }
+        for invalid in [
+            "d",
+            "y /foo",
+            "d? /foo",
+            "d /foo 0999",
+            "d /foo 07777777",
+            "d \"/foo",
+        ] {
+            assert!(TmpfilesEntry::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let s = Specifiers {
+            os_release: [("ID".to_owned(), "fedora".to_owned())].into(),
+            ..Default::default()
+        };
+        let mut e = TmpfilesEntry::parse("L %t/foo - - - - %S/%o")
+            .unwrap()
+            .unwrap();
+        e.expand(&s).unwrap();
+        assert_eq!(e.path, PathBuf::from("/run/foo"));
+        assert_eq!(e.argument.as_deref(), Some("/var/lib/fedora"));
+        let mut e = TmpfilesEntry::parse("f~ /var/foo - - - - JXU=")
+            .unwrap()
+            .unwrap();
+        e.expand(&s).unwrap();
+        assert_eq!(e.argument.as_deref(), Some("JXU="));
+        let mut e = TmpfilesEntry::parse("d /var/lib/%m").unwrap().unwrap();
+        assert!(matches!(e.expand(&s), Err(Error::UnresolvedSpecifier('m'))));
+        let mut e = TmpfilesEntry::parse("d /var/lib/%Y").unwrap().unwrap();
+        assert!(matches!(e.expand(&s), Err(Error::UnknownSpecifier('Y'))));
+    }
+}
@@ -0,0 +1,320 @@
+//! Offline evaluation of tmpfiles.d entries.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use crate::entry::{EntryType, TmpfilesEntry};
+
+/// The default directory holding the sources for `L` and `C` entries without an argument.
+const FACTORY_DIR: &str = "/usr/share/factory";
+
+/// The kind of a filesystem object created by tmpfiles.d.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A directory (or subvolume)
+    Directory,
+    /// A regular file. The contents are unset if they come from a credential
+    /// or are base64 encoded.
+    File {
+        /// The contents to write
+        contents: Option<Vec<u8>>,
+    },
+    /// A symbolic link
+    Symlink {
+        /// The link target
+        target: PathBuf,
+    },
+    /// A named pipe
+    Fifo,
+    /// A character device
+    CharDevice {
+        /// The device number, as `major:minor`
+        device: Option<String>,
+    },
+    /// A block device
+    BlockDevice {
+        /// The device number, as `major:minor`
+        device: Option<String>,
+    },
+    /// A (possibly recursive) copy of a path in the root
+    Copy {
+        /// The absolute source path
+        source: PathBuf,
+    },
+}
+
+/// A filesystem object created by tmpfiles.d.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// What to create
+    pub kind: NodeKind,
+    /// The access mode
+    pub mode: u32,
+    /// The owning user name or numeric ID
+    pub user: String,
+    /// The owning group name or numeric ID
+    pub group: String,
+}
+
+impl Node {
+    fn new(kind: NodeKind, mode: u32) -> Self {
+        Self {
+            kind,
+            mode,
+            user: "root".into(),
+            group: "root".into(),
+        }
+    }
+}
+
+/// Whether an entry can be evaluated offline; we only model creating
+/// filesystem objects and adjusting their mode and ownership.
+fn is_modeled(ty: EntryType) -> bool {
+    ty.is_create() || matches!(ty, EntryType::AdjustMode | EntryType::AdjustModeRecursive)
+}
+
+fn default_mode(ty: EntryType) -> u32 {
+    match ty {
+        EntryType::CreateDirectory
+        | EntryType::CreateOrCleanDirectory
+        | EntryType::CleanDirectory
+        | EntryType::CreateSubvolume
+        | EntryType::CreateSubvolumeInheritQuota
+        | EntryType::CreateSubvolumeNewQuota => 0o755,
+        EntryType::CreateSymlink => 0o777,
+        _ => 0o644,
+    }
+}
+
+fn factory_path(path: &Path) -> PathBuf {
+    let rel = path.strip_prefix("/").unwrap_or(path);
+    Path::new(FACTORY_DIR).join(rel)
+}
+
+/// Set the mode and ownership of a node as requested by `entry`.
+fn adjust(node: &mut Node, entry: &TmpfilesEntry, created: bool) {
+    if let Some(mode) = entry.mode {
+        if created || !mode.only_create {
+            node.mode = if mode.masked && !created {
+                node.mode & mode.mode
+            } else {
+                mode.mode
+            };
+        }
+    }
+    if let Some(user) = entry.user.as_ref() {
+        node.user = user.clone();
+    }
+    if let Some(group) = entry.group.as_ref() {
+        node.group = group.clone();
+    }
+}
+
+/// Add a node for a creating entry, along with any missing parent directories
+/// underneath `prefix`.
+fn apply_create(tree: &mut BTreeMap<PathBuf, Node>, prefix: &Path, entry: &TmpfilesEntry) {
+    let path = &entry.path;
+    let kind = match entry.ty {
+        EntryType::CreateFile => NodeKind::File {
+            contents: (!(entry.flags.credential || entry.flags.base64))
+                .then(|| entry.argument.clone().unwrap_or_default().into_bytes()),
+        },
+        // These only operate on existing objects, and nothing exists yet when
+        // the entry for a path is processed.
+        EntryType::WriteFile | EntryType::CleanDirectory => return,
+        EntryType::CreateDirectory
+        | EntryType::CreateOrCleanDirectory
+        | EntryType::CreateSubvolume
+        | EntryType::CreateSubvolumeInheritQuota
+        | EntryType::CreateSubvolumeNewQuota => NodeKind::Directory,
+        EntryType::CreateFifo => NodeKind::Fifo,
+        EntryType::CreateSymlink => NodeKind::Symlink {
+            target: entry
+                .argument
+                .as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| factory_path(path)),
+        },
+        EntryType::CreateCharDevice => NodeKind::CharDevice {
+            device: entry.argument.clone(),
+        },
+        EntryType::CreateBlockDevice => NodeKind::BlockDevice {
+            device: entry.argument.clone(),
+        },
+        EntryType::Copy => NodeKind::Copy {
+            source: entry
+                .argument
+                .as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| factory_path(path)),
+        },
+        // SAFETY: Only creating entries are passed here
+        _ => unreachable!(),
+    };
+    let parents = path
+        .ancestors()
+        .skip(1)
+        .take_while(|p| *p != prefix && p.starts_with(prefix))
+        .collect::<Vec<_>>();
+    let blocked = parents.iter().any(|p| {
+        tree.get(*p)
+            .is_some_and(|n: &Node| n.kind != NodeKind::Directory)
+    });
+    if blocked {
+        // This would fail at runtime as well
+        return;
+    }
+    for parent in parents {
+        tree.entry(parent.to_owned())
+            .or_insert_with(|| Node::new(NodeKind::Directory, 0o755));
+    }
+    let mut node = Node::new(kind, default_mode(entry.ty));
+    adjust(&mut node, entry, true);
+    tree.insert(path.clone(), node);
+}
+
+/// Compute the filesystem objects which `systemd-tmpfiles --create --boot`
+/// creates at or underneath `prefix` (e.g. `/var`), starting from an empty tree.
+///
+/// Entries are expected in configuration order, with specifiers expanded
+/// (see [`crate::read_entries`]). As with `systemd-tmpfiles`, only the first
+/// creating and the first adjusting line for each path are used, and parent
+/// directories are processed before their contents. Removal, cleanup, extended
+/// attributes, file attributes and ACLs are not modeled.
+pub fn evaluate(entries: &[TmpfilesEntry], prefix: &Path) -> BTreeMap<PathBuf, Node> {
+    let mut seen_create = BTreeSet::new();
+    let mut seen_adjust = BTreeSet::new();
+    let mut selected = entries
+        .iter()
+        .filter(|e| is_modeled(e.ty) && e.path.starts_with(prefix))
+        .filter(|e| {
+            let seen = if e.ty.is_create() {
+                &mut seen_create
+            } else {
+                &mut seen_adjust
+            };
+            seen.insert(e.path.as_path())
+        })
+        .collect::<Vec<_>>();
+    // Parents before children; for the same path, create before adjusting
+    selected.sort_by(|a, b| {
+        a.path
+            .cmp(&b.path)
+            .then_with(|| b.ty.is_create().cmp(&a.ty.is_create()))
+    });
+
+    let mut tree = BTreeMap::new();
+    for entry in selected {
+        match entry.ty {
+            EntryType::AdjustMode => {
+                if let Some(node) = tree.get_mut(&entry.path) {
+                    adjust(node, entry, false);
+                }
+            }
+            EntryType::AdjustModeRecursive => {
+                for (_, node) in tree
+                    .range_mut(entry.path.clone()..)
+                    .take_while(|(p, _)| p.starts_with(&entry.path))
+                {
+                    adjust(node, entry, false);
+                }
+            }
+            _ => apply_create(&mut tree, prefix, entry),
+        }
+    }
+    tree
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Vec<TmpfilesEntry> {
+        s.lines()
+            .filter_map(|l| TmpfilesEntry::parse(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let entries = parse(indoc::indoc! { r#"
+            d /var/lib/foo 0750 foo foo -
+            # Duplicate, ignored
+            d /var/lib/foo 0700 bar bar -
+            f /var/lib/foo/bar/config 0600 - - - key=value\n
+            # Only modifies existing files
+            w /var/lib/foo/missing - - - - ignored
+            L /var/lib/foo/link - - - - ../other
+            L /var/lib/factory
+            e /var/lib/nonexistent 0700
+            Z /var/lib/foo - baz - -
+            d /run/foo 0755 - - -
+            C /var/lib/copy - - - - /usr/share/copy
+            r /var/lib/foo/link
+            p /var/lib/fifo 0600
+            x /var/tmp/foo
+        "# });
+        let tree = evaluate(&entries, Path::new("/var"));
+        let summary = tree
+            .iter()
+            .map(|(p, n)| {
+                let kind = match &n.kind {
+                    NodeKind::Directory => "dir".to_owned(),
+                    NodeKind::File { contents } => {
+                        format!(
+                            "file {:?}",
+                            String::from_utf8_lossy(contents.as_ref().unwrap())
+                        )
+                    }
+                    NodeKind::Symlink { target } => format!("link {}", target.display()),
+                    NodeKind::Fifo => "fifo".to_owned(),
+                    NodeKind::Copy { source } => format!("copy {}", source.display()),
+                    o => format!("{o:?}"),
+                };
+                format!("{} {kind} {:o} {}:{}", p.display(), n.mode, n.user, n.group)
+            })
+            .collect::<Vec<_>>();
+        similar_asserts::assert_eq!(
+            summary,
+            [
+                "/var/lib dir 755 root:root",
+                "/var/lib/copy copy /usr/share/copy 644 root:root",
+                "/var/lib/factory link /usr/share/factory/var/lib/factory 777 root:root",
+                "/var/lib/fifo fifo 600 root:root",
+                "/var/lib/foo dir 750 baz:foo",
+                "/var/lib/foo/bar dir 755 root:root",
+                "/var/lib/foo/bar/config file \"key=value\\n\" 600 root:root",
+                "/var/lib/foo/link link ../other 777 root:root",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_evaluate_parents() {
+        let entries = parse(indoc::indoc! { r#"
+            f /var/lib/foo/file
+            L /var/lib/foo - - - - /var/lib/bar
+            f /var/lib/baz/file
+            d /var/lib/baz 0700
+        "# });
+        let tree = evaluate(&entries, Path::new("/var"));
+        let paths = tree.keys().map(|p| p.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "/var/lib",
+                "/var/lib/baz",
+                "/var/lib/baz/file",
+                "/var/lib/foo"
+            ]
+        );
+        assert_eq!(tree[Path::new("/var/lib/baz")].mode, 0o700);
+        assert_eq!(
+            tree[Path::new("/var/lib/foo")].kind,
+            NodeKind::Symlink {
+                target: "/var/lib/bar".into()
+            }
+        );
+    }
+}
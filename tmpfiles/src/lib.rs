@@ -2,12 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use std::collections::{BTreeMap, BTreeSet};
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsStr;
 use std::fmt::Write as WriteFmt;
 use std::io::{BufRead, BufReader, Write as StdWrite};
 use std::iter::Peekable;
 use std::num::NonZeroUsize;
-use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
 use camino::Utf8PathBuf;
@@ -19,7 +19,20 @@ use rustix::fs::Mode;
 use rustix::path::Arg;
 use thiserror::Error;
 
+mod entry;
+mod eval;
+pub use bootc_utils::Specifiers;
+pub use entry::{EntryFlags, EntryMode, EntryType, TmpfilesEntry};
+pub use eval::{evaluate, Node, NodeKind};
+
 const TMPFILESD: &str = "usr/lib/tmpfiles.d";
+/// The tmpfiles.d directories, in order of precedence
+const TMPFILES_DIRS: &[&str] = &[
+    "etc/tmpfiles.d",
+    "run/tmpfiles.d",
+    "usr/local/lib/tmpfiles.d",
+    TMPFILESD,
+];
 /// The path to the file we use for generation
 const BOOTC_GENERATED_PREFIX: &str = "bootc-autogenerated-var";
 
@@ -63,6 +76,12 @@ pub enum Error {
     MalformedTmpfilesPath,
     #[error("Malformed tmpfiles.d line {0}")]
     MalformedTmpfilesEntry(String),
+    #[error("Unknown specifier %{0}")]
+    UnknownSpecifier(char),
+    #[error("Specifier %{0} cannot be resolved offline")]
+    UnresolvedSpecifier(char),
+    #[error("In {path}: {err}")]
+    InFile { path: PathBuf, err: Box<Error> },
     #[error("Unsupported regular file for tmpfiles.d {0}")]
     UnsupportedRegfile(PathBuf),
     #[error("Unsupported file of type {ty:?} for tmpfiles.d {path}")]
@@ -72,6 +91,15 @@ pub enum Error {
     },
 }
 
+impl From<bootc_utils::SpecifierError> for Error {
+    fn from(e: bootc_utils::SpecifierError) -> Self {
+        match e {
+            bootc_utils::SpecifierError::Unknown(c) => Self::UnknownSpecifier(c),
+            bootc_utils::SpecifierError::Unresolved(c) => Self::UnresolvedSpecifier(c),
+        }
+    }
+}
+
 /// The type of Result.
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -105,20 +133,31 @@ fn escape_path<W: std::fmt::Write>(path: &Path, out: &mut W) -> std::fmt::Result
     std::fmt::Result::Ok(())
 }
 
-fn impl_unescape_path_until<I>(
+/// Where an escaped record ends.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum EndOfRecord {
+    /// At the next whitespace
+    Whitespace,
+    /// At the next (unescaped) quote
+    Quote,
+    /// At the end of the input
+    End,
+}
+
+pub(crate) fn impl_unescape_path_until<I>(
     src: &mut Peekable<I>,
     buf: &mut Vec<u8>,
-    end_of_record_is_quote: bool,
+    end: EndOfRecord,
 ) -> Result<()>
 where
     I: Iterator<Item = u8>,
 {
     let should_take_next = |c: &u8| {
         let c = *c;
-        if end_of_record_is_quote {
-            c != b'"'
-        } else {
-            !c.is_ascii_whitespace()
+        match end {
+            EndOfRecord::Whitespace => !c.is_ascii_whitespace(),
+            EndOfRecord::Quote => c != b'"',
+            EndOfRecord::End => true,
         }
     };
     while let Some(c) = src.next_if(should_take_next) {
@@ -134,6 +173,7 @@ where
             b'n' => b'\n',
             b'r' => b'\r',
             b't' => b'\t',
+            b'"' => b'"',
             b'x' => {
                 let mut s = String::new();
                 s.push(
@@ -156,20 +196,6 @@ where
     Ok(())
 }
 
-fn unescape_path<I>(src: &mut Peekable<I>) -> Result<PathBuf>
-where
-    I: Iterator<Item = u8>,
-{
-    let mut r = Vec::new();
-    if let Some(_) = src.next_if_eq(&b'"') {
-        impl_unescape_path_until(src, &mut r, true)?;
-    } else {
-        impl_unescape_path_until(src, &mut r, false)?;
-    };
-    let r = OsString::from_vec(r);
-    Ok(PathBuf::from(r))
-}
-
 /// Canonicalize and escape a path value for tmpfiles.d
 /// At the current time the only canonicalization we do is remap /var/run -> /run.
 fn canonicalize_escape_path<W: std::fmt::Write>(path: &Path, out: &mut W) -> std::fmt::Result {
@@ -241,8 +267,8 @@ pub(crate) fn translate_to_tmpfiles_d(
 pub struct TmpfilesWrittenResult {
     /// Set if we generated entries; this is the count and the path.
     pub generated: Option<(NonZeroUsize, Utf8PathBuf)>,
-    /// Total number of unsupported files that were skipped
-    pub unsupported: usize,
+    /// Unsupported files that were skipped, relative to the root
+    pub unsupported: Vec<PathBuf>,
 }
 
 /// Translate the content of `/var` underneath the target root to use tmpfiles.d.
@@ -282,7 +308,10 @@ pub fn var_to_tmpfiles<U: uzers::Users, G: uzers::Groups>(
 
     // If there's no entries, don't write a file
     let Some(entries_count) = NonZeroUsize::new(entries.len()) else {
-        return Ok(TmpfilesWrittenResult::default());
+        return Ok(TmpfilesWrittenResult {
+            generated: None,
+            unsupported,
+        });
     };
 
     let path = generation.path();
@@ -312,7 +341,7 @@ pub fn var_to_tmpfiles<U: uzers::Users, G: uzers::Groups>(
 
     Ok(TmpfilesWrittenResult {
         generated: Some((entries_count, path)),
-        unsupported: unsupported.len(),
+        unsupported,
     })
 }
 
@@ -457,61 +486,79 @@ pub fn find_missing_tmpfiles_current_root() -> Result<TmpfilesResult> {
     })
 }
 
+/// Find the tmpfiles.d configuration files in the target root, returning their
+/// paths relative to the root in the order they are processed (sorted by file name).
+/// A file in a directory with higher precedence overrides files with the same name
+/// in the others; if it is a symlink to `/dev/null`, it masks them.
+pub fn config_files(rootfs: &Dir) -> Result<Vec<PathBuf>> {
+    Ok(bootc_utils::config_files(rootfs, TMPFILES_DIRS)?)
+}
+
+/// Parse a line of the tmpfiles.d file `path` and expand its specifiers.  Like
+/// `systemd-tmpfiles`, invalid lines are skipped with a warning.  Entries using
+/// specifiers whose value is not known (such as `%b`) are skipped silently, as they
+/// can only be processed at runtime.
+fn parse_line(line: &str, path: &Path, specifiers: &Specifiers) -> Option<TmpfilesEntry> {
+    let r = TmpfilesEntry::parse(line).and_then(|entry| {
+        let Some(mut entry) = entry else {
+            return Ok(None);
+        };
+        entry.expand(specifiers)?;
+        Ok(Some(entry))
+    });
+    match r {
+        Ok(entry) => entry,
+        Err(Error::UnresolvedSpecifier(_)) => None,
+        Err(e) => {
+            tracing::warn!("Ignoring invalid line in /{}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Read and parse all tmpfiles.d entries in the target root in configuration order,
+/// expanding specifiers; see [`parse_line`] for the lines which are skipped.
+pub fn read_entries(rootfs: &Dir, specifiers: &Specifiers) -> Result<Vec<TmpfilesEntry>> {
+    let mut r = Vec::new();
+    for path in config_files(rootfs)? {
+        let f = BufReader::new(rootfs.open(&path)?);
+        for line in f.lines() {
+            let line = line?;
+            r.extend(parse_line(&line, &path, specifiers));
+        }
+    }
+    Ok(r)
+}
+
 /// Read all tmpfiles.d entries in the target directory, and return a mapping
 /// from (file path) => (single tmpfiles.d entry line)
 fn read_tmpfiles(rootfs: &Dir) -> Result<(BTreeMap<PathBuf, String>, BootcTmpfilesGeneration)> {
-    let Some(tmpfiles_dir) = rootfs.open_dir_optional(TMPFILESD)? else {
-        return Ok(Default::default());
-    };
     let mut result = BTreeMap::new();
     let mut generation = BootcTmpfilesGeneration::default();
-    for entry in tmpfiles_dir.entries()? {
-        let entry = entry?;
-        let name = entry.file_name();
-        let (Some(stem), Some(extension)) =
-            (Path::new(&name).file_stem(), Path::new(&name).extension())
-        else {
-            continue;
-        };
-        if extension != "conf" {
-            continue;
-        }
-        if let Ok(s) = stem.as_str() {
-            if s.starts_with(BOOTC_GENERATED_PREFIX) {
-                generation = generation.increment();
-            }
+    let specifiers = Specifiers::from_root(rootfs)?;
+    for path in config_files(rootfs)? {
+        if path.starts_with(TMPFILESD)
+            && path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(BOOTC_GENERATED_PREFIX))
+        {
+            generation = generation.increment();
         }
-        let r = BufReader::new(entry.open()?);
+        let r = BufReader::new(rootfs.open(&path)?);
         for line in r.lines() {
             let line = line?;
-            if line.is_empty() || line.starts_with("#") {
+            // Specifiers which can't be resolved offline can't match
+            // content in the image anyway.
+            let Some(entry) = parse_line(&line, &path, &specifiers) else {
                 continue;
-            }
-            let path = tmpfiles_entry_get_path(&line)?;
-            result.insert(path.to_owned(), line);
+            };
+            result.insert(entry.path, line);
         }
     }
     Ok((result, generation))
 }
 
-fn tmpfiles_entry_get_path(line: &str) -> Result<PathBuf> {
-    let err = || Error::MalformedTmpfilesEntry(line.to_string());
-    let mut it = line.as_bytes().iter().copied().peekable();
-    // Skip leading whitespace
-    while let Some(_) = it.next_if(|c| c.is_ascii_whitespace()) {}
-    // Skip the file type
-    let mut found_ftype = false;
-    while let Some(_) = it.next_if(|c| !c.is_ascii_whitespace()) {
-        found_ftype = true
-    }
-    if !found_ftype {
-        return Err(err());
-    }
-    // Skip trailing whitespace
-    while let Some(_) = it.next_if(|c| c.is_ascii_whitespace()) {}
-    unescape_path(&mut it)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -519,7 +566,7 @@ mod tests {
     use cap_std_ext::cap_std::fs::DirBuilderExt as _;
 
     #[test]
-    fn test_tmpfiles_entry_get_path() {
+    fn test_tmpfiles_entry_path() {
         let cases = [
               ("z /dev/kvm          0666 - kvm -", "/dev/kvm"),
               ("d /run/lock/lvm 0700 root root -", "/run/lock/lvm"),
@@ -531,8 +578,8 @@ mod tests {
             ),
         ];
         for (input, expected) in cases {
-            let path = tmpfiles_entry_get_path(input).unwrap();
-            assert_eq!(path, Path::new(expected), "Input: {input}");
+            let entry = TmpfilesEntry::parse(input).unwrap().unwrap();
+            assert_eq!(entry.path, Path::new(expected), "Input: {input}");
         }
     }
 
@@ -614,7 +661,7 @@ mod tests {
         let w = var_to_tmpfiles(rootfs, userdb, userdb).unwrap();
         let wg = w.generated.as_ref().unwrap();
         assert_eq!(wg.0, NonZeroUsize::new(1).unwrap());
-        assert_eq!(w.unsupported, 0);
+        assert!(w.unsupported.is_empty());
         let gen = gen.increment();
         let autovar_path = &gen.path();
         assert_eq!(autovar_path, &wg.1);
@@ -622,6 +669,49 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_read_entries() -> anyhow::Result<()> {
+        let rootfs = &newroot()?;
+        rootfs.write(
+            Path::new(TMPFILESD).join("journal.conf"),
+            indoc::indoc! { r#"
+            d /var/log/journal 2755 root systemd-journal - -
+            d /var/log/journal/%m 2755 root systemd-journal - -
+            d /var/lib/%o 0755 - - -
+        "#},
+        )?;
+        rootfs.write(Path::new(TMPFILESD).join("masked.conf"), "d /var/masked\n")?;
+        rootfs.create_dir_all("etc/tmpfiles.d")?;
+        rootfs.symlink_contents("/dev/null", "etc/tmpfiles.d/masked.conf")?;
+        let specifiers = Specifiers {
+            os_release: [("ID".to_owned(), "fedora".to_owned())].into(),
+            ..Default::default()
+        };
+        let entries = read_entries(rootfs, &specifiers)?;
+        let paths = entries
+            .iter()
+            .map(|e| e.path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        // The machine ID is not known
+        assert_eq!(paths, ["/var/log/journal", "/var/lib/fedora"]);
+
+        // Invalid lines are skipped
+        rootfs.write(
+            Path::new(TMPFILESD).join("invalid.conf"),
+            "d /var/%Q\nq\nd /var/lib/valid\n",
+        )?;
+        let entries = read_entries(rootfs, &specifiers)?;
+        let paths = entries
+            .iter()
+            .map(|e| e.path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            ["/var/lib/valid", "/var/log/journal", "/var/lib/fedora"]
+        );
+        Ok(())
+    }
+
     /// Verify that we emit ignores for regular files
     #[test]
     fn test_log_regfile() -> anyhow::Result<()> {
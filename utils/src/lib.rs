@@ -8,5 +8,7 @@ mod path;
 pub use path::*;
 mod iterators;
 pub use iterators::*;
+mod systemd_config;
+pub use systemd_config::*;
 mod tracing_util;
 pub use tracing_util::*;
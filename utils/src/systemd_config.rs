@@ -0,0 +1,254 @@
+//! Helpers shared by the parsers of systemd configuration files
+//! such as `tmpfiles.d` and `sysusers.d`.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use thiserror::Error;
+
+/// An error when expanding specifiers.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SpecifierError {
+    /// The specifier is not known
+    #[error("Unknown specifier %{0}")]
+    Unknown(char),
+    /// The value of the specifier is only known at runtime
+    #[error("Specifier %{0} cannot be resolved offline")]
+    Unresolved(char),
+}
+
+/// Values for the specifiers (e.g. `%m`) which may be used in systemd
+/// configuration files. Values which are only known at runtime (such as
+/// the boot ID) are unset by default, and using them is an error.
+#[derive(Debug, Clone, Default)]
+pub struct Specifiers {
+    /// `%a`: The architecture, in the systemd naming
+    pub architecture: Option<String>,
+    /// `%b`: The boot ID
+    pub boot_id: Option<String>,
+    /// `%H`: The hostname
+    pub hostname: Option<String>,
+    /// `%m`: The machine ID
+    pub machine_id: Option<String>,
+    /// `%v`: The kernel release
+    pub kernel_release: Option<String>,
+    /// The contents of os-release, used for e.g. `%o`
+    pub os_release: BTreeMap<String, String>,
+}
+
+/// The architecture we are running on, in the naming used by systemd (see
+/// `systemd-analyze architectures`); the target root is expected to match it.
+fn systemd_architecture() -> Option<&'static str> {
+    let r = match std::env::consts::ARCH {
+        "x86_64" => "x86-64",
+        "x86" => "x86",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64-le",
+        "powerpc64" => "ppc64",
+        "s390x" => "s390x",
+        "riscv64" => "riscv64",
+        "loongarch64" => "loongarch64",
+        _ => return None,
+    };
+    Some(r)
+}
+
+/// Parse the contents of an os-release file.
+pub fn parse_os_release(s: &str) -> BTreeMap<String, String> {
+    s.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.starts_with('#') {
+                return None;
+            }
+            let (k, v) = line.split_once('=')?;
+            let v = v
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| v.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(v);
+            Some((k.to_owned(), v.to_owned()))
+        })
+        .collect()
+}
+
+impl Specifiers {
+    /// Gather the values which can be determined offline from a root filesystem.
+    pub fn from_root(rootfs: &Dir) -> std::io::Result<Self> {
+        let read_optional = |path: &str| -> std::io::Result<Option<String>> {
+            let Some(mut f) = rootfs.open_optional(path)? else {
+                return Ok(None);
+            };
+            let mut s = String::new();
+            std::io::Read::read_to_string(&mut f, &mut s)?;
+            Ok(Some(s))
+        };
+        let mut r = Self::default();
+        let os_release = match read_optional("etc/os-release")? {
+            Some(s) => Some(s),
+            None => read_optional("usr/lib/os-release")?,
+        };
+        if let Some(s) = os_release {
+            r.os_release = parse_os_release(&s);
+        }
+        let nonempty = |s: Option<String>| s.map(|s| s.trim().to_owned()).filter(|s| !s.is_empty());
+        r.machine_id = nonempty(read_optional("etc/machine-id")?);
+        r.hostname = nonempty(read_optional("etc/hostname")?);
+        r.architecture = systemd_architecture().map(ToOwned::to_owned);
+        Ok(r)
+    }
+
+    fn os_release_value(&self, key: &str) -> String {
+        self.os_release.get(key).cloned().unwrap_or_default()
+    }
+
+    /// Expand the specifiers in `s` which are common to all systemd configuration files.
+    pub fn expand(&self, s: &str) -> Result<String, SpecifierError> {
+        self.expand_with(s, |_| None)
+    }
+
+    /// Expand the specifiers in `s`; `extra` provides the values of specifiers
+    /// which are specific to a configuration format.
+    pub fn expand_with(
+        &self,
+        s: &str,
+        extra: impl Fn(char) -> Option<&'static str>,
+    ) -> Result<String, SpecifierError> {
+        let required = |v: &Option<String>, c| v.clone().ok_or(SpecifierError::Unresolved(c));
+        let mut r = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                r.push(c);
+                continue;
+            }
+            let c = chars.next().ok_or(SpecifierError::Unknown('%'))?;
+            let v = match c {
+                '%' => "%".to_owned(),
+                'a' => required(&self.architecture, c)?,
+                'b' => required(&self.boot_id, c)?,
+                'H' => required(&self.hostname, c)?,
+                'l' => {
+                    let h = required(&self.hostname, c)?;
+                    h.split('.').next().unwrap_or_default().to_owned()
+                }
+                'm' => required(&self.machine_id, c)?,
+                'v' => required(&self.kernel_release, c)?,
+                'A' => self.os_release_value("IMAGE_VERSION"),
+                'B' => self.os_release_value("BUILD_ID"),
+                'M' => self.os_release_value("IMAGE_ID"),
+                'o' => self.os_release_value("ID"),
+                'w' => self.os_release_value("VERSION_ID"),
+                'W' => self.os_release_value("VARIANT_ID"),
+                'T' => "/tmp".to_owned(),
+                'V' => "/var/tmp".to_owned(),
+                o => extra(o).ok_or(SpecifierError::Unknown(o))?.to_owned(),
+            };
+            r.push_str(&v);
+        }
+        Ok(r)
+    }
+}
+
+/// Find the `.conf` files in the drop-in directories `dirs` (given in order of
+/// precedence) of the target root, returning their paths relative to the root
+/// in the order they are processed (sorted by file name). A file in a directory
+/// with higher precedence overrides files with the same name in the others; if
+/// it is a symlink to `/dev/null`, it masks them.
+pub fn config_files(rootfs: &Dir, dirs: &[&str]) -> std::io::Result<Vec<PathBuf>> {
+    let mut found = BTreeMap::new();
+    for dir in dirs {
+        let Some(d) = rootfs.open_dir_optional(dir)? else {
+            continue;
+        };
+        for entry in d.entries()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if Path::new(&name).extension() != Some(OsStr::new("conf")) {
+                continue;
+            }
+            if found.contains_key(&name) {
+                continue;
+            }
+            let path = Path::new(dir).join(&name);
+            let masked = entry.file_type()?.is_symlink()
+                && d.read_link_contents(&name)? == Path::new("/dev/null");
+            found.insert(name, (!masked).then_some(path));
+        }
+    }
+    Ok(found.into_values().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    #[test]
+    fn test_specifiers() {
+        let mut s = Specifiers {
+            os_release: parse_os_release("ID=fedora\nVERSION_ID=\"41\"\n# comment\n"),
+            ..Default::default()
+        };
+        assert_eq!(s.expand("%T/%o-%w/%%").unwrap(), "/tmp/fedora-41/%");
+        assert_eq!(s.expand("%B").unwrap(), "");
+        assert_eq!(
+            s.expand("/var/lib/%m"),
+            Err(SpecifierError::Unresolved('m'))
+        );
+        assert_eq!(s.expand("%Y"), Err(SpecifierError::Unknown('Y')));
+        assert_eq!(s.expand("%"), Err(SpecifierError::Unknown('%')));
+        assert_eq!(
+            s.expand_with("%Y/%o", |c| (c == 'Y').then_some("/y"))
+                .unwrap(),
+            "/y/fedora"
+        );
+        s.machine_id = Some("abcd".into());
+        s.hostname = Some("node1.example.com".into());
+        assert_eq!(s.expand("%m-%l").unwrap(), "abcd-node1");
+    }
+
+    #[test]
+    fn test_specifiers_from_root() {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+        td.create_dir_all("usr/lib").unwrap();
+        td.write("usr/lib/os-release", "ID='fedora'\n").unwrap();
+        td.create_dir("etc").unwrap();
+        td.write("etc/machine-id", "abcd\n").unwrap();
+        let s = Specifiers::from_root(&td).unwrap();
+        assert_eq!(s.expand("%m-%o").unwrap(), "abcd-fedora");
+        assert_eq!(s.expand("%b"), Err(SpecifierError::Unresolved('b')));
+        assert_eq!(s.expand("%H"), Err(SpecifierError::Unresolved('H')));
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(s.expand("%a").unwrap(), "x86-64");
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(s.expand("%a").unwrap(), "arm64");
+    }
+
+    #[test]
+    fn test_config_files() {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+        td.create_dir_all("usr/lib/foo.d").unwrap();
+        td.create_dir_all("etc/foo.d").unwrap();
+        for name in ["a.conf", "b.conf", "c.conf", "README"] {
+            td.write(Path::new("usr/lib/foo.d").join(name), "").unwrap();
+        }
+        td.write("etc/foo.d/b.conf", "").unwrap();
+        td.symlink_contents("/dev/null", "etc/foo.d/c.conf")
+            .unwrap();
+        td.write("etc/foo.d/0.conf", "").unwrap();
+        let files = config_files(&td, &["etc/foo.d", "run/foo.d", "usr/lib/foo.d"]).unwrap();
+        assert_eq!(
+            files,
+            [
+                Path::new("etc/foo.d/0.conf"),
+                Path::new("usr/lib/foo.d/a.conf"),
+                Path::new("etc/foo.d/b.conf"),
+            ]
+        );
+    }
+}